@@ -0,0 +1,25 @@
+// The command line is shared with the binary through include!, since a build script can't depend
+// on the library it builds. The binary reads fields the man page has no use for.
+#![allow(dead_code)]
+
+use std::{env, fs::File, io, path::Path};
+
+use clap::CommandFactory;
+
+
+/// The option values the command line parses into, defined in the library
+mod repo_cleaner {
+    include!("src/options.rs");
+}
+
+include!("src/cli.rs");
+
+/// Render the man page into OUT_DIR, for `repo_cleaner man` to print
+fn main() -> io::Result<()> {
+    println!("cargo:rerun-if-changed=src/cli.rs");
+    println!("cargo:rerun-if-changed=src/options.rs");
+
+    let out = env::var_os("OUT_DIR").expect("cargo sets OUT_DIR");
+    let mut page = File::create(Path::new(&out).join("repo_cleaner.1"))?;
+    clap_mangen::Man::new(Args::command()).render(&mut page)
+}
@@ -0,0 +1,82 @@
+use std::{collections::HashSet, path::Path};
+
+use eyre::{Context, Result};
+use git2::Repository;
+use serde::Serialize;
+
+use crate::git::is_quarantined;
+
+
+/// The emails a repository's history may contain, from a file with one entry per line.
+///
+/// An entry is either a whole email, a domain like `example.com`, or `*.example.com` to also allow
+/// every subdomain. Matching ignores case, and blank lines and lines starting with `#` are skipped.
+#[derive(Debug, Clone, Default)]
+pub struct Allowlist {
+    emails: HashSet<String>,
+    domains: HashSet<String>,
+    /// Domains whose subdomains are allowed too
+    parents: Vec<String>,
+}
+
+/// A commit whose author or committer email isn't on the allowlist
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DisallowedEmail {
+    pub commit: String,
+    /// `author` or `committer`
+    pub field: &'static str,
+    pub email: String,
+}
+
+impl Allowlist {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .wrap_err(format!("Unable to read allowlist {path:?}"))?;
+
+        let mut allowlist = Allowlist::default();
+        for entry in text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            let entry = entry.to_lowercase();
+            if entry.contains('@') {
+                allowlist.emails.insert(entry);
+            } else if let Some(parent) = entry.strip_prefix("*.") {
+                allowlist.domains.insert(parent.to_string());
+                allowlist.parents.push(parent.to_string());
+            } else {
+                allowlist.domains.insert(entry);
+            }
+        }
+        Ok(allowlist)
+    }
+
+    pub fn allows(&self, email: &str) -> bool {
+        let email = email.to_lowercase();
+        let domain = email.rsplit_once('@').map_or("", |(_, domain)| domain);
+        self.emails.contains(&email)
+            || self.domains.contains(domain)
+            || self.parents.iter().any(|parent| domain.strip_suffix(parent.as_str()).is_some_and(|sub| sub.ends_with('.')))
+    }
+
+    /// Every author and committer email reachable from any ref of `repository` that isn't allowed,
+    /// oldest commit first
+    pub fn scan(&self, repository: &Repository) -> Result<Vec<DisallowedEmail>> {
+        let mut walk = repository.revwalk()?;
+        walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+        for reference in repository.references()?.filter_map(|r| r.ok()).filter(|r| !r.name().is_some_and(is_quarantined)) {
+            if let Ok(commit) = reference.peel_to_commit() {
+                walk.push(commit.id())?;
+            }
+        }
+
+        let mut disallowed = Vec::new();
+        for oid in walk {
+            let commit = repository.find_commit(oid?)?;
+            for (field, signature) in [("author", commit.author()), ("committer", commit.committer())] {
+                let email = String::from_utf8_lossy(signature.email_bytes()).into_owned();
+                if !self.allows(&email) {
+                    disallowed.push(DisallowedEmail { commit: commit.id().to_string(), field, email });
+                }
+            }
+        }
+        Ok(disallowed)
+    }
+}
@@ -0,0 +1,122 @@
+use std::{collections::{BTreeMap, HashMap}, fmt::Write, path::Path};
+
+use eyre::{Context, Result};
+use git2::{Oid, Repository};
+use serde::Serialize;
+
+
+/// How many commits an identity authored before and after the rewrite, like `git shortlog -sne`
+/// run on both histories
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct IdentityStats {
+    /// `Name <email>`, as the author of commits
+    pub identity: String,
+    pub commits_before: usize,
+    pub commits_after: usize,
+    /// The other identities its commits were authored as before the rewrite, with how many each
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub rewritten_from: BTreeMap<String, usize>,
+}
+
+/// How many commits a `merge_identities` entry folded into its canonical identity
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct MergeReport {
+    /// The canonical identity, as `Name <email>`
+    pub identity: String,
+    /// Commits authored as another identity before the rewrite, and as this one after it
+    pub commits_folded: usize,
+    /// The identities folded in, with how many commits each
+    pub merged_from: BTreeMap<String, usize>,
+}
+
+fn author(repository: &Repository, oid: Oid) -> Result<String> {
+    let commit = repository.find_commit(oid)?;
+    let author = commit.author();
+    Ok(format!("{} <{}>", String::from_utf8_lossy(author.name_bytes()), String::from_utf8_lossy(author.email_bytes())))
+}
+
+/// The author of every commit reachable from `tips`, recorded before rewriting since filter-repo
+/// drops the original objects
+pub fn authors(repository: &Repository, tips: &[Oid]) -> Result<HashMap<Oid, String>> {
+    let mut walk = repository.revwalk()?;
+    for tip in tips {
+        walk.push(*tip)?;
+    }
+
+    let mut authors = HashMap::new();
+    for oid in walk {
+        let oid = oid?;
+        authors.insert(oid, author(repository, oid)?);
+    }
+    Ok(authors)
+}
+
+/// Every identity that authored any of the commits in `before`, or any of what `commit_map`
+/// rewrote them to in `repository`, most commits after the rewrite first. Commits missing from
+/// `commit_map` weren't rewritten, and pruned ones count for no one afterwards.
+pub fn compare(repository: &Repository, before: &HashMap<Oid, String>, commit_map: &HashMap<String, String>) -> Result<Vec<IdentityStats>> {
+    let pruned = Oid::zero().to_string();
+    let mut stats = BTreeMap::new();
+    for (oid, old) in before {
+        entry(&mut stats, old).commits_before += 1;
+
+        let new = match commit_map.get(&oid.to_string()) {
+            Some(new) if *new == pruned => continue,
+            Some(new) => author(repository, Oid::from_str(new)?)?,
+            None => old.clone(),
+        };
+        let stat = entry(&mut stats, &new);
+        stat.commits_after += 1;
+        if new != *old {
+            *stat.rewritten_from.entry(old.clone()).or_default() += 1;
+        }
+    }
+
+    let mut stats = stats.into_values().collect::<Vec<_>>();
+    stats.sort_by(|a, b| b.commits_after.cmp(&a.commits_after).then_with(|| a.identity.cmp(&b.identity)));
+    Ok(stats)
+}
+
+/// What was folded into each of the `canonical` identities, according to `stats` from
+/// [`compare`]. Identities nothing was folded into are left out.
+pub fn merges(stats: &[IdentityStats], canonical: &[String]) -> Vec<MergeReport> {
+    stats.iter()
+        .filter(|stat| canonical.contains(&stat.identity) && !stat.rewritten_from.is_empty())
+        .map(|stat| MergeReport {
+            identity: stat.identity.clone(),
+            commits_folded: stat.rewritten_from.values().sum(),
+            merged_from: stat.rewritten_from.clone(),
+        })
+        .collect()
+}
+
+fn entry<'a>(stats: &'a mut BTreeMap<String, IdentityStats>, identity: &str) -> &'a mut IdentityStats {
+    stats.entry(identity.to_string()).or_insert_with(|| IdentityStats {
+        identity: identity.to_string(),
+        commits_before: 0,
+        commits_after: 0,
+        rewritten_from: BTreeMap::new(),
+    })
+}
+
+/// Write `ATTRIBUTION.md` into `dir`, listing who authored how many commits of `repository` before
+/// and after the rewrite, so anyone whose identity was rewritten can see exactly what changed
+pub fn write(dir: &Path, repository: &str, stats: &[IdentityStats]) -> Result<()> {
+    std::fs::create_dir_all(dir)
+        .wrap_err(format!("Unable to create migration directory {dir:?}"))?;
+
+    let mut text = format!("# Authorship of {repository} before and after rewriting\n\n");
+    text += "Commits by each author, as `git shortlog -sne` counts them, grouped by the identity they are attributed to after the rewrite.\n\n";
+    text += "| Author | Commits before | Commits after | Rewritten from |\n| --- | ---: | ---: | --- |\n";
+    for stat in stats {
+        let from = stat.rewritten_from.iter()
+            .map(|(old, count)| format!("`{}` ({count})", old.replace('|', "\\|")))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let _ = writeln!(text, "| `{}` | {} | {} | {from} |", stat.identity.replace('|', "\\|"), stat.commits_before, stat.commits_after);
+    }
+
+    let path = dir.join("ATTRIBUTION.md");
+    std::fs::write(&path, text)
+        .wrap_err(format!("Unable to write attribution report to {path:?}"))
+}
@@ -0,0 +1,71 @@
+#[cfg(feature = "gix")]
+pub use self::gix::{clone as gix_clone, fetch as gix_fetch, GixError};
+
+#[cfg(feature = "gix")]
+mod gix {
+    use std::path::Path;
+
+    use eyre::Result;
+    use gix::{progress::Discard, remote::Direction};
+
+    use crate::git::is_transient;
+
+
+    /// A gitoxide error, flattened so it can be checked for transient network problems
+    #[derive(Debug, thiserror::Error)]
+    #[error("{0}")]
+    pub struct GixError(String);
+
+    impl GixError {
+        pub fn is_retryable(&self) -> bool {
+            is_transient(&self.0)
+        }
+    }
+
+    fn flatten(e: impl std::error::Error + Send + Sync + 'static) -> GixError {
+        GixError(format!("{:#}", eyre::Report::new(e)))
+    }
+
+    /// Clone `url` into `dir` and check out its default branch, unless the clone is `bare`
+    pub fn clone(url: &str, dir: &Path, bare: bool) -> Result<()> {
+        // gitoxide doesn't know the `git+ssh` scheme, which is plain ssh
+        let url = url.strip_prefix("git+").unwrap_or(url);
+
+        if bare {
+            ::gix::prepare_clone_bare(url, dir).map_err(flatten)?
+                .fetch_only(Discard, &::gix::interrupt::IS_INTERRUPTED)
+                .map_err(flatten)?;
+            return Ok(());
+        }
+
+        let mut prepare = ::gix::prepare_clone(url, dir).map_err(flatten)?;
+        let (mut checkout, _) = prepare.fetch_then_checkout(Discard, &::gix::interrupt::IS_INTERRUPTED)
+            .map_err(flatten)?;
+        checkout.main_worktree(Discard, &::gix::interrupt::IS_INTERRUPTED)
+            .map_err(flatten)?;
+
+        Ok(())
+    }
+
+    /// Fetch every branch, tag, and notes ref of the default remote into an existing clone, with
+    /// `force_tags` replacing tags that differ from the remote's
+    pub fn fetch(dir: &Path, force_tags: bool) -> Result<()> {
+        let repository = ::gix::open(dir).map_err(flatten)?;
+        let remote = repository.find_default_remote(Direction::Fetch)
+            .ok_or_else(|| GixError("Repository has no remote to fetch from".to_string()))?
+            .map_err(flatten)?
+            .with_refspecs(Some(crate::git::NOTES_REFSPEC), Direction::Fetch)
+            .map_err(flatten)?;
+        let remote = if force_tags {
+            remote.with_refspecs(Some(crate::refresh::TAGS_REFSPEC), Direction::Fetch).map_err(flatten)?
+        } else {
+            remote
+        };
+
+        remote.connect(Direction::Fetch).map_err(flatten)?
+            .prepare_fetch(Discard, Default::default()).map_err(flatten)?
+            .receive(Discard, &::gix::interrupt::IS_INTERRUPTED).map_err(flatten)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,276 @@
+use std::{collections::BTreeMap, fs::File, io::{ErrorKind, Read}, path::{Path, PathBuf}, process::{Command, Stdio}};
+
+use eyre::{bail, eyre, Context, Result};
+use git2::Repository;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::git::git;
+
+
+/// How backups are encrypted at rest. Backups hold exactly the identities being removed, so they
+/// shouldn't be left lying around in plain text.
+///
+/// The tar is streamed straight into the encrypting tool, so it never touches the disk unencrypted.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BackupEncryption {
+    /// Encrypt to these age recipients with `age`
+    Age(Vec<String>),
+    /// Encrypt to these GPG key ids or emails with `gpg`
+    Gpg(Vec<String>),
+}
+
+impl BackupEncryption {
+    fn extension(&self) -> &'static str {
+        match self {
+            BackupEncryption::Age(_) => "tar.age",
+            BackupEncryption::Gpg(_) => "tar.gpg",
+        }
+    }
+
+    fn command(&self, output: &Path) -> Command {
+        let (mut command, recipients) = match self {
+            BackupEncryption::Age(recipients) => (Command::new("age"), recipients),
+            BackupEncryption::Gpg(recipients) => {
+                let mut gpg = Command::new("gpg");
+                gpg.args(["--batch", "--yes", "--encrypt"]);
+                (gpg, recipients)
+            },
+        };
+        for recipient in recipients {
+            command.args(["--recipient", recipient]);
+        }
+        command.arg("--output").arg(output);
+        command
+    }
+}
+
+/// What a backup holds, written next to it as evidence of what existed before the rewrite, and
+/// checked whenever the backup is verified or restored
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackupManifest {
+    /// The repository as written in the config
+    pub repository: String,
+    pub created_at: String,
+    /// The archive's file name, which is next to the manifest
+    pub archive: String,
+    /// The SHA-256 of the archive as written, after encrypting it if it is encrypted
+    pub sha256: String,
+    pub size: u64,
+    /// Every ref the repository had, and the object it pointed to
+    pub refs: BTreeMap<String, String>,
+    /// The version of repo_cleaner that took the backup
+    pub tool_version: String,
+    /// The SHA-256 of the config the repository was about to be cleaned with
+    pub config_hash: String,
+}
+
+impl BackupManifest {
+    /// Describe `archive`, the backup of `repository` just taken from `dir`
+    pub fn new(repository: &str, dir: &Path, archive: &Path, config_hash: &str) -> Result<Self> {
+        Ok(Self {
+            repository: repository.to_string(),
+            created_at: chrono::Utc::now().to_rfc3339(),
+            archive: archive.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+            sha256: sha256(archive)?,
+            size: std::fs::metadata(archive)?.len(),
+            refs: refs(dir)?.into_iter().map(|(name, oid)| (name, oid.to_string())).collect(),
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            config_hash: config_hash.to_string(),
+        })
+    }
+
+    /// Where the manifest of a backup goes, given the path the backup would have without an extension
+    pub fn path(stem: &Path) -> PathBuf {
+        with_extension(stem, "manifest.json")
+    }
+
+    /// The manifest of the backup at `stem`, if it has one. Backups taken before manifests were
+    /// written don't.
+    pub fn load(stem: &Path) -> Result<Option<Self>> {
+        let path = Self::path(stem);
+        match std::fs::read_to_string(&path) {
+            Ok(text) => Ok(Some(serde_json::from_str(&text).wrap_err(format!("Unable to read backup manifest {path:?}"))?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).wrap_err(format!("Unable to read backup manifest {path:?}")),
+        }
+    }
+
+    pub fn write(&self, stem: &Path) -> Result<()> {
+        let path = Self::path(stem);
+        std::fs::write(&path, serde_json::to_string_pretty(self)?)
+            .wrap_err(format!("Unable to write backup manifest {path:?}"))
+    }
+
+    /// Check that `archive` is exactly the archive described, so a backup that was changed or
+    /// corrupted since is never trusted
+    pub fn check_archive(&self, archive: &Path) -> Result<()> {
+        if sha256(archive)? != self.sha256 {
+            bail!("Backup {archive:?} doesn't match the checksum in its manifest, so it changed since it was taken");
+        }
+        Ok(())
+    }
+
+    /// Check that the repository at `dir` has exactly the refs described
+    pub fn check_refs(&self, dir: &Path) -> Result<()> {
+        let actual = refs(dir)?;
+        for (name, oid) in &self.refs {
+            if actual.get(name).map(git2::Oid::to_string).as_ref() != Some(oid) {
+                bail!("{name} isn't at {oid} as the manifest of {}'s backup says", self.repository);
+            }
+        }
+        if let Some(name) = actual.keys().find(|name| !self.refs.contains_key(*name)) {
+            bail!("{name} isn't in the manifest of {}'s backup", self.repository);
+        }
+        Ok(())
+    }
+}
+
+/// The SHA-256 of the file at `path`, in hex
+fn sha256(path: &Path) -> Result<String> {
+    let mut file = File::open(path)
+        .wrap_err(format!("Unable to open backup {path:?}"))?;
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        context.update(&buffer[..read]);
+    }
+    Ok(context.finish().as_ref().iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// Where the backup of a repository goes, given the path it would have without an extension
+pub fn path(stem: &Path, encryption: Option<&BackupEncryption>) -> PathBuf {
+    with_extension(stem, encryption.map_or("tar", BackupEncryption::extension))
+}
+
+/// The backup of a repository, however it was encrypted, given the path it would have without an extension
+pub fn find(stem: &Path) -> Option<PathBuf> {
+    ["tar", "tar.age", "tar.gpg"].into_iter()
+        .map(|extension| with_extension(stem, extension))
+        .find(|path| path.exists())
+}
+
+/// `stem` with `extension` added. Unlike [`Path::with_extension`], this keeps dots already in repository names.
+pub(crate) fn with_extension(stem: &Path, extension: &str) -> PathBuf {
+    let mut path = stem.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+    path.into()
+}
+
+/// Archive the whole of `dir` into a tar file at `archive`, creating parent directories as needed,
+/// and encrypting it if configured
+pub fn archive(dir: &Path, archive: &Path, encryption: Option<&BackupEncryption>) -> Result<()> {
+    if let Some(parent) = archive.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let Some(encryption) = encryption else {
+        let backup_file = File::create(archive)
+            .wrap_err(format!("Unable to create backup {archive:?}"))?;
+        let mut backup_tar = tar::Builder::new(backup_file);
+        backup_tar.append_dir_all(".", dir)?;
+        backup_tar.finish()?;
+        return Ok(());
+    };
+
+    let mut child = encryption.command(archive)
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .wrap_err("Unable to run the backup encryption tool. Is it installed?")?;
+
+    let mut backup_tar = tar::Builder::new(child.stdin.take().expect("stdin is piped"));
+    let written = backup_tar.append_dir_all(".", dir).and_then(|_| backup_tar.finish());
+    // Closing stdin lets the tool finish
+    drop(backup_tar);
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let _ = std::fs::remove_file(archive);
+        bail!("Unable to encrypt backup {archive:?}: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    written.wrap_err(format!("Unable to write backup {archive:?}"))?;
+
+    Ok(())
+}
+
+/// Unpack the backup at `archive` into `dir`, decrypting it first if needed.
+/// age backups need the identity they were encrypted to, while GPG uses the keyring.
+pub fn unpack(archive: &Path, dir: &Path, age_identity: Option<&Path>) -> Result<()> {
+    let name = archive.to_string_lossy();
+    let command = if name.ends_with(".age") {
+        let identity = age_identity
+            .ok_or_else(|| eyre!("{archive:?} is encrypted with age, so decrypting it needs --age-identity"))?;
+        let mut age = Command::new("age");
+        age.arg("--decrypt").arg("--identity").arg(identity).arg(archive);
+        Some(age)
+    } else if name.ends_with(".gpg") {
+        let mut gpg = Command::new("gpg");
+        gpg.args(["--batch", "--decrypt"]).arg(archive);
+        Some(gpg)
+    } else {
+        None
+    };
+
+    let Some(mut command) = command else {
+        let backup_file = File::open(archive)
+            .wrap_err(format!("Unable to open backup {archive:?}"))?;
+        return tar::Archive::new(backup_file).unpack(dir)
+            .wrap_err(format!("Unable to unpack backup {archive:?}"));
+    };
+
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .wrap_err("Unable to run the backup decryption tool. Is it installed?")?;
+    let unpacked = tar::Archive::new(child.stdout.take().expect("stdout is piped")).unpack(dir);
+
+    let mut stderr = String::new();
+    child.stderr.take().expect("stderr is piped").read_to_string(&mut stderr)?;
+    if !child.wait()?.success() {
+        bail!("Unable to decrypt backup {archive:?}: {}", stderr.trim());
+    }
+    unpacked.wrap_err(format!("Unable to unpack backup {archive:?}"))
+}
+
+/// Check that the backup at `archive` is the one `manifest` describes, can be unpacked, and holds a
+/// complete copy of every ref the manifest lists
+pub fn verify(archive: &Path, manifest: &BackupManifest, age_identity: Option<&Path>) -> Result<()> {
+    manifest.check_archive(archive)?;
+
+    let unpacked = with_extension(archive, "verify");
+    let _ = std::fs::remove_dir_all(&unpacked);
+
+    let result = (|| {
+        unpack(archive, &unpacked, age_identity)?;
+
+        manifest.check_refs(&unpacked)
+            .wrap_err(format!("Backup {archive:?} does not match the repository"))?;
+
+        // Every commit, tree, and blob the refs point to has to be in the backup too
+        git(&unpacked, &["fsck", "--connectivity-only", "--no-progress"])
+            .wrap_err(format!("Backup {archive:?} is missing objects"))?;
+
+        Ok(())
+    })();
+
+    let _ = std::fs::remove_dir_all(&unpacked);
+    result
+}
+
+/// Every direct ref in the repository at `dir`, and what it points to
+fn refs(dir: &Path) -> Result<BTreeMap<String, git2::Oid>> {
+    let repository = Repository::open(dir)?;
+    let refs = repository.references()?
+        .filter_map(|r| r.ok())
+        .filter_map(|r| Some((r.name()?.to_string(), r.target()?)))
+        .collect();
+    Ok(refs)
+}
@@ -0,0 +1,83 @@
+use std::{collections::BTreeMap, path::{Path, PathBuf}};
+
+use eyre::{Context, Result};
+use git2::{Oid, Repository};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::git::git_dir;
+
+
+/// How much of a repository's history the clean phase rewrote
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RewriteScope {
+    /// Every commit
+    #[default]
+    Full,
+    /// Only the commits added since the last clean with the same rules
+    Incremental,
+    /// Nothing, since no branch moved since the last clean with the same rules
+    Unchanged,
+}
+
+/// What the last successful clean of a repository left it as, so a later run with the same rules
+/// only has to rewrite the commits added since
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RewriteCache {
+    /// Hash of every rule and option the history was rewritten with
+    pub fingerprint: String,
+    /// Every selected branch, and the commit the clean phase left it at
+    pub branches: BTreeMap<String, String>,
+}
+
+/// Where the cache is kept, inside the clone so it goes wherever the clone goes
+fn path(repo_dir: &Path) -> PathBuf {
+    git_dir(repo_dir).join("repo-cleaner-cache.json")
+}
+
+impl RewriteCache {
+    /// The cache the last clean left in `repo_dir`, if there is one that can be read
+    pub fn load(repo_dir: &Path) -> Option<Self> {
+        let path = path(repo_dir);
+        let text = std::fs::read_to_string(&path).ok()?;
+        match serde_json::from_str(&text) {
+            Ok(cache) => Some(cache),
+            Err(e) => {
+                debug!("Ignoring unreadable rewrite cache {path:?}: {e}");
+                None
+            },
+        }
+    }
+
+    pub fn save(&self, repo_dir: &Path) -> Result<()> {
+        let path = path(repo_dir);
+        std::fs::write(&path, serde_json::to_string_pretty(self)?)
+            .wrap_err(format!("Unable to write rewrite cache to {path:?}"))
+    }
+
+    /// How much of `repository` has to be rewritten with rules hashing to `fingerprint`, now that
+    /// its selected branches are at `branches`, and the already rewritten commits to leave out.
+    ///
+    /// Everything reachable from a cached tip came out of a rewrite with the same rules, so it
+    /// doesn't need rewriting again, whatever happened to the branches since.
+    pub fn scope(&self, repository: &Repository, fingerprint: &str, branches: &BTreeMap<String, String>) -> (RewriteScope, Vec<Oid>) {
+        if self.fingerprint != fingerprint {
+            return (RewriteScope::Full, Vec::new());
+        }
+        if self.branches == *branches {
+            return (RewriteScope::Unchanged, Vec::new());
+        }
+
+        // Tips that were garbage collected since can't be excluded
+        let tips = self.branches.values()
+            .filter_map(|tip| Oid::from_str(tip).ok())
+            .filter(|tip| repository.find_commit(*tip).is_ok())
+            .collect::<Vec<_>>();
+        if tips.is_empty() {
+            (RewriteScope::Full, tips)
+        } else {
+            (RewriteScope::Incremental, tips)
+        }
+    }
+}
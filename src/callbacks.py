@@ -0,0 +1,292 @@
+# The fixed half of the git-filter-repo callbacks. It is loaded once per filter-repo run, and reads
+# every configured value from the JSON rules file named by REPO_CLEANER_RULES, so nothing from the
+# config ever becomes Python source.
+import atexit, collections, hashlib, hmac, json, os, random, re
+
+with open(os.environ['REPO_CLEANER_RULES'], encoding='utf-8') as f:
+    rules = json.load(f)
+
+# Email keys are already lowercased by repo_cleaner when matching is case insensitive
+emails = rules['emails']
+authors = rules['authors']
+# The fields ("author.email", "committer.name", ...) each rule may change
+fields = set(rules['fields'])
+email_fields = {k: set(f) for k, f in rules['email_fields'].items()}
+# Already in the order they are tried, longest pattern first
+names = [(re.compile(rx), new, set(rules['name_fields'].get(rx, fields))) for rx, new in rules['names']]
+email_flags = re.IGNORECASE if rules['case_insensitive_emails'] else 0
+noreply = rules['noreply']
+identities = [(re.compile(r['name']), re.compile(r['email'], email_flags), r.get('new_name'), r.get('new_email'),
+               set(r['fields']) if r.get('fields') is not None else fields)
+              for r in rules['identities']]
+merges = [(set(m.get('emails', [])), set(m.get('names', [])), m['name'], m['email']) for m in rules['merges']]
+republish = rules['republish']
+preserved = set(rules['preserved'])
+since = rules['since']
+until = rules['until']
+salt = rules['anonymize_salt']
+dates = rules['anonymize_dates']
+strip_signatures = rules['strip_message_signatures']
+trailers = rules['trailers']
+run_id = rules['run_id']
+
+# How many commits each rule matched, and how many tags any did, written out when filter-repo exits
+matches = collections.Counter()
+
+
+@atexit.register
+def write_matches():
+    with open(os.environ['REPO_CLEANER_STATS'], 'w', encoding='utf-8') as f:
+        json.dump(matches, f)
+
+
+gitlinks = {}
+if rules['gitlinks']:
+    with open(rules['gitlinks'], 'rb') as f:
+        gitlinks = dict(l.split() for l in f if l.strip())
+
+
+# Each plugin runs in its own namespace, and must define rewrite(commit)
+plugins = []
+for path in rules['plugins']:
+    namespace = {'__file__': path, '__name__': 'repo_cleaner_plugin'}
+    with open(path, encoding='utf-8') as f:
+        exec(compile(f.read(), path, 'exec'), namespace)
+    if not callable(namespace.get('rewrite')):
+        raise SystemExit('Plugin %s does not define rewrite(commit)' % path)
+    plugins.append((path, namespace['rewrite']))
+
+# The commit fields plugins are given as text, and may change
+META = ('author_name', 'author_email', 'author_date', 'committer_name', 'committer_email', 'committer_date', 'message')
+
+
+def text(value):
+    return value.decode('utf-8', 'replace')
+
+
+def key(email):
+    email = text(email)
+    return email.lower() if rules['case_insensitive_emails'] else email
+
+
+# The optional numeric prefix is the account id, which GitHub keeps when a user is renamed
+NOREPLY = re.compile(r'^(?:(\d+)\+)?([^@+]+)@users\.noreply\.github\.com$', re.IGNORECASE)
+
+
+# The new email of a GitHub noreply address whose username is substituted (usernames are case
+# insensitive), keeping its id prefix when only the username changes, and the id of the rule
+def noreply_email(email):
+    match = NOREPLY.match(text(email))
+    user = match and match.group(2).lower()
+    if user not in noreply:
+        return None, None
+    new = noreply[user]
+    if '@' not in new:
+        new = (match.group(1) + '+' if match.group(1) else '') + new + '@users.noreply.github.com'
+    return new.encode(), 'noreply:' + user
+
+
+# Replacement names and emails may use parts of the identity they matched, like "{local}@example.com".
+# Unknown variables are left as they are, since repo_cleaner refuses to run with any.
+TEMPLATE = re.compile(r'\{\{|\}\}|\{(\w+)\}')
+
+
+def fill(template, name, email):
+    if '{' not in template and '}' not in template:
+        return template.encode()
+    name, email = text(name), text(email)
+    words = name.split()
+    local, _, domain = email.rpartition('@') if '@' in email else (email, '', '')
+    values = {'name': name, 'first': words[0] if words else '', 'last': words[-1] if words else '',
+              'email': email, 'local': local, 'domain': domain}
+    values['first_initial'] = values['first'][:1]
+    values['last_initial'] = values['last'][:1]
+    return TEMPLATE.sub(lambda m: values.get(m.group(1), m.group(0)) if m.group(1) else m.group(0)[0], template).encode()
+
+
+def in_range(date):
+    ts = int(date.split(b' ')[0])
+    return (since is None or ts >= since) and (until is None or ts < until)
+
+
+# Protected emails are never matched, and neither is anything a rule's fields (from its own "fields"
+# or "apply_to", or the config's "apply_to") leave out. When republishing, every other identity
+# becomes the republished one and nothing else is tried. An identity whose email substitution applies
+# to neither of its fields is left alone entirely. Identities with an email or name that is merged
+# become the canonical identity before anything else is tried. Emails are substituted by their old email, or
+# failing that by their GitHub noreply username. Names are matched by their old email first, then by
+# name and email regex pairs, then by name regex alone. Their replacements are filled in from the
+# identity as it was. Returns the new name and email, the ids of
+# the rules that changed anything, and which of "name" and "email" they were allowed to change.
+def rename(name, email, role):
+    k = key(email)
+    if k in preserved:
+        return name, email, [], set()
+    if republish:
+        changes = {part for part in ('name', 'email') if role + '.' + part in fields}
+        if not changes:
+            return name, email, [], set()
+        return (republish['name'].encode() if 'name' in changes else name,
+                republish['email'].encode() if 'email' in changes else email,
+                ['republish'], changes)
+    for i, (merged_emails, merged_names, canonical_name, canonical_email) in enumerate(merges):
+        changes = {part for part in ('name', 'email') if role + '.' + part in fields}
+        if changes and (k in merged_emails or text(name) in merged_names):
+            return (canonical_name.encode() if 'name' in changes else name,
+                    canonical_email.encode() if 'email' in changes else email,
+                    ['merge:%d' % i], changes)
+    own = email_fields.get(k, fields) if k in emails else fields
+    if not own & {role + '.name', role + '.email'}:
+        return name, email, [], set()
+    new_name, new_email, matched, parts = name, email, [], set()
+    if k in emails:
+        if role + '.email' in own:
+            new_email = fill(emails[k], name, email)
+            parts.add('email')
+        if k in authors and role + '.name' in own:
+            new_name = fill(authors[k], name, email)
+            parts.add('name')
+        if parts:
+            matched.append('email:' + k)
+        if k in authors:
+            return new_name, new_email, matched, parts
+    elif role + '.email' in fields:
+        noreply_new, rule = noreply_email(email)
+        if rule:
+            new_email, matched, parts = noreply_new, [rule], {'email'}
+    for i, (name_rx, email_rx, rule_name, rule_email, rule_fields) in enumerate(identities):
+        changes = {part for part, new in (('name', rule_name), ('email', rule_email))
+                   if new is not None and role + '.' + part in rule_fields}
+        if changes and name_rx.search(text(name)) and email_rx.search(text(email)):
+            return (fill(rule_name, name, email) if 'name' in changes else new_name,
+                    fill(rule_email, name, email) if 'email' in changes else new_email,
+                    matched + ['identity:%d' % i], parts | changes)
+    for rx, new, rule_fields in names:
+        if role + '.name' in rule_fields and rx.search(text(name)):
+            return fill(new, name, email), new_email, matched + ['name:' + rx.pattern], parts | {'name'}
+    return new_name, new_email, matched, parts
+
+
+# When anonymizing, the fields matched rules may change are replaced by a pseudonym derived from a
+# salted hash of the email, so the same person always gets the same pseudonym without revealing who they are.
+# A republished identity already reveals no one, so it is kept.
+def identity(name, email, role):
+    new_name, new_email, matched, parts = rename(name, email, role)
+    if not matched or salt is None or republish:
+        return new_name, new_email, matched
+    digest = hashlib.sha256((salt + text(email).lower()).encode()).hexdigest()[:8].encode()
+    return (b'contributor-' + digest if 'name' in parts else name,
+            digest + b'@users.invalid' if 'email' in parts else email, matched)
+
+
+# Dates (b"<timestamp> <+zone>") are rounded down to the start of their window in their own timezone,
+# then optionally moved to a random point in it. The offset is keyed with the secret salt, so reruns
+# produce the same result but nobody without it can work back from the offset to the original date.
+def fuzz(date, seed):
+    if dates is None:
+        return date
+    window = dates['window']
+    ts, tz = date.split(b' ')
+    offset = (-1 if tz.startswith(b'-') else 1) * (int(tz[1:3]) * 3600 + int(tz[3:5]) * 60)
+    local = int(ts) + offset
+    start = local - local % window
+    if dates['randomize']:
+        key = hmac.new(dates['salt'].encode(), seed, hashlib.sha256).digest()
+        start += random.Random(key).randrange(window)
+    return b'%d %s' % (start - offset, tz)
+
+
+# Each plugin gets a dict of the commit's fields (plus its branch and original id, which it can't
+# change), and returns the fields to change, or None to leave the commit alone. Returns the ids of
+# the plugins that changed anything.
+def run_plugins(commit):
+    matched = []
+    for path, rewrite in plugins:
+        meta = {k: text(getattr(commit, k)) for k in META}
+        meta['branch'] = text(commit.branch)
+        meta['original_id'] = text(commit.original_id or b'')
+        new = rewrite(dict(meta))
+        if new is None:
+            continue
+        changed = False
+        for k in META:
+            if k in new and new[k] != meta[k]:
+                setattr(commit, k, new[k].encode())
+                changed = True
+        if changed:
+            matched.append('plugin:' + path)
+    return matched
+
+
+# An ASCII armored signature pasted into a message, with the `gpgsig` a pasted header starts with and
+# the space its continuation lines are indented by
+SIGNATURE = re.compile(rb'^(?:gpgsig(?:-sha256)? )?[ \t]*-----BEGIN PGP SIGNATURE-----$.*?^[ \t]*-----END PGP SIGNATURE-----[ \t]*$\n?',
+                       re.MULTILINE | re.DOTALL)
+
+
+def strip_signature(commit):
+    if not strip_signatures:
+        return []
+    message = SIGNATURE.sub(b'', commit.message)
+    if message == commit.message:
+        return []
+    # Leave no run of blank lines where the signature was, or blank lines where it ended the message
+    message = re.sub(rb'\n{3,}', b'\n\n', message).rstrip()
+    commit.message = message + b'\n' if message else b''
+    return ['signature']
+
+
+# A line of a trailer block, like "Signed-off-by: Alex <alex@example.com>"
+TRAILER = re.compile(rb'^[A-Za-z0-9-]+: ')
+
+
+# The trailers are filled in with the run id and the author as rewritten, and added to the message's
+# own trailer block if it ends with one, as `git interpret-trailers` would. Trailers the message
+# already has aren't added again.
+def add_trailers(commit):
+    if not trailers:
+        return []
+    values = {'run_id': run_id, 'name': text(commit.author_name), 'email': text(commit.author_email)}
+    body = commit.message.rstrip(b'\n')
+    lines = body.split(b'\n')
+    new = [t for t in (TEMPLATE.sub(lambda m: values.get(m.group(1), m.group(0)) if m.group(1) else m.group(0)[0], t).encode()
+                       for t in trailers) if t not in lines]
+    if not new:
+        return []
+    paragraphs = body.rsplit(b'\n\n', 1)
+    if not body:
+        separator = b''
+    elif len(paragraphs) > 1 and all(TRAILER.match(l) for l in paragraphs[-1].split(b'\n')):
+        separator = b'\n'
+    else:
+        separator = b'\n\n'
+    commit.message = body + separator + b'\n'.join(new) + b'\n'
+    return ['trailers']
+
+
+# Commits outside the date range keep their identities, but still get their submodule pointers
+# rewritten so they never point at a commit that is gone
+def rewrite_commit(commit):
+    for change in commit.file_changes:
+        if change.mode == b'160000' and change.blob_id in gitlinks:
+            change.blob_id = gitlinks[change.blob_id]
+
+    if not in_range(commit.author_date):
+        return
+    commit.author_name, commit.author_email, author_rules = identity(commit.author_name, commit.author_email, 'author')
+    if author_rules:
+        commit.author_date = fuzz(commit.author_date, commit.author_email + commit.author_date)
+    commit.committer_name, commit.committer_email, committer_rules = identity(commit.committer_name, commit.committer_email, 'committer')
+    if committer_rules:
+        commit.committer_date = fuzz(commit.committer_date, commit.committer_email + commit.committer_date)
+    # A commit counts once for a rule, even if it matched both identities
+    matches.update(set(author_rules + committer_rules + strip_signature(commit) + run_plugins(commit) + add_trailers(commit)))
+
+
+def rewrite_tag(tag):
+    if tag.tagger_email and in_range(tag.tagger_date):
+        tag.tagger_name, tag.tagger_email, matched = identity(tag.tagger_name, tag.tagger_email, 'tagger')
+        if matched:
+            tag.tagger_date = fuzz(tag.tagger_date, tag.tagger_email + tag.tagger_date)
+            # Tags aren't counted against rules, only whether any needed rewriting
+            matches['tags'] += 1
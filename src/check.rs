@@ -0,0 +1,596 @@
+use std::{fmt, path::Path};
+
+use eyre::Result;
+use regex::Regex;
+use serde::Serialize;
+
+use crate::{config::{canonical_email, canonical_name, fill_template, fill_trailer, parse_date, CloneStrategy, Config, ConfigFormat, DateMode, Field, InterpolationError, read_config, Substitution, toml_to_json}, forge::Provider, ssh::{expand_home, HostKeyChecking}};
+
+
+/// How serious a configuration problem is
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// The configuration must not be used
+    Error,
+    /// The configuration works, but probably doesn't do what was intended
+    Warning,
+}
+
+/// A single problem found in a configuration file
+#[derive(Serialize, Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Path to the offending field, e.g. `repositories[2]`
+    pub field: String,
+    /// Line in the file the problem was found on, if it could be located
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{severity}: ")?;
+        if let Some(line) = self.line {
+            write!(f, "line {line}: ")?;
+        }
+        if !self.field.is_empty() {
+            write!(f, "{}: ", self.field)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Whether any diagnostic is an error
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(|d| d.severity == Severity::Error)
+}
+
+/// Validate a configuration file, and everything it includes, beyond what deserializing it checks.
+///
+/// Only failing to read the file is returned as an error; everything wrong with its contents is
+/// returned as a [`Diagnostic`].
+pub fn check_config(path: &Path) -> Result<Vec<Diagnostic>> {
+    let text = read_config(path)?;
+
+    let mut checker = Checker { text: &text, format: ConfigFormat::of(path, &text), diagnostics: Vec::new() };
+
+    if checker.check_structure() {
+        match Config::load(path) {
+            Ok(config) => checker.check(&config),
+            Err(e) => match e.downcast_ref::<InterpolationError>() {
+                Some(e) => {
+                    for name in &e.missing {
+                        checker.error("", Some(&format!("${{{name}}}")), format!("Environment variable {name} is not set"));
+                    }
+                },
+                None => checker.error("include", Some("\"include\""), format!("{e:#}")),
+            },
+        }
+    }
+
+    // Map iteration order is arbitrary, so present problems in file order
+    checker.diagnostics.sort_by_key(|d| d.line);
+    Ok(checker.diagnostics)
+}
+
+/// Whether `email` looks like a syntactically valid email address
+pub(crate) fn is_valid_email(email: &str) -> bool {
+    let Some((local, domain)) = email.split_once('@') else {
+        return false;
+    };
+
+    !local.is_empty()
+        && !local.starts_with('.')
+        && !local.ends_with('.')
+        && !local.contains("..")
+        && !domain.contains('@')
+        && !domain.split('.').any(str::is_empty)
+        && !email.chars().any(|c| c.is_whitespace() || c.is_control() || is_invisible(c) || matches!(c, '<' | '>' | '"' | ','))
+}
+
+/// Whether git can write `name` into an identity as it is: not blank, and without control
+/// characters, invisible ones, or the angle brackets that delimit the email
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    !name.trim().is_empty() && !name.chars().any(|c| c.is_control() || is_invisible(c) || matches!(c, '<' | '>'))
+}
+
+/// Whether `line` is a single git trailer: a token of letters, digits, and dashes, then `: ` and a value
+/// Deserialize a [`Config`] from `de`, tracking the path to any error and collecting every key it ignores
+fn deserialize<'de, D: serde::Deserializer<'de>>(de: D, track: &mut serde_path_to_error::Track, unknown: &mut Vec<String>) -> Result<Config, D::Error> {
+    serde_ignored::deserialize(serde_path_to_error::Deserializer::new(de, track), |path| unknown.push(path.to_string()))
+}
+
+fn is_trailer(line: &str) -> bool {
+    line.split_once(": ").is_some_and(|(token, value)| {
+        !token.is_empty() && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') && !value.trim().is_empty()
+    }) && !line.chars().any(char::is_control)
+}
+
+/// Zero-width and bidirectional formatting characters, which render as nothing
+fn is_invisible(c: char) -> bool {
+    matches!(c, '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2060}'..='\u{2064}' | '\u{2066}'..='\u{2069}' | '\u{FEFF}')
+}
+
+/// The username of a GitHub noreply address, in either its `12345+user@` or legacy `user@` form
+fn noreply_username(email: &str) -> Option<&str> {
+    let local = email.strip_suffix("@users.noreply.github.com")?;
+    Some(local.split_once('+').map_or(local, |(_, user)| user))
+}
+
+/// Whether `name` could be a GitHub username: up to 39 letters, digits, and single hyphens, not at either end
+fn is_valid_github_username(name: &str) -> bool {
+    (1..=39).contains(&name.len())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && !name.contains("--")
+}
+
+struct Checker<'a> {
+    text: &'a str,
+    format: ConfigFormat,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Checker<'_> {
+    /// Deserialize the file on its own, recording the path to any error as well as every key
+    /// serde ignored. Returns whether the file is structurally valid.
+    fn check_structure(&mut self) -> bool {
+        let mut unknown = Vec::new();
+        let mut track = serde_path_to_error::Track::new();
+        let result = match self.format {
+            ConfigFormat::Json => {
+                let mut de = serde_json::Deserializer::from_str(self.text);
+                deserialize(&mut de, &mut track, &mut unknown).map_err(|e| (e.to_string(), Some(e.line())))
+            },
+            // Checked as the JSON it is loaded as, so dates and times are strings like they are in JSON
+            ConfigFormat::Toml => match toml::from_str(self.text) {
+                Ok(value) => deserialize(toml_to_json(value), &mut track, &mut unknown).map_err(|e| (e.to_string(), None)),
+                Err(e) => Err((e.message().to_string(), e.span().map(|span| self.text[..span.start].matches('\n').count() + 1))),
+            },
+        };
+
+        // Editors find the schema of a file through "$schema", which the tool itself has no use for
+        for field in unknown.into_iter().filter(|field| field != "$schema") {
+            let key = field.rsplit('.').next().unwrap_or(&field).to_string();
+            self.warning(&field, Some(&format!("\"{key}\"")), format!("Unknown key \"{key}\" will be ignored"));
+        }
+
+        match result {
+            Ok(_) => true,
+            Err((message, line)) => {
+                let path = track.path().to_string();
+                let key = path.rsplit('.').next().and_then(|key| key.split('[').next()).unwrap_or_default();
+                let line = line.or_else(|| self.line_of(&format!("\"{key}\"")));
+                self.diagnostics.push(Diagnostic {
+                    severity: Severity::Error,
+                    field: if path == "." { String::new() } else { path },
+                    line,
+                    message,
+                });
+                false
+            },
+        }
+    }
+
+    /// Find the first line containing `needle`, to point the user at a problem
+    fn line_of(&self, needle: &str) -> Option<usize> {
+        let position = |needle: &str| self.text.lines().position(|l| l.contains(needle));
+        // Needles are written as JSON, but TOML keys are usually bare
+        let found = match self.format {
+            ConfigFormat::Json => position(needle),
+            ConfigFormat::Toml => position(needle).or_else(|| position(needle.trim_matches('"'))),
+        };
+        found.map(|i| i + 1)
+    }
+
+    fn push(&mut self, severity: Severity, field: &str, needle: Option<&str>, message: String) {
+        let line = needle.and_then(|n| self.line_of(n));
+        self.diagnostics.push(Diagnostic {
+            severity,
+            field: field.to_string(),
+            line,
+            message,
+        });
+    }
+
+    fn error(&mut self, field: &str, needle: Option<&str>, message: String) {
+        self.push(Severity::Error, field, needle, message);
+    }
+
+    fn warning(&mut self, field: &str, needle: Option<&str>, message: String) {
+        self.push(Severity::Warning, field, needle, message);
+    }
+
+    /// Check a replacement name, which is an error if git can't store it, and worth a warning if
+    /// `canonicalize_identities` would change it
+    fn check_name(&mut self, field: &str, needle: &str, name: &str) {
+        if name.trim().is_empty() {
+            self.error(field, Some(needle), "Replacement name is empty".to_string());
+        } else if !is_valid_name(name) {
+            self.error(field, Some(needle), format!("Replacement name {name:?} has control characters, invisible characters, or angle brackets"));
+        } else if canonical_name(name) != name {
+            self.warning(field, Some(needle), format!("Replacement name {name:?} has stray whitespace or isn't in Unicode NFC. Set \"canonicalize_identities\": true to fix it"));
+        }
+    }
+
+    /// Fill in a replacement's template variables for a made up identity, so what it becomes can be
+    /// checked, or report why it can't be filled in
+    fn fill_sample(&mut self, field: &str, needle: &str, template: &str) -> Option<String> {
+        fill_template(template, "Alex Smith", "alex.smith@example.com")
+            .map_err(|e| self.error(field, Some(needle), e))
+            .ok()
+    }
+
+    /// Check a replacement email like [`Checker::check_name`]
+    fn check_email(&mut self, field: &str, needle: &str, email: &str) {
+        if !is_valid_email(email) {
+            self.error(field, Some(needle), format!("\"{email}\" is not a valid email address"));
+        } else if canonical_email(email) != email {
+            self.warning(field, Some(needle), format!("\"{email}\" isn't in Unicode NFC. Set \"canonicalize_identities\": true to fix it"));
+        }
+    }
+
+    /// Warn about a rule's `fields` that it never changes, given whether it replaces names and emails
+    fn check_fields(&mut self, field: &str, needle: &str, fields: &[Field], changes_name: bool, changes_email: bool) {
+        if fields.is_empty() {
+            self.warning(field, Some(needle), "\"fields\" is empty, so the rule changes nothing".to_string());
+        }
+        for f in fields.iter().filter(|f| if f.is_email() { !changes_email } else { !changes_name }) {
+            let part = if f.is_email() { "email" } else { "name" };
+            self.warning(field, Some(needle), format!("The rule replaces no {part}, so \"{}\" in its \"fields\" does nothing", f.as_str()));
+        }
+    }
+
+    fn check(&mut self, config: &Config) {
+        let repo_name = Regex::new(r"^[A-Za-z0-9_.-]+/[A-Za-z0-9_.-]+$").unwrap();
+        // Azure DevOps project names may have spaces
+        let azure_name = Regex::new(r"^[A-Za-z0-9_.-]+/[^/]+/[A-Za-z0-9_. -]+$").unwrap();
+        // GitLab groups nest to any depth
+        let gitlab_name = Regex::new(r"^[A-Za-z0-9_.-]+(/[A-Za-z0-9_.-]+)+$").unwrap();
+
+        if config.apply_to.is_empty() {
+            self.warning("apply_to", Some("\"apply_to\""), "\"apply_to\" is empty, so only rules with their own \"apply_to\" or \"fields\" are applied".to_string());
+        }
+
+        for (i, plugin) in config.plugins.iter().enumerate() {
+            if !plugin.is_file() {
+                let name = plugin.file_name().unwrap_or_default().to_string_lossy();
+                self.error(&format!("plugins[{i}]"), Some(&name), format!("Plugin {plugin:?} does not exist"));
+            }
+        }
+
+        // accept-new creates the file if it doesn't exist yet
+        let known_hosts = config.known_hosts.iter().map(|file| ("known_hosts".to_string(), file, None))
+            .chain(config.ssh.iter().filter_map(|(host, settings)| settings.known_hosts.as_ref().map(|file| (format!("ssh.{host}.known_hosts"), file, settings.host_key_checking))));
+        for (field, file, checking) in known_hosts {
+            if checking != Some(HostKeyChecking::AcceptNew) && !expand_home(file).is_file() {
+                self.error(&field, Some(&file.to_string_lossy()), format!("known_hosts file {file:?} does not exist"));
+            }
+        }
+
+        for (prefix, route) in &config.credentials {
+            let field = format!("credentials.{prefix}");
+            if route.ssh_key.is_none() && route.token.is_none() {
+                self.warning(&field, Some(prefix), "Neither \"ssh_key\" nor \"token\" is set, so the entry changes nothing".to_string());
+            }
+            if route.username.is_some() && route.token.is_none() {
+                self.warning(&field, Some(prefix), "\"username\" is only sent with a \"token\"".to_string());
+            }
+            if let Some(key) = route.ssh_key.as_deref().filter(|key| !expand_home(key).is_file()) {
+                self.error(&field, Some(prefix), format!("ssh key {key:?} does not exist"));
+            }
+        }
+
+        for (provider, limit) in &config.concurrency {
+            if *limit == 0 {
+                let name = serde_json::to_value(provider).ok().and_then(|v| v.as_str().map(str::to_string)).unwrap_or_default();
+                self.error(&format!("concurrency.{name}"), Some(&format!("\"{name}\"")), format!("{provider} repositories would never be processed with a limit of 0"));
+            }
+        }
+
+        let discovers = !config.discovery.github_organizations.is_empty()
+            || config.gitea.as_ref().is_some_and(|g| !g.organizations.is_empty());
+        if config.repositories.is_empty() && !discovers {
+            self.warning("repositories", Some("\"repositories\""), "No repositories are configured".to_string());
+        }
+        if !discovers && !config.discovery.is_default() {
+            self.warning("discovery", Some("\"discovery\""), "Neither \"discovery\" nor \"gitea\" lists any organizations, so its filters apply to nothing".to_string());
+        }
+
+        for (i, entry) in config.repositories.iter().enumerate() {
+            let repo = &entry.name;
+            let field = format!("repositories[{i}]");
+            let needle = format!("\"{repo}\"");
+            match entry.local_path() {
+                Some(path) if !path.exists() => self.warning(&field, Some(&needle), format!("{path:?} does not exist")),
+                Some(_) => {},
+                None if entry.provider == Provider::AzureDevOps && !azure_name.is_match(repo) => self.error(&field, Some(&needle), format!("\"{repo}\" is not of the form Org/Project/reponame")),
+                None if entry.provider == Provider::AzureDevOps => {},
+                None if entry.provider == Provider::GitLab && !gitlab_name.is_match(repo) => self.error(&field, Some(&needle), format!("\"{repo}\" is not of the form group/project or group/subgroup/.../project")),
+                None if entry.provider == Provider::GitLab => {},
+                None if !repo_name.is_match(repo) => self.error(&field, Some(&needle), format!("\"{repo}\" is not of the form Org/reponame, or a local path")),
+                None => {},
+            }
+            if entry.provider == Provider::Gitea && config.gitea.is_none() {
+                self.error(&format!("{field}.provider"), Some(&needle), "Gitea repositories need \"gitea\" in the config".to_string());
+            }
+            if config.repositories[..i].iter().any(|r| &r.name == repo) {
+                self.warning(&field, Some(&needle), format!("\"{repo}\" is listed more than once"));
+            }
+
+            let mut range = [None, None];
+            for ((key, date), bound) in [("since", &entry.since), ("until", &entry.until)].into_iter().zip(&mut range) {
+                if let Some(date) = date {
+                    match parse_date(date) {
+                        Ok(ts) => *bound = Some(ts),
+                        Err(e) => self.error(&format!("{field}.{key}"), Some(&format!("\"{date}\"")), format!("\"{date}\" is not a YYYY-MM-DD date or RFC 3339 timestamp: {e}")),
+                    }
+                }
+            }
+            if let [Some(since), Some(until)] = range {
+                if since >= until {
+                    self.error(&format!("{field}.until"), Some(&needle), "\"until\" is not after \"since\", so nothing would be rewritten".to_string());
+                }
+            }
+            for branch in &entry.branches {
+                if let Err(e) = glob::Pattern::new(branch) {
+                    self.error(&format!("{field}.branches"), Some(&format!("\"{branch}\"")), format!("\"{branch}\" is not a valid branch glob: {e}"));
+                }
+            }
+            for refspec in &entry.push_refspecs {
+                let (src, dst) = refspec.trim_start_matches('+').split_once(':').unwrap_or((refspec, refspec));
+                if src.is_empty() || dst.is_empty() {
+                    self.error(&format!("{field}.push_refspecs"), Some(&format!("\"{refspec}\"")), format!("\"{refspec}\" needs a branch on both sides of the colon"));
+                } else if src.matches('*').count() != dst.matches('*').count() || src.matches('*').count() > 1 {
+                    self.error(&format!("{field}.push_refspecs"), Some(&format!("\"{refspec}\"")), format!("\"{refspec}\" must have a single * on both sides, or none"));
+                }
+            }
+            if let Some(branch) = &entry.default_branch {
+                let needle = format!("\"{branch}\"");
+                if !git2::Branch::name_is_valid(branch).unwrap_or(false) {
+                    self.error(&format!("{field}.default_branch"), Some(&needle), format!("\"{branch}\" is not a valid branch name"));
+                } else if !entry.selects_branch(branch) {
+                    self.warning(&format!("{field}.default_branch"), Some(&needle), format!("\"{branch}\" isn't one of \"branches\", so it is never pushed to become the default"));
+                }
+            }
+            if entry.push_to.as_deref().is_some_and(|u| u.trim().is_empty()) {
+                self.error(&format!("{field}.push_to"), Some(&needle), "Push destination is empty".to_string());
+            }
+            if entry.clone == Some(CloneStrategy::Bare) && !(entry.hooks.is_empty() && config.hooks.is_empty()) {
+                self.warning(&format!("{field}.clone"), Some(&needle), "Hooks run in a bare clone, which has no working tree to read files from".to_string());
+            }
+            if entry.start_commit.as_deref().is_some_and(|c| c.trim().is_empty()) {
+                self.error(&format!("{field}.start_commit"), Some(&needle), "Start commit is empty".to_string());
+            }
+        }
+
+        for (old, substitution) in &config.email_substitutions {
+            let field = format!("email_substitutions.{old}");
+            let needle = format!("\"{old}\"");
+            let new = substitution.new_email();
+            if let Some(email) = self.fill_sample(&field, &needle, new) {
+                self.check_email(&field, &needle, &email);
+            }
+            if let Some(name) = substitution.new_author().and_then(|name| self.fill_sample(&field, &needle, name)) {
+                self.check_name(&field, &needle, &name);
+            }
+            if let Substitution::Identity { fields: Some(fields), apply_to, .. } = substitution {
+                if apply_to.is_some() {
+                    self.warning(&field, Some(&needle), "\"fields\" takes precedence over \"apply_to\", which is ignored".to_string());
+                }
+                self.check_fields(&field, &needle, fields, substitution.new_author().is_some(), true);
+            }
+            if old == new && substitution.new_author().is_none() {
+                self.warning(&field, Some(&needle), "Email is substituted with itself".to_string());
+            }
+            // Substitutions aren't chained, so a target that is itself substituted is left as it is
+            if new != old && config.email_substitutions.contains_key(new) {
+                self.warning(&field, Some(&needle), format!("\"{new}\" is also substituted, but substitutions aren't chained, so \"{old}\" still becomes \"{new}\""));
+            }
+            // Without regard to case, two keys may be the same email with different replacements,
+            // and only one of them can be kept
+            if config.case_insensitive_emails {
+                for (other, other_substitution) in &config.email_substitutions {
+                    if other < old && other.to_lowercase() == old.to_lowercase() && other_substitution != substitution {
+                        self.error(&field, Some(&needle), format!("\"{other}\" is the same email ignoring case, but is substituted differently"));
+                    }
+                }
+            }
+            if let Some(user) = noreply_username(old).filter(|user| config.github_noreply_substitutions.keys().any(|u| u.eq_ignore_ascii_case(user))) {
+                self.warning(&field, Some(&needle), format!("\"{user}\" is also in \"github_noreply_substitutions\", which this substitution takes precedence over"));
+            }
+        }
+
+        for (old, new) in &config.github_noreply_substitutions {
+            let field = format!("github_noreply_substitutions.{old}");
+            let needle = format!("\"{old}\"");
+            if !is_valid_github_username(old) {
+                self.error(&field, Some(&needle), format!("\"{old}\" is not a GitHub username"));
+            }
+            if new.contains('@') {
+                self.check_email(&field, &needle, new);
+            } else if !new.contains('@') && !is_valid_github_username(new) {
+                self.error(&field, Some(&needle), format!("\"{new}\" is neither a GitHub username nor an email address"));
+            }
+            if old.eq_ignore_ascii_case(new) {
+                self.warning(&field, Some(&needle), "Username is substituted with itself".to_string());
+            }
+            // Usernames match regardless of case, so only one of these can be kept
+            for (other, other_new) in &config.github_noreply_substitutions {
+                if other < old && other.eq_ignore_ascii_case(old) && other_new != new {
+                    self.error(&field, Some(&needle), format!("\"{other}\" is the same username ignoring case, but is substituted differently"));
+                }
+            }
+        }
+
+        for email in &config.preserve_emails {
+            let field = "preserve_emails";
+            let needle = format!("\"{email}\"");
+            if !is_valid_email(email) {
+                self.error(field, Some(&needle), format!("\"{email}\" is not a valid email address"));
+            }
+            let substituted = config.email_substitutions.keys().any(|old| {
+                old == email || (config.case_insensitive_emails && old.to_lowercase() == email.to_lowercase())
+            });
+            if substituted {
+                self.warning(field, Some(&needle), format!("\"{email}\" is also substituted, but is preserved so the substitution never applies"));
+            }
+        }
+
+        let mut patterns = Vec::new();
+        for (pattern, new) in &config.name_substitutions {
+            let field = format!("name_substitutions.{pattern}");
+            let needle = format!("\"{pattern}\"");
+            if let Some(name) = self.fill_sample(&field, &needle, new.new_name()) {
+                self.check_name(&field, &needle, &name);
+            }
+            if let Some(fields) = new.fields() {
+                self.check_fields(&field, &needle, fields, true, false);
+            }
+            match Regex::new(pattern) {
+                Ok(rx) => patterns.push((pattern, rx)),
+                Err(e) => self.error(&field, Some(&needle), format!("Invalid regex: {e}")),
+            }
+        }
+
+        for (i, merge) in config.merge_identities.iter().enumerate() {
+            let field = format!("merge_identities.{i}");
+            let needle = format!("\"{}\"", merge.email);
+            self.check_name(&field, &needle, &merge.name);
+            self.check_email(&field, &needle, &merge.email);
+            if merge.emails.is_empty() && merge.names.is_empty() {
+                self.warning(&field, Some(&needle), "Neither \"emails\" nor \"names\" is set, so nothing is merged".to_string());
+            }
+            let key = |email: &str| if config.case_insensitive_emails { email.to_lowercase() } else { email.to_string() };
+            for email in &merge.emails {
+                let needle = format!("\"{email}\"");
+                if config.merge_identities[..i].iter().any(|other| other.emails.iter().any(|e| key(e) == key(email))) {
+                    self.error(&field, Some(&needle), format!("\"{email}\" is merged by an earlier entry too, so this one never applies to it"));
+                }
+                if config.email_substitutions.keys().any(|old| key(old) == key(email)) {
+                    self.warning(&field, Some(&needle), format!("\"{email}\" is also in \"email_substitutions\", which the merge takes precedence over"));
+                }
+            }
+            for name in &merge.names {
+                if config.merge_identities[..i].iter().any(|other| other.names.contains(name)) {
+                    self.error(&field, Some(&format!("\"{name}\"")), format!("\"{name}\" is merged by an earlier entry too, so this one never applies to it"));
+                }
+            }
+        }
+
+        if let Some(republish) = &config.republish {
+            let needle = format!("\"{}\"", republish.email);
+            self.check_name("republish", &needle, &republish.name);
+            self.check_email("republish", &needle, &republish.email);
+            let shadowed = [
+                ("email_substitutions", config.email_substitutions.is_empty()),
+                ("name_substitutions", config.name_substitutions.is_empty()),
+                ("identity_substitutions", config.identity_substitutions.is_empty()),
+                ("merge_identities", config.merge_identities.is_empty()),
+                ("github_noreply_substitutions", config.github_noreply_substitutions.is_empty()),
+            ].into_iter().filter(|(_, empty)| !empty).map(|(key, _)| format!("\"{key}\"")).collect::<Vec<_>>();
+            if !shadowed.is_empty() {
+                self.warning("republish", Some(&needle), format!("Every identity is republished, so {} never apply", shadowed.join(", ")));
+            }
+        }
+
+        for (i, trailer) in config.trailers.iter().enumerate() {
+            let field = format!("trailers.{i}");
+            let needle = format!("\"{trailer}\"");
+            match fill_trailer(trailer, "20240131T120000Z-3fa2c1", "Alex Smith", "alex.smith@example.com") {
+                Ok(filled) if !is_trailer(&filled) => self.error(&field, Some(&needle), "Trailers are a token and a value on one line, like \"History-Rewritten-By: repo_cleaner {run_id}\"".to_string()),
+                Ok(_) => {},
+                Err(e) => self.error(&field, Some(&needle), e),
+            }
+        }
+
+        if let Some(dates) = &config.anonymize_dates {
+            if dates.mode == DateMode::Randomize && dates.salt.as_deref().is_none_or(str::is_empty) {
+                self.error("anonymize_dates", Some("\"anonymize_dates\""), "Randomizing dates needs a secret \"salt\", or the original dates could be worked out again".to_string());
+            }
+        }
+
+        if let Some(releases) = &config.releases {
+            if releases.tags.is_empty() {
+                self.warning("releases", Some("\"releases\""), "\"tags\" is empty, so no release is moved".to_string());
+            }
+            for (i, tag) in releases.tags.iter().enumerate() {
+                if let Err(e) = glob::Pattern::new(tag) {
+                    self.error(&format!("releases.tags.{i}"), Some(&format!("\"{tag}\"")), format!("\"{tag}\" is not a valid tag glob: {e}"));
+                }
+            }
+        }
+
+        for (i, rule) in config.identity_substitutions.iter().enumerate() {
+            let field = format!("identity_substitutions.{i}");
+            for pattern in [&rule.name, &rule.email] {
+                if let Err(e) = Regex::new(pattern) {
+                    self.error(&field, Some(&format!("\"{pattern}\"")), format!("Invalid regex: {e}"));
+                }
+            }
+            if rule.new_name.is_none() && rule.new_email.is_none() {
+                self.warning(&field, Some(&format!("\"{}\"", rule.name)), "Rule has neither \"new_name\" nor \"new_email\", so it changes nothing".to_string());
+            }
+            if let Some(name) = &rule.new_name {
+                let needle = format!("\"{}\"", rule.name);
+                if let Some(name) = self.fill_sample(&field, &needle, name) {
+                    self.check_name(&field, &needle, &name);
+                }
+            }
+            if let Some(new) = &rule.new_email {
+                let needle = format!("\"{new}\"");
+                if let Some(email) = self.fill_sample(&field, &needle, new) {
+                    self.check_email(&field, &needle, &email);
+                }
+            }
+            if let Some(fields) = &rule.fields {
+                self.check_fields(&field, &format!("\"{}\"", rule.name), fields, rule.new_name.is_some(), rule.new_email.is_some());
+            }
+        }
+
+        // Two name patterns overlap if one of them matches the other's text.
+        // This only catches the obvious cases, but those are the common ones.
+        let order = config.ordered_name_substitutions().into_iter().map(|(pattern, _)| pattern).collect::<Vec<_>>();
+        let rank = |pattern: &String| order.iter().position(|p| *p == pattern);
+        for (a, rx) in &patterns {
+            for (b, _) in &patterns {
+                if a != b && rx.is_match(b) && config.name_substitutions[*a] != config.name_substitutions[*b] {
+                    let first = if rank(a) < rank(b) { a } else { b };
+                    self.warning(
+                        &format!("name_substitutions.{b}"),
+                        Some(&format!("\"{b}\"")),
+                        format!("Names matching \"{b}\" are also matched by \"{a}\". \"{first}\" is tried first, being longer or alphabetically earlier"),
+                    );
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_emails_pass() {
+        assert!(is_valid_email("alex@example.com"));
+        assert!(is_valid_email("alex.smith+tag@mail.example.com"));
+        assert!(is_valid_email("12345+alex@users.noreply.github.com"));
+    }
+
+    #[test]
+    fn malformed_emails_fail() {
+        for email in ["alex", "@example.com", "alex@", ".alex@example.com", "alex.@example.com", "al..ex@example.com",
+                      "alex@example..com", "alex@a@example.com", "alex smith@example.com", "<alex@example.com>",
+                      "alex@example.com,", "alex\u{200b}@example.com"] {
+            assert!(!is_valid_email(email), "{email:?} should be invalid");
+        }
+    }
+}
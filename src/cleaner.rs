@@ -0,0 +1,1117 @@
+use std::{cell::RefCell, collections::{BTreeMap, HashMap}, num::NonZeroUsize, path::{Path, PathBuf}, sync::{atomic::{AtomicUsize, Ordering}, Mutex}, time::Instant};
+
+use eyre::{bail, eyre, Context, Report, Result};
+use git2::{ErrorCode, Repository};
+use indicatif::HumanBytes;
+use ring::rand::{SecureRandom, SystemRandom};
+use tracing::{info, warn};
+
+use crate::{
+    allowlist::Allowlist,
+    backup::{self, BackupManifest},
+    config::{CloneStrategy, Config, RepositoryConfig},
+    credentials::{self, CredentialRoute, Credentials},
+    diff::{self, HistoryDiff},
+    error::{self, AuthError, PushRejected},
+    gc,
+    git::{self, fast_forward, git, git_dir, git_with, is_local, order_branches, proxy_options, track_origin, TimeoutError, NOTES_REFSPEC},
+    forge::{Forge, Provider},
+    gitea::Gitea,
+    github::GitHub,
+    hooks::{self, Hook},
+    lfs,
+    manifest::Manifest,
+    pipeline::{incomplete_step, Pipeline},
+    progress::{Phase, RepoProgress},
+    reference::ReferenceCache,
+    options::{Backend, BlobScan, GcMode, RefreshPolicy},
+    refresh::{self, TAGS_REFSPEC},
+    retry::{RetryPolicy, Timeouts},
+    rewrite::{self, Callbacks},
+    ssh::{expand_home, shell_quote, HostKeyChecking, SshSettings},
+    submodule,
+    summary::RepoReport,
+    throttle::{PushSchedule, PushThrottle},
+};
+
+
+/// Run-wide settings for a [`Cleaner`]
+#[derive(Debug, Clone)]
+pub struct CleanerOptions {
+    /// Re-sign every commit with the default GPG signing key after rewriting, keeping every commit's
+    /// committer name, email, and date exactly as they were
+    pub sign: bool,
+    /// Replace matched identities with pseudonyms derived from this salt, instead of their configured substitutions
+    pub anonymize: Option<String>,
+    /// How network operations are retried
+    pub retry: RetryPolicy,
+    /// How long cloning, fetching, rewriting, and pushing may each take
+    pub timeouts: Timeouts,
+    /// Which git implementation clones and fetches repositories
+    pub backend: Backend,
+    /// Make partial clones without any blobs, fetching them only where git needs them
+    pub blobless: bool,
+    /// Share objects between clones through this cache, rather than each downloading and storing its own
+    pub reference_cache: Option<ReferenceCache>,
+    /// How hard to collect garbage after rewriting
+    pub gc: GcMode,
+    /// Push every cleaned repository to a repository of the same name in this GitHub organization, instead of back to its origin
+    pub mirror_to_org: Option<String>,
+    /// The GitHub API, used to create destinations and archive originals
+    pub github: Option<GitHub>,
+    /// The Gitea API, and the instance repositories with `"provider": "gitea"` are cloned from
+    pub gitea: Option<Gitea>,
+    /// Create each repository's destination on GitHub before pushing to it, copying the original's description and visibility
+    pub create_destination: bool,
+    /// Archive each original on GitHub once its cleaned history has been pushed to its destination
+    pub archive_original: bool,
+    /// Skip repositories with open pull requests, unless they allow them in the config
+    pub block_if_open_prs: bool,
+    /// Keys, users, ports, and host key checking for each ssh host
+    pub ssh: SshSettings,
+    /// The proxy HTTP connections go through, instead of the one the environment names
+    pub proxy: Option<String>,
+    /// Never touch the network. Only local repositories and existing clones are cleaned, and only
+    /// to local destinations pushed
+    pub offline: bool,
+    /// Don't back repositories up before rewriting them, for when they are already backed up some other way
+    pub skip_backup: bool,
+    /// Check every backup can be restored before rewriting anything
+    pub verify_backup: bool,
+    /// The age identity backups encrypted with age are decrypted with, to verify or restore them
+    pub age_identity: Option<PathBuf>,
+    /// Instead of pushing, only find which refs a push would move, and from what to what
+    pub preview_push: bool,
+    /// How pushes are spread out over time
+    pub push_schedule: PushSchedule,
+    /// Rewrite every repository's whole history, even the parts an earlier run already rewrote with the same rules
+    pub full_rewrite: bool,
+    /// Back up, rewrite, and push repositories no rule matches anything in too, rather than leaving them clean
+    pub rewrite_unmatched: bool,
+    /// What to do with a clone an earlier run left behind
+    pub refresh: RefreshPolicy,
+    /// Keep the branches and tags from before each rewrite under `refs/pre-clean/<date>/`
+    pub keep_original: bool,
+    /// Push the history kept by `keep_original` to this URL before pushing the rewrite, with
+    /// `{repository}` and `{name}` filled in
+    pub push_originals_to: Option<String>,
+    /// Search file contents for the old emails when planning, and after rewriting
+    pub scan_blobs: Option<BlobScan>,
+    /// Where spans for every repository and step are recorded
+    #[cfg(feature = "otlp")]
+    pub telemetry: Option<std::sync::Arc<crate::Telemetry>>,
+}
+
+/// A single repository to be cleaned or pushed
+#[derive(Debug, Clone)]
+pub struct RepoJob {
+    /// The repository as written in the config, e.g. `Org/reponame`
+    pub repository: String,
+    /// Where the repository is cloned from, and pushed to unless it has a `push_to`
+    pub url: String,
+    /// Where the cleaned history is published instead of force pushing the original
+    pub push_to: Option<String>,
+    /// Where the repository is cloned to
+    pub dir: PathBuf,
+    /// The repository's entry in the config
+    pub config: RepositoryConfig,
+}
+
+/// Which repositories use which others as submodules, found before cleaning them
+#[derive(Debug, Default)]
+pub struct Dependencies {
+    /// The other repositories each one uses as submodules, by name
+    pub found: HashMap<String, Vec<String>>,
+    /// Repositories whose submodules couldn't be found, and why, in the order they were given
+    pub failures: Vec<(String, Report)>,
+}
+
+/// Clones, backs up, rewrites, and pushes repositories inside a working directory
+#[derive(Debug)]
+pub struct Cleaner {
+    /// The working directory, where the manifest of cleaned repositories is kept
+    pub(crate) dir: PathBuf,
+    /// The path repos will be put in
+    repos: PathBuf,
+    /// The path backups will be put in
+    backups: PathBuf,
+    pub(crate) config: Config,
+    pub(crate) options: CleanerOptions,
+    /// Identifies this run, in trailers and wherever else it is recorded
+    pub(crate) run_id: String,
+    /// Spaces pushes out as the options' push schedule says
+    pub(crate) push_throttle: PushThrottle,
+}
+
+impl Cleaner {
+    /// Create a cleaner that works inside `base`
+    pub fn new(config: &Config, base: &Path, options: CleanerOptions) -> Self {
+        Self {
+            dir: base.to_path_buf(),
+            repos: base.join("repos"),
+            backups: base.join("backups"),
+            config: config.clone(),
+            push_throttle: PushThrottle::new(options.push_schedule),
+            options,
+            run_id: new_run_id(),
+        }
+    }
+
+    /// The id of this run, the time it started in UTC and a random suffix, like `20240131T120000Z-3fa2c1`
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    /// The config every repository is cleaned with
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Where spans and counters are recorded, if anywhere
+    #[cfg(feature = "otlp")]
+    pub fn telemetry(&self) -> Option<&crate::Telemetry> {
+        self.options.telemetry.as_deref()
+    }
+
+    /// Build the job for a repository named in the config
+    pub fn job(&self, repository: &RepositoryConfig) -> RepoJob {
+        let name = &repository.name;
+        RepoJob {
+            repository: name.clone(),
+            // Construct the repo URL. Local repositories are cloned from wherever the clone is made
+            url: match repository.local_path() {
+                Some(path) => std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf()).display().to_string(),
+                None => self.remote_url(repository.provider, name),
+            },
+            // A destination written in the config wins over --mirror-to-org
+            push_to: repository.push_to.clone().or_else(|| {
+                let org = self.options.mirror_to_org.as_ref()?;
+                Some(self.remote_url(repository.provider, &format!("{org}/{}", repository.short_name())))
+            }),
+            dir: self.repos.join(repository.dir_name()),
+            config: repository.clone(),
+        }
+    }
+
+    /// The URL of `name` on `provider`, over HTTPS if the config has a token for it and over ssh otherwise
+    fn remote_url(&self, provider: Provider, name: &str) -> String {
+        let (ssh, https) = match provider {
+            Provider::GitHub => (format!("git+ssh://git@github.com/{name}.git"), format!("https://github.com/{name}.git")),
+            Provider::Gitea => match &self.options.gitea {
+                Some(gitea) => (gitea.url(name), gitea.https_url(name)),
+                None => return self.remote_url(Provider::GitHub, name),
+            },
+            // Org/Project/reponame, cloned over HTTPS from dev.azure.com/Org/Project/_git/reponame
+            Provider::AzureDevOps => {
+                // Project names may have spaces, which URLs can't
+                let name = name.replace(' ', "%20");
+                let (project, repo) = name.rsplit_once('/').unwrap_or(("", &name));
+                (format!("git+ssh://git@ssh.dev.azure.com/v3/{project}/{repo}"), format!("https://dev.azure.com/{project}/_git/{repo}"))
+            },
+            Provider::Bitbucket => (format!("git+ssh://git@bitbucket.org/{name}.git"), format!("https://bitbucket.org/{name}.git")),
+            // group/subgroup/project, at any depth, on gitlab.com unless the config names an instance
+            Provider::GitLab => {
+                let gitlab = self.config.gitlab.clone().unwrap_or_default();
+                (gitlab.url(name), gitlab.https_url(name))
+            },
+        };
+
+        // GitHub Apps have no ssh key, so they use HTTPS with a token
+        let has_token = match provider {
+            Provider::GitHub => self.options.github.as_ref().is_some_and(GitHub::is_app),
+            Provider::Gitea => false,
+            Provider::AzureDevOps => self.config.azure_devops.is_some(),
+            Provider::Bitbucket => self.config.bitbucket.is_some(),
+            Provider::GitLab => self.config.gitlab.as_ref().is_some_and(|g| g.token.is_some()),
+        };
+        if has_token || self.route(&https).is_some_and(|route| route.token.is_some()) { https } else { ssh }
+    }
+
+    /// The credentials the config routes to `url`, if any
+    fn route(&self, url: &str) -> Option<&CredentialRoute> {
+        credentials::route(&self.config.credentials, url)
+    }
+
+    /// Environment for git subprocesses that talk to `url` for `job`: the ssh key routed to it, and
+    /// when authenticating as a GitHub App, a fresh token for the owner of the repository. Installation
+    /// tokens only last an hour, so this is called for every clone, fetch, and push.
+    /// Every other setting comes from what was installed at startup, like [`SshSettings::install`].
+    pub(crate) fn git_env(&self, job: &RepoJob, url: &str) -> Result<Vec<(&'static str, String)>> {
+        let mut env = Vec::new();
+        if let Some(key) = self.route(url).and_then(|route| route.ssh_key.as_deref()) {
+            let ssh = git::env_var("GIT_SSH_COMMAND").unwrap_or_else(|| "ssh".to_string());
+            env.push(("GIT_SSH_COMMAND", format!("{ssh} -i {} -o IdentitiesOnly=yes", shell_quote(&expand_home(key)))));
+        }
+        if let Some(github) = self.options.github.as_ref().filter(|g| g.is_app()) {
+            let name = submodule::repository_name(url, &job.repository).unwrap_or_else(|| job.repository.clone());
+            env.extend(github.git_env(name.split('/').next().unwrap_or(&name))
+                .wrap_err(AuthError { url: url.to_string() })?);
+        }
+        Ok(env)
+    }
+
+    /// Order `repositories` so that repositories used as submodules by others are rewritten first,
+    /// letting their dependents' submodule pointers be rewritten to match.
+    ///
+    /// Repositories whose submodules can't be found are left where they are, and most likely fail
+    /// again when cleaned.
+    pub fn order(&self, repositories: &[RepositoryConfig]) -> Vec<RepositoryConfig> {
+        submodule::dependency_order(repositories, &self.dependencies(repositories, NonZeroUsize::MIN, false).found)
+    }
+
+    /// The other repositories of `repositories` each one uses as submodules, by name, looking `jobs`
+    /// at a time. Existing clones are read as they are, and otherwise only the `.gitmodules` of the
+    /// remote's default branch is fetched, so nothing is cloned, or cloned again, just to find out.
+    ///
+    /// Repositories whose submodules couldn't be found are returned with why. With `fail_fast`, no
+    /// more are looked at after the first.
+    pub fn dependencies(&self, repositories: &[RepositoryConfig], jobs: NonZeroUsize, fail_fast: bool) -> Dependencies {
+        if repositories.len() < 2 {
+            return Dependencies::default();
+        }
+
+        info!("Looking for submodules between repositories");
+        let next = AtomicUsize::new(0);
+        let dependencies = Mutex::new(HashMap::new());
+        let failures = Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            for _ in 0..jobs.get().min(repositories.len()) {
+                scope.spawn(|| {
+                    loop {
+                        let i = next.fetch_add(1, Ordering::SeqCst);
+                        let Some(repo) = repositories.get(i) else {
+                            break;
+                        };
+                        if fail_fast && !failures.lock().expect("failures are never poisoned").is_empty() {
+                            break;
+                        }
+                        match self.submodule_urls(&self.job(repo)) {
+                            Ok(urls) => {
+                                let found = submodule::dependencies_of(&urls, &repo.name, &self.config.repositories);
+                                dependencies.lock().expect("dependencies are never poisoned").insert(repo.name.clone(), found);
+                            },
+                            Err(e) => failures.lock().expect("failures are never poisoned").push((i, repo.name.clone(), e)),
+                        }
+                    }
+                });
+            }
+        });
+
+        let mut failures = failures.into_inner().expect("failures are never poisoned");
+        failures.sort_by_key(|(i, ..)| *i);
+        Dependencies {
+            found: dependencies.into_inner().expect("dependencies are never poisoned"),
+            failures: failures.into_iter().map(|(_, repo, e)| (repo, e)).collect(),
+        }
+    }
+
+    /// The URL of every submodule of the repository, from its clone, or the local repository it is
+    /// cloned from, if there is one
+    fn submodule_urls(&self, job: &RepoJob) -> Result<Vec<String>> {
+        let local = is_local(&job.url).then(|| PathBuf::from(job.url.strip_prefix("file://").unwrap_or(&job.url)));
+        if let Some(repository) = [Some(job.dir.clone()), local].into_iter().flatten().find_map(|dir| Repository::open(dir).ok()) {
+            return Ok(submodule::urls(&repository));
+        }
+        if self.options.offline {
+            bail!("{} has never been cloned, and can't be while offline", job.repository);
+        }
+
+        let env = self.git_env(job, &job.url)?;
+        let probe = self.dir.join("submodules").join(job.config.dir_name());
+        let _ = std::fs::remove_dir_all(&probe);
+        std::fs::create_dir_all(&probe)
+            .wrap_err(format!("Unable to create {probe:?}"))?;
+        let urls = self.fetch_gitmodules(job, &probe, &env);
+        let _ = std::fs::remove_dir_all(&probe);
+        urls.map_err(|e| error::auth_failure(e, &job.url))
+            .wrap_err(format!("Unable to find the submodules of {}", job.repository))
+    }
+
+    /// The URL of every submodule in the `.gitmodules` at the tip of the remote's default branch,
+    /// fetched into the empty repository `probe` without any history, or the other files, with `env`
+    /// added to git's environment
+    fn fetch_gitmodules(&self, job: &RepoJob, probe: &Path, env: &[(&str, String)]) -> Result<Vec<String>> {
+        let timeout = self.options.timeouts.clone;
+        git(probe, &["init", "--quiet", "--bare"])?;
+        git(probe, &["remote", "add", "origin", &job.url])?;
+        self.options.retry.run(&format!("Fetching the submodules of {}", job.repository), || {
+            git_with(probe, &["fetch", "--quiet", "--depth", "1", "--filter=blob:none", "origin", "HEAD"], timeout, env)
+        })?;
+
+        if git(probe, &["ls-tree", "--name-only", "FETCH_HEAD", ".gitmodules"])?.stdout.is_empty() {
+            return Ok(Vec::new());
+        }
+        // Only this one blob is downloaded
+        let gitmodules = git_with(probe, &["show", "FETCH_HEAD:.gitmodules"], timeout, env)?.stdout;
+        let path = probe.join("gitmodules");
+        std::fs::write(&path, gitmodules)?;
+        submodule::gitmodules_urls(&path)
+    }
+
+    /// Clone, back up, and rewrite a single repository, recording what happened in `report` and
+    /// showing each phase on `progress`
+    pub fn clean(&self, job: &RepoJob, report: &mut RepoReport, progress: &RepoProgress) -> Result<()> {
+        // Until this run finishes cleaning it, whatever an earlier run left must not be pushed
+        Manifest::load(&self.dir)?.remove(&job.repository)?;
+        // Recloning replaces the clone anyway
+        if self.options.refresh != RefreshPolicy::Reclone {
+            self.rollback(job)?;
+        }
+
+        Pipeline::clean(&self.config, &self.options).run(self, job, report, progress)
+    }
+
+    /// Clone or fetch a single repository and find what cleaning it would change, without backing it up
+    /// or rewriting anything
+    pub fn plan(&self, job: &RepoJob, report: &mut RepoReport, progress: &RepoProgress) -> Result<()> {
+        Pipeline::plan(&self.options).run(self, job, report, progress)
+    }
+
+    /// Clone or fetch the repository and check every author and committer email in its history
+    /// against `allowlist`, without changing anything. Any that aren't on it fail the repository.
+    pub fn enforce(&self, job: &RepoJob, allowlist: &Allowlist, report: &mut RepoReport, progress: &RepoProgress) -> Result<()> {
+        Pipeline::enforce(allowlist.clone()).run(self, job, report, progress)
+    }
+
+    /// Clone the repository, or open it if it has already been cloned, showing the transfer on `progress`
+    pub fn clone_repository(&self, job: &RepoJob, progress: &RepoProgress) -> Result<Repository> {
+        let repo = &job.repository;
+        let repo_dir = job.dir.as_path();
+
+        if self.options.offline && !is_local(&job.url) {
+            return Repository::open(repo_dir)
+                .wrap_err(format!("{repo} has never been cloned, and can't be while offline"));
+        }
+
+        let env = self.git_env(job, &job.url)?;
+
+        if self.options.refresh == RefreshPolicy::Reclone && repo_dir.exists() {
+            info!("Deleting the existing clone of {repo} to clone it again");
+            std::fs::remove_dir_all(repo_dir)
+                .wrap_err(format!("Unable to delete existing clone {repo_dir:?}"))?;
+        }
+
+        // An existing clone is used as it is, so only a new one needs deciding on
+        let bare = Repository::open(repo_dir).is_err() && self.clone_bare(job);
+        if bare {
+            info!("Cloning {repo} bare, without a working tree");
+        }
+
+        // Neither libgit2 nor gitoxide can make partial clones, or borrow objects from another repository
+        if self.options.blobless || self.options.reference_cache.is_some() {
+            return self.clone_git(job, bare, &env);
+        }
+
+        // gitoxide only sees the process's environment, not the ssh and credential settings installed for git
+        #[cfg(feature = "gix")]
+        if self.options.backend == Backend::Gix && !git::has_installed_env() {
+            return self.clone_gix(job, bare);
+        }
+
+        // Create clone dir
+        std::fs::create_dir_all(repo_dir)?;
+
+        // libgit2 doesn't read the ssh config, so the configured user and port go in the URL
+        let url = self.options.ssh.url(&job.url);
+
+        // Clone the repository
+        let cloned = self.options.retry.run(&format!("Cloning {repo}"), || {
+            let deadline = self.options.timeouts.clone.map(|timeout| Instant::now() + timeout);
+
+            let credentials = RefCell::new(self.credentials(&url, &env));
+            let mut cb = self.remote_callbacks(&url, &credentials, deadline);
+            cb.transfer_progress(|stats| {
+                progress.transfer(Phase::Clone, &stats);
+                deadline.is_none_or(|deadline| Instant::now() < deadline)
+            });
+
+            // Set fetch options
+            let mut fo = git2::FetchOptions::new();
+            fo.remote_callbacks(cb)
+                .proxy_options(proxy_options(self.options.proxy.as_deref()))
+                .download_tags(git2::AutotagOption::All)
+                .update_fetchhead(true);
+
+            let result = git2::build::RepoBuilder::new()
+                .bare(bare)
+                .fetch_options(fo)
+                .clone(&url, repo_dir);
+
+            if result.is_ok() {
+                credentials.borrow().approve();
+            }
+
+            // Clear out anything a failed clone left behind so the next attempt starts fresh
+            if let Err(e) = &result {
+                if e.code() != ErrorCode::Exists {
+                    let _ = std::fs::remove_dir_all(repo_dir);
+                    std::fs::create_dir_all(repo_dir)?;
+                }
+            }
+
+            if let (Err(_), Some(timeout)) = (&result, self.options.timeouts.clone.filter(|_| deadline.is_some_and(|d| Instant::now() >= d))) {
+                return Err(TimeoutError { command: format!("clone {url}"), timeout }.into());
+            }
+            Ok(result?)
+        });
+
+        match cloned {
+            Ok(r) => Ok(r),
+            Err(e) => {
+                if e.downcast_ref::<git2::Error>().map(|e| e.code()) != Some(ErrorCode::Exists) {
+                    return Err(error::clone_failure(e, &job.url));
+                }
+
+                // If it exists, just open the repo
+                info!("Repository {repo} already exists, opening it");
+                Repository::open(repo_dir)
+                    .wrap_err("Unable to open existing clone")
+            },
+        }
+    }
+
+    /// Whether a new clone of `job` should be bare: as its config says, or else if the repository is
+    /// larger than `bare_clone_above_mb`. Repositories whose size can't be found before cloning get a
+    /// working tree
+    fn clone_bare(&self, job: &RepoJob) -> bool {
+        if let Some(strategy) = job.config.clone {
+            return strategy == CloneStrategy::Bare;
+        }
+        let size = match job.config.local_path() {
+            Some(path) => gc::dir_size(path).ok(),
+            None if self.options.offline => None,
+            None => self.forge(job).ok()
+                .and_then(|forge| forge.repository(&job.repository).ok().flatten())
+                .map(|info| info.size * 1000),
+        };
+        size.is_some_and(|size| size > self.config.bare_clone_above_mb * 1_000_000)
+    }
+
+    /// The credentials libgit2 tries for `url`, starting with the key routed to it or configured
+    /// for its host. Credential helpers are run with `env` added to their environment.
+    fn credentials(&self, url: &str, env: &[(&'static str, String)]) -> Credentials {
+        let mut ssh = self.options.ssh.for_url(url).unwrap_or_default();
+        if let Some(key) = self.route(url).and_then(|route| route.ssh_key.as_deref()) {
+            ssh.key = Some(expand_home(key));
+        }
+        Credentials::new(&ssh, env.to_vec())
+    }
+
+    /// libgit2 callbacks that authenticate to `url` with `credentials`, and give up on sideband
+    /// progress once `deadline` passes. Returning false from a progress callback aborts the
+    /// transfer, though only once more data arrives. libgit2's socket timeout catches connections
+    /// that stop sending anything.
+    fn remote_callbacks<'a>(&self, url: &str, credentials: &'a RefCell<Credentials>, deadline: Option<Instant>) -> git2::RemoteCallbacks<'a> {
+        let ssh = self.options.ssh.for_url(url).unwrap_or_default();
+        let mut cb = git2::RemoteCallbacks::new();
+        cb.credentials(|url, username, allowed| credentials.borrow_mut().next(url, username, allowed));
+        if ssh.host_key_checking == Some(HostKeyChecking::No) {
+            cb.certificate_check(|_, _| Ok(git2::CertificateCheckStatus::CertificateOk));
+        } else if let Some(check) = self.options.ssh.host_key_check(url) {
+            cb.certificate_check(move |cert, _| {
+                let Some(hostkey) = cert.as_hostkey() else {
+                    return Ok(git2::CertificateCheckStatus::CertificatePassthrough);
+                };
+                let (Some(key_type), Some(key)) = (hostkey.hostkey_type(), hostkey.hostkey()) else {
+                    return Ok(git2::CertificateCheckStatus::CertificatePassthrough);
+                };
+                check.verify(key_type.name(), key)
+                    .map(|()| git2::CertificateCheckStatus::CertificateOk)
+                    .map_err(|e| git2::Error::new(ErrorCode::Certificate, git2::ErrorClass::Ssh, e.to_string()))
+            });
+        }
+        cb.sideband_progress(move |_| deadline.is_none_or(|deadline| Instant::now() < deadline));
+        cb
+    }
+
+    /// Clone the repository with git, leaving every blob on the remote until something reads it with
+    /// --blobless, and borrowing every object the reference cache has with --reference-cache, or
+    /// open it if it has already been cloned. `env` is added to git's environment.
+    fn clone_git(&self, job: &RepoJob, bare: bool, env: &[(&str, String)]) -> Result<Repository> {
+        let repo = &job.repository;
+        let repo_dir = job.dir.as_path();
+
+        if let Ok(repository) = Repository::open(repo_dir) {
+            info!("Repository {repo} already exists, opening it");
+            return Ok(repository);
+        }
+        if let Some(parent) = repo_dir.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let dir = repo_dir.to_string_lossy();
+        let cache = self.options.reference_cache.as_ref().map(|cache| cache.dir().to_string_lossy());
+        self.options.retry.run(&format!("Cloning {repo}"), || {
+            // Run from here, so local paths resolve the same as they do for the other backends
+            let mut args = vec!["clone", "--quiet"];
+            if bare {
+                args.push("--bare");
+            }
+            // The checkout only downloads the blobs of the default branch, and filter-repo streams
+            // blob ids rather than their contents when no callback needs them
+            if self.options.blobless {
+                args.push("--filter=blob:none");
+            }
+            // Only what the cache doesn't have yet is downloaded into it, and then nothing is into the clone
+            if let (Some(cache), Some(dir)) = (&self.options.reference_cache, &cache) {
+                let key = job.config.dir_name().to_string_lossy().replace('\\', "/");
+                cache.add(&key, &job.url, self.options.timeouts.clone, env)?;
+                args.extend(["--reference", dir]);
+            }
+            args.extend([job.url.as_str(), &dir]);
+            let result = git_with(Path::new("."), &args, self.options.timeouts.clone, env)
+                .and_then(|_| if bare { track_origin(repo_dir, self.options.timeouts.clone, env) } else { Ok(()) });
+
+            // Clear out anything a failed clone left behind so the next attempt starts fresh
+            if result.is_err() {
+                let _ = std::fs::remove_dir_all(repo_dir);
+            }
+
+            result
+        }).map_err(|e| error::clone_failure(e, &job.url))?;
+
+        Repository::open(repo_dir)
+            .wrap_err("Unable to open new clone")
+    }
+
+    /// Clone the repository with gitoxide, or open it if it has already been cloned
+    #[cfg(feature = "gix")]
+    fn clone_gix(&self, job: &RepoJob, bare: bool) -> Result<Repository> {
+        let repo = &job.repository;
+        let repo_dir = job.dir.as_path();
+
+        // gitoxide refuses to clone into anything but an empty directory, so check for an existing clone first
+        if let Ok(repository) = Repository::open(repo_dir) {
+            info!("Repository {repo} already exists, opening it");
+            return Ok(repository);
+        }
+        if let Some(parent) = repo_dir.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        self.options.retry.run(&format!("Cloning {repo}"), || {
+            let result = crate::backend::gix_clone(&job.url, repo_dir, bare);
+
+            // Clear out anything a failed clone left behind so the next attempt starts fresh
+            if result.is_err() {
+                let _ = std::fs::remove_dir_all(repo_dir);
+            }
+
+            result
+        }).map_err(|e| error::clone_failure(e, &job.url))?;
+
+        Repository::open(repo_dir)
+            .wrap_err("Unable to open new clone")
+    }
+
+    /// Fetch all branches and notes, and fast-forward the checked out branch, showing the transfer on
+    /// `progress`. With [`RefreshPolicy::Reset`], every branch and tag is reset to the remote's instead.
+    pub fn fetch(&self, job: &RepoJob, progress: &RepoProgress) -> Result<()> {
+        self.fetch_branches(job, progress)
+            .map_err(|e| error::auth_failure(e, &job.url))
+    }
+
+    fn fetch_branches(&self, job: &RepoJob, progress: &RepoProgress) -> Result<()> {
+        if self.options.offline && !is_local(&job.url) {
+            info!("Not fetching {}, since we are offline", job.repository);
+            return Ok(());
+        }
+
+        info!("Fetching all branches...");
+        let env = self.git_env(job, &job.url)?;
+        let reset = self.options.refresh == RefreshPolicy::Reset;
+
+        #[cfg(feature = "gix")]
+        if self.options.backend == Backend::Gix && !git::has_installed_env() {
+            self.options.retry.run(&format!("Fetching {}", job.repository), || crate::backend::gix_fetch(&job.dir, reset))
+                .wrap_err("Unable to fetch all branches")?;
+
+            // Like a pull, bring the checked out branch up to date. This is local, so needs no retrying
+            let repository = Repository::open(&job.dir)?;
+            if reset {
+                return self.reset(job, &repository, &env);
+            }
+            return fast_forward(&repository).wrap_err("Unable to fast-forward the checked out branch");
+        }
+
+        // libgit2 would download every blob a partial clone left on the remote
+        if self.options.blobless {
+            if reset {
+                let args = ["fetch", "--prune", "origin", "+refs/heads/*:refs/remotes/origin/*", TAGS_REFSPEC, NOTES_REFSPEC];
+                self.options.retry.run(&format!("Fetching {}", job.repository), || git_with(&job.dir, &args, self.options.timeouts.pull, &env))
+                    .wrap_err("Unable to fetch all branches")?;
+                return self.reset(job, &Repository::open(&job.dir)?, &env);
+            }
+            let repository = Repository::open(&job.dir)?;
+            // A bare clone has no working tree to pull into
+            let args: &[&str] = if repository.is_bare() { &["fetch", "--all"] } else { &["pull", "--all"] };
+            self.options.retry.run(&format!("Fetching {}", job.repository), || git_with(&job.dir, args, self.options.timeouts.pull, &env))
+                .wrap_err("Unable to fetch all branches")?;
+            self.options.retry.run(&format!("Fetching notes of {}", job.repository), || git_with(&job.dir, &["fetch", "origin", NOTES_REFSPEC], self.options.timeouts.pull, &env))
+                .wrap_err("Unable to fetch notes")?;
+            if repository.is_bare() {
+                return fast_forward(&repository).wrap_err("Unable to fast-forward the checked out branch");
+            }
+            return Ok(());
+        }
+
+        let repository = Repository::open(&job.dir)?;
+        let url = self.options.ssh.url(&job.url);
+        let timeout = self.options.timeouts.pull;
+        self.options.retry.run(&format!("Fetching {}", job.repository), || {
+            let deadline = timeout.map(|timeout| Instant::now() + timeout);
+            let credentials = RefCell::new(self.credentials(&url, &env));
+            let mut cb = self.remote_callbacks(&url, &credentials, deadline);
+            cb.transfer_progress(|stats| {
+                progress.transfer(Phase::Fetch, &stats);
+                deadline.is_none_or(|deadline| Instant::now() < deadline)
+            });
+
+            let mut fo = git2::FetchOptions::new();
+            fo.remote_callbacks(cb)
+                .proxy_options(proxy_options(self.options.proxy.as_deref()))
+                .download_tags(git2::AutotagOption::All);
+
+            // An anonymous remote, so the URL follows the config rather than what it was cloned
+            // from. Tags that exist already are left alone, since they may have been rewritten,
+            // unless they are being reset along with everything else
+            let mut refspecs = vec!["+refs/heads/*:refs/remotes/origin/*", NOTES_REFSPEC];
+            if reset {
+                refspecs.push(TAGS_REFSPEC);
+                fo.prune(git2::FetchPrune::On);
+            }
+            let result = repository.remote_anonymous(&url)
+                .and_then(|mut remote| remote.fetch(&refspecs, Some(&mut fo), None));
+            if result.is_ok() {
+                credentials.borrow().approve();
+            }
+
+            if let (Err(_), Some(timeout)) = (&result, timeout.filter(|_| deadline.is_some_and(|d| Instant::now() >= d))) {
+                return Err(TimeoutError { command: format!("fetch {url}"), timeout }.into());
+            }
+            Ok(result?)
+        }).wrap_err("Unable to fetch all branches")?;
+
+        if reset {
+            return self.reset(job, &repository, &env);
+        }
+        fast_forward(&repository).wrap_err("Unable to fast-forward the checked out branch")
+    }
+
+    /// Reset every branch of a freshly fetched clone to the remote's, and the working tree with it
+    fn reset(&self, job: &RepoJob, repository: &Repository, env: &[(&str, String)]) -> Result<()> {
+        info!("Resetting {} to the remote...", job.repository);
+        let checkout = refresh::reset(repository)
+            .wrap_err("Unable to reset branches to the remote")?;
+        if checkout.is_some() && !repository.is_bare() {
+            // git rather than libgit2, which can't fetch the blobs a partial clone is missing
+            git_with(&job.dir, &["reset", "--hard", "--quiet"], self.options.timeouts.pull, env)
+                .wrap_err("Unable to reset the working tree")?;
+        }
+        Ok(())
+    }
+
+    /// Force push `refspecs` to `url`, showing the transfer on `progress`, with the environment from
+    /// [`Cleaner::git_env`] in `env`. Any ref the remote rejects fails the push.
+    pub(crate) fn push_refs(&self, repository: &Repository, url: &str, env: &[(&'static str, String)], refspecs: &[String], progress: &RepoProgress) -> Result<()> {
+        let url = self.options.ssh.url(url);
+        let timeout = self.options.timeouts.push;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        let credentials = RefCell::new(self.credentials(&url, env));
+        let rejected = RefCell::new(Vec::new());
+        let mut cb = self.remote_callbacks(&url, &credentials, deadline);
+        cb.push_transfer_progress(|current, total, bytes| progress.upload(current, total, bytes));
+        cb.push_update_reference(|name, status| {
+            if let Some(status) = status {
+                rejected.borrow_mut().push(format!("{name} ({status})"));
+            }
+            Ok(())
+        });
+
+        let mut po = git2::PushOptions::new();
+        po.remote_callbacks(cb)
+            .proxy_options(proxy_options(self.options.proxy.as_deref()));
+
+        let result = repository.remote_anonymous(&url)
+            .and_then(|mut remote| remote.push(refspecs, Some(&mut po)));
+        if let (Err(_), Some(timeout)) = (&result, timeout.filter(|_| deadline.is_some_and(|d| Instant::now() >= d))) {
+            return Err(TimeoutError { command: format!("push {url}"), timeout }.into());
+        }
+        result.map_err(|e| error::auth_failure(e.into(), &url))?;
+        credentials.borrow().approve();
+
+        let rejected = rejected.take();
+        if !rejected.is_empty() {
+            return Err(PushRejected { url, reason: rejected.join(", ") }.into());
+        }
+        Ok(())
+    }
+
+    /// Fetch every LFS object, so none are missing once the rewritten history is pushed
+    pub fn fetch_lfs(&self, job: &RepoJob) -> Result<()> {
+        // LFS objects live on a server even for local repositories
+        if self.options.offline || !self.lfs_ready(job) {
+            return Ok(());
+        }
+
+        info!("Fetching all LFS objects...");
+
+        let env = self.git_env(job, &job.url)?;
+        self.options.retry.run(&format!("Fetching LFS objects for {}", job.repository), || git_with(&job.dir, &["lfs", "fetch", "--all"], None, &env))
+            .wrap_err("Unable to fetch LFS objects")?;
+
+        Ok(())
+    }
+
+    /// Whether the repository uses LFS and git-lfs is available to handle it, warning if it isn't
+    pub(crate) fn lfs_ready(&self, job: &RepoJob) -> bool {
+        if !lfs::uses_lfs(&job.dir) {
+            return false;
+        }
+        if !lfs::installed(&job.dir) {
+            warn!("{} uses Git LFS, but git-lfs isn't installed. LFS objects will not be fetched or pushed, so pointers may break", job.repository);
+            return false;
+        }
+        true
+    }
+
+    /// Back the whole repository up to a tar file, returning how large it is
+    pub fn backup(&self, job: &RepoJob) -> Result<u64> {
+        info!("Backing up repository");
+
+        let stem = self.backups.join(job.config.dir_name());
+        let archive = backup::path(&stem, self.config.backup_encryption.as_ref());
+        backup::archive(&job.dir, &archive, self.config.backup_encryption.as_ref())?;
+        let manifest = BackupManifest::new(&job.repository, &job.dir, &archive, &self.config_hash()?)?;
+        manifest.write(&stem)?;
+
+        if self.options.verify_backup {
+            info!("Verifying backup");
+            backup::verify(&archive, &manifest, self.options.age_identity.as_deref())?;
+        }
+
+        Ok(manifest.size)
+    }
+
+    /// Check that the backup of a repository is still the one its manifest describes, and holds
+    /// every ref the manifest lists
+    pub fn verify_backup(&self, job: &RepoJob) -> Result<()> {
+        let repo = &job.repository;
+        let stem = self.backups.join(job.config.dir_name());
+        let archive = backup::find(&stem)
+            .ok_or_else(|| eyre!("{repo} has no backup to verify"))?;
+        let manifest = BackupManifest::load(&stem)?
+            .ok_or_else(|| eyre!("The backup of {repo} has no manifest to verify it against"))?;
+        backup::verify(&archive, &manifest, self.options.age_identity.as_deref())
+    }
+
+    /// Compare the history in the backup of `job` with its rewritten clone, commit by commit
+    pub fn diff(&self, job: &RepoJob) -> Result<HistoryDiff> {
+        let repo = &job.repository;
+        let stem = self.backups.join(job.config.dir_name());
+        let archive = backup::find(&stem)
+            .ok_or_else(|| eyre!("{repo} has no backup to compare with"))?;
+        match BackupManifest::load(&stem)? {
+            Some(manifest) => manifest.check_archive(&archive)?,
+            None => warn!("The backup of {repo} has no manifest, so it can't be checked before comparing with it"),
+        }
+        let after = Repository::open(&job.dir)
+            .wrap_err(format!("{repo} has not been cloned"))?;
+
+        let unpacked = backup::with_extension(&archive, "diff");
+        let _ = std::fs::remove_dir_all(&unpacked);
+        let result = (|| {
+            backup::unpack(&archive, &unpacked, self.options.age_identity.as_deref())?;
+            let before = Repository::open(&unpacked)
+                .wrap_err(format!("Unable to open the backup of {repo}"))?;
+            diff::compare(&before, &after, &rewrite::read_saved_commit_map(&job.dir))
+        })();
+        let _ = std::fs::remove_dir_all(&unpacked);
+        result
+    }
+
+    /// A SHA-256 of the whole config, recorded with every backup
+    pub(crate) fn config_hash(&self) -> Result<String> {
+        // Going through a Value sorts every map, so the same config always hashes the same
+        let config = serde_json::to_value(&self.config)?;
+        let digest = ring::digest::digest(&ring::digest::SHA256, config.to_string().as_bytes());
+        Ok(digest.as_ref().iter().map(|b| format!("{b:02x}")).collect())
+    }
+
+    /// Replace the local clone of a repository with its backup, undoing everything since it was taken
+    pub fn restore(&self, job: &RepoJob) -> Result<()> {
+        let repo = &job.repository;
+        let stem = self.backups.join(job.config.dir_name());
+        let archive = backup::find(&stem)
+            .ok_or_else(|| eyre!("{repo} has no backup to restore"))?;
+        let manifest = BackupManifest::load(&stem)?;
+        match &manifest {
+            Some(manifest) => manifest.check_archive(&archive)?,
+            None => warn!("The backup of {repo} has no manifest, so it can't be checked before restoring it"),
+        }
+
+        info!("Restoring {repo} from {archive:?}");
+
+        // Unpack next to the clone first, so a backup that can't be read leaves the clone alone
+        let unpacked = backup::with_extension(&job.dir, "restoring");
+        let _ = std::fs::remove_dir_all(&unpacked);
+        backup::unpack(&archive, &unpacked, self.options.age_identity.as_deref())?;
+        if let Some(manifest) = &manifest {
+            if let Err(e) = manifest.check_refs(&unpacked) {
+                let _ = std::fs::remove_dir_all(&unpacked);
+                return Err(e);
+            }
+        }
+
+        if job.dir.exists() {
+            std::fs::remove_dir_all(&job.dir)
+                .wrap_err(format!("Unable to remove the clone of {repo}"))?;
+        }
+        std::fs::rename(&unpacked, &job.dir)
+            .wrap_err(format!("Unable to move the restored clone of {repo} into place"))?;
+        // The original history must never be pushed as if it were cleaned
+        Manifest::load(&self.dir)?.remove(repo)?;
+
+        Ok(())
+    }
+
+    /// Restore the clone of a repository from its backup if a run failed or was interrupted partway
+    /// through changing it, returning the step it was left in
+    pub fn rollback(&self, job: &RepoJob) -> Result<Option<String>> {
+        let Some(step) = incomplete_step(&job.dir) else {
+            return Ok(None);
+        };
+        warn!("{} was left partway through the {step} step, so it is being restored from its backup", job.repository);
+        self.restore(job)
+            .wrap_err(format!("Unable to roll {} back. Clean it with --refresh reclone to start over instead", job.repository))?;
+        Ok(Some(step))
+    }
+
+    /// The callbacks that rewrite the repository, pointing submodules at their rewritten commits
+    pub(crate) fn callbacks(&self, job: &RepoJob, repository: &Repository) -> Result<Callbacks> {
+        // Submodules that are also being cleaned have already been rewritten, so point at their new commits
+        let submodule_dirs = submodule::dependencies(repository, &job.repository, &self.config.repositories).iter()
+            .filter_map(|dep| self.config.repositories.iter().find(|r| &r.name == dep))
+            .map(|dep| self.repos.join(dep.dir_name()))
+            .collect::<Vec<_>>();
+        let gitlinks = submodule::write_gitlink_map(&job.dir, &submodule_dirs)?;
+        if gitlinks.is_some() {
+            info!("Rewriting submodule pointers of {}", job.repository);
+        }
+
+        Callbacks::new(&self.config, self.options.anonymize.as_deref(), &self.run_id, &job.config, gitlinks.as_deref())
+    }
+
+    /// A hash of everything that decides what cleaning the repository produces
+    pub(crate) fn fingerprint(&self, job: &RepoJob, callbacks: &Callbacks) -> Result<String> {
+        let fingerprint = callbacks.fingerprint(&job.config)?;
+        // Signing gives every commit a new id too, so turning it on or off can't reuse an earlier rewrite
+        Ok(if self.options.sign { format!("{fingerprint}+signed-preserving-committer") } else { fingerprint })
+    }
+
+    /// Run garbage collection, dropping the objects the rewrite left behind
+    pub fn gc(&self, job: &RepoJob) -> Option<u64> {
+        if self.options.gc == GcMode::Off {
+            return None;
+        }
+        info!("Running garbage collection on {}", job.repository);
+
+        // A repository that is rewritten but not collected still works, so this doesn't fail it
+        match gc::collect(&job.dir, self.options.gc) {
+            Ok(reclaimed) => {
+                info!("Reclaimed {} from {}", HumanBytes(reclaimed), job.repository);
+                Some(reclaimed)
+            },
+            Err(e) => {
+                warn!("Unable to collect garbage in {}: {e:#}", job.repository);
+                None
+            },
+        }
+    }
+
+    /// Run the hooks configured for every repository, then the ones configured for this repository
+    pub(crate) fn run_hooks(&self, hook: Hook, job: &RepoJob, old_head: Option<&str>) -> Result<()> {
+        let commands = [&self.config.hooks, &job.config.hooks].into_iter()
+            .filter_map(|h| h.command(hook))
+            .collect::<Vec<_>>();
+        if commands.is_empty() {
+            return Ok(());
+        }
+
+        // Before rewriting, the new head isn't known yet
+        let current = Repository::open(&job.dir).ok().and_then(|r| head(&r));
+        let new_head = match hook {
+            Hook::PreRewrite => None,
+            _ => current.as_ref().map(|(_, oid)| oid.clone()),
+        };
+
+        let env = [
+            ("REPO", job.repository.clone()),
+            ("BRANCH", current.map(|(branch, _)| branch).unwrap_or_default()),
+            ("OLD_HEAD", old_head.unwrap_or_default().to_string()),
+            ("NEW_HEAD", new_head.unwrap_or_default()),
+        ];
+
+        for command in commands {
+            hooks::run(hook, command, &job.dir, &env)
+                .wrap_err(format!("{} hook failed for {}", hook.name(), job.repository))?;
+        }
+
+        Ok(())
+    }
+
+    /// Local branches selected by the repository's `branches` list, the default branch first
+    pub(crate) fn selected_branches(&self, job: &RepoJob) -> Result<Vec<String>> {
+        let mut branches = self.branch_tips(job)?.into_keys().collect::<Vec<_>>();
+        order_branches(&Repository::open(&job.dir)?, &mut branches);
+        Ok(branches)
+    }
+
+    /// The local branches the repository's config selects, and the commit each is at
+    pub(crate) fn branch_tips(&self, job: &RepoJob) -> Result<BTreeMap<String, String>> {
+        let repository = Repository::open(&job.dir)?;
+        let branches = repository.branches(Some(git2::BranchType::Local))?
+            .filter_map(|b| b.ok())
+            .filter_map(|b| Some((b.0.name().ok().flatten()?.to_string(), b.0.get().target()?.to_string())))
+            .filter(|(name, _)| job.config.selects_branch(name))
+            .collect();
+
+        Ok(branches)
+    }
+
+    /// Verify and force push a single previously cleaned repository, recording what happened in `report`
+    pub fn push(&self, job: &RepoJob, report: &mut RepoReport) -> Result<()> {
+        Pipeline::push(&self.config, &self.options).run(self, job, report, &RepoProgress::hidden())
+    }
+
+    /// Record the open pull requests and forks of the original repository, which force pushing breaks.
+    /// Returns whether the repository may be processed, skipping it in `report` if not.
+    pub(crate) fn preflight(&self, job: &RepoJob, report: &mut RepoReport) -> bool {
+        let Ok(forge) = self.forge(job) else {
+            return true;
+        };
+        if self.options.offline || job.config.local_path().is_some() {
+            return true;
+        }
+        let repo = &job.repository;
+        // A mirror leaves the original, and everything based on it, untouched
+        let blocking = self.options.block_if_open_prs && !job.config.allow_open_prs && job.push_to.is_none();
+
+        let pulls = match forge.open_pull_requests(repo) {
+            Ok(pulls) => pulls,
+            Err(e) if blocking => {
+                warn!("Skipping {repo}, since its open pull requests can't be checked: {e:#}");
+                report.skip(format!("Unable to check for open pull requests: {e:#}"));
+                return false;
+            },
+            Err(e) => {
+                warn!("{e:#}");
+                return true;
+            },
+        };
+        let forks = forge.repository(repo).ok().flatten().map(|r| r.forks_count);
+        report.open_pull_requests = Some(pulls.len());
+        report.forks = forks;
+
+        for pull in &pulls {
+            info!("{repo} has open pull request #{}: {}", pull.number, pull.title);
+        }
+        if !pulls.is_empty() || forks.unwrap_or_default() > 0 {
+            warn!("{repo} has {} open pull requests and {} forks, which will no longer share its history", pulls.len(), forks.unwrap_or_default());
+        }
+
+        if blocking && !pulls.is_empty() {
+            warn!("Skipping {repo}, since it has open pull requests. Set \"allow_open_prs\": true on it to process it anyway.");
+            report.skip(format!("{} open pull requests", pulls.len()));
+            return false;
+        }
+
+        true
+    }
+
+    /// The API of the code host `job` is on
+    pub(crate) fn forge(&self, job: &RepoJob) -> Result<&dyn Forge> {
+        match job.config.provider {
+            Provider::GitHub => self.options.github.as_ref()
+                .filter(|g| g.has_token(job.repository.split('/').next().unwrap_or_default()))
+                .map(|g| g as &dyn Forge)
+                .ok_or_else(|| eyre!("This needs a GitHub token")),
+            Provider::Gitea => self.options.gitea.as_ref().map(|g| g as &dyn Forge)
+                .ok_or_else(|| eyre!("This needs \"gitea\" in the config")),
+            provider @ (Provider::AzureDevOps | Provider::Bitbucket | Provider::GitLab) => Err(eyre!("This isn't supported for {provider} repositories")),
+        }
+    }
+
+    /// Tell collaborators that their clones are out of date, if configured.
+    /// Like the other notifications, failures are logged rather than returned.
+    pub(crate) fn open_issue(&self, job: &RepoJob, old_head: Option<&str>) {
+        let Some(template) = &self.config.notify.github_issue else {
+            return;
+        };
+        if self.options.offline || job.config.local_path().is_some() {
+            return;
+        }
+        let repo = &job.repository;
+        let Ok(forge) = self.forge(job) else {
+            warn!("Not opening an issue on {repo}, since there is no {} token", job.config.provider);
+            return;
+        };
+
+        let (branch, new_head) = Repository::open(&job.dir).ok()
+            .and_then(|r| head(&r))
+            .unwrap_or_default();
+        let url = job.push_to.as_deref().unwrap_or(&job.url);
+        let (title, body) = template.render(&[
+            ("repository", repo),
+            ("branch", &branch),
+            ("old_head", old_head.unwrap_or("an unknown commit").trim()),
+            ("new_head", &new_head),
+            ("url", url.strip_prefix("git+").unwrap_or(url)),
+        ]);
+
+        match forge.create_issue(repo, &title, &body) {
+            Ok(issue) => info!("Opened {issue} to tell collaborators about the rewrite of {repo}"),
+            Err(e) => warn!("Unable to open an issue on {repo}: {e:#}"),
+        }
+    }
+
+    /// Create the repository `url` points to, on the same code host as the original and with its
+    /// settings, unless it already exists
+    pub(crate) fn create_destination(&self, job: &RepoJob, url: &str) -> Result<()> {
+        let forge = self.forge(job)?;
+        let provider = job.config.provider;
+        let destination = submodule::repository_name(url, &job.repository)
+            .ok_or_else(|| eyre!("{url} does not name a {provider} repository"))?;
+
+        if forge.repository(&destination)?.is_some() {
+            info!("{destination} already exists, so it is pushed to as it is");
+            return Ok(());
+        }
+
+        let settings = forge.repository(&job.repository)?
+            .ok_or_else(|| eyre!("{} does not exist on {provider}, so there is nothing to copy its settings from", job.repository))?;
+        forge.create_repository(&destination, &settings)
+    }
+}
+
+/// Where the commit HEAD pointed to before rewriting is kept, for the hooks that run when pushing
+pub(crate) fn old_head_path(repo_dir: &Path) -> PathBuf {
+    git_dir(repo_dir).join("repo-cleaner-old-head")
+}
+
+/// The checked out branch and the commit it points to
+pub(crate) fn head(repository: &Repository) -> Option<(String, String)> {
+    let head = repository.head().ok()?;
+    let branch = head.shorthand().unwrap_or_default().to_string();
+    let oid = head.peel_to_commit().ok()?.id().to_string();
+    Some((branch, oid))
+}
+
+/// An id for a run starting now, random past the second so runs started together don't share one
+fn new_run_id() -> String {
+    let mut suffix = [0; 3];
+    SystemRandom::new().fill(&mut suffix).expect("the system random number generator is available");
+    let suffix = suffix.iter().map(|b| format!("{b:02x}")).collect::<String>();
+    format!("{}-{suffix}", chrono::Utc::now().format("%Y%m%dT%H%M%SZ"))
+}
@@ -0,0 +1,354 @@
+use std::{num::NonZeroUsize, path::PathBuf, time::Duration};
+
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use repo_cleaner::{Backend, BlobScan, GcMode, PushWindow, RefreshPolicy};
+
+
+/// Simple tool to clean emails from the patches of a given list of git repositories.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Args {
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// The configuration file to load from, whose keys are described under Configuration in the README and in the output of `repo_cleaner schema`.
+    #[arg(required = true)]
+    config: Option<PathBuf>,
+    /// Set this flag after running the command the first time. It will force push every repository the clean phase rewrote successfully, and refuse any whose branches changed since.
+    #[arg(long)]
+    commit: bool,
+    /// With --commit, show which refs of every repository would move from what to what, without pushing anything.
+    #[arg(long, requires = "commit")]
+    preview: bool,
+    /// Clone or fetch every repository and show how many commits each rule would match, without backing up or rewriting anything.
+    /// The same counts are shown after cleaning, so unused or overly broad rules stand out.
+    #[arg(long, conflicts_with = "commit")]
+    dry_run: bool,
+    /// Keep running, auditing every repository each --interval for commits the rules still match, and alert, clean, or clean and push them as --watch-action says.
+    #[arg(long, conflicts_with_all = ["commit", "dry_run"])]
+    watch: bool,
+    /// How long to wait between audits with --watch, like 30m, 24h, or 7d.
+    #[arg(long, value_parser = parse_interval, default_value = "24h", requires = "watch")]
+    interval: Duration,
+    /// What to do with repositories the rules still match when watching.
+    #[arg(long, value_enum, default_value_t = WatchAction::Alert, requires = "watch")]
+    watch_action: WatchAction,
+    /// Set this flag to sign all commits on every branch with your default GPG signing key, keeping each commit's committer name, email, and date exactly as they were.
+    /// WARNING: This will sign *every* commit, including those not made by you!
+    /// Without it, commits that were signed before the rewrite and were changed by it are listed in the summary, since their signatures no longer verify.
+    #[arg(long)]
+    sign: bool,
+    /// Replace every matched identity with a pseudonym like `contributor-3f9a0c12 <3f9a0c12@users.invalid>` instead of its configured substitution.
+    /// Pseudonyms are derived from a salted hash of the old email, so the same person always gets the same pseudonym.
+    #[arg(long, requires = "anonymize_salt")]
+    anonymize: bool,
+    /// The secret salt pseudonyms are derived from. Keep it private and reuse it between runs to keep pseudonyms stable.
+    #[arg(long, env = "REPO_CLEANER_ANONYMIZE_SALT", hide_env_values = true)]
+    anonymize_salt: Option<String>,
+    /// With --commit, push each cleaned repository to a repository of the same name in this GitHub organization instead of force pushing the original, which is left untouched.
+    /// A repository's "push_to" in the config takes precedence.
+    #[arg(long, value_name = "ORG")]
+    mirror_to_org: Option<String>,
+    /// With --commit, create each destination repository on GitHub before pushing to it, copying the original's description and visibility.
+    /// Destinations that already exist are pushed to as they are. Needs --github-token or a GitHub App, or "gitea" in the config for Gitea repositories.
+    #[arg(long)]
+    github_create_dest: bool,
+    /// With --github-create-dest, archive each original repository once its cleaned history has been pushed to its destination.
+    #[arg(long, requires = "github_create_dest")]
+    archive_original: bool,
+    /// Skip repositories with open pull requests, which force pushing would leave pointing at history that is gone.
+    /// Set "allow_open_prs": true on a repository object to process it anyway. Needs --github-token or a GitHub App, or "gitea" in the config for Gitea repositories.
+    #[arg(long)]
+    block_if_open_prs: bool,
+    /// The GitHub token used to create and archive repositories, and to open issues. With it, the open pull requests and forks of every repository are also reported.
+    #[arg(long, env = "GITHUB_TOKEN", hide_env_values = true)]
+    github_token: Option<String>,
+    /// Don't back repositories up before rewriting them, for when they are already snapshotted some other way.
+    #[arg(long, conflicts_with = "verify_backup")]
+    no_backup: bool,
+    /// Rewrite every repository's whole history again. Otherwise, a repository cleaned before with the same rules only has the commits added since rewritten, and is left alone if none were.
+    #[arg(long)]
+    no_cache: bool,
+    /// Back up, rewrite, and push repositories that no rule matches anything in too, instead of leaving them as they are and reporting them as clean.
+    #[arg(long)]
+    rewrite_unmatched: bool,
+    /// Don't check that every tool and credential cleaning needs works before starting, as `doctor` does.
+    #[arg(long)]
+    no_doctor: bool,
+    /// Keep the branches and tags from before each rewrite under refs/pre-clean/<date>/ in the clone, to diff against or restore from. They are never pushed with the rewrite.
+    #[arg(long)]
+    keep_original: bool,
+    /// With --commit, push the history --keep-original kept to this URL before pushing the rewrite, like git@github.com:Archive/{name}.git.
+    /// {repository} is replaced by the repository's name in the config, and {name} by its name without the owner.
+    #[arg(long, value_name = "URL", requires = "keep_original")]
+    push_originals_to: Option<String>,
+    /// After backing each repository up, unpack the backup and check it holds every ref its manifest lists, and every object, before rewriting anything.
+    #[arg(long)]
+    verify_backup: bool,
+    /// The age identity file to decrypt backups encrypted with age with, when verifying or restoring them.
+    #[arg(long, env = "REPO_CLEANER_AGE_IDENTITY", value_name = "PATH")]
+    age_identity: Option<PathBuf>,
+    /// Stop at the first repository that fails or is skipped, instead of continuing with the rest.
+    /// With --jobs, the repositories already started are finished first.
+    #[arg(long)]
+    fail_fast: bool,
+    /// How many repositories to process at once. "concurrency" in the config limits how many of those may be on each provider.
+    /// A repository used by another as a submodule is always finished before the other starts.
+    #[arg(short, long, default_value_t = NonZeroUsize::MIN)]
+    jobs: NonZeroUsize,
+    /// Only process every Nth repository in the order the config lists them, starting with the Ith, like 2/4, so N runs between them cover every repository once.
+    #[arg(long, value_name = "I/N", value_parser = parse_shard)]
+    shard: Option<(usize, usize)>,
+    /// Skip every repository up to and including this one, in the order the config lists them, to continue from where an earlier run with --limit stopped.
+    #[arg(long, value_name = "REPOSITORY")]
+    start_after: Option<String>,
+    /// Only process this many repositories, and log what to pass to --start-after to continue with the rest.
+    #[arg(long, value_name = "N")]
+    limit: Option<NonZeroUsize>,
+    /// How many times to retry a clone, fetch, or push that failed because of a network error.
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+    /// Seconds to wait before the first retry. The delay doubles after every failed attempt.
+    #[arg(long, default_value_t = 2)]
+    retry_delay: u64,
+    /// Kill a clone that takes longer than this, like 30m or 2h, and fail its repository. A connection that sends nothing for this long is dropped too.
+    #[arg(long, value_parser = parse_interval, value_name = "DURATION")]
+    clone_timeout: Option<Duration>,
+    /// Kill a fetch of an already cloned repository that takes longer than this, and fail its repository.
+    #[arg(long, value_parser = parse_interval, value_name = "DURATION")]
+    pull_timeout: Option<Duration>,
+    /// Kill git-filter-repo if it takes longer than this on a repository, and fail the repository.
+    #[arg(long, value_parser = parse_interval, value_name = "DURATION")]
+    filter_repo_timeout: Option<Duration>,
+    /// Kill a push that takes longer than this, and fail its repository.
+    #[arg(long, value_parser = parse_interval, value_name = "DURATION")]
+    push_timeout: Option<Duration>,
+    /// Wait this long, like 30s or 5m, between the start of one push and the next. Pushes start one at a time whenever any of --push-delay, --push-rate, or --push-window is set, even with --jobs.
+    #[arg(long, value_parser = parse_interval, value_name = "DURATION")]
+    push_delay: Option<Duration>,
+    /// Start at most this many pushes an hour, spread out evenly.
+    #[arg(long, value_name = "PER_HOUR", value_parser = clap::value_parser!(u32).range(1..))]
+    push_rate: Option<u32>,
+    /// Only start pushes within these hours of the day, in local time, like 22:00-05:00, waiting for the window to open otherwise. Pushes already going when it closes are finished.
+    #[arg(long, value_name = "HH:MM-HH:MM")]
+    push_window: Option<PushWindow>,
+    /// Never touch the network: only clean local repositories and ones already cloned, and with --commit, only push to local destinations.
+    /// Nothing is sent to GitHub or any webhook.
+    #[arg(long)]
+    offline: bool,
+    /// List the repositories of the organizations in "discovery" and "gitea" again, rather than reusing listings from the last "cache_minutes". Pages that didn't change are still answered from the cache.
+    #[arg(long)]
+    refresh_discovery: bool,
+    /// Private key to authenticate to every ssh host with, instead of the ssh agent and default keys.
+    /// Keys for specific hosts can be set in the config's "ssh" block.
+    #[arg(long, value_name = "PATH")]
+    ssh_key: Option<PathBuf>,
+    /// Refuse every ssh host whose key isn't already in its known_hosts file, whatever the config's "host_key_checking" or the user's own ssh config say. For hardened CI images, where no key should ever be accepted without being pinned.
+    #[arg(long)]
+    strict_host_key_checking: bool,
+    /// Send every HTTP connection, from git and to the APIs, through this HTTP proxy, like http://proxy.example.com:3128.
+    /// Otherwise the proxy named by https_proxy, http_proxy, or all_proxy is used. ssh connections never go through it.
+    #[arg(long, value_name = "URL")]
+    proxy: Option<String>,
+    /// Remove the lock on the working directory left behind by a run that died, instead of refusing to start.
+    /// Make sure no other run is still using it first.
+    #[arg(long)]
+    force_unlock: bool,
+    /// Show a full screen dashboard instead of progress bars, listing every repository with the phase it is in, and the log of the selected one.
+    /// A repository that fails pauses the run until it is retried with r or skipped with s. Only available when built with the tui feature (`cargo build --features tui`).
+    #[cfg(feature = "tui")]
+    #[arg(long, conflicts_with_all = ["watch", "quiet", "fail_fast", "jobs"])]
+    tui: bool,
+    /// Send a span for every repository and each step it goes through, and counters of repositories processed, commits rewritten, and bytes backed up, to this OpenTelemetry collector over OTLP/HTTP when each phase ends, like http://localhost:4318.
+    /// Headers are read from OTEL_EXPORTER_OTLP_HEADERS, and the service name from OTEL_SERVICE_NAME. Only available when built with the otlp feature (`cargo build --features otlp`).
+    #[cfg(feature = "otlp")]
+    #[arg(long, value_name = "URL", env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+    /// Only log warnings and errors, and hide progress bars.
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Log more detail. Pass twice for everything, including every git command run. RUST_LOG overrides this.
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+    /// Which git implementation clones and fetches repositories. gix is only available when built with the gix feature.
+    #[arg(long, value_enum, default_value_t = Backend::default())]
+    backend: Backend,
+    /// Clone without file contents, which rewriting names, emails, and messages never reads, with git itself rather than --backend. Only the files of the checked out branch are downloaded. Backups hold no file contents either, so restoring one needs the original to still be reachable, and pushing to a different destination downloads every file first.
+    #[arg(long, conflicts_with = "backend")]
+    blobless: bool,
+    /// Share objects between clones through a bare repository in DIR, created if need be. Every repository is fetched into it before it is cloned, downloading only what the cache doesn't have yet, and the clone borrows its objects rather than storing its own, which saves most of the time and space forks and mirrors of the same history take. Runs may share a cache. Clones are made with git itself rather than --backend. Backups borrow from the cache too, so restoring one needs it to still be there.
+    #[arg(long, value_name = "DIR", conflicts_with = "backend")]
+    reference_cache: Option<PathBuf>,
+    /// How to collect garbage after rewriting. aggressive packs much tighter, but is slow on large repositories. The space reclaimed is shown in the summary.
+    #[arg(long, value_enum, default_value_t = GcMode::default())]
+    gc: GcMode,
+    /// What to do with a clone an earlier run left behind. pull fetches and fast-forwards, which fails once a branch was rewritten and not pushed; reuse cleans it as it is; reset fetches and resets every branch and tag to the remote's; reclone deletes it and clones again. Defaults to reset with --watch, and pull otherwise.
+    #[arg(long, value_enum, value_name = "POLICY")]
+    refresh: Option<RefreshPolicy>,
+    /// Also search file contents for email-shaped strings matching the old emails the config replaces, when planning with --dry-run or --watch and after rewriting. tree searches the files at the tip of every branch and tag, and history every version of every file, which is slow on large repositories. Rewriting only changes commits and tags, so any found are listed in the summary as needing a content rewrite.
+    #[arg(long, value_enum, value_name = "DEPTH")]
+    scan_blobs: Option<BlobScan>,
+}
+
+/// What watching does with repositories the rules still match
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum WatchAction {
+    /// Send the audit to the configured notifications
+    Alert,
+    /// Also clean them, leaving them for --commit
+    Clean,
+    /// Also clean and force push them
+    Push,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Validate a configuration file without touching any repositories.
+    /// Reports unknown keys, malformed repository names, invalid emails, regexes that don't compile, and overlapping rules.
+    CheckConfig {
+        /// The configuration file to validate, or - for standard input
+        config: PathBuf,
+    },
+    /// Check that git, git-filter-repo, Python, and git-lfs are installed, and that the first repository of every host and owner in the config can be read with the credentials configured for it, without changing anything.
+    /// The API token is checked too where there is one, and so are the signing key with --sign and the backup encryption tools if the config encrypts backups. The same checks run before every clean, unless --no-doctor is passed.
+    Doctor {
+        /// The configuration file to check the environment for, or - for standard input
+        config: PathBuf,
+        /// Check the key commits would be signed with, as when cleaning with --sign
+        #[arg(long)]
+        sign: bool,
+        /// Private key to authenticate to every ssh host with, as when cleaning with --ssh-key
+        #[arg(long, value_name = "PATH")]
+        ssh_key: Option<PathBuf>,
+    },
+    /// Convert a CSV file of old_email,new_email,new_name rows into email_substitutions, printed as JSON.
+    /// The output can be pasted into a config, or saved and included from one.
+    ImportSubstitutions {
+        /// The CSV file to convert. The new_name column is optional, and a header row is skipped
+        csv: PathBuf,
+    },
+    /// Replace the local clone of a repository with its backup, undoing the rewrite. Encrypted backups are decrypted.
+    /// The backup is checked against the checksum and refs in its manifest first, and refused if it changed since it was taken.
+    Restore {
+        /// The configuration file the repository was cleaned with, or - for standard input
+        config: PathBuf,
+        /// The repository to restore, as it is named in the config
+        repository: String,
+        /// The age identity file to decrypt backups encrypted with age with
+        #[arg(long, env = "REPO_CLEANER_AGE_IDENTITY", value_name = "PATH")]
+        age_identity: Option<PathBuf>,
+    },
+    /// Restore from their backups the clones a clean that failed or was interrupted left partway through rewriting or signing them.
+    /// Such clones are never pushed, and are rolled back anyway when they are next cleaned.
+    Rollback {
+        /// The configuration file the repositories were cleaned with, or - for standard input
+        config: PathBuf,
+        /// Only roll back this repository, as it is named in the config
+        repository: Option<String>,
+        /// The age identity file to decrypt backups encrypted with age with
+        #[arg(long, env = "REPO_CLEANER_AGE_IDENTITY", value_name = "PATH")]
+        age_identity: Option<PathBuf>,
+    },
+    /// Check that the backup of every repository is still exactly the one that was taken, and unpacks with every object.
+    /// Every backup has a manifest next to it, `cleaner/backups/<repository>.manifest.json`, recording when it was taken, every ref and what it pointed to, the archive's SHA-256, the repo_cleaner version, and a hash of the config, as evidence of what existed before the rewrite. Backups are checked against it.
+    VerifyBackup {
+        /// The configuration file listing the repositories whose backups to check, or - for standard input
+        config: PathBuf,
+        /// The age identity file to decrypt backups encrypted with age with
+        #[arg(long, env = "REPO_CLEANER_AGE_IDENTITY", value_name = "PATH")]
+        age_identity: Option<PathBuf>,
+    },
+    /// Compare the history in a repository's backup with its rewritten clone, commit by commit, and flag every commit whose files changed.
+    /// Rewriting only changes names, emails, and messages, so every rewritten commit should have exactly the files it had, apart from submodule pointers moved to the submodules' rewritten commits, and only commits that changed nothing may be pruned. Fails if any other commit changed, unless the config has a commit or filename callback, which may change files on purpose.
+    Diff {
+        /// The configuration file the repository was cleaned with, or - for standard input
+        config: PathBuf,
+        /// The repository to compare, as it is named in the config
+        repository: String,
+        /// The age identity file to decrypt backups encrypted with age with
+        #[arg(long, env = "REPO_CLEANER_AGE_IDENTITY", value_name = "PATH")]
+        age_identity: Option<PathBuf>,
+    },
+    /// Check every author and committer email in the history of every repository against an allowlist, without rewriting anything.
+    /// Exits with 2, listing every offending commit, if any email isn't on it. Meant to run in CI as a leak detector.
+    Enforce {
+        /// The configuration file listing the repositories to check, or - for standard input
+        config: PathBuf,
+        /// File of allowed emails and domains, one per line. `*.example.com` also allows every subdomain, and lines starting with # are ignored
+        #[arg(long, value_name = "PATH")]
+        allowlist: PathBuf,
+    },
+    /// Remove from the working directory the clones, backups, and migration notes of repositories no longer in the config, the lock files runs that died left in clones, and what git-filter-repo leaves in each clone's .git/filter-repo/.
+    /// Reports how much space was reclaimed.
+    GcWorkdir {
+        /// The configuration file listing the repositories to keep, or - for standard input
+        config: PathBuf,
+        /// Only report what would be removed
+        #[arg(long)]
+        dry_run: bool,
+        /// Remove the lock on the working directory left behind by a run that died, instead of refusing to start
+        #[arg(long)]
+        force_unlock: bool,
+    },
+    /// List every run in the working directory, from `cleaner/history.jsonl`, with its phases, how many repositories succeeded, were clean, were skipped, or failed, and how long it took.
+    /// Every phase of every run appends a record there, with the run's id, the repo_cleaner version, a hash of the config, and every repository's outcome and duration, as evidence of what was run when.
+    History {
+        #[command(subcommand)]
+        command: Option<HistoryCommand>,
+    },
+    /// Print a completion script for every flag and subcommand.
+    /// For example, `repo_cleaner completions bash > /etc/bash_completion.d/repo_cleaner`, or save the zsh script as `_repo_cleaner` somewhere on $fpath.
+    Completions {
+        /// The shell to complete in
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Print a JSON Schema of the configuration file, for editors to validate and complete configs with.
+    /// Point an editor at the saved schema, or reference it from a config as "$schema": "repo_cleaner.schema.json".
+    Schema,
+    /// Print the man page, in roff. Install it with `repo_cleaner man > /usr/local/share/man/man1/repo_cleaner.1`.
+    /// The page is rendered when repo_cleaner is built, with the flags of the features it was built with.
+    Man,
+}
+
+#[derive(Subcommand, Debug)]
+enum HistoryCommand {
+    /// Print every phase of a run, and the outcome of every repository in it
+    Show {
+        /// The run's id, like 20240131T120000Z-3fa2c1
+        id: String,
+    },
+}
+
+/// Parse an interval like `30m`, `24h`, or `7d`
+fn parse_interval(text: &str) -> Result<Duration, String> {
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number = number.parse::<u64>().map_err(|_| format!("{text} does not start with a number"))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "" => return Err(format!("{text} needs a unit of time, like {text}m or {text}h")),
+        _ => return Err(format!("{unit} is not a unit of time. Use s, m, h, or d")),
+    };
+    match number.checked_mul(seconds).ok_or_else(|| format!("{text} is too long"))? {
+        0 => Err("The interval must be longer than zero".to_string()),
+        seconds => Ok(Duration::from_secs(seconds)),
+    }
+}
+
+/// Parse a shard like 2/4 into its 1-based index and the number of shards
+fn parse_shard(text: &str) -> Result<(usize, usize), String> {
+    let (shard, shards) = text.split_once('/')
+        .ok_or_else(|| format!("{text} is not a shard like 2/4"))?;
+    let shard = shard.parse::<usize>().map_err(|_| format!("{shard} is not a number"))?;
+    let shards = shards.parse::<usize>().map_err(|_| format!("{shards} is not a number"))?;
+    if shard == 0 || shard > shards {
+        return Err(format!("The shard must be between 1 and {shards}"));
+    }
+    Ok((shard, shards))
+}
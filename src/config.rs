@@ -0,0 +1,1123 @@
+use std::{collections::{BTreeMap, HashMap}, path::{Path, PathBuf}, sync::OnceLock};
+
+use eyre::{bail, eyre, Context, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::warn;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{backup::BackupEncryption, credentials::CredentialRoute, forge::{HostToken, Provider}, discovery::Discovery, gitea::GiteaConfig, github::GitHubAppConfig, gitlab::GitLabConfig, hooks::Hooks, import, notify::NotifyConfig, ssh::SshHost};
+
+
+/// The configuration file
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[schemars(deny_unknown_fields)]
+pub struct Config {
+    /// Other config files to merge into this one, relative to this file.
+    /// Repository lists are concatenated, and maps are merged with this file taking precedence.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<PathBuf>,
+    /// Repositories to clean, as `Org/reponame` or as an object that also limits what is rewritten
+    #[serde(default)]
+    pub repositories: Vec<RepositoryConfig>,
+    /// Maps old emails to the email, and optionally the name, that should replace them
+    #[serde(default)]
+    pub email_substitutions: HashMap<String, Substitution>,
+    /// Emails that are never rewritten, even if a substitution or name rule matches them, e.g. bot accounts.
+    /// They are checked again before pushing, to make sure they survived untouched.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub preserve_emails: Vec<String>,
+    /// Match old emails regardless of case, so `John@Example.com` and `john@example.com` are treated as the same email
+    #[serde(default)]
+    pub case_insensitive_emails: bool,
+    /// Trim and collapse the whitespace of every replacement name and email, and normalize them to
+    /// Unicode NFC, so stray spaces or decomposed accents aren't written into history
+    #[serde(default)]
+    pub canonicalize_identities: bool,
+    /// CSV files of `old_email,new_email,new_name` rows to add to `email_substitutions`, relative to this file.
+    /// Entries written directly in `email_substitutions` take precedence.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub substitutions_csv: Vec<PathBuf>,
+    /// Maps name regexes to the name that should replace any match, tried longest pattern first
+    #[serde(default)]
+    pub name_substitutions: HashMap<String, NameSubstitution>,
+    /// Renames that only apply to identities matching both a name and an email regex, tried in order
+    /// before `name_substitutions`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub identity_substitutions: Vec<IdentityRule>,
+    /// Old emails and names to fold into a single canonical identity each, tried before every other rule
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub merge_identities: Vec<IdentityMerge>,
+    /// The one identity every author, committer, and tagger is replaced with, so no individual
+    /// attribution is left. Takes precedence over every other rule
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub republish: Option<Republish>,
+    /// Maps old GitHub usernames to new ones (or to any email), rewriting their
+    /// `12345+user@users.noreply.github.com` and legacy `user@users.noreply.github.com` addresses.
+    /// Usernames match regardless of case, as on GitHub, and `email_substitutions` take precedence
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub github_noreply_substitutions: HashMap<String, String>,
+    /// Which identities of a commit or tag substitutions are applied to. A rule's own `apply_to` or `fields` overrides this
+    #[serde(default = "Role::all", skip_serializing_if = "Role::is_all")]
+    pub apply_to: Vec<Role>,
+    /// Whether commits the rewrite leaves empty are dropped. filter-repo's own default is `auto`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prune_empty: Option<PruneEmpty>,
+    /// Which `refs/replace/` refs filter-repo keeps from old commits to the rewritten ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replace_refs: Option<ReplaceRefs>,
+    /// Fuzz the author and committer dates of commits by matched identities
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub anonymize_dates: Option<AnonymizeDates>,
+    /// Remove ASCII armored PGP signatures pasted into commit messages, along with any `gpgsig`
+    /// header they were pasted with, since they no longer match once a commit is rewritten
+    #[serde(default)]
+    pub strip_message_signatures: bool,
+    /// Trailers added to the message of every rewritten commit, like
+    /// `History-Rewritten-By: repo_cleaner {run_id}`, with `{run_id}`, `{name}`, and `{email}` filled in
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trailers: Vec<String>,
+    /// Release tags to move onto the rewritten commits and push, along with the releases made from them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub releases: Option<Releases>,
+    /// Where to send a summary when a phase finishes
+    #[serde(default)]
+    pub notify: NotifyConfig,
+    /// Commands run for every repository before and after it is rewritten or pushed
+    #[serde(default, skip_serializing_if = "Hooks::is_empty")]
+    pub hooks: Hooks,
+    /// Extra git-filter-repo callbacks for rewriting the generated substitutions can't express
+    #[serde(default, skip_serializing_if = "UserCallbacks::is_empty")]
+    pub callbacks: UserCallbacks,
+    /// Python scripts defining `rewrite(commit)`, relative to this file. Each is called with every
+    /// commit's metadata during the rewrite, after the substitutions, and returns what should change
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub plugins: Vec<PathBuf>,
+    /// How to connect to each ssh host, by hostname
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ssh: BTreeMap<String, SshHost>,
+    /// known_hosts file every ssh host's key is checked against, unless its `ssh` entry names another
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub known_hosts: Option<PathBuf>,
+    /// The ssh key or token for the repositories under each host or path prefix, like
+    /// `github.com/Org`. The longest prefix of a repository's URL wins
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub credentials: BTreeMap<String, CredentialRoute>,
+    /// Authenticate to GitHub as this app, for cloning, pushing, and the API
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub github_app: Option<GitHubAppConfig>,
+    /// The Gitea or Forgejo instance repositories with `"provider": "gitea"` are on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gitea: Option<GiteaConfig>,
+    /// The GitHub organizations to clean every repository of, and which repositories of those and
+    /// of `gitea`'s organizations are cleaned
+    #[serde(default, skip_serializing_if = "Discovery::is_default")]
+    pub discovery: Discovery,
+    /// Clone and push repositories with `"provider": "azure_devops"` over HTTPS with this token, instead of over ssh
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub azure_devops: Option<HostToken>,
+    /// Clone and push repositories with `"provider": "bitbucket"` over HTTPS with this token, instead of over ssh
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bitbucket: Option<HostToken>,
+    /// The GitLab instance repositories with `"provider": "gitlab"` are on, and how to authenticate
+    /// to it. Without it, they are on gitlab.com and cloned over ssh
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gitlab: Option<GitLabConfig>,
+    /// The most repositories on each provider processed at once with `--jobs`, to stay clear of its
+    /// rate limits. Local repositories are never limited
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub concurrency: BTreeMap<Provider, usize>,
+    /// Encrypt backups, which hold exactly the identities being removed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backup_encryption: Option<BackupEncryption>,
+    /// Clone repositories larger than this many megabytes bare, without a working tree, unless
+    /// their own `clone` says otherwise
+    #[serde(default = "default_bare_clone_above_mb")]
+    pub bare_clone_above_mb: u64,
+}
+
+fn default_bare_clone_above_mb() -> u64 {
+    500
+}
+
+/// Raw git-filter-repo callbacks, each written as the body of the Python function filter-repo wraps it in.
+///
+/// The commit and tag callbacks run after the generated ones, in the same function, so they see the
+/// already substituted identities. The others are passed to filter-repo as they are.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default, PartialEq, Eq)]
+#[schemars(deny_unknown_fields)]
+pub struct UserCallbacks {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit_callback: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag_callback: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_callback: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name_callback: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email_callback: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refname_callback: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename_callback: Option<String>,
+}
+
+impl UserCallbacks {
+    pub fn is_empty(&self) -> bool {
+        *self == UserCallbacks::default()
+    }
+
+    /// The callbacks passed straight to filter-repo, with the flag for each
+    pub fn passthrough(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("--message-callback", &self.message_callback),
+            ("--name-callback", &self.name_callback),
+            ("--email-callback", &self.email_callback),
+            ("--refname-callback", &self.refname_callback),
+            ("--filename-callback", &self.filename_callback),
+        ].into_iter()
+            .filter_map(|(flag, body)| body.as_deref().map(|body| (flag, body)))
+            .collect()
+    }
+}
+
+/// A repository to clean, and which slice of its history to rewrite
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[serde(from = "RepositoryEntry", into = "RepositoryEntry")]
+pub struct RepositoryConfig {
+    /// The repository, as `Org/reponame`
+    pub name: String,
+    /// The code host the repository is on, when it isn't a local path
+    pub provider: Provider,
+    /// Only rewrite commits authored at or after this date (`YYYY-MM-DD` or RFC 3339)
+    pub since: Option<String>,
+    /// Only rewrite commits authored before this date (`YYYY-MM-DD` or RFC 3339)
+    pub until: Option<String>,
+    /// Only rewrite this commit and the commits after it, leaving its ancestors untouched
+    pub start_commit: Option<String>,
+    /// Branches to rewrite and push, as exact names or globs like `release/*`. Empty means every branch
+    pub branches: Vec<String>,
+    /// Commands run for this repository, after the ones configured for every repository
+    pub hooks: Hooks,
+    /// Publish the cleaned history to this URL instead of force pushing the original
+    pub push_to: Option<String>,
+    /// Refspecs the commit phase pushes, like `main` or `refs/heads/release/*:refs/heads/legacy/*`,
+    /// instead of every selected branch
+    pub push_refspecs: Vec<String>,
+    /// Process this repository even though it has open pull requests and `--block-if-open-prs` is set
+    pub allow_open_prs: bool,
+    /// How the repository is cloned, instead of by its size against `bare_clone_above_mb`
+    pub clone: Option<CloneStrategy>,
+    /// The branch the remote's HEAD is pointed at after pushing, instead of the original's default branch
+    pub default_branch: Option<String>,
+}
+
+/// A repository as written in the config: either just its name, or an object
+#[derive(Deserialize, Serialize, JsonSchema)]
+#[serde(untagged)]
+#[schemars(deny_unknown_fields)]
+// Only lives long enough to be converted, so its size doesn't matter
+#[allow(clippy::large_enum_variant)]
+enum RepositoryEntry {
+    Name(String),
+    Detailed {
+        /// The repository, as `Org/reponame`
+        name: String,
+        /// The code host the repository is on, when it isn't a local path
+        #[serde(default, skip_serializing_if = "Provider::is_default")]
+        provider: Provider,
+        /// Only rewrite commits authored at or after this date (`YYYY-MM-DD` or RFC 3339)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        since: Option<String>,
+        /// Only rewrite commits authored before this date (`YYYY-MM-DD` or RFC 3339)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        until: Option<String>,
+        /// Only rewrite this commit and the commits after it, leaving its ancestors untouched
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        start_commit: Option<String>,
+        /// Branches to rewrite and push, as exact names or globs like `release/*`. Empty means every branch
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        branches: Vec<String>,
+        /// Commands run for this repository, after the ones configured for every repository
+        #[serde(default, skip_serializing_if = "Hooks::is_empty")]
+        hooks: Hooks,
+        /// Publish the cleaned history to this URL instead of force pushing the original
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        push_to: Option<String>,
+        /// Refspecs the commit phase pushes, like `main` or `refs/heads/release/*:refs/heads/legacy/*`,
+        /// instead of every selected branch
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        push_refspecs: Vec<String>,
+        /// Process this repository even though it has open pull requests and `--block-if-open-prs` is set
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        allow_open_prs: bool,
+        /// How the repository is cloned, instead of by its size against `bare_clone_above_mb`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        clone: Option<CloneStrategy>,
+        /// The branch the remote's HEAD is pointed at after pushing, instead of the original's default branch
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        default_branch: Option<String>,
+    },
+}
+
+impl From<RepositoryEntry> for RepositoryConfig {
+    fn from(entry: RepositoryEntry) -> Self {
+        match entry {
+            RepositoryEntry::Name(name) => Self::new(&name),
+            RepositoryEntry::Detailed { name, provider, since, until, start_commit, branches, hooks, push_to, push_refspecs, allow_open_prs, clone, default_branch } => Self { name, provider, since, until, start_commit, branches, hooks, push_to, push_refspecs, allow_open_prs, clone, default_branch },
+        }
+    }
+}
+
+impl From<RepositoryConfig> for RepositoryEntry {
+    fn from(repo: RepositoryConfig) -> Self {
+        // Keep plain repositories plain when writing a config back out
+        match repo {
+            RepositoryConfig { name, provider: Provider::GitHub, since: None, until: None, start_commit: None, branches, hooks, push_to: None, push_refspecs, allow_open_prs: false, clone: None, default_branch: None } if branches.is_empty() && hooks.is_empty() && push_refspecs.is_empty() => RepositoryEntry::Name(name),
+            RepositoryConfig { name, provider, since, until, start_commit, branches, hooks, push_to, push_refspecs, allow_open_prs, clone, default_branch } => RepositoryEntry::Detailed { name, provider, since, until, start_commit, branches, hooks, push_to, push_refspecs, allow_open_prs, clone, default_branch },
+        }
+    }
+}
+
+impl RepositoryConfig {
+    /// A repository whose whole history is rewritten
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            provider: Provider::default(),
+            since: None,
+            until: None,
+            start_commit: None,
+            branches: Vec::new(),
+            hooks: Hooks::default(),
+            push_to: None,
+            push_refspecs: Vec::new(),
+            allow_open_prs: false,
+            clone: None,
+            default_branch: None,
+        }
+    }
+
+    /// The repository's path, if it is a local repository (an absolute path, a `./` or `../` relative
+    /// one, or a `file://` URL) rather than `Org/reponame` on a code host
+    pub fn local_path(&self) -> Option<&Path> {
+        if let Some(path) = self.name.strip_prefix("file://") {
+            return Some(Path::new(path));
+        }
+        let path = Path::new(&self.name);
+        (path.is_absolute() || self.name.starts_with("./") || self.name.starts_with("../")).then_some(path)
+    }
+
+    /// The repository's own name, without its org or the directories it is in
+    pub fn short_name(&self) -> &str {
+        let name = self.name.trim_end_matches('/');
+        let name = name.strip_suffix(".git").unwrap_or(name);
+        name.rsplit('/').next().unwrap_or(name)
+    }
+
+    /// Where the repository's clone and backup go, relative to the directories that hold them.
+    /// GitLab paths go under their own directory, so their groups can't collide with GitHub owners.
+    pub fn dir_name(&self) -> PathBuf {
+        match self.local_path() {
+            Some(_) => Path::new("local").join(self.short_name()),
+            None if self.provider == Provider::GitLab => Path::new("gitlab").join(&self.name),
+            None => PathBuf::from(&self.name),
+        }
+    }
+
+    /// Whether the branch called `name` (without its remote) should be rewritten and pushed
+    pub fn selects_branch(&self, name: &str) -> bool {
+        self.branches.is_empty() || self.branches.iter().any(|b| {
+            b == name || glob::Pattern::new(b).is_ok_and(|p| p.matches(name))
+        })
+    }
+
+    /// `since` and `until` as unix timestamps
+    pub fn date_range(&self) -> Result<(Option<i64>, Option<i64>)> {
+        let parse = |date: &Option<String>| date.as_deref().map(parse_date).transpose();
+        Ok((
+            parse(&self.since).wrap_err(format!("Invalid \"since\" date for {}", self.name))?,
+            parse(&self.until).wrap_err(format!("Invalid \"until\" date for {}", self.name))?,
+        ))
+    }
+}
+
+/// Parse a `YYYY-MM-DD` date (as midnight UTC) or an RFC 3339 timestamp into a unix timestamp
+pub(crate) fn parse_date(date: &str) -> Result<i64, chrono::ParseError> {
+    match chrono::DateTime::parse_from_rfc3339(date) {
+        Ok(d) => Ok(d.timestamp()),
+        Err(e) => match chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+            Ok(d) => Ok(d.and_time(chrono::NaiveTime::MIN).and_utc().timestamp()),
+            // A date-only value that failed is more useful to report as such
+            Err(date_err) => Err(if date.len() <= 10 { date_err } else { e }),
+        },
+    }
+}
+
+/// The tags releases are made from, which are moved onto the rewritten commits and pushed with
+/// them, and whose GitHub or Gitea releases are moved too
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+#[schemars(deny_unknown_fields)]
+pub struct Releases {
+    /// Release tags, as exact names or globs like `v*`
+    pub tags: Vec<String>,
+    /// Re-sign the annotated release tags that were moved with the default GPG signing key, since
+    /// their signatures no longer match
+    #[serde(default)]
+    pub sign: bool,
+}
+
+impl Releases {
+    /// Whether the tag `name` is a release tag
+    pub fn matches(&self, name: &str) -> bool {
+        self.tags.iter().any(|t| t == name || glob::Pattern::new(t).is_ok_and(|p| p.matches(name)))
+    }
+}
+
+/// How the dates of commits by matched identities are anonymized
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default)]
+#[schemars(deny_unknown_fields)]
+pub struct AnonymizeDates {
+    #[serde(default)]
+    pub mode: DateMode,
+    /// The window dates are rounded to, or randomized within
+    #[serde(default)]
+    pub granularity: DateGranularity,
+    /// The secret randomized dates are derived from, required when randomizing. Without it, anyone
+    /// could repeat the derivation for every date in the window and recover the original
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DateMode {
+    /// Round dates down to the start of their window
+    #[default]
+    Round,
+    /// Move dates to a random point within their window, derived from the salt. The same commit always
+    /// gets the same date
+    Randomize,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DateGranularity {
+    Hour,
+    #[default]
+    Day,
+    Week,
+}
+
+impl DateGranularity {
+    pub fn seconds(self) -> u64 {
+        match self {
+            DateGranularity::Hour => 60 * 60,
+            DateGranularity::Day => 24 * 60 * 60,
+            DateGranularity::Week => 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+/// How a repository is cloned
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CloneStrategy {
+    /// A bare repository. Rewriting only touches headers, so needs no working tree, and skipping
+    /// it roughly halves the disk a clone takes
+    Bare,
+    /// A clone with the default branch checked out, for hooks that read or change its files
+    Worktree,
+}
+
+/// When filter-repo drops commits that end up changing nothing
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PruneEmpty {
+    /// Drop every empty commit, including ones that were empty before the rewrite
+    Always,
+    /// Only drop commits the rewrite made empty
+    Auto,
+    /// Keep every commit
+    Never,
+}
+
+impl PruneEmpty {
+    pub fn as_arg(self) -> &'static str {
+        match self {
+            PruneEmpty::Always => "always",
+            PruneEmpty::Auto => "auto",
+            PruneEmpty::Never => "never",
+        }
+    }
+}
+
+/// What filter-repo does with `refs/replace/` refs, which map old commit ids to rewritten ones
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReplaceRefs {
+    /// Delete existing replace refs, and add none
+    DeleteNoAdd,
+    /// Delete existing replace refs, and add one for every rewritten commit
+    DeleteAndAdd,
+    /// Update existing replace refs, and add none
+    UpdateNoAdd,
+    /// Update existing replace refs, and add one for every rewritten commit that has none
+    UpdateOrAdd,
+    /// Update existing replace refs, and add one for every rewritten commit
+    UpdateAndAdd,
+}
+
+impl ReplaceRefs {
+    pub fn as_arg(self) -> &'static str {
+        match self {
+            ReplaceRefs::DeleteNoAdd => "delete-no-add",
+            ReplaceRefs::DeleteAndAdd => "delete-and-add",
+            ReplaceRefs::UpdateNoAdd => "update-no-add",
+            ReplaceRefs::UpdateOrAdd => "update-or-add",
+            ReplaceRefs::UpdateAndAdd => "update-and-add",
+        }
+    }
+}
+
+/// One of the identities recorded on a commit or tag
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Author,
+    Committer,
+    Tagger,
+}
+
+impl Role {
+    pub fn all() -> Vec<Role> {
+        vec![Role::Author, Role::Committer, Role::Tagger]
+    }
+
+    fn is_all(roles: &[Role]) -> bool {
+        Role::all().iter().all(|r| roles.contains(r))
+    }
+}
+
+/// A single name or email recorded on a commit or tag, which a rule can be limited to
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Field {
+    #[serde(rename = "author.name")]
+    AuthorName,
+    #[serde(rename = "author.email")]
+    AuthorEmail,
+    #[serde(rename = "committer.name")]
+    CommitterName,
+    #[serde(rename = "committer.email")]
+    CommitterEmail,
+    #[serde(rename = "tagger.name")]
+    TaggerName,
+    #[serde(rename = "tagger.email")]
+    TaggerEmail,
+}
+
+impl Field {
+    /// Both fields of every role in `roles`
+    pub fn of(roles: &[Role]) -> Vec<Field> {
+        roles.iter()
+            .flat_map(|role| match role {
+                Role::Author => [Field::AuthorName, Field::AuthorEmail],
+                Role::Committer => [Field::CommitterName, Field::CommitterEmail],
+                Role::Tagger => [Field::TaggerName, Field::TaggerEmail],
+            })
+            .collect()
+    }
+
+    pub fn is_email(self) -> bool {
+        matches!(self, Field::AuthorEmail | Field::CommitterEmail | Field::TaggerEmail)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Field::AuthorName => "author.name",
+            Field::AuthorEmail => "author.email",
+            Field::CommitterName => "committer.name",
+            Field::CommitterEmail => "committer.email",
+            Field::TaggerName => "tagger.name",
+            Field::TaggerEmail => "tagger.email",
+        }
+    }
+}
+
+/// What an old email is replaced with
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+#[schemars(deny_unknown_fields)]
+pub enum Substitution {
+    /// Replace only the email
+    Email(String),
+    /// Replace the email, and the name of anyone using the old email
+    Identity {
+        new_email: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        new_author: Option<String>,
+        /// Which identities this substitution is applied to, instead of the config's `apply_to`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        apply_to: Option<Vec<Role>>,
+        /// Exactly which fields this substitution may change, instead of both fields of every identity it is applied to
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fields: Option<Vec<Field>>,
+    },
+}
+
+impl Substitution {
+    pub fn new_email(&self) -> &str {
+        match self {
+            Substitution::Email(email) => email,
+            Substitution::Identity { new_email, .. } => new_email,
+        }
+    }
+
+    pub fn new_author(&self) -> Option<&str> {
+        match self {
+            Substitution::Email(_) => None,
+            Substitution::Identity { new_author, .. } => new_author.as_deref(),
+        }
+    }
+
+    pub fn apply_to(&self) -> Option<&[Role]> {
+        match self {
+            Substitution::Email(_) => None,
+            Substitution::Identity { apply_to, .. } => apply_to.as_deref(),
+        }
+    }
+
+    /// The fields this substitution may change, if it narrows the config's `apply_to`
+    pub fn fields(&self) -> Option<Vec<Field>> {
+        match self {
+            Substitution::Email(_) => None,
+            Substitution::Identity { fields: Some(fields), .. } => Some(fields.clone()),
+            Substitution::Identity { apply_to, .. } => apply_to.as_deref().map(Field::of),
+        }
+    }
+}
+
+/// What names matching a regex are replaced with
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+#[schemars(deny_unknown_fields)]
+pub enum NameSubstitution {
+    Name(String),
+    /// A name only replaced in some fields
+    Detailed {
+        new_name: String,
+        /// The name fields this rule may change, instead of those of every identity in `apply_to`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fields: Option<Vec<Field>>,
+    },
+}
+
+impl NameSubstitution {
+    pub fn new_name(&self) -> &str {
+        match self {
+            NameSubstitution::Name(name) => name,
+            NameSubstitution::Detailed { new_name, .. } => new_name,
+        }
+    }
+
+    pub fn fields(&self) -> Option<&[Field]> {
+        match self {
+            NameSubstitution::Name(_) => None,
+            NameSubstitution::Detailed { fields, .. } => fields.as_deref(),
+        }
+    }
+}
+
+/// Several old identities merged into one, so whoever committed under any of them ends up as the same person
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[schemars(deny_unknown_fields)]
+pub struct IdentityMerge {
+    /// The canonical name
+    pub name: String,
+    /// The canonical email
+    pub email: String,
+    /// Old emails, matched exactly, or regardless of case with `case_insensitive_emails`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub emails: Vec<String>,
+    /// Old names, matched exactly
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub names: Vec<String>,
+}
+
+impl IdentityMerge {
+    /// `Name <email>`, as the identity is reported
+    pub fn identity(&self) -> String {
+        format!("{} <{}>", self.name, self.email)
+    }
+}
+
+/// The identity a republished history is attributed to, like `Project Bot <bot@example.com>`
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[schemars(deny_unknown_fields)]
+pub struct Republish {
+    pub name: String,
+    pub email: String,
+}
+
+impl Republish {
+    /// `Name <email>`, as the identity is reported
+    pub fn identity(&self) -> String {
+        format!("{} <{}>", self.name, self.email)
+    }
+}
+
+/// A rename for identities whose name and email both match, so a common name only renames the intended person
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[schemars(deny_unknown_fields)]
+pub struct IdentityRule {
+    /// Regex the old name has to match
+    pub name: String,
+    /// Regex the old email has to match
+    pub email: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_name: Option<String>,
+    /// Replaces the email, instead of any `email_substitutions` entry for it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_email: Option<String>,
+    /// Exactly which fields this rule may change, instead of both fields of every identity in `apply_to`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fields: Option<Vec<Field>>,
+}
+
+impl Config {
+    /// Load a configuration file from disk, along with everything it includes
+    pub fn load(path: &Path) -> Result<Self> {
+        let value = load_value(path, &mut Vec::new())?;
+
+        let mut config: Self = serde_json::from_value(value)
+            .wrap_err("Error reading configuration file")?;
+        if config.canonicalize_identities {
+            config.canonicalize();
+        }
+        Ok(config)
+    }
+
+    /// `name_substitutions` in the order they are tried: longest pattern first, so a specific
+    /// pattern wins over a broader one that also matches, then alphabetically
+    pub fn ordered_name_substitutions(&self) -> Vec<(&String, &NameSubstitution)> {
+        let mut rules = self.name_substitutions.iter().collect::<Vec<_>>();
+        rules.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        rules
+    }
+
+    /// Canonicalize every replacement name and email, as `canonicalize_identities` asks
+    fn canonicalize(&mut self) {
+        for substitution in self.email_substitutions.values_mut() {
+            match substitution {
+                Substitution::Email(email) => *email = canonical_email(email),
+                Substitution::Identity { new_email, new_author, .. } => {
+                    *new_email = canonical_email(new_email);
+                    if let Some(name) = new_author {
+                        *name = canonical_name(name);
+                    }
+                },
+            }
+        }
+        for substitution in self.name_substitutions.values_mut() {
+            match substitution {
+                NameSubstitution::Name(name) | NameSubstitution::Detailed { new_name: name, .. } => *name = canonical_name(name),
+            }
+        }
+        for rule in &mut self.identity_substitutions {
+            rule.new_name = rule.new_name.as_deref().map(canonical_name);
+            rule.new_email = rule.new_email.as_deref().map(canonical_email);
+        }
+        for merge in &mut self.merge_identities {
+            merge.name = canonical_name(&merge.name);
+            merge.email = canonical_email(&merge.email);
+        }
+        for new in self.github_noreply_substitutions.values_mut() {
+            *new = canonical_email(new);
+        }
+    }
+}
+
+/// `name` with its whitespace trimmed and collapsed to single spaces, in Unicode NFC
+pub(crate) fn canonical_name(name: &str) -> String {
+    name.split_whitespace().collect::<Vec<_>>().join(" ").nfc().collect()
+}
+
+/// `email` with surrounding whitespace trimmed, in Unicode NFC
+pub(crate) fn canonical_email(email: &str) -> String {
+    email.trim().nfc().collect()
+}
+
+/// Fill in the `{name}`, `{first}`, `{last}`, `{first_initial}`, `{last_initial}`, `{email}`, `{local}`,
+/// and `{domain}` variables of a replacement name or email from the identity a rule matched, as the
+/// rewrite does. `{first}` and `{last}` are the first and last words of the name, and `{{` and `}}` are
+/// literal braces. Fails on an unknown variable or a lone brace.
+pub(crate) fn fill_template(template: &str, name: &str, email: &str) -> Result<String, String> {
+    let words = name.split_whitespace().collect::<Vec<_>>();
+    let first = words.first().copied().unwrap_or_default();
+    let last = words.last().copied().unwrap_or_default();
+    let (local, domain) = email.rsplit_once('@').unwrap_or((email, ""));
+    let initial = |word: &str| word.chars().take(1).collect::<String>();
+
+    fill_variables(template, "{name}, {first}, {last}, {first_initial}, {last_initial}, {email}, {local}, or {domain}", |variable| Some(match variable {
+        "name" => name.to_string(),
+        "first" => first.to_string(),
+        "last" => last.to_string(),
+        "first_initial" => initial(first),
+        "last_initial" => initial(last),
+        "email" => email.to_string(),
+        "local" => local.to_string(),
+        "domain" => domain.to_string(),
+        _ => return None,
+    }))
+}
+
+/// Fill in the `{run_id}` of a trailer, and the `{name}` and `{email}` of the commit's author after
+/// rewriting, as the rewrite does. Fails like [`fill_template`].
+pub(crate) fn fill_trailer(template: &str, run_id: &str, name: &str, email: &str) -> Result<String, String> {
+    fill_variables(template, "{run_id}, {name}, or {email}", |variable| Some(match variable {
+        "run_id" => run_id.to_string(),
+        "name" => name.to_string(),
+        "email" => email.to_string(),
+        _ => return None,
+    }))
+}
+
+/// Fill in every `{variable}` of `template` with `value`, where `{{` and `}}` are literal braces.
+/// Fails on a lone brace, or a variable `value` doesn't know, naming the `known` ones.
+fn fill_variables(template: &str, known: &str, value: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut filled = String::new();
+    let mut rest = template;
+    while let Some(i) = rest.find(['{', '}']) {
+        filled.push_str(&rest[..i]);
+        let (brace, after) = rest[i..].split_at(1);
+        if let Some(after) = after.strip_prefix(brace) {
+            filled.push_str(brace);
+            rest = after;
+            continue;
+        }
+        let Some((variable, after)) = after.split_once('}').filter(|_| brace == "{") else {
+            return Err(format!("{template:?} has a lone {brace}. Write {brace}{brace} for a literal one"));
+        };
+        let Some(filled_in) = value(variable) else {
+            return Err(format!("{template:?} uses {{{variable}}}, which isn't one of {known}"));
+        };
+        filled.push_str(&filled_in);
+        rest = after;
+    }
+    filled.push_str(rest);
+    Ok(filled)
+}
+
+/// The config path that reads the config from standard input instead of a file
+pub const STDIN: &str = "-";
+
+/// Read the config file at `path`, or standard input when it is [`STDIN`].
+/// Standard input is read once and kept, since the config is read again after being checked.
+pub(crate) fn read_config(path: &Path) -> Result<String> {
+    static STDIN_TEXT: OnceLock<Result<String, String>> = OnceLock::new();
+
+    if path == Path::new(STDIN) {
+        return STDIN_TEXT.get_or_init(|| std::io::read_to_string(std::io::stdin()).map_err(|e| e.to_string()))
+            .clone()
+            .map_err(|e| eyre!("Unable to read the configuration from standard input: {e}"));
+    }
+    std::fs::read_to_string(path)
+        .wrap_err(format!("Unable to open configuration file {path:?}"))
+}
+
+/// The formats a config file can be written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConfigFormat {
+    Json,
+    Toml,
+}
+
+impl ConfigFormat {
+    /// The format of the config at `path`, which is TOML if it ends in `.toml`. Standard input is
+    /// JSON if `text` starts like an object, which a TOML document never can.
+    pub(crate) fn of(path: &Path, text: &str) -> Self {
+        let toml = if path == Path::new(STDIN) {
+            !text.trim_start_matches('\u{feff}').trim_start().starts_with('{')
+        } else {
+            path.extension().is_some_and(|e| e.eq_ignore_ascii_case("toml"))
+        };
+        if toml { ConfigFormat::Toml } else { ConfigFormat::Json }
+    }
+
+    /// Parse `text` in this format
+    fn parse(self, text: &str) -> Result<Value> {
+        Ok(match self {
+            ConfigFormat::Json => serde_json::from_str(text)?,
+            ConfigFormat::Toml => toml_to_json(toml::from_str(text)?),
+        })
+    }
+}
+
+/// `value` as JSON, with dates and times as the strings they were written as
+pub(crate) fn toml_to_json(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => Value::from(i),
+        toml::Value::Float(f) => Value::from(f),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(d) => Value::String(d.to_string()),
+        toml::Value::Array(a) => Value::Array(a.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(t) => Value::Object(t.into_iter().map(|(k, v)| (k, toml_to_json(v))).collect()),
+    }
+}
+
+/// Read a single config file as JSON, or TOML, with placeholders expanded, and merge in everything it includes.
+/// `stack` holds the files currently being loaded, to detect include cycles.
+fn load_value(path: &Path, stack: &mut Vec<PathBuf>) -> Result<Value> {
+    // Standard input can't be included, so it can't include itself either
+    let canonical = if path == Path::new(STDIN) {
+        path.to_path_buf()
+    } else {
+        path.canonicalize()
+            .wrap_err(format!("Unable to open configuration file {path:?}"))?
+    };
+    if stack.contains(&canonical) {
+        bail!("Configuration file {path:?} includes itself");
+    }
+
+    let text = read_config(path)?;
+    let mut value = ConfigFormat::of(path, &text).parse(&text)
+        .wrap_err(format!("Error reading configuration file {path:?}"))?;
+    interpolate(&mut value)?;
+
+    // Includes are relative to the file that includes them, or the current directory for standard input
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    import_csv(&mut value, dir)?;
+    resolve_plugins(&mut value, dir)?;
+
+    let includes = match value.as_object_mut().and_then(|o| o.remove("include")) {
+        Some(includes) => serde_json::from_value::<Vec<PathBuf>>(includes)
+            .wrap_err(format!("\"include\" in {path:?} must be a list of paths"))?,
+        None => return Ok(value),
+    };
+
+    stack.push(canonical);
+    let mut merged = Value::Object(Default::default());
+    for include in includes {
+        let included = load_value(&dir.join(&include), stack)
+            .wrap_err(format!("Error loading {include:?}, included from {path:?}"))?;
+        merge(&mut merged, included, "");
+    }
+    stack.pop();
+
+    // The including file takes precedence over everything it includes
+    merge(&mut merged, value, "");
+    Ok(merged)
+}
+
+/// Replace `substitutions_csv` with the substitutions read from each CSV file, relative to `dir`
+fn import_csv(value: &mut Value, dir: &Path) -> Result<()> {
+    let Some(object) = value.as_object_mut() else {
+        return Ok(());
+    };
+    let Some(files) = object.remove("substitutions_csv") else {
+        return Ok(());
+    };
+    let files = serde_json::from_value::<Vec<PathBuf>>(files)
+        .wrap_err("\"substitutions_csv\" must be a list of paths")?;
+
+    let mut imported = Value::Object(Default::default());
+    for file in files {
+        let substitutions = import::read_substitutions_csv(&dir.join(file))?;
+        merge(&mut imported, serde_json::to_value(substitutions)?, "email_substitutions");
+    }
+
+    // Substitutions written in the file itself win over imported ones
+    if let Some(own) = object.remove("email_substitutions") {
+        merge(&mut imported, own, "email_substitutions");
+    }
+    object.insert("email_substitutions".to_string(), imported);
+
+    Ok(())
+}
+
+/// Make each of `plugins` relative to `dir` rather than to the file that lists it, so included
+/// files can list their own
+fn resolve_plugins(value: &mut Value, dir: &Path) -> Result<()> {
+    let Some(plugins) = value.as_object_mut().and_then(|o| o.get_mut("plugins")) else {
+        return Ok(());
+    };
+    let files = serde_json::from_value::<Vec<PathBuf>>(plugins.take())
+        .wrap_err("\"plugins\" must be a list of paths")?;
+    *plugins = serde_json::to_value(files.iter().map(|f| dir.join(f)).collect::<Vec<_>>())?;
+    Ok(())
+}
+
+/// Merge `overlay` into `base`. Lists are concatenated without duplicates, objects are merged
+/// key by key, and anything else in `overlay` replaces what is in `base`.
+fn merge(base: &mut Value, overlay: Value, path: &str) {
+    match (base, overlay) {
+        (Value::Array(base), Value::Array(overlay)) => {
+            for item in overlay {
+                if !base.contains(&item) {
+                    base.push(item);
+                }
+            }
+        },
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                let path = if path.is_empty() { key.clone() } else { format!("{path}.{key}") };
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value, &path),
+                    None => {
+                        base.insert(key, value);
+                    },
+                }
+            }
+        },
+        (base, overlay) => {
+            if *base != overlay {
+                warn!("Config value {path} is set more than once, using {overlay}");
+            }
+            *base = overlay;
+        },
+    }
+}
+
+/// Environment variables referenced by the config that aren't set
+#[derive(Debug, thiserror::Error)]
+#[error("Undefined environment variables referenced in config: {}", .missing.join(", "))]
+pub struct InterpolationError {
+    pub missing: Vec<String>,
+}
+
+/// Fields of the config, and of each repository object, holding shell commands or Python, which
+/// are left as written. A hook's `${NEW_HEAD}` is for the shell to expand when it runs
+const VERBATIM: [&str; 2] = ["hooks", "callbacks"];
+
+/// Expand `${VAR}` placeholders in every string (and every object key) of `value`, but those under
+/// [`VERBATIM`] fields. `$${` escapes a literal `${`.
+pub(crate) fn interpolate(value: &mut Value) -> Result<(), InterpolationError> {
+    interpolate_with(value, &|name| std::env::var(name).ok())
+}
+
+/// [`interpolate`], looking variables up with `lookup` instead of the process environment
+fn interpolate_with(value: &mut Value, lookup: &dyn Fn(&str) -> Option<String>) -> Result<(), InterpolationError> {
+    let mut missing = Vec::new();
+    interpolate_fields(value, lookup, &mut missing);
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        missing.sort();
+        missing.dedup();
+        Err(InterpolationError { missing })
+    }
+}
+
+/// Expand the placeholders in the fields of the config, or of one of its repositories
+fn interpolate_fields(value: &mut Value, lookup: &dyn Fn(&str) -> Option<String>, missing: &mut Vec<String>) {
+    let Value::Object(fields) = value else {
+        return interpolate_value(value, lookup, missing);
+    };
+
+    *fields = std::mem::take(fields).into_iter()
+        .map(|(key, mut field)| {
+            match (key.as_str(), &mut field) {
+                (key, _) if VERBATIM.contains(&key) => {},
+                ("repositories", Value::Array(repositories)) => repositories.iter_mut().for_each(|r| interpolate_fields(r, lookup, missing)),
+                (_, field) => interpolate_value(field, lookup, missing),
+            }
+            (expand(&key, lookup, missing), field)
+        })
+        .collect();
+}
+
+fn interpolate_value(value: &mut Value, lookup: &dyn Fn(&str) -> Option<String>, missing: &mut Vec<String>) {
+    match value {
+        Value::String(s) => *s = expand(s, lookup, missing),
+        Value::Array(items) => items.iter_mut().for_each(|v| interpolate_value(v, lookup, missing)),
+        Value::Object(map) => {
+            *map = std::mem::take(map).into_iter()
+                .map(|(k, mut v)| {
+                    interpolate_value(&mut v, lookup, missing);
+                    (expand(&k, lookup, missing), v)
+                })
+                .collect();
+        },
+        _ => {},
+    }
+}
+
+/// Expand the placeholders in a single string, recording the name of any variable that isn't set
+fn expand(s: &str, lookup: &dyn Fn(&str) -> Option<String>, missing: &mut Vec<String>) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        let tail = &rest[start..];
+
+        if let Some(escaped) = tail.strip_prefix("$${") {
+            out.push_str("${");
+            rest = escaped;
+        } else if let Some((name, after)) = tail.strip_prefix("${").and_then(|t| t.split_once('}')) {
+            match lookup(name) {
+                Some(v) => out.push_str(&v),
+                None => missing.push(name.to_string()),
+            }
+            rest = after;
+        } else {
+            out.push('$');
+            rest = &tail[1..];
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolate_expands_variables_and_escapes() {
+        let lookup = |name: &str| (name == "REPO_CLEANER_TEST_DOMAIN").then(|| "example.com".to_string());
+        let mut value = serde_json::json!({ "${REPO_CLEANER_TEST_DOMAIN}": ["a@${REPO_CLEANER_TEST_DOMAIN}", "$${HOME}", "$5"] });
+        interpolate_with(&mut value, &lookup).unwrap();
+        assert_eq!(value, serde_json::json!({ "example.com": ["a@example.com", "${HOME}", "$5"] }));
+    }
+
+    #[test]
+    fn interpolate_leaves_hooks_and_callbacks_as_written() {
+        let hooks = serde_json::json!({ "post_rewrite": "git tag rewritten-${REPO} ${NEW_HEAD}" });
+        let mut value = serde_json::json!({
+            "hooks": hooks,
+            "callbacks": { "message_callback": "return message.replace(b'${OLD}', b'')" },
+            "repositories": ["Org/${REPO_CLEANER_TEST_REPO}", { "name": "Org/other", "hooks": hooks }],
+        });
+        let lookup = |name: &str| (name == "REPO_CLEANER_TEST_REPO").then(|| "project".to_string());
+        interpolate_with(&mut value, &lookup).unwrap();
+
+        assert_eq!(value["repositories"][0], "Org/project");
+        assert_eq!(value["repositories"][1]["hooks"], hooks);
+        assert_eq!(value["hooks"], hooks);
+        assert_eq!(value["callbacks"]["message_callback"], "return message.replace(b'${OLD}', b'')");
+        let config: Config = serde_json::from_value(value).unwrap();
+        assert_eq!(config.hooks.post_rewrite.as_deref(), Some("git tag rewritten-${REPO} ${NEW_HEAD}"));
+    }
+
+    #[test]
+    fn interpolate_leaves_unterminated_placeholders() {
+        let mut value = serde_json::json!("a ${UNTERMINATED");
+        interpolate_with(&mut value, &|_| None).unwrap();
+        assert_eq!(value, "a ${UNTERMINATED");
+    }
+
+    #[test]
+    fn interpolate_reports_every_missing_variable_once() {
+        let mut value = serde_json::json!(["${REPO_CLEANER_TEST_UNSET_B}", "${REPO_CLEANER_TEST_UNSET_A}", "${REPO_CLEANER_TEST_UNSET_B}"]);
+        let error = interpolate_with(&mut value, &|_| None).unwrap_err();
+        assert_eq!(error.missing, ["REPO_CLEANER_TEST_UNSET_A", "REPO_CLEANER_TEST_UNSET_B"]);
+    }
+}
@@ -0,0 +1,186 @@
+use std::{collections::BTreeMap, io::Write, path::PathBuf, process::Stdio};
+
+use git2::{Cred, CredentialType};
+use git2_credentials::CredentialHandler;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{git, ssh::SshHost};
+
+
+/// Credentials for libgit2, tried in the order git itself would: a configured ssh key, the ssh agent
+/// and default keys, and for HTTPS, git's configured credential helpers.
+pub struct Credentials {
+    ssh_key: Option<PathBuf>,
+    handler: CredentialHandler,
+    helper_tried: bool,
+    /// What the credential helpers answered, to approve once it has worked
+    filled: Option<(String, String, String)>,
+    /// Environment for the credential helpers, like the token of a GitHub App installation
+    env: Vec<(&'static str, String)>,
+}
+
+impl Credentials {
+    pub fn new(ssh: &SshHost, env: Vec<(&'static str, String)>) -> Self {
+        let git_config = git2::Config::open_default().unwrap();
+        Self {
+            ssh_key: ssh.key.clone(),
+            handler: CredentialHandler::new(git_config),
+            helper_tried: false,
+            filled: None,
+            env,
+        }
+    }
+
+    /// The next credential to try for `url`, as called from libgit2's credentials callback
+    pub fn next(&mut self, url: &str, username: Option<&str>, allowed: CredentialType) -> Result<Cred, git2::Error> {
+        // A configured key is tried once, before everything the credential handler would try
+        if allowed.contains(CredentialType::SSH_KEY) {
+            if let Some(key) = self.ssh_key.take() {
+                return Cred::ssh_key(username.unwrap_or("git"), None, &key, None);
+            }
+        }
+
+        // libgit2's own credential helper support misses helpers like manager-core and
+        // osxkeychain, so ask `git credential` instead, exactly once
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) && !self.helper_tried {
+            self.helper_tried = true;
+            if let Some((user, password)) = fill(url, username, &self.env) {
+                let cred = Cred::userpass_plaintext(&user, &password);
+                self.filled = Some((url.to_string(), user, password));
+                return cred;
+            }
+        }
+
+        self.handler.try_next_credential(url, username, allowed)
+    }
+
+    /// Tell the credential helpers that their answer worked, so caching helpers keep it
+    pub fn approve(&self) {
+        if let Some((url, user, password)) = &self.filled {
+            if let Err(e) = credential("approve", url, &[("username", user), ("password", password)], &self.env) {
+                warn!("Unable to save credentials for {url}: {e}");
+            }
+        }
+    }
+}
+
+/// The credentials for every repository under a host or path prefix, like `github.com/Org`
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default, PartialEq, Eq)]
+#[schemars(deny_unknown_fields)]
+pub struct CredentialRoute {
+    /// Private key to authenticate over ssh with, instead of the one configured for the host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssh_key: Option<PathBuf>,
+    /// A token to authenticate over HTTPS with. Repositories it applies to are cloned and pushed over HTTPS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// The username to send with the token, instead of the one the host expects for tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+}
+
+/// `url` as `host/path`, without its scheme, user, port, or `.git`, which is what credential routes
+/// are prefixes of. Routes themselves may be written as URLs too.
+pub(crate) fn location(url: &str) -> String {
+    let url = url.strip_prefix("git+").unwrap_or(url);
+    let rest = match url.split_once("://") {
+        Some((_, rest)) => rest.to_string(),
+        // scp-like, as in `git@host:Org/reponame`
+        None => url.replacen(':', "/", 1),
+    };
+    let rest = rest.split_once('@').filter(|(user, _)| !user.contains('/')).map_or(rest.as_str(), |(_, rest)| rest);
+    let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+    let host = host.split(':').next().unwrap_or(host);
+    let path = path.trim_end_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
+    if path.is_empty() { host.to_string() } else { format!("{host}/{path}") }
+}
+
+/// The route of `routes` for `url`, which is the one whose prefix covers the most of it, in whole
+/// path segments
+pub(crate) fn route<'a>(routes: &'a BTreeMap<String, CredentialRoute>, url: &str) -> Option<&'a CredentialRoute> {
+    let target = location(url);
+    routes.iter()
+        .map(|(prefix, route)| (location(prefix), route))
+        .filter(|(prefix, _)| target == *prefix || target.starts_with(&format!("{prefix}/")))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, route)| route)
+}
+
+/// Have every git subprocess answer for HTTPS URLs under each route's prefix with its token. Install
+/// these last, so they take precedence over the helpers for whole hosts.
+pub fn install_routes(routes: &BTreeMap<String, CredentialRoute>) {
+    for (i, (prefix, route)) in routes.iter().enumerate() {
+        let Some(token) = &route.token else {
+            continue;
+        };
+        let prefix = location(prefix);
+        let default_username = match prefix.split('/').next() {
+            Some("github.com") => "x-access-token",
+            Some("bitbucket.org") => "x-token-auth",
+            // GitLab's, which Gitea and Azure DevOps don't mind
+            _ => "oauth2",
+        };
+
+        let (username, password) = (format!("REPO_CLEANER_CREDENTIAL_{i}_USERNAME"), format!("REPO_CLEANER_CREDENTIAL_{i}_TOKEN"));
+        git::set_env(&username, route.username.as_deref().unwrap_or(default_username));
+        git::set_env(&password, token);
+        install_helper(&format!("https://{prefix}"), &username, &password);
+    }
+}
+
+/// Point every git subprocess at a credential helper that answers for `url` with the username and
+/// password in the environment variables `username` and `password`, so tokens never end up in a URL
+/// or on disk. The variables are read whenever git asks, so a subprocess can be given its own.
+pub(crate) fn install_helper(url: &str, username: &str, password: &str) {
+    let helper = format!(r#"!f() {{ test "$1" = get && echo "username=${username}" && echo "password=${password}"; }}; f"#);
+
+    let key = format!("credential.{url}.helper");
+    // An empty helper first clears the helpers configured for the host, which could answer with stale credentials
+    git::add_config_env(&[(&key, ""), (&key, &helper)]);
+}
+
+/// Ask git's credential helpers for a username and password for `url`, without ever prompting
+fn fill(url: &str, username: Option<&str>, env: &[(&str, String)]) -> Option<(String, String)> {
+    let fields = username.map(|u| vec![("username", u)]).unwrap_or_default();
+    let output = match credential("fill", url, &fields, env) {
+        Ok(output) => output,
+        Err(e) => {
+            debug!("No credentials for {url} from credential helpers: {e}");
+            return None;
+        },
+    };
+
+    let value = |key: &str| output.lines()
+        .find_map(|l| l.strip_prefix(key)?.strip_prefix('='))
+        .map(str::to_string);
+    Some((value("username")?, value("password")?))
+}
+
+/// Run `git credential <action>` for `url` with `fields` and `env` added to its environment,
+/// returning what it printed
+fn credential(action: &str, url: &str, fields: &[(&str, &str)], env: &[(&str, String)]) -> std::io::Result<String> {
+    let mut child = git::command("git")
+        .args(["credential", action])
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        // Fail instead of prompting on a terminal the progress bars are drawn over
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut input = format!("url={url}\n");
+    for (key, value) in fields {
+        input += &format!("{key}={value}\n");
+    }
+    child.stdin.take().expect("stdin is piped").write_all(format!("{input}\n").as_bytes())?;
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
@@ -0,0 +1,148 @@
+use std::{collections::{BTreeSet, HashMap}, fmt};
+
+use eyre::Result;
+use git2::{FileMode, Oid, Repository, Sort, Tree, TreeEntry};
+use serde::Serialize;
+
+
+/// How many changed paths are listed for each commit before the rest are only counted
+const LISTED_PATHS: usize = 10;
+
+/// How the history in a backup compares with its rewrite, commit by commit
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct HistoryDiff {
+    /// Commits in the backup's branches, remote-tracking branches, and tags
+    pub compared: usize,
+    /// Commits the rewrite kept as they were
+    pub unchanged: usize,
+    /// Commits rewritten with exactly the same files
+    pub rewritten: usize,
+    /// Commits rewritten with only their submodule pointers changed, to the submodules' rewritten commits
+    pub submodules: usize,
+    /// Commits that were dropped since they changed nothing
+    pub pruned: usize,
+    /// Commits whose files changed, or that are gone although they changed something
+    pub changed: Vec<ChangedCommit>,
+}
+
+/// A commit the rewrite changed the files of
+#[derive(Serialize, Debug, Clone)]
+pub struct ChangedCommit {
+    pub old: String,
+    /// What it was rewritten to, or `None` if it is gone
+    pub new: Option<String>,
+    /// The paths whose contents differ
+    pub paths: Vec<String>,
+}
+
+impl fmt::Display for HistoryDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} commits compared: {} unchanged, {} rewritten with the same files, {} with only submodule pointers changed, {} pruned since they changed nothing",
+            self.compared, self.unchanged, self.rewritten, self.submodules, self.pruned,
+        )?;
+        for commit in &self.changed {
+            let Some(new) = &commit.new else {
+                writeln!(f, "missing: {} changed files, but was neither kept nor rewritten", commit.old)?;
+                continue;
+            };
+            let mut paths = commit.paths.iter().take(LISTED_PATHS).cloned().collect::<Vec<_>>();
+            if commit.paths.len() > LISTED_PATHS {
+                paths.push(format!("and {} more", commit.paths.len() - LISTED_PATHS));
+            }
+            writeln!(f, "changed: {} became {}, changing {}", commit.old, new, paths.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Compare every commit reachable from a branch, remote-tracking branch, or tag of `before` with
+/// what `commit_map` says it was rewritten to in `after`, or itself if it isn't in the map.
+/// Rewriting only ever changes identities and messages, so every commit should keep its tree,
+/// apart from submodule pointers.
+pub fn compare(before: &Repository, after: &Repository, commit_map: &HashMap<String, String>) -> Result<HistoryDiff> {
+    let mut walk = before.revwalk()?;
+    for reference in before.references()?.filter_map(|r| r.ok()) {
+        // Notes commits are replaced wholesale when their notes move, so only history is compared
+        if !reference.name().is_some_and(|name| ["refs/heads/", "refs/remotes/", "refs/tags/"].iter().any(|prefix| name.starts_with(prefix))) {
+            continue;
+        }
+        if let Ok(commit) = reference.peel_to_commit() {
+            walk.push(commit.id())?;
+        }
+    }
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+
+    let mut diff = HistoryDiff::default();
+    for oid in walk {
+        let oid = oid?;
+        let old = before.find_commit(oid)?;
+        diff.compared += 1;
+
+        let new_id = match commit_map.get(&oid.to_string()) {
+            Some(new) => Oid::from_str(new)?,
+            None => oid,
+        };
+        // Pruned commits are left out of the commit map
+        let Ok(new) = after.find_commit(new_id) else {
+            let empty = match old.parent(0) {
+                Ok(parent) => parent.tree_id() == old.tree_id(),
+                Err(_) => old.tree()?.is_empty(),
+            };
+            if empty {
+                diff.pruned += 1;
+            } else {
+                diff.changed.push(ChangedCommit { old: oid.to_string(), new: None, paths: Vec::new() });
+            }
+            continue;
+        };
+
+        if new.tree_id() == old.tree_id() {
+            if new_id == oid {
+                diff.unchanged += 1;
+            } else {
+                diff.rewritten += 1;
+            }
+            continue;
+        }
+        let mut changed = BTreeSet::new();
+        changed_paths(before, &old.tree()?, after, &new.tree()?, "", &mut changed)?;
+        if changed.iter().all(|(_, gitlink)| *gitlink) {
+            diff.submodules += 1;
+        } else {
+            diff.changed.push(ChangedCommit {
+                old: oid.to_string(),
+                new: Some(new_id.to_string()),
+                paths: changed.into_iter().map(|(path, _)| path).collect(),
+            });
+        }
+    }
+    Ok(diff)
+}
+
+/// Add every path whose entry differs between `old` in `before` and `new` in `after` to `changed`,
+/// along with whether it is a submodule pointer on either side
+fn changed_paths(before: &Repository, old: &Tree<'_>, after: &Repository, new: &Tree<'_>, prefix: &str, changed: &mut BTreeSet<(String, bool)>) -> Result<()> {
+    let names = old.iter().chain(new.iter())
+        .map(|entry| entry.name_bytes().to_vec())
+        .collect::<BTreeSet<_>>();
+    for name in names {
+        let (old_entry, new_entry) = (old.get_name_bytes(&name), new.get_name_bytes(&name));
+        let same = |a: &TreeEntry<'_>, b: &TreeEntry<'_>| a.id() == b.id() && a.filemode() == b.filemode();
+        if old_entry.as_ref().zip(new_entry.as_ref()).is_some_and(|(a, b)| same(a, b)) {
+            continue;
+        }
+
+        let path = format!("{prefix}{}", String::from_utf8_lossy(&name));
+        let is_tree = |entry: &Option<TreeEntry<'_>>| entry.as_ref().is_some_and(|e| e.filemode() == i32::from(FileMode::Tree));
+        if is_tree(&old_entry) && is_tree(&new_entry) {
+            let (old_entry, new_entry) = (old_entry.expect("checked above"), new_entry.expect("checked above"));
+            changed_paths(before, &before.find_tree(old_entry.id())?, after, &after.find_tree(new_entry.id())?, &format!("{path}/"), changed)?;
+            continue;
+        }
+        let gitlink = [&old_entry, &new_entry].into_iter().flatten().any(|e| e.filemode() == i32::from(FileMode::Commit));
+        changed.insert((path, gitlink));
+    }
+    Ok(())
+}
@@ -0,0 +1,209 @@
+use std::{collections::BTreeMap, io::ErrorKind, path::{Path, PathBuf}, time::Duration};
+
+use chrono::Utc;
+use eyre::{Context, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::forge::RateLimit;
+
+
+/// Which repositories of the organizations on GitHub and Gitea are cleaned, and how long their
+/// listings are reused for
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[schemars(deny_unknown_fields)]
+pub struct Discovery {
+    /// GitHub organizations (or users) every repository of which is cleaned, besides the
+    /// repositories listed themselves
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub github_organizations: Vec<String>,
+    /// Also clean archived repositories, which can't be pushed to until they are unarchived
+    #[serde(default)]
+    pub archived: bool,
+    /// Also clean forks
+    #[serde(default = "yes")]
+    pub forks: bool,
+    #[serde(default)]
+    pub visibility: Visibility,
+    /// Only clean repositories with at least one of these topics
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub topics: Vec<String>,
+    /// How many minutes a listing is reused for before the API is asked whether it changed
+    #[serde(default = "default_cache_minutes")]
+    pub cache_minutes: u64,
+}
+
+fn yes() -> bool {
+    true
+}
+
+fn default_cache_minutes() -> u64 {
+    60
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Self {
+            github_organizations: Vec::new(),
+            archived: false,
+            forks: yes(),
+            visibility: Visibility::default(),
+            topics: Vec::new(),
+            cache_minutes: default_cache_minutes(),
+        }
+    }
+}
+
+impl Discovery {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Whether `repository` passes every filter
+    pub fn selects(&self, repository: &ListedRepository) -> bool {
+        (self.archived || !repository.archived)
+            && (self.forks || !repository.fork)
+            && match self.visibility {
+                Visibility::All => true,
+                Visibility::Public => !repository.private,
+                Visibility::Private => repository.private,
+            }
+            && (self.topics.is_empty() || repository.topics.iter().any(|t| self.topics.contains(t)))
+    }
+
+    /// How long listings are reused for
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.cache_minutes * 60)
+    }
+}
+
+/// Which repositories are discovered by whether they are private
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    #[default]
+    All,
+    Public,
+    Private,
+}
+
+/// A repository as listed by an organization
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ListedRepository {
+    pub full_name: String,
+    #[serde(default)]
+    pub archived: bool,
+    #[serde(default)]
+    pub fork: bool,
+    #[serde(default)]
+    pub private: bool,
+    #[serde(default)]
+    pub topics: Vec<String>,
+}
+
+/// One page of a listing, and what is needed to ask whether it changed
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct CachedPage {
+    url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    /// The page after this one, if there is one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    next: Option<String>,
+    repositories: Vec<ListedRepository>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct CachedListing {
+    /// When the listing was last fetched or confirmed unchanged, as a unix timestamp
+    checked_at: i64,
+    pages: Vec<CachedPage>,
+}
+
+/// Organization listings kept in `discovery-cache.json`, so discovering the repositories of large
+/// organizations doesn't list them all again every run. Each page is kept with its ETag, and
+/// pages that didn't change since are answered with a 304, which doesn't count towards GitHub's
+/// rate limit.
+#[derive(Debug)]
+pub struct DiscoveryCache {
+    path: PathBuf,
+    listings: BTreeMap<String, CachedListing>,
+}
+
+impl DiscoveryCache {
+    /// Load the cache kept in `dir`, starting over if there is none or it can't be read
+    pub fn load(dir: &Path) -> Self {
+        let path = dir.join("discovery-cache.json");
+        let listings = match std::fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                warn!("Ignoring the discovery cache {path:?}, since it can't be read: {e}");
+                BTreeMap::new()
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                warn!("Ignoring the discovery cache {path:?}, since it can't be read: {e}");
+                BTreeMap::new()
+            },
+        };
+        Self { path, listings }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        std::fs::write(&self.path, serde_json::to_string(&self.listings)?)
+            .wrap_err(format!("Unable to write the discovery cache {:?}", self.path))
+    }
+
+    /// Every repository listed from `url` on, following each page's `Link: <...>; rel="next"` to
+    /// the next. A listing checked within `ttl` is used as it is, and otherwise every page is only
+    /// fetched again if it changed. `request` makes an authenticated GET request for a page.
+    pub(crate) fn list(&mut self, url: &str, ttl: Duration, limit: &RateLimit, request: impl Fn(&str) -> ureq::Request) -> Result<Vec<ListedRepository>, Box<ureq::Error>> {
+        let now = Utc::now().timestamp();
+        let cached = self.listings.get(url);
+        if let Some(listing) = cached.filter(|l| now - l.checked_at < ttl.as_secs() as i64) {
+            debug!("Using the cached listing of {url}");
+            return Ok(listing.pages.iter().flat_map(|p| p.repositories.clone()).collect());
+        }
+        let cached_pages = cached.map(|l| l.pages.clone()).unwrap_or_default();
+
+        let mut pages = Vec::new();
+        let mut next = Some(url.to_string());
+        while let Some(page_url) = next.take() {
+            let cached_page = cached_pages.iter().find(|p| p.url == page_url);
+            let mut page_request = request(&page_url);
+            if let Some(etag) = cached_page.and_then(|p| p.etag.as_deref()) {
+                page_request = page_request.set("If-None-Match", etag);
+            }
+            let response = limit.call(page_request)?;
+
+            let page = match cached_page {
+                Some(page) if response.status() == 304 => page.clone(),
+                _ => CachedPage {
+                    url: page_url,
+                    etag: response.header("etag").map(str::to_string),
+                    next: response.header("link").and_then(next_link),
+                    repositories: response.into_json().map_err(|e| Box::new(e.into()))?,
+                },
+            };
+            // A page past the last may come back empty, with a link to the one after it
+            if page.repositories.is_empty() {
+                break;
+            }
+            next.clone_from(&page.next);
+            pages.push(page);
+        }
+
+        let repositories = pages.iter().flat_map(|p| p.repositories.clone()).collect();
+        self.listings.insert(url.to_string(), CachedListing { checked_at: now, pages });
+        Ok(repositories)
+    }
+}
+
+/// The URL of the next page in a `Link` header, like `<https://...&page=2>; rel="next", <...>; rel="last"`
+fn next_link(header: &str) -> Option<String> {
+    header.split(',').find_map(|link| {
+        let (url, params) = link.split_once(';')?;
+        params.split(';').any(|p| p.trim() == "rel=\"next\"")
+            .then(|| url.trim().trim_start_matches('<').trim_end_matches('>').to_string())
+    })
+}
@@ -0,0 +1,205 @@
+use std::{collections::BTreeSet, fmt, path::Path, process::Command, time::Duration};
+
+use serde::Serialize;
+
+use crate::{backup::BackupEncryption, check::Severity, cleaner::Cleaner, config::RepositoryConfig, credentials, git::git_with, ssh::expand_home};
+
+
+/// How long reaching a host may take, unless --pull-timeout allows longer
+const REMOTE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The oldest git git-filter-repo runs with
+const MIN_GIT: (u32, u32) = (2, 22);
+
+/// The outcome of one of the [`run`] checks
+#[derive(Serialize, Debug, Clone)]
+pub struct Finding {
+    /// What was checked, like `git-filter-repo` or `github.com/Org`
+    pub subject: String,
+    /// How serious the problem is, or `None` if there is none
+    pub severity: Option<Severity>,
+    /// What was found, or what is wrong and how to fix it
+    pub message: String,
+}
+
+impl Finding {
+    fn ok(subject: &str, message: String) -> Self {
+        Self { subject: subject.to_string(), severity: None, message }
+    }
+
+    fn error(subject: &str, message: String) -> Self {
+        Self { subject: subject.to_string(), severity: Some(Severity::Error), message }
+    }
+
+    fn warning(subject: &str, message: String) -> Self {
+        Self { subject: subject.to_string(), severity: Some(Severity::Warning), message }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Some(Severity::Error)
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            None => "ok",
+            Some(Severity::Error) => "error",
+            Some(Severity::Warning) => "warning",
+        };
+        write!(f, "{severity}: {}: {}", self.subject, self.message)
+    }
+}
+
+/// Check that everything cleaning `repositories` with `cleaner` needs is installed and works: git,
+/// git-filter-repo and the Python it runs on, git-lfs, the signing key and backup encryption tools
+/// if they are used, and the credentials for every host and owner repositories are cloned from.
+///
+/// Problems are returned rather than failing, so they can all be reported at once.
+pub fn run(cleaner: &Cleaner, repositories: &[RepositoryConfig]) -> Vec<Finding> {
+    let mut findings = vec![check_git()];
+
+    findings.push(match probe("python3", &["--version"]) {
+        Ok(version) => Finding::ok("python3", version),
+        Err(e) => Finding::error("python3", format!("{e}. git-filter-repo runs on Python 3, so install it")),
+    });
+    findings.push(match probe("git", &["filter-repo", "--version"]) {
+        Ok(version) => Finding::ok("git-filter-repo", format!("version {version}")),
+        Err(e) => Finding::error("git-filter-repo", format!("{e}. Install it, like with `pip install git-filter-repo`, and put it on PATH")),
+    });
+    findings.push(match probe("git", &["lfs", "version"]) {
+        Ok(version) => Finding::ok("git-lfs", version),
+        Err(_) => Finding::warning("git-lfs", "Not installed, so repositories that store files in Git LFS will fail".to_string()),
+    });
+
+    if cleaner.options.sign {
+        findings.push(check_signing_key());
+    }
+    if let Some(encryption) = cleaner.config.backup_encryption.as_ref().filter(|_| !cleaner.options.skip_backup) {
+        findings.extend(check_encryption(encryption));
+    }
+    if let Some(identity) = &cleaner.options.age_identity {
+        if !expand_home(identity).is_file() {
+            findings.push(Finding::error("age identity", format!("{identity:?} doesn't exist")));
+        }
+    }
+
+    if !cleaner.options.offline {
+        findings.extend(check_remotes(cleaner, repositories));
+    }
+    findings
+}
+
+/// Whether git runs, and is new enough for git-filter-repo
+fn check_git() -> Finding {
+    let version = match probe("git", &["--version"]) {
+        Ok(version) => version,
+        Err(e) => return Finding::error("git", format!("{e}. Install git")),
+    };
+    let mut numbers = version.trim_start_matches("git version ").split(['.', ' ']).map(|n| n.parse::<u32>().unwrap_or_default());
+    let found = (numbers.next().unwrap_or_default(), numbers.next().unwrap_or_default());
+    if found < MIN_GIT {
+        return Finding::error("git", format!("{version} is too old for git-filter-repo, which needs {}.{} or newer", MIN_GIT.0, MIN_GIT.1));
+    }
+    Finding::ok("git", version)
+}
+
+/// Whether git has a key to sign commits with, and the key is there
+fn check_signing_key() -> Finding {
+    let setting = |key: &str| probe("git", &["config", "--get", key]).ok().filter(|value| !value.is_empty());
+    let key = setting("user.signingkey");
+    match setting("gpg.format").as_deref() {
+        Some("ssh") => match key {
+            // An ssh signing key may also be written out as the public key itself
+            Some(key) if key.starts_with("key::") || key.starts_with("ssh-") || expand_home(Path::new(&key)).is_file() => Finding::ok("signing key", format!("ssh key {key}")),
+            Some(key) => Finding::error("signing key", format!("user.signingkey is {key:?}, which doesn't exist")),
+            None => Finding::error("signing key", "gpg.format is ssh, but user.signingkey isn't set. Set it with `git config --global user.signingkey ~/.ssh/id_ed25519.pub`".to_string()),
+        },
+        _ => {
+            let program = setting("gpg.program").unwrap_or_else(|| "gpg".to_string());
+            let mut args = vec!["--batch", "--list-secret-keys"];
+            args.extend(key.as_deref());
+            match probe(&program, &args) {
+                Ok(keys) if !keys.is_empty() => Finding::ok("signing key", key.map_or("the default GPG key".to_string(), |key| format!("GPG key {key}"))),
+                Ok(_) => Finding::error("signing key", format!("{program} has no secret key to sign with. Create one, or set user.signingkey")),
+                Err(e) => Finding::error("signing key", format!("{e}. Check user.signingkey{}", key.map_or(String::new(), |key| format!(", which is {key:?}")))),
+            }
+        },
+    }
+}
+
+/// Whether the tool backups are encrypted with is installed, and GPG has every recipient's key
+fn check_encryption(encryption: &BackupEncryption) -> Vec<Finding> {
+    match encryption {
+        BackupEncryption::Age(_) => vec![match probe("age", &["--version"]) {
+            Ok(version) => Finding::ok("age", version),
+            Err(e) => Finding::error("age", format!("{e}. Backups are encrypted with age, so install it")),
+        }],
+        BackupEncryption::Gpg(recipients) => recipients.iter().map(|recipient| {
+            let subject = format!("backup recipient {recipient}");
+            match probe("gpg", &["--batch", "--list-keys", recipient]) {
+                Ok(_) => Finding::ok(&subject, "GPG has its key".to_string()),
+                Err(e) => Finding::error(&subject, format!("{e}. Import the recipient's public key with `gpg --import`")),
+            }
+        }).collect(),
+    }
+}
+
+/// Whether the first repository of every host and owner can be read with the credentials
+/// configured for it, and the forge's API answers with them if it is used
+fn check_remotes(cleaner: &Cleaner, repositories: &[RepositoryConfig]) -> Vec<Finding> {
+    let timeout = cleaner.options.timeouts.pull.unwrap_or(REMOTE_TIMEOUT).max(REMOTE_TIMEOUT);
+    let mut seen = BTreeSet::new();
+    let mut findings = Vec::new();
+    for repository in repositories.iter().filter(|r| r.local_path().is_none()) {
+        let job = cleaner.job(repository);
+        // Credentials are routed by host and path, so the owner is as far as they differ
+        let location = credentials::location(&job.url);
+        let owner = location.splitn(3, '/').take(2).collect::<Vec<_>>().join("/");
+        if !seen.insert(owner.clone()) {
+            continue;
+        }
+
+        let env = match cleaner.git_env(&job, &job.url) {
+            Ok(env) => env,
+            Err(e) => {
+                findings.push(Finding::error(&owner, format!("{e:#}")));
+                continue;
+            },
+        };
+        let url = cleaner.options.ssh.url(&job.url);
+        let read = git_with(Path::new("."), &["ls-remote", "--heads", &url], Some(timeout), &env);
+        findings.push(match read {
+            Ok(_) => Finding::ok(&owner, format!("Can read {}", job.repository)),
+            Err(e) => Finding::error(&owner, format!("Unable to read {} from {url}: {}. Check the ssh key or token configured for it", job.repository, first_line(&format!("{e:#}")))),
+        });
+
+        // The API is only needed, and only has credentials, for some hosts
+        if let Ok(forge) = cleaner.forge(&job) {
+            findings.push(match forge.repository(&job.repository) {
+                Ok(Some(_)) => Finding::ok(&owner, "The API token can see it".to_string()),
+                Ok(None) => Finding::warning(&owner, format!("The API token can't see {}, so pull requests, forks, and sizes aren't known", job.repository)),
+                Err(e) => Finding::error(&owner, format!("The API rejected the token: {}", first_line(&format!("{e:#}")))),
+            });
+        }
+    }
+    findings
+}
+
+/// Run `program` with `args` and return the first line it printed, or why it didn't work
+fn probe(program: &str, args: &[&str]) -> Result<String, String> {
+    let output = Command::new(program).args(args).output()
+        .map_err(|e| format!("Unable to run {program}: {e}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(match first_line(&stderr) {
+            "" => format!("`{program} {}` failed with {}", args.join(" "), output.status),
+            line => line.to_string(),
+        });
+    }
+    Ok(first_line(&String::from_utf8_lossy(&output.stdout)).to_string())
+}
+
+fn first_line(text: &str) -> &str {
+    text.trim().lines().next().unwrap_or_default()
+}
@@ -0,0 +1,123 @@
+use std::fmt;
+
+use eyre::Report;
+use git2::ErrorCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{git::{GitCommandError, TimeoutError}, retry::is_retryable, ssh::HostKeyError};
+
+
+/// What git, libgit2, and ssh say when credentials are missing or refused
+const AUTH_FAILURES: &[&str] = &[
+    "Authentication failed",
+    "authentication required",
+    "failed to authenticate",
+    "Permission denied (publickey",
+    "could not read Username",
+    "Invalid username or password",
+    "too many redirects or authentication replays",
+];
+
+/// A repository that couldn't be cloned, for any reason but its credentials
+#[derive(Debug, thiserror::Error)]
+#[error("Unable to clone {url}")]
+pub struct CloneError {
+    pub url: String,
+}
+
+/// Credentials for a repository or its destination that were refused, or couldn't be had
+#[derive(Debug, thiserror::Error)]
+#[error("Unable to authenticate to {url}")]
+pub struct AuthError {
+    pub url: String,
+}
+
+/// git-filter-repo failing to rewrite a repository
+#[derive(Debug, thiserror::Error)]
+#[error("Unable to rewrite the history of {repository}")]
+pub struct RewriteError {
+    pub repository: String,
+}
+
+/// A push the remote refused some refs of, as protected branches and server hooks do
+#[derive(Debug, thiserror::Error)]
+#[error("{url} rejected {reason}")]
+pub struct PushRejected {
+    pub url: String,
+    /// Every rejected ref, with the remote's reason, like `refs/heads/main (protected branch hook declined)`
+    pub reason: String,
+}
+
+/// How a repository failed, and so what it takes to get it through
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    /// A network problem or timeout, which running again may get past
+    Retryable,
+    /// Credentials, host keys, or settings that need fixing first
+    Configuration,
+    /// Anything else, such as a failed rewrite or a rejected push
+    Fatal,
+}
+
+impl FailureKind {
+    /// Classify `e` by the errors it wraps
+    pub fn of(e: &Report) -> Self {
+        if is_configuration(e) {
+            FailureKind::Configuration
+        } else if e.downcast_ref::<PushRejected>().is_some() {
+            FailureKind::Fatal
+        } else if is_retryable(e) || e.downcast_ref::<TimeoutError>().is_some() {
+            FailureKind::Retryable
+        } else {
+            FailureKind::Fatal
+        }
+    }
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailureKind::Retryable => write!(f, "retryable"),
+            FailureKind::Configuration => write!(f, "configuration"),
+            FailureKind::Fatal => write!(f, "fatal"),
+        }
+    }
+}
+
+fn is_configuration(e: &Report) -> bool {
+    if e.downcast_ref::<AuthError>().is_some() || e.downcast_ref::<HostKeyError>().is_some() {
+        return true;
+    }
+    if let Some(e) = e.downcast_ref::<git2::Error>() {
+        return matches!(e.code(), ErrorCode::Auth | ErrorCode::Certificate);
+    }
+    e.downcast_ref::<GitCommandError>().is_some_and(GitCommandError::is_host_key_failure)
+}
+
+/// Whether `e` is because credentials were missing or refused, whichever backend it came from
+fn is_auth_failure(e: &Report) -> bool {
+    if e.downcast_ref::<git2::Error>().is_some_and(|e| e.code() == ErrorCode::Auth) {
+        return true;
+    }
+    let message = format!("{e:#}");
+    AUTH_FAILURES.iter().any(|failure| message.contains(failure))
+}
+
+/// Wrap a failed clone of `url` in an [`AuthError`] if its credentials were refused, or else a [`CloneError`]
+pub(crate) fn clone_failure(e: Report, url: &str) -> Report {
+    if is_auth_failure(&e) {
+        e.wrap_err(AuthError { url: url.to_string() })
+    } else {
+        e.wrap_err(CloneError { url: url.to_string() })
+    }
+}
+
+/// Wrap a failed fetch or push to `url` in an [`AuthError`] if its credentials were refused
+pub(crate) fn auth_failure(e: Report, url: &str) -> Report {
+    if is_auth_failure(&e) {
+        e.wrap_err(AuthError { url: url.to_string() })
+    } else {
+        e
+    }
+}
@@ -0,0 +1,201 @@
+use std::{fmt, sync::Mutex, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use eyre::Result;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{credentials, git};
+
+
+/// How many times a request the host throttled is sent again
+const RATE_LIMIT_RETRIES: u32 = 5;
+
+
+/// Which code host a repository lives on
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    #[default]
+    GitHub,
+    /// A Gitea or Forgejo instance, configured in the config's `gitea` block
+    Gitea,
+    /// Azure DevOps Services, with repositories named `Org/Project/reponame`
+    #[serde(rename = "azure_devops")]
+    AzureDevOps,
+    /// Bitbucket Cloud, with repositories named `Workspace/reponame`
+    Bitbucket,
+    /// GitLab, with repositories named by their full path, like `group/subgroup/project`
+    GitLab,
+}
+
+impl Provider {
+    pub fn is_default(&self) -> bool {
+        *self == Provider::default()
+    }
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Provider::GitHub => "GitHub",
+            Provider::Gitea => "Gitea",
+            Provider::AzureDevOps => "Azure DevOps",
+            Provider::Bitbucket => "Bitbucket",
+            Provider::GitLab => "GitLab",
+        })
+    }
+}
+
+/// A token git authenticates to a code host with over HTTPS, instead of using ssh
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[schemars(deny_unknown_fields)]
+pub struct HostToken {
+    /// A personal access token, access token, or app password
+    pub token: String,
+    /// The username to send with it, which the host's own default is used without
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+}
+
+impl HostToken {
+    /// Have git authenticate to `provider` over HTTPS with this token from now on
+    pub fn install(&self, provider: Provider) {
+        let (url, default_username, variable) = match provider {
+            // Azure DevOps ignores the username of a personal access token
+            Provider::AzureDevOps => ("https://dev.azure.com", "pat", "REPO_CLEANER_AZURE_DEVOPS"),
+            // The username repository and workspace access tokens are sent with
+            Provider::Bitbucket => ("https://bitbucket.org", "x-token-auth", "REPO_CLEANER_BITBUCKET"),
+            // GitHub Apps and GitLab install their own helpers, and Gitea repositories go over ssh
+            Provider::GitHub | Provider::Gitea | Provider::GitLab => return,
+        };
+        let (username, password) = (format!("{variable}_USERNAME"), format!("{variable}_TOKEN"));
+        git::set_env(&username, self.username.as_deref().unwrap_or(default_username));
+        git::set_env(&password, &self.token);
+        credentials::install_helper(url, &username, &password);
+    }
+}
+
+/// Keeps the requests of every thread using a client within the API's rate limit. Once a response
+/// says none are left, requests wait for the limit to reset, and a request the host throttles anyway
+/// (with a 429, or a 403 that says why) is sent again after the wait the host asks for.
+#[derive(Debug, Default)]
+pub(crate) struct RateLimit {
+    /// When requests may be sent again, if the limit is used up
+    resume_at: Mutex<Option<SystemTime>>,
+}
+
+impl RateLimit {
+    /// Send `request`, waiting out the rate limit first
+    pub(crate) fn call(&self, request: ureq::Request) -> Result<ureq::Response, Box<ureq::Error>> {
+        self.send(|| request.clone().call().map_err(Box::new))
+    }
+
+    /// Send `request` with `body`, waiting out the rate limit first
+    pub(crate) fn send_json(&self, request: ureq::Request, body: &serde_json::Value) -> Result<ureq::Response, Box<ureq::Error>> {
+        self.send(|| request.clone().send_json(body).map_err(Box::new))
+    }
+
+    fn send(&self, request: impl Fn() -> Result<ureq::Response, Box<ureq::Error>>) -> Result<ureq::Response, Box<ureq::Error>> {
+        let mut attempt = 0;
+        loop {
+            let resume_at = *self.resume_at.lock().expect("rate limit is never poisoned");
+            if let Some(wait) = resume_at.and_then(|at| at.duration_since(SystemTime::now()).ok()) {
+                if wait > Duration::from_secs(5) {
+                    info!("Waiting {:.0}s for the API rate limit to reset", wait.as_secs_f64());
+                }
+                std::thread::sleep(wait);
+            }
+
+            let result = request();
+            let (status, response) = match &result {
+                Ok(response) => (response.status(), response),
+                Err(e) => match e.as_ref() {
+                    ureq::Error::Status(status, response) => (*status, response),
+                    ureq::Error::Transport(_) => return result,
+                },
+            };
+
+            let header = |name| response.header(name).and_then(|v| v.trim().parse::<u64>().ok());
+            let reset = header("x-ratelimit-reset").map(|at| UNIX_EPOCH + Duration::from_secs(at));
+            let exhausted = header("x-ratelimit-remaining") == Some(0);
+            let retry_after = header("retry-after").map(Duration::from_secs);
+            if exhausted {
+                *self.resume_at.lock().expect("rate limit is never poisoned") = reset;
+            }
+
+            let throttled = status == 429 || (status == 403 && (exhausted || retry_after.is_some()));
+            if !throttled || attempt == RATE_LIMIT_RETRIES {
+                return result;
+            }
+            // Secondary rate limits come without a reset, and GitHub asks for at least a minute's wait
+            let resume_at = match (retry_after, reset) {
+                (Some(wait), _) => SystemTime::now() + wait,
+                (None, Some(reset)) => reset,
+                (None, None) => SystemTime::now() + Duration::from_secs(60),
+            };
+            let wait = resume_at.duration_since(SystemTime::now()).unwrap_or_default();
+            warn!("{} is rate limiting requests, so waiting {:.0}s before trying again", response.get_url(), wait.as_secs_f64());
+            *self.resume_at.lock().expect("rate limit is never poisoned") = Some(resume_at);
+            attempt += 1;
+        }
+    }
+}
+
+/// The settings of a repository that are copied to its cleaned destination
+#[derive(Deserialize, Debug, Clone)]
+pub struct RepositoryInfo {
+    pub description: Option<String>,
+    pub private: bool,
+    /// How many forks the repository has. Not copied, but broken by force pushing
+    #[serde(default)]
+    pub forks_count: u64,
+    /// How large the repository is, in kilobytes. Not copied, but decides how it is cloned
+    #[serde(default)]
+    pub size: u64,
+}
+
+/// An open pull request, which a force push leaves pointing at history that is gone
+#[derive(Deserialize, Debug, Clone)]
+pub struct PullRequest {
+    pub number: u64,
+    pub title: String,
+}
+
+/// A release, made from a tag
+#[derive(Deserialize, Debug, Clone)]
+pub struct Release {
+    pub id: u64,
+    pub tag_name: String,
+    /// The branch or commit the release's tag is created from if it doesn't exist yet
+    pub target_commitish: String,
+}
+
+/// The API of a code host, used to check repositories before force pushing them and to publish
+/// them afterwards. Repositories are named `Org/reponame`.
+pub trait Forge: fmt::Debug + Send + Sync {
+    /// The settings of `name`, or `None` if it doesn't exist
+    fn repository(&self, name: &str) -> Result<Option<RepositoryInfo>>;
+
+    /// Every open pull request on `name`
+    fn open_pull_requests(&self, name: &str) -> Result<Vec<PullRequest>>;
+
+    /// Create an empty `name` with the given settings. If the owner isn't an organization, it is
+    /// assumed to be the authenticated user.
+    fn create_repository(&self, name: &str, settings: &RepositoryInfo) -> Result<()>;
+
+    /// Open an issue on `name`, returning its URL
+    fn create_issue(&self, name: &str, title: &str, body: &str) -> Result<String>;
+
+    /// Archive `name`, making it read only
+    fn archive(&self, name: &str) -> Result<()>;
+
+    /// Make `branch` the default branch of `name`, which its HEAD points to
+    fn set_default_branch(&self, name: &str, branch: &str) -> Result<()>;
+
+    /// Every release of `name`, including drafts
+    fn releases(&self, name: &str) -> Result<Vec<Release>>;
+
+    /// Make the release `id` of `name` target `commit`
+    fn set_release_target(&self, name: &str, id: u64, commit: &str) -> Result<()>;
+}
@@ -0,0 +1,35 @@
+use std::path::Path;
+
+use eyre::Result;
+
+use crate::{git::{git, git_dir}, options::GcMode};
+
+
+/// Collect garbage in the repository at `repo_dir`, returning how many bytes of `.git` it freed
+pub fn collect(repo_dir: &Path, mode: GcMode) -> Result<u64> {
+    let args: &[&str] = match mode {
+        GcMode::Off => return Ok(0),
+        GcMode::Normal => &["gc", "--prune=now", "--quiet"],
+        GcMode::Aggressive => &["gc", "--prune=now", "--aggressive", "--quiet"],
+    };
+
+    let git_dir = git_dir(repo_dir);
+    let before = dir_size(&git_dir)?;
+    // Reflogs still point at the original history, which would keep all of it alive
+    git(repo_dir, &["reflog", "expire", "--expire=now", "--all"])?;
+    git(repo_dir, args)?;
+    let after = dir_size(&git_dir)?;
+
+    Ok(before.saturating_sub(after))
+}
+
+/// Total size of every file under `dir`
+pub(crate) fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() { dir_size(&entry.path())? } else { metadata.len() };
+    }
+    Ok(size)
+}
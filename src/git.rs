@@ -0,0 +1,478 @@
+use std::{io::Read, path::{Path, PathBuf}, process::{Command, ExitStatus, Output, Stdio}, sync::{OnceLock, RwLock}, thread, time::{Duration, Instant}};
+
+use eyre::{bail, Context, Result};
+use git2::{build::CheckoutBuilder, Branch, BranchType, Repository};
+use serde::Serialize;
+use tracing::debug;
+
+
+/// Environment every git subprocess is started with on top of the process's own, set up by the
+/// `install` functions. The process environment itself is never changed, since other threads read it.
+static GIT_ENV: RwLock<Vec<(String, String)>> = RwLock::new(Vec::new());
+
+/// The proxy API requests go through, from [`install_proxy`]
+static API_PROXY: OnceLock<String> = OnceLock::new();
+
+/// Fetches every notes ref as is, since they are rewritten in place rather than fast-forwarded
+pub(crate) const NOTES_REFSPEC: &str = "+refs/notes/*:refs/notes/*";
+
+/// Fragments of git's stderr that indicate a transient network problem worth retrying
+const TRANSIENT_ERRORS: &[&str] = &[
+    "Could not resolve host",
+    "Connection timed out",
+    "Connection reset",
+    "Connection refused",
+    "Operation timed out",
+    "Temporary failure",
+    "early EOF",
+    "unexpected disconnect",
+    "remote end hung up",
+    "RPC failed",
+    "TLS connection was non-properly terminated",
+];
+
+/// A git subprocess that exited unsuccessfully
+#[derive(Debug, thiserror::Error)]
+#[error("`git {args}` exited with {status}: {stderr}")]
+pub struct GitCommandError {
+    pub args: String,
+    pub status: ExitStatus,
+    pub stderr: String,
+}
+
+impl GitCommandError {
+    /// Whether the failure looks like a network hiccup rather than a permanent problem such as bad credentials
+    pub fn is_retryable(&self) -> bool {
+        is_transient(&self.stderr)
+    }
+
+    /// Whether ssh refused the host's key, because it is unknown or changed
+    pub fn is_host_key_failure(&self) -> bool {
+        self.stderr.contains("Host key verification failed") || self.stderr.contains("REMOTE HOST IDENTIFICATION HAS CHANGED")
+    }
+}
+
+/// A command that was killed for running longer than it was allowed to
+#[derive(Debug, thiserror::Error)]
+#[error("`{command}` was killed after running for longer than {timeout:?}")]
+pub struct TimeoutError {
+    pub command: String,
+    pub timeout: Duration,
+}
+
+/// Whether an error message looks like a transient network problem
+pub(crate) fn is_transient(message: &str) -> bool {
+    TRANSIENT_ERRORS.iter().any(|e| message.contains(e))
+}
+
+/// Whether `url` is a local path or `file://` URL, which needs no network to reach
+pub(crate) fn is_local(url: &str) -> bool {
+    if url.starts_with("file://") {
+        return true;
+    }
+    if url.contains("://") {
+        return false;
+    }
+    // `host:path` is ssh, unless the part before the colon is a Windows drive or contains a slash
+    match url.split_once(':') {
+        Some((before, _)) => before.len() == 1 || before.contains('/'),
+        None => true,
+    }
+}
+
+/// A command running `program` with the environment installed for git subprocesses, for git itself
+/// and anything that may run it, like hooks
+pub(crate) fn command(program: &str) -> Command {
+    let mut command = Command::new(program);
+    command.envs(GIT_ENV.read().expect("git environment is never poisoned").iter().map(|(k, v)| (k, v)));
+    command
+}
+
+/// The value of `name` in git subprocesses' environment
+pub(crate) fn env_var(name: &str) -> Option<String> {
+    let installed = GIT_ENV.read().expect("git environment is never poisoned")
+        .iter().rev().find(|(k, _)| k == name).map(|(_, v)| v.clone());
+    installed.or_else(|| std::env::var(name).ok())
+}
+
+/// Whether anything has been installed into git subprocesses' environment
+#[cfg(feature = "gix")]
+pub(crate) fn has_installed_env() -> bool {
+    !GIT_ENV.read().expect("git environment is never poisoned").is_empty()
+}
+
+/// Set `name` to `value` in the environment of every git subprocess started from now on
+pub(crate) fn set_env(name: &str, value: &str) {
+    let mut env = GIT_ENV.write().expect("git environment is never poisoned");
+    env.retain(|(k, _)| k != name);
+    env.push((name.to_string(), value.to_string()));
+}
+
+/// Pass `entries` to every git subprocess as config, after any config already passed through the environment
+pub(crate) fn add_config_env(entries: &[(&str, &str)]) {
+    let count = env_var("GIT_CONFIG_COUNT")
+        .and_then(|c| c.parse::<usize>().ok())
+        .unwrap_or_default();
+    for (i, (key, value)) in entries.iter().enumerate() {
+        set_env(&format!("GIT_CONFIG_KEY_{}", count + i), key);
+        set_env(&format!("GIT_CONFIG_VALUE_{}", count + i), value);
+    }
+    set_env("GIT_CONFIG_COUNT", &(count + entries.len()).to_string());
+}
+
+/// Send every HTTP connection git subprocesses and the API clients make through `proxy`, instead of
+/// the proxy the environment names
+pub fn install_proxy(proxy: &str) {
+    add_config_env(&[("http.proxy", proxy)]);
+    let _ = API_PROXY.set(proxy.to_string());
+}
+
+/// The HTTP client API requests are made with, which goes through the proxy from [`install_proxy`],
+/// or else the one the environment names
+pub(crate) fn http_agent() -> ureq::Agent {
+    match API_PROXY.get().map(ureq::Proxy::new) {
+        Some(Ok(proxy)) => ureq::AgentBuilder::new().proxy(proxy).build(),
+        _ => ureq::agent(),
+    }
+}
+
+/// libgit2's proxy settings for a clone or fetch: `proxy` if there is one, and otherwise the proxy
+/// git's config or the environment names. libgit2 only looks for `https_proxy` and `http_proxy`
+/// itself, so `all_proxy` is looked up here, as curl does for git.
+pub(crate) fn proxy_options(proxy: Option<&str>) -> git2::ProxyOptions<'static> {
+    let env = |names: &[&str]| names.iter().find_map(|n| std::env::var(n).ok().filter(|v| !v.is_empty()));
+
+    let mut options = git2::ProxyOptions::new();
+    let all_proxy = env(&["https_proxy", "HTTPS_PROXY", "http_proxy", "HTTP_PROXY"]).is_none()
+        .then(|| env(&["all_proxy", "ALL_PROXY"]))
+        .flatten();
+    match proxy.map(str::to_string).or(all_proxy) {
+        Some(url) => options.url(&url),
+        None => options.auto(),
+    };
+    options
+}
+
+/// Run `command` to completion like [`Command::output`], but kill it once it has run for longer than `timeout`
+pub(crate) fn output_within(command: &mut Command, timeout: Option<Duration>) -> Result<Output> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let Some(timeout) = timeout else {
+        return command.output().wrap_err(format!("Unable to run {program}"));
+    };
+
+    let mut child = command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()
+        .wrap_err(format!("Unable to run {program}"))?;
+    // Both pipes are drained as the command runs, so it never blocks on a full one
+    fn drain(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<Vec<u8>> {
+        thread::spawn(move || {
+            let mut output = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut output);
+            }
+            output
+        })
+    }
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            // Whatever the command started, like ssh, may hold the pipes open for a while yet, so
+            // the threads draining them are left to finish on their own
+            // The subcommand says what was running, and the options may be whole scripts
+            let subcommands = command.get_args()
+                .map(|a| a.to_string_lossy())
+                .take_while(|a| !a.starts_with('-'))
+                .fold(program, |command, arg| format!("{command} {arg}"));
+            return Err(TimeoutError { command: subcommands, timeout }.into());
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+/// Run git with the given arguments in `dir`, returning an error if it exits unsuccessfully
+pub fn git(dir: &Path, args: &[&str]) -> Result<Output> {
+    git_within(dir, args, None)
+}
+
+/// Run git like [`git`], killing it if it runs for longer than `timeout`
+pub fn git_within(dir: &Path, args: &[&str], timeout: Option<Duration>) -> Result<Output> {
+    git_with(dir, args, timeout, &[])
+}
+
+/// Run git like [`git_within`], with `env` added to its environment
+pub(crate) fn git_with(dir: &Path, args: &[&str], timeout: Option<Duration>, env: &[(&str, String)]) -> Result<Output> {
+    debug!("Running `git {}` in {dir:?}", args.join(" "));
+
+    let mut command = command("git");
+    command.args(args).current_dir(dir).envs(env.iter().map(|(k, v)| (k, v)));
+    let output = output_within(&mut command, timeout)?;
+
+    if !output.status.success() {
+        let error = GitCommandError {
+            args: args.join(" "),
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        };
+        if error.is_host_key_failure() {
+            return Err(eyre::Report::new(error).wrap_err("ssh refused the host's key. Add the host's key to the known_hosts file configured for it, or replace the key there if it was changed on purpose"));
+        }
+        return Err(error.into());
+    }
+
+    Ok(output)
+}
+
+/// A ref a push would move, as reported by `git push --dry-run --porcelain`
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RefUpdate {
+    /// The ref on the remote
+    pub remote_ref: String,
+    /// The commit it is at now, if it exists
+    pub from: Option<String>,
+    /// The commit it would be moved to, if it isn't deleted
+    pub to: Option<String>,
+    /// What kind of update it would be, like `forced update` or `new branch`
+    pub kind: String,
+}
+
+/// Run `git push --dry-run --porcelain` with `args` in `dir`, returning every ref it would update.
+/// `env` is added to git's environment.
+pub fn push_preview(dir: &Path, args: &[&str], env: &[(&str, String)]) -> Result<Vec<RefUpdate>> {
+    let mut preview = vec!["push", "--dry-run", "--porcelain"];
+    preview.extend(args.iter().skip_while(|a| **a == "push"));
+    let output = git_with(dir, &preview, None, env)?;
+
+    let mut updates = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        // Ref lines are `<flag>\t<from>:<to>\t<summary>`, and everything else is decoration
+        let mut fields = line.splitn(3, '\t');
+        let (Some(flag), Some(refs), Some(summary)) = (fields.next(), fields.next(), fields.next()) else {
+            continue;
+        };
+        let Some((local_ref, remote_ref)) = refs.split_once(':') else {
+            continue;
+        };
+
+        let (from, to, kind) = match (flag, summary.split_once("..")) {
+            ("=", _) => (None, None, "up to date".to_string()),
+            ("-", _) => (None, None, "deleted".to_string()),
+            ("*", _) => (None, Some(short_id(dir, local_ref)?), summary.trim_matches(['[', ']']).to_string()),
+            ("!", _) => (None, None, format!("rejected {summary}")),
+            (flag, Some((old, new))) => {
+                let new = new.trim_start_matches('.');
+                let (new, _) = new.split_once(' ').unwrap_or((new, ""));
+                let kind = if flag == "+" { "forced update" } else { "fast-forward" };
+                (Some(old.to_string()), Some(new.to_string()), kind.to_string())
+            },
+            (_, None) => (None, None, summary.to_string()),
+        };
+        updates.push(RefUpdate { remote_ref: remote_ref.to_string(), from, to, kind });
+    }
+
+    Ok(updates)
+}
+
+/// Bring the checked out branch up to its upstream, like `git merge --ff-only @{upstream}` does. A
+/// branch without an upstream, or already ahead of it, is left alone.
+pub(crate) fn fast_forward(repository: &Repository) -> Result<()> {
+    let head = repository.head()?;
+    if !head.is_branch() {
+        return Ok(());
+    }
+    let branch = Branch::wrap(head);
+    let Ok(upstream) = branch.upstream() else {
+        return Ok(());
+    };
+    let (Some(local), Some(target)) = (branch.get().target(), upstream.get().target()) else {
+        return Ok(());
+    };
+    if local == target || repository.graph_descendant_of(local, target)? {
+        return Ok(());
+    }
+
+    let name = branch.name()?.unwrap_or_default().to_string();
+    let upstream_name = upstream.name()?.unwrap_or_default().to_string();
+    if !repository.graph_descendant_of(target, local)? {
+        bail!("{name} has diverged from {upstream_name}, so it can't be fast-forwarded");
+    }
+
+    // Check out first, so changes in the working tree stop it before the branch moves
+    if !repository.is_bare() {
+        repository.checkout_tree(&repository.find_object(target, None)?, Some(CheckoutBuilder::new().safe()))?;
+    }
+    branch.into_reference().set_target(target, &format!("fast-forward to {upstream_name}"))?;
+    debug!("Fast-forwarded {name} to {upstream_name}");
+    Ok(())
+}
+
+/// Where git keeps the repository cloned to `repo_dir`: its `.git` directory, or the clone itself
+/// if it is bare
+pub(crate) fn git_dir(repo_dir: &Path) -> PathBuf {
+    let dot_git = repo_dir.join(".git");
+    if dot_git.is_dir() { dot_git } else { repo_dir.to_path_buf() }
+}
+
+/// Where the tips of branches and tags are kept from before a rewrite, as
+/// `refs/pre-clean/<date>/heads/<branch>` and `refs/pre-clean/<date>/tags/<tag>`. These refs are
+/// never rewritten or pushed with the rest, and are left out wherever history is inspected.
+pub(crate) const QUARANTINE: &str = "refs/pre-clean/";
+
+/// Whether the ref called `name` holds history kept from before a rewrite
+pub(crate) fn is_quarantined(name: &str) -> bool {
+    name.starts_with(QUARANTINE)
+}
+
+/// Give a bare clone git made the remote-tracking branches every other clone has, and have its
+/// branches track them. `git clone --bare` fetches straight into its branches instead
+pub(crate) fn track_origin(repo_dir: &Path, timeout: Option<Duration>, env: &[(&str, String)]) -> Result<()> {
+    git_with(repo_dir, &["config", "remote.origin.fetch", "+refs/heads/*:refs/remotes/origin/*"], None, env)?;
+    git_with(repo_dir, &["fetch", "--quiet", "origin"], timeout, env)?;
+
+    let repository = Repository::open(repo_dir)?;
+    for branch in repository.branches(Some(BranchType::Local))? {
+        let (mut branch, _) = branch?;
+        let Some(upstream) = branch.name()?.map(|name| format!("origin/{name}")) else {
+            continue;
+        };
+        if repository.find_branch(&upstream, BranchType::Remote).is_ok() {
+            branch.set_upstream(Some(&upstream))?;
+        }
+    }
+    Ok(())
+}
+
+/// The remote's default branch, which `origin/HEAD` points to, or else the branch checked out
+pub(crate) fn default_branch(repository: &Repository) -> Option<String> {
+    let remote_head = repository.find_reference("refs/remotes/origin/HEAD").ok()
+        .and_then(|r| r.symbolic_target().map(str::to_string))
+        .and_then(|target| target.strip_prefix("refs/remotes/origin/").map(str::to_string));
+    remote_head.or_else(|| {
+        let head = repository.head().ok().filter(|h| h.is_branch())?;
+        head.shorthand().map(str::to_string)
+    })
+}
+
+/// The branch the HEAD of the remote at `url` points to, if it points to one, and every branch it has
+pub(crate) fn remote_branches(dir: &Path, url: &str, timeout: Option<Duration>, env: &[(&str, String)]) -> Result<(Option<String>, Vec<String>)> {
+    let output = git_with(dir, &["ls-remote", "--symref", url], timeout, env)?;
+    let mut head = None;
+    let mut branches = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if let Some(target) = line.strip_prefix("ref: ").and_then(|line| line.strip_suffix("\tHEAD")) {
+            head = target.strip_prefix("refs/heads/").map(str::to_string);
+        } else if let Some((_, name)) = line.split_once('\t') {
+            branches.extend(name.strip_prefix("refs/heads/").map(str::to_string));
+        }
+    }
+    Ok((head, branches))
+}
+
+/// Sort `branches` by name, with the default branch of `repository` first
+pub(crate) fn order_branches(repository: &Repository, branches: &mut [String]) {
+    let default = default_branch(repository);
+    branches.sort_by_key(|b| (Some(b) != default.as_ref(), b.clone()));
+}
+
+/// The name of the local branch that tracks the remote-tracking branch `name`, like `feature/x`
+/// for `refs/remotes/origin/feature/x`, or `None` for a remote's `HEAD`
+pub(crate) fn tracked_name(repository: &Repository, name: &str) -> Option<String> {
+    // Remote names may contain slashes themselves, so ask which remote the ref belongs to
+    let remote = repository.branch_remote_name(name).ok()
+        .and_then(|remote| remote.as_str().map(str::to_string))
+        .unwrap_or_else(|| "origin".to_string());
+    let local = name.strip_prefix(&format!("refs/remotes/{remote}/"))?;
+    (local != "HEAD").then(|| local.to_string())
+}
+
+/// The forced refspecs pushing with `git push` arguments like `push --force origin --all` pushes:
+/// every branch for `--all`, every tag for `--tags`, and each other argument with short names and
+/// globs resolved against the refs of `repository`, since libgit2 takes neither
+pub(crate) fn push_refspecs(repository: &Repository, args: &[&str]) -> Result<Vec<String>> {
+    let mut refspecs = Vec::new();
+    for (flag, glob) in [("--all", "refs/heads/*"), ("--tags", "refs/tags/*")] {
+        if args.contains(&flag) {
+            refspecs.extend(expand_refspec(repository, glob, glob)?);
+        }
+    }
+
+    // The first argument that isn't a flag is the remote
+    for spec in args.iter().skip_while(|a| **a == "push").filter(|a| !a.starts_with('-')).skip(1) {
+        let spec = spec.trim_start_matches('+');
+        let (src, dst) = spec.split_once(':').unwrap_or((spec, ""));
+
+        if src.is_empty() {
+            // Deleting a ref on the remote
+            refspecs.push(format!(":{}", qualify(dst, "refs/heads/")));
+        } else if src.contains('*') {
+            let dst = if dst.is_empty() { src } else { dst };
+            refspecs.extend(expand_refspec(repository, &qualify(src, "refs/heads/"), &qualify(dst, "refs/heads/"))?);
+        } else {
+            let reference = repository.resolve_reference_from_short_name(src)
+                .wrap_err(format!("Unable to push {src}, since no ref of that name exists"))?;
+            let src = reference.name().unwrap_or(src).to_string();
+            let kind = if src.starts_with("refs/tags/") { "refs/tags/" } else { "refs/heads/" };
+            let dst = if dst.is_empty() { src.clone() } else { qualify(dst, kind) };
+            refspecs.push(format!("+{src}:{dst}"));
+        }
+    }
+    Ok(refspecs)
+}
+
+/// A forced refspec for every ref matching `src`, a glob with one `*`, pushed to where `dst` puts
+/// what the `*` matched
+fn expand_refspec(repository: &Repository, src: &str, dst: &str) -> Result<Vec<String>> {
+    let (prefix, suffix) = src.split_once('*').unwrap_or((src, ""));
+    let mut refspecs = Vec::new();
+    for reference in repository.references_glob(src)? {
+        let reference = reference?;
+        let Some(name) = reference.name() else {
+            continue;
+        };
+        let matched = &name[prefix.len()..name.len() - suffix.len()];
+        refspecs.push(format!("+{name}:{}", dst.replacen('*', matched, 1)));
+    }
+    Ok(refspecs)
+}
+
+/// Every `refs/notes/` ref of `repository`
+pub(crate) fn notes_refs(repository: &Repository) -> Result<Vec<String>> {
+    Ok(repository.references_glob("refs/notes/*")?
+        .filter_map(|r| r.ok())
+        .filter_map(|r| r.name().map(str::to_string))
+        .collect())
+}
+
+/// A forced refspec for every notes ref of `repository`, pushed under the same name
+pub(crate) fn notes_refspecs(repository: &Repository) -> Result<Vec<String>> {
+    expand_refspec(repository, "refs/notes/*", "refs/notes/*")
+}
+
+/// A forced refspec for every quarantined ref of `repository`, pushed under the same name
+pub(crate) fn quarantine_refspecs(repository: &Repository) -> Result<Vec<String>> {
+    let glob = format!("{QUARANTINE}*");
+    expand_refspec(repository, &glob, &glob)
+}
+
+/// `name` as a full ref name, under `kind` unless it already is one
+fn qualify(name: &str, kind: &str) -> String {
+    if name.starts_with("refs/") { name.to_string() } else { format!("{kind}{name}") }
+}
+
+/// The abbreviated id of the commit `rev` names in `dir`
+fn short_id(dir: &Path, rev: &str) -> Result<String> {
+    let output = git(dir, &["rev-parse", "--short", rev])?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
@@ -0,0 +1,191 @@
+use std::{sync::Arc, time::Duration};
+
+use eyre::{eyre, Context, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{debug, info};
+
+use crate::{discovery::{Discovery, DiscoveryCache}, forge::{Forge, PullRequest, RateLimit, Release, RepositoryInfo}, git::http_agent};
+
+
+/// A Gitea or Forgejo instance to clean repositories on
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[schemars(deny_unknown_fields)]
+pub struct GiteaConfig {
+    /// The instance's web address, like `https://git.example.com`
+    pub url: String,
+    /// An access token with repository read and write access, for the API
+    pub token: String,
+    /// Organizations (or users) every repository of which is cleaned, besides the repositories
+    /// listed with `"provider": "gitea"`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub organizations: Vec<String>,
+}
+
+/// A client for the parts of the Gitea API used to publish cleaned repositories. Forgejo keeps the
+/// same API.
+#[derive(Debug, Clone)]
+pub struct Gitea {
+    config: GiteaConfig,
+    limit: Arc<RateLimit>,
+}
+
+impl Gitea {
+    pub fn new(config: &GiteaConfig) -> Self {
+        Self { config: config.clone(), limit: Arc::default() }
+    }
+
+    /// The instance's hostname, without its scheme, port, or path
+    pub fn host(&self) -> &str {
+        let url = self.config.url.split_once("://").map_or(self.config.url.as_str(), |(_, rest)| rest);
+        let host = url.split('/').next().unwrap_or(url);
+        host.split(':').next().unwrap_or(host)
+    }
+
+    /// The URL `Org/reponame` is cloned and pushed over, which is ssh like on GitHub
+    pub fn url(&self, name: &str) -> String {
+        format!("git+ssh://git@{}/{name}.git", self.host())
+    }
+
+    /// The URL `Org/reponame` is cloned and pushed over when a token is routed to it
+    pub fn https_url(&self, name: &str) -> String {
+        format!("{}/{name}.git", self.config.url.trim_end_matches('/'))
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        self.request_url(method, &format!("{}/api/v1{path}", self.config.url.trim_end_matches('/')))
+    }
+
+    fn request_url(&self, method: &str, url: &str) -> ureq::Request {
+        http_agent().request(method, url)
+            .set("Authorization", &format!("token {}", self.config.token))
+            .set("Accept", "application/json")
+            .set("User-Agent", "repo_cleaner")
+    }
+
+    /// Every item of a paged list at `path`
+    fn list<T: for<'de> Deserialize<'de>>(&self, path: &str) -> Result<Vec<T>, Box<ureq::Error>> {
+        // The instance's MAX_RESPONSE_ITEMS may cap this lower, which only means more pages
+        const LIMIT: usize = 50;
+
+        let mut items = Vec::new();
+        for page in 1.. {
+            let request = self.request("GET", path)
+                .query("limit", &LIMIT.to_string())
+                .query("page", &page.to_string());
+            let batch: Vec<T> = self.limit.call(request)?
+                .into_json()
+                .map_err(|e| Box::new(e.into()))?;
+
+            let last = batch.is_empty();
+            items.extend(batch);
+            if last {
+                break;
+            }
+        }
+        Ok(items)
+    }
+
+    /// Every repository of the configured organizations that `discovery` selects, as
+    /// `Org/reponame`, with listings kept in `cache`
+    pub fn discover(&self, discovery: &Discovery, cache: &mut DiscoveryCache, ttl: Duration) -> Result<Vec<String>> {
+        // The instance's MAX_RESPONSE_ITEMS may cap this lower, which only means more pages
+        const LIMIT: usize = 50;
+
+        let base = format!("{}/api/v1", self.config.url.trim_end_matches('/'));
+        let mut names = Vec::new();
+        for owner in &self.config.organizations {
+            let mut list = |kind| cache.list(&format!("{base}/{kind}/{owner}/repos?limit={LIMIT}"), ttl, &self.limit, |url| self.request_url("GET", url));
+            let listed = match list("orgs") {
+                Err(e) if matches!(*e, ureq::Error::Status(404, _)) => list("users"),
+                result => result,
+            }.wrap_err(format!("Unable to list the repositories of {owner} on {}", self.config.url))?;
+
+            for repo in listed {
+                if discovery.selects(&repo) {
+                    names.push(repo.full_name);
+                } else {
+                    debug!("Leaving out {}, since discovery doesn't select it", repo.full_name);
+                }
+            }
+        }
+        Ok(names)
+    }
+}
+
+impl Forge for Gitea {
+    fn repository(&self, name: &str) -> Result<Option<RepositoryInfo>> {
+        match self.limit.call(self.request("GET", &format!("/repos/{name}"))) {
+            Ok(response) => Ok(Some(response.into_json().wrap_err(format!("Unable to read Gitea's description of {name}"))?)),
+            Err(e) if matches!(*e, ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(e).wrap_err(format!("Unable to look up {name} on Gitea")),
+        }
+    }
+
+    fn open_pull_requests(&self, name: &str) -> Result<Vec<PullRequest>> {
+        self.list(&format!("/repos/{name}/pulls?state=open"))
+            .wrap_err(format!("Unable to list the open pull requests of {name}"))
+    }
+
+    fn create_repository(&self, name: &str, settings: &RepositoryInfo) -> Result<()> {
+        let (owner, repo) = name.split_once('/')
+            .ok_or_else(|| eyre!("{name} is not of the form Org/reponame"))?;
+        let body = json!({
+            "name": repo,
+            "description": settings.description,
+            "private": settings.private,
+        });
+
+        match self.limit.send_json(self.request("POST", &format!("/orgs/{owner}/repos")), &body) {
+            Ok(_) => {},
+            Err(e) if matches!(*e, ureq::Error::Status(404, _)) => {
+                self.limit.send_json(self.request("POST", "/user/repos"), &body)
+                    .wrap_err(format!("Unable to create {name} on Gitea"))?;
+            },
+            Err(e) => return Err(e).wrap_err(format!("Unable to create {name} on Gitea")),
+        }
+
+        info!("Created {name} on Gitea");
+        Ok(())
+    }
+
+    fn create_issue(&self, name: &str, title: &str, body: &str) -> Result<String> {
+        #[derive(Deserialize)]
+        struct Issue {
+            html_url: String,
+        }
+
+        let issue: Issue = self.limit.send_json(self.request("POST", &format!("/repos/{name}/issues")), &json!({ "title": title, "body": body }))
+            .wrap_err(format!("Unable to open an issue on {name}"))?
+            .into_json()
+            .wrap_err(format!("Unable to read the issue opened on {name}"))?;
+
+        Ok(issue.html_url)
+    }
+
+    fn archive(&self, name: &str) -> Result<()> {
+        self.limit.send_json(self.request("PATCH", &format!("/repos/{name}")), &json!({ "archived": true }))
+            .wrap_err(format!("Unable to archive {name} on Gitea"))?;
+
+        info!("Archived {name} on Gitea");
+        Ok(())
+    }
+
+    fn set_default_branch(&self, name: &str, branch: &str) -> Result<()> {
+        self.limit.send_json(self.request("PATCH", &format!("/repos/{name}")), &json!({ "default_branch": branch }))
+            .wrap_err(format!("Unable to make {branch} the default branch of {name} on Gitea"))?;
+        Ok(())
+    }
+
+    fn releases(&self, name: &str) -> Result<Vec<Release>> {
+        self.list(&format!("/repos/{name}/releases"))
+            .wrap_err(format!("Unable to list the releases of {name}"))
+    }
+
+    fn set_release_target(&self, name: &str, id: u64, commit: &str) -> Result<()> {
+        self.limit.send_json(self.request("PATCH", &format!("/repos/{name}/releases/{id}")), &json!({ "target_commitish": commit }))
+            .wrap_err(format!("Unable to point release {id} of {name} at {commit} on Gitea"))?;
+        Ok(())
+    }
+}
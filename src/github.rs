@@ -0,0 +1,364 @@
+use std::{collections::{BTreeMap, HashMap}, path::PathBuf, sync::{Arc, Mutex}};
+
+use base64::{engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD}, Engine};
+use chrono::{DateTime, Duration, Utc};
+use eyre::{bail, eyre, Context, Result};
+use ring::{rand::SystemRandom, signature::{RsaKeyPair, RSA_PKCS1_SHA256}};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{debug, info};
+
+use crate::{credentials::{self, CredentialRoute}, discovery::{Discovery, DiscoveryCache}, forge::{Forge, PullRequest, RateLimit, Release, RepositoryInfo}, git::{self, http_agent}};
+
+
+const API_URL: &str = "https://api.github.com";
+
+/// The environment variable the credential helper for github.com reads the token from
+const TOKEN_VARIABLE: &str = "REPO_CLEANER_GITHUB_TOKEN";
+
+/// A client for the parts of the GitHub REST API used to publish cleaned repositories
+#[derive(Debug, Clone)]
+pub struct GitHub {
+    auth: Auth,
+    api_url: String,
+    /// Tokens for the owners that have their own, which take precedence over `auth`
+    owner_tokens: BTreeMap<String, String>,
+    limit: Arc<RateLimit>,
+}
+
+#[derive(Debug, Clone)]
+enum Auth {
+    Token(String),
+    App(Arc<GitHubApp>),
+}
+
+/// A GitHub App to authenticate as, instead of a personal access token
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[schemars(deny_unknown_fields)]
+pub struct GitHubAppConfig {
+    pub app_id: u64,
+    /// The app's PEM private key, relative to the working directory
+    pub private_key: PathBuf,
+    /// The installation to use for every repository. Otherwise, each owner's installation is looked up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub installation_id: Option<u64>,
+}
+
+/// A GitHub App, minting short lived installation tokens as they are needed
+#[derive(Debug)]
+pub struct GitHubApp {
+    config: GitHubAppConfig,
+    key: RsaKeyPair,
+    /// Installation tokens and when they expire, by owner
+    tokens: Mutex<HashMap<String, (String, DateTime<Utc>)>>,
+    limit: RateLimit,
+}
+
+impl GitHubApp {
+    pub fn load(config: &GitHubAppConfig) -> Result<Self> {
+        let path = &config.private_key;
+        let pem = std::fs::read_to_string(path)
+            .wrap_err(format!("Unable to read GitHub App private key {path:?}"))?;
+        let der = STANDARD.decode(pem.lines().filter(|l| !l.starts_with("-----")).collect::<String>())
+            .wrap_err(format!("{path:?} is not a PEM private key"))?;
+
+        // GitHub hands out PKCS#1 keys, but converted PKCS#8 keys work too
+        let key = if pem.contains("BEGIN RSA PRIVATE KEY") {
+            RsaKeyPair::from_der(&der).map_err(|e| eyre!("{e}"))
+        } else {
+            RsaKeyPair::from_pkcs8(&der).map_err(|e| eyre!("{e}"))
+        }.wrap_err(format!("{path:?} is not an RSA private key"))?;
+
+        Ok(Self { config: config.clone(), key, tokens: Mutex::new(HashMap::new()), limit: RateLimit::default() })
+    }
+
+    /// A JSON web token identifying the app itself, valid for a few minutes
+    fn jwt(&self) -> Result<String> {
+        let now = Utc::now().timestamp();
+        let header = URL_SAFE_NO_PAD.encode(json!({ "alg": "RS256", "typ": "JWT" }).to_string());
+        // Backdated a minute to allow for clock drift, as GitHub recommends
+        let claims = URL_SAFE_NO_PAD.encode(json!({ "iat": now - 60, "exp": now + 540, "iss": self.config.app_id.to_string() }).to_string());
+        let message = format!("{header}.{claims}");
+
+        let mut signature = vec![0; self.key.public().modulus_len()];
+        self.key.sign(&RSA_PKCS1_SHA256, &SystemRandom::new(), message.as_bytes(), &mut signature)
+            .map_err(|e| eyre!("Unable to sign GitHub App token: {e}"))?;
+        Ok(format!("{message}.{}", URL_SAFE_NO_PAD.encode(signature)))
+    }
+
+    fn app_request(&self, method: &str, path: &str) -> Result<ureq::Request> {
+        Ok(http_agent().request(method, &format!("{API_URL}{path}"))
+            .set("Authorization", &format!("Bearer {}", self.jwt()?))
+            .set("Accept", "application/vnd.github+json")
+            .set("User-Agent", "repo_cleaner"))
+    }
+
+    /// The id of the app's installation on `owner`'s account
+    fn installation(&self, owner: &str) -> Result<u64> {
+        #[derive(Deserialize)]
+        struct Installation {
+            id: u64,
+        }
+
+        if let Some(id) = self.config.installation_id {
+            return Ok(id);
+        }
+
+        let installation: Installation = match self.limit.call(self.app_request("GET", &format!("/orgs/{owner}/installation"))?) {
+            Err(e) if matches!(*e, ureq::Error::Status(404, _)) => self.limit.call(self.app_request("GET", &format!("/users/{owner}/installation"))?),
+            result => result,
+        }
+            .wrap_err(format!("The GitHub App isn't installed on {owner}"))?
+            .into_json()?;
+        Ok(installation.id)
+    }
+
+    /// An installation token for `owner`, minting a new one if there is none or it is about to expire
+    pub fn token(&self, owner: &str) -> Result<String> {
+        #[derive(Deserialize)]
+        struct AccessToken {
+            token: String,
+            expires_at: String,
+        }
+
+        let mut tokens = self.tokens.lock().expect("token cache is never poisoned");
+        if let Some((token, expires)) = tokens.get(owner) {
+            // Leave enough time for a slow push to authenticate
+            if *expires - Utc::now() > Duration::minutes(10) {
+                return Ok(token.clone());
+            }
+        }
+
+        let installation = self.installation(owner)?;
+        let minted: AccessToken = self.limit.call(self.app_request("POST", &format!("/app/installations/{installation}/access_tokens"))?)
+            .wrap_err(format!("Unable to get a GitHub App installation token for {owner}"))?
+            .into_json()?;
+
+        let expires = DateTime::parse_from_rfc3339(&minted.expires_at)
+            .wrap_err("GitHub returned an installation token with an invalid expiry")?
+            .to_utc();
+        debug!("Minted a GitHub App installation token for {owner}, valid until {expires}");
+        tokens.insert(owner.to_string(), (minted.token.clone(), expires));
+        Ok(minted.token)
+    }
+}
+
+impl GitHub {
+    pub fn new(token: &str) -> Self {
+        Self { auth: Auth::Token(token.to_string()), api_url: API_URL.to_string(), owner_tokens: BTreeMap::new(), limit: Arc::default() }
+    }
+
+    /// A client authenticated as a GitHub App
+    pub fn app(app: GitHubApp) -> Self {
+        Self { auth: Auth::App(Arc::new(app)), api_url: API_URL.to_string(), owner_tokens: BTreeMap::new(), limit: Arc::default() }
+    }
+
+    /// Use the token of each `github.com/Org` route of `routes` for that owner's repositories
+    pub fn with_routes(mut self, routes: &BTreeMap<String, CredentialRoute>) -> Self {
+        for (prefix, route) in routes {
+            let prefix = credentials::location(prefix);
+            if let (Some(owner), Some(token)) = (prefix.strip_prefix("github.com/").filter(|o| !o.contains('/')), &route.token) {
+                self.owner_tokens.insert(owner.to_string(), token.clone());
+            }
+        }
+        self
+    }
+
+    /// A client with only the tokens `routes` give GitHub owners, if they give any
+    pub fn from_routes(routes: &BTreeMap<String, CredentialRoute>) -> Option<Self> {
+        Some(Self::new("").with_routes(routes)).filter(|github| !github.owner_tokens.is_empty())
+    }
+
+    /// Whether git has to be given tokens from [`GitHub::token`] to clone and push, since the
+    /// app has no ssh key
+    pub fn is_app(&self) -> bool {
+        matches!(self.auth, Auth::App(_))
+    }
+
+    /// Whether there is a token for acting on repositories owned by `owner`
+    pub fn has_token(&self, owner: &str) -> bool {
+        self.owner_tokens.contains_key(owner) || !matches!(&self.auth, Auth::Token(token) if token.is_empty())
+    }
+
+    /// A token for acting on repositories owned by `owner`
+    pub fn token(&self, owner: &str) -> Result<String> {
+        if let Some(token) = self.owner_tokens.get(owner) {
+            return Ok(token.clone());
+        }
+        match &self.auth {
+            Auth::Token(token) if token.is_empty() => bail!("There is no GitHub token for {owner}. Pass --github-token, or add one for github.com/{owner} to \"credentials\""),
+            Auth::Token(token) => Ok(token.clone()),
+            Auth::App(app) => app.token(owner),
+        }
+    }
+
+    /// Environment for a git subprocess that authenticates to `owner`'s repositories on GitHub over
+    /// HTTPS, holding the token for `owner`. Each subprocess gets its own, so repositories of
+    /// different owners can be cloned and pushed at the same time.
+    /// Only has an effect once [`GitHub::install_credential_helper`] has run.
+    pub fn git_env(&self, owner: &str) -> Result<Vec<(&'static str, String)>> {
+        Ok(vec![(TOKEN_VARIABLE, self.token(owner)?)])
+    }
+
+    /// Point every git subprocess at a credential helper that answers for github.com with the token
+    /// [`GitHub::git_env`] gives it, so tokens never end up in a URL or on disk
+    pub fn install_credential_helper(&self) {
+        git::set_env("REPO_CLEANER_GITHUB_USERNAME", "x-access-token");
+        credentials::install_helper("https://github.com", "REPO_CLEANER_GITHUB_USERNAME", TOKEN_VARIABLE);
+    }
+
+    /// A request to `path` on behalf of `Org/reponame`, or of the org itself
+    fn request(&self, method: &str, path: &str, name: &str) -> Result<ureq::Request> {
+        let owner = name.split('/').next().unwrap_or(name);
+        Ok(request_with(method, &format!("{}{path}", self.api_url), &self.token(owner)?))
+    }
+
+    /// Every repository of `discovery`'s GitHub organizations that it selects, as `Org/reponame`,
+    /// with listings kept in `cache`
+    pub fn discover(&self, discovery: &Discovery, cache: &mut DiscoveryCache, ttl: std::time::Duration) -> Result<Vec<String>> {
+        const PER_PAGE: usize = 100;
+
+        let mut names = Vec::new();
+        for owner in &discovery.github_organizations {
+            let token = self.token(owner)?;
+            let mut list = |path: String| cache.list(&format!("{}{path}", self.api_url), ttl, &self.limit, |url| request_with("GET", url, &token));
+            let listed = match list(format!("/orgs/{owner}/repos?type=all&per_page={PER_PAGE}")) {
+                Err(e) if matches!(*e, ureq::Error::Status(404, _)) => list(format!("/users/{owner}/repos?type=owner&per_page={PER_PAGE}")),
+                result => result,
+            }.wrap_err(format!("Unable to list the repositories of {owner} on GitHub"))?;
+
+            for repo in listed {
+                if discovery.selects(&repo) {
+                    names.push(repo.full_name);
+                } else {
+                    debug!("Leaving out {}, since discovery doesn't select it", repo.full_name);
+                }
+            }
+        }
+        Ok(names)
+    }
+
+}
+
+/// A request to the API at `url`, authenticated with `token`
+fn request_with(method: &str, url: &str, token: &str) -> ureq::Request {
+    http_agent().request(method, url)
+        .set("Authorization", &format!("Bearer {token}"))
+        .set("Accept", "application/vnd.github+json")
+        .set("User-Agent", "repo_cleaner")
+}
+
+impl Forge for GitHub {
+    fn repository(&self, name: &str) -> Result<Option<RepositoryInfo>> {
+        match self.limit.call(self.request("GET", &format!("/repos/{name}"), name)?) {
+            Ok(response) => Ok(Some(response.into_json().wrap_err(format!("Unable to read GitHub's description of {name}"))?)),
+            Err(e) if matches!(*e, ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(e).wrap_err(format!("Unable to look up {name} on GitHub")),
+        }
+    }
+
+    fn open_pull_requests(&self, name: &str) -> Result<Vec<PullRequest>> {
+        const PER_PAGE: usize = 100;
+
+        let mut pulls = Vec::new();
+        for page in 1.. {
+            let request = self.request("GET", &format!("/repos/{name}/pulls"), name)?
+                .query("state", "open")
+                .query("per_page", &PER_PAGE.to_string())
+                .query("page", &page.to_string());
+            let batch: Vec<PullRequest> = self.limit.call(request)
+                .wrap_err(format!("Unable to list the open pull requests of {name}"))?
+                .into_json()
+                .wrap_err(format!("Unable to read the open pull requests of {name}"))?;
+
+            let last = batch.len() < PER_PAGE;
+            pulls.extend(batch);
+            if last {
+                break;
+            }
+        }
+
+        Ok(pulls)
+    }
+
+    fn create_repository(&self, name: &str, settings: &RepositoryInfo) -> Result<()> {
+        let (owner, repo) = name.split_once('/')
+            .ok_or_else(|| eyre!("{name} is not of the form Org/reponame"))?;
+        let body = json!({
+            "name": repo,
+            "description": settings.description,
+            "private": settings.private,
+        });
+
+        match self.limit.send_json(self.request("POST", &format!("/orgs/{owner}/repos"), name)?, &body) {
+            Ok(_) => {},
+            Err(e) if self.is_app() && matches!(*e, ureq::Error::Status(404, _)) => bail!("{owner} is not an organization, and GitHub Apps can only create repositories in organizations"),
+            Err(e) if matches!(*e, ureq::Error::Status(404, _)) => {
+                self.limit.send_json(self.request("POST", "/user/repos", name)?, &body)
+                    .wrap_err(format!("Unable to create {name} on GitHub"))?;
+            },
+            Err(e) => return Err(e).wrap_err(format!("Unable to create {name} on GitHub")),
+        }
+
+        info!("Created {name} on GitHub");
+        Ok(())
+    }
+
+    fn create_issue(&self, name: &str, title: &str, body: &str) -> Result<String> {
+        #[derive(Deserialize)]
+        struct Issue {
+            html_url: String,
+        }
+
+        let issue: Issue = self.limit.send_json(self.request("POST", &format!("/repos/{name}/issues"), name)?, &json!({ "title": title, "body": body }))
+            .wrap_err(format!("Unable to open an issue on {name}"))?
+            .into_json()
+            .wrap_err(format!("Unable to read the issue opened on {name}"))?;
+
+        Ok(issue.html_url)
+    }
+
+    fn archive(&self, name: &str) -> Result<()> {
+        self.limit.send_json(self.request("PATCH", &format!("/repos/{name}"), name)?, &json!({ "archived": true }))
+            .wrap_err(format!("Unable to archive {name} on GitHub"))?;
+
+        info!("Archived {name} on GitHub");
+        Ok(())
+    }
+
+    fn set_default_branch(&self, name: &str, branch: &str) -> Result<()> {
+        self.limit.send_json(self.request("PATCH", &format!("/repos/{name}"), name)?, &json!({ "default_branch": branch }))
+            .wrap_err(format!("Unable to make {branch} the default branch of {name} on GitHub"))?;
+        Ok(())
+    }
+
+    fn releases(&self, name: &str) -> Result<Vec<Release>> {
+        const PER_PAGE: usize = 100;
+
+        let mut releases = Vec::new();
+        for page in 1.. {
+            let request = self.request("GET", &format!("/repos/{name}/releases"), name)?
+                .query("per_page", &PER_PAGE.to_string())
+                .query("page", &page.to_string());
+            let batch: Vec<Release> = self.limit.call(request)
+                .wrap_err(format!("Unable to list the releases of {name}"))?
+                .into_json()
+                .wrap_err(format!("Unable to read the releases of {name}"))?;
+
+            let last = batch.len() < PER_PAGE;
+            releases.extend(batch);
+            if last {
+                break;
+            }
+        }
+
+        Ok(releases)
+    }
+
+    fn set_release_target(&self, name: &str, id: u64, commit: &str) -> Result<()> {
+        self.limit.send_json(self.request("PATCH", &format!("/repos/{name}/releases/{id}"), name)?, &json!({ "target_commitish": commit }))
+            .wrap_err(format!("Unable to point release {id} of {name} at {commit} on GitHub"))?;
+        Ok(())
+    }
+}
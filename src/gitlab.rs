@@ -0,0 +1,61 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{credentials, git};
+
+
+/// GitLab, or a self-managed instance of it, that repositories with `"provider": "gitlab"` are on.
+/// Repositories are named by their full path, like `group/subgroup/project`, however deeply their
+/// groups are nested.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[schemars(deny_unknown_fields)]
+pub struct GitLabConfig {
+    /// The instance's web address, gitlab.com unless it is self-managed
+    #[serde(default = "default_url")]
+    pub url: String,
+    /// Clone and push over HTTPS with this personal, group, or project access token, instead of over ssh
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// The username sent with the token. GitLab accepts any for access tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+}
+
+fn default_url() -> String {
+    "https://gitlab.com".to_string()
+}
+
+impl Default for GitLabConfig {
+    fn default() -> Self {
+        Self { url: default_url(), token: None, username: None }
+    }
+}
+
+impl GitLabConfig {
+    /// The instance's hostname, without its scheme, port, or path
+    pub fn host(&self) -> &str {
+        let url = self.url.split_once("://").map_or(self.url.as_str(), |(_, rest)| rest);
+        let host = url.split('/').next().unwrap_or(url);
+        host.split(':').next().unwrap_or(host)
+    }
+
+    /// The ssh URL of the repository at `path`
+    pub fn url(&self, path: &str) -> String {
+        format!("git+ssh://git@{}/{path}.git", self.host())
+    }
+
+    /// The HTTPS URL of the repository at `path`, used when there is a token
+    pub fn https_url(&self, path: &str) -> String {
+        format!("{}/{path}.git", self.url.trim_end_matches('/'))
+    }
+
+    /// Have git authenticate to the instance over HTTPS with the token from now on, if there is one
+    pub fn install(&self) {
+        let Some(token) = &self.token else {
+            return;
+        };
+        git::set_env("REPO_CLEANER_GITLAB_USERNAME", self.username.as_deref().unwrap_or("oauth2"));
+        git::set_env("REPO_CLEANER_GITLAB_TOKEN", token);
+        credentials::install_helper(self.url.trim_end_matches('/'), "REPO_CLEANER_GITLAB_USERNAME", "REPO_CLEANER_GITLAB_TOKEN");
+    }
+}
@@ -0,0 +1,141 @@
+use std::{fmt, fs::OpenOptions, io::{ErrorKind, Write}, path::Path};
+
+use chrono::{SecondsFormat, Utc};
+use eyre::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{cleaner::Cleaner, summary::{write_table, RepoStatus, Summary}};
+
+
+/// One phase of one run, as it is kept in `history.jsonl`, for evidence of what was run when,
+/// with which config, and how it went
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RunRecord {
+    /// The run's id, which every phase of the same run shares
+    pub run_id: String,
+    /// Which phase this is, as in the summary
+    pub phase: String,
+    pub started_at: String,
+    pub finished_at: String,
+    pub seconds: f64,
+    /// The repo_cleaner version that ran
+    pub version: String,
+    /// A SHA-256 of the whole config, the same as in backup manifests
+    pub config_hash: String,
+    pub repositories: Vec<RunRepository>,
+}
+
+/// What happened to a single repository during a [`RunRecord`]'s phase
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RunRepository {
+    pub repository: String,
+    pub status: RepoStatus,
+    /// Why the repository was skipped or failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub commits_changed: usize,
+    pub pushed: bool,
+    pub seconds: f64,
+}
+
+impl RunRecord {
+    /// Record `summary`'s phase, which `cleaner` ran and has just finished
+    pub fn new(cleaner: &Cleaner, summary: &Summary) -> Result<Self> {
+        let finished = Utc::now();
+        Ok(Self {
+            run_id: cleaner.run_id().to_string(),
+            phase: summary.phase.clone(),
+            started_at: summary.started_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            finished_at: finished.to_rfc3339_opts(SecondsFormat::Secs, true),
+            seconds: (finished - summary.started_at).num_milliseconds() as f64 / 1000.0,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            config_hash: cleaner.config_hash()?,
+            repositories: summary.repositories.iter().map(|report| RunRepository {
+                repository: report.repository.clone(),
+                status: report.status,
+                reason: report.reason.clone(),
+                commits_changed: report.commits_changed,
+                pushed: report.pushed,
+                seconds: report.seconds,
+            }).collect(),
+        })
+    }
+
+    fn count(&self, status: RepoStatus) -> usize {
+        self.repositories.iter().filter(|r| r.status == status).count()
+    }
+}
+
+impl fmt::Display for RunRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Run {}, {} phase", self.run_id, self.phase)?;
+        writeln!(f, "Started {}, finished {} after {:.1}s", self.started_at, self.finished_at, self.seconds)?;
+        writeln!(f, "repo_cleaner {}, config {}", self.version, self.config_hash)?;
+        writeln!(f)?;
+
+        let rows = self.repositories.iter().map(|r| [
+            r.repository.clone(),
+            r.status.to_string(),
+            r.commits_changed.to_string(),
+            if r.pushed { "yes" } else { "no" }.to_string(),
+            format!("{:.1}s", r.seconds),
+            r.reason.clone().unwrap_or_default(),
+        ]).collect::<Vec<_>>();
+        write_table(f, ["Repository", "Status", "Commits changed", "Pushed", "Took", "Reason"], &rows)
+    }
+}
+
+/// Every phase of every run in a working directory, oldest first
+#[derive(Debug, Default)]
+pub struct RunHistory {
+    pub runs: Vec<RunRecord>,
+}
+
+impl RunHistory {
+    /// Load the history kept in `dir`, which is empty before the first run there
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join("history.jsonl");
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).wrap_err(format!("Unable to read run history {path:?}")),
+        };
+
+        let runs = text.lines().enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| serde_json::from_str(line).wrap_err(format!("Line {} of run history {path:?} is not a run", i + 1)))
+            .collect::<Result<_>>()?;
+        Ok(Self { runs })
+    }
+
+    /// Append `record` to the history kept in `dir`. Records are only ever added, never rewritten.
+    pub fn append(dir: &Path, record: &RunRecord) -> Result<()> {
+        let path = dir.join("history.jsonl");
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)
+            .wrap_err(format!("Unable to open run history {path:?}"))?;
+        writeln!(file, "{}", serde_json::to_string(record)?)
+            .wrap_err(format!("Unable to write to run history {path:?}"))
+    }
+
+    /// Every phase of the run with the id `run_id`
+    pub fn run(&self, run_id: &str) -> Vec<&RunRecord> {
+        self.runs.iter().filter(|r| r.run_id == run_id).collect()
+    }
+}
+
+impl fmt::Display for RunHistory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rows = self.runs.iter().map(|r| [
+            r.run_id.clone(),
+            r.phase.clone(),
+            r.finished_at.clone(),
+            r.repositories.len().to_string(),
+            r.count(RepoStatus::Succeeded).to_string(),
+            r.count(RepoStatus::Clean).to_string(),
+            r.count(RepoStatus::Skipped).to_string(),
+            r.count(RepoStatus::Failed).to_string(),
+            format!("{:.1}s", r.seconds),
+        ]).collect::<Vec<_>>();
+        write_table(f, ["Run", "Phase", "Finished", "Repositories", "Succeeded", "Clean", "Skipped", "Failed", "Took"], &rows)
+    }
+}
@@ -0,0 +1,94 @@
+use std::path::Path;
+
+use eyre::{bail, Context, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::git;
+
+
+/// Shell commands run in a repository's directory before and after it is rewritten or pushed.
+///
+/// Every hook gets `REPO`, `BRANCH`, `OLD_HEAD`, and `NEW_HEAD` in its environment. `OLD_HEAD` is
+/// the checked out commit before rewriting and `NEW_HEAD` the one after, or empty if not known yet.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default, PartialEq, Eq)]
+#[schemars(deny_unknown_fields)]
+pub struct Hooks {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_rewrite: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_rewrite: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_push: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_push: Option<String>,
+}
+
+/// The point in a repository's processing a hook runs at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    PreRewrite,
+    PostRewrite,
+    PrePush,
+    PostPush,
+}
+
+impl Hook {
+    pub fn name(self) -> &'static str {
+        match self {
+            Hook::PreRewrite => "pre_rewrite",
+            Hook::PostRewrite => "post_rewrite",
+            Hook::PrePush => "pre_push",
+            Hook::PostPush => "post_push",
+        }
+    }
+}
+
+impl Hooks {
+    pub fn is_empty(&self) -> bool {
+        *self == Hooks::default()
+    }
+
+    /// The command configured for `hook`, if any
+    pub fn command(&self, hook: Hook) -> Option<&str> {
+        match hook {
+            Hook::PreRewrite => self.pre_rewrite.as_deref(),
+            Hook::PostRewrite => self.post_rewrite.as_deref(),
+            Hook::PrePush => self.pre_push.as_deref(),
+            Hook::PostPush => self.post_push.as_deref(),
+        }
+    }
+}
+
+/// Run a hook's command through the shell in `dir`, failing if it exits unsuccessfully
+pub fn run(hook: Hook, command: &str, dir: &Path, env: &[(&str, String)]) -> Result<()> {
+    info!("Running {} hook: {command}", hook.name());
+
+    let mut shell = if cfg!(windows) {
+        let mut shell = git::command("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = git::command("sh");
+        shell.arg("-c");
+        shell
+    };
+
+    let output = shell.arg(command)
+        .current_dir(dir)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .output()
+        .wrap_err(format!("Unable to run {} hook", hook.name()))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !stdout.trim().is_empty() {
+        info!("{} hook output: {}", hook.name(), stdout.trim());
+    }
+
+    if !output.status.success() {
+        bail!("{} hook exited with {}: {}", hook.name(), output.status, String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use std::{collections::HashMap, path::Path};
+
+use eyre::{bail, Context, Result};
+
+use crate::config::Substitution;
+
+
+/// Read `old_email,new_email,new_name` rows from a CSV file into email substitutions.
+///
+/// `new_name` may be left empty or omitted to only replace the email. A header row is skipped
+/// if present.
+pub fn read_substitutions_csv(path: &Path) -> Result<HashMap<String, Substitution>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .wrap_err(format!("Unable to open substitutions CSV {path:?}"))?;
+
+    let mut substitutions = HashMap::new();
+    for (i, record) in reader.records().enumerate() {
+        let record = record.wrap_err(format!("Error reading {path:?}"))?;
+        let line = i + 1;
+
+        let old_email = record.get(0).unwrap_or_default();
+        let new_email = record.get(1).unwrap_or_default();
+        let new_name = record.get(2).unwrap_or_default();
+
+        // Skip blank lines, and a header row, which won't contain an email
+        if record.iter().all(str::is_empty) || (line == 1 && !old_email.contains('@')) {
+            continue;
+        }
+
+        if old_email.is_empty() || new_email.is_empty() {
+            bail!("{path:?} line {line}: expected old_email,new_email[,new_name]");
+        }
+
+        let substitution = if new_name.is_empty() {
+            Substitution::Email(new_email.to_string())
+        } else {
+            Substitution::Identity {
+                new_email: new_email.to_string(),
+                new_author: Some(new_name.to_string()),
+                apply_to: None,
+                fields: None,
+            }
+        };
+        substitutions.insert(old_email.to_string(), substitution);
+    }
+
+    Ok(substitutions)
+}
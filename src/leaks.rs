@@ -0,0 +1,169 @@
+use std::collections::{BTreeMap, HashSet};
+
+use eyre::Result;
+use git2::{ErrorCode, ObjectType, Oid, Repository, Tree};
+use regex::Regex;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{config::Config, git::is_quarantined, options::BlobScan};
+
+
+/// Files larger than this are skipped, since they are almost never text anyone wrote an email into
+const MAX_BLOB_BYTES: usize = 10 << 20;
+
+/// The domain GitHub's noreply addresses are under
+const NOREPLY_DOMAIN: &str = "@users.noreply.github.com";
+
+/// An old email found in the contents of a file, which rewriting commits and tags leaves in place
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct LeakedEmail {
+    pub email: String,
+    /// The file's path, in the first tree it was found in
+    pub path: String,
+    /// The blob it was found in
+    pub blob: String,
+}
+
+/// The emails the config's rules replace, recognized the way the rewrite matches them
+struct OldEmails {
+    /// `email_substitutions` and `merge_identities` emails
+    exact: HashSet<String>,
+    /// `identity_substitutions` email regexes
+    patterns: Vec<Regex>,
+    /// `github_noreply_substitutions` usernames, in lowercase
+    usernames: HashSet<String>,
+    case_insensitive: bool,
+}
+
+impl OldEmails {
+    fn new(config: &Config) -> Self {
+        let normalize = |email: &str| if config.case_insensitive_emails { email.to_lowercase() } else { email.to_string() };
+        Self {
+            exact: config.email_substitutions.keys()
+                .chain(config.merge_identities.iter().flat_map(|merge| &merge.emails))
+                .map(|email| normalize(email))
+                .collect(),
+            // check-config reports regexes that don't compile
+            patterns: config.identity_substitutions.iter()
+                .filter_map(|rule| Regex::new(&rule.email).ok())
+                .collect(),
+            usernames: config.github_noreply_substitutions.keys().map(|user| user.to_lowercase()).collect(),
+            case_insensitive: config.case_insensitive_emails,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.patterns.is_empty() && self.usernames.is_empty()
+    }
+
+    fn matches(&self, email: &str) -> bool {
+        let normalized = if self.case_insensitive { email.to_lowercase() } else { email.to_string() };
+        if self.exact.contains(&normalized) || self.patterns.iter().any(|pattern| pattern.is_match(email)) {
+            return true;
+        }
+        let lowercase = email.to_lowercase();
+        lowercase.strip_suffix(NOREPLY_DOMAIN)
+            // Newer noreply addresses start with the account's id, like 12345+user
+            .map(|user| user.split_once('+').filter(|(id, _)| id.bytes().all(|b| b.is_ascii_digit())).map_or(user, |(_, user)| user))
+            .is_some_and(|user| self.usernames.contains(user))
+    }
+}
+
+/// Search the file contents of `repository` for the emails `config` replaces, every version of
+/// every file with [`BlobScan::History`] or only those at the tip of each ref with [`BlobScan::Tree`].
+///
+/// Binary files are skipped, and every email is reported once for each path it is found at.
+pub fn scan(repository: &Repository, config: &Config, depth: BlobScan) -> Result<Vec<LeakedEmail>> {
+    let old = OldEmails::new(config);
+    if old.is_empty() {
+        return Ok(Vec::new());
+    }
+    let shaped = Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)+")?;
+
+    let mut tips = Vec::new();
+    for reference in repository.references()?.filter_map(|r| r.ok()).filter(|r| !r.name().is_some_and(is_quarantined)) {
+        // Refs that don't lead to a commit, like tags of blobs, have no tree to search
+        if let Ok(commit) = reference.peel_to_commit() {
+            tips.push(commit.id());
+        }
+    }
+    let commits = match depth {
+        BlobScan::Tree => tips,
+        BlobScan::History => {
+            let mut walk = repository.revwalk()?;
+            for tip in tips {
+                walk.push(tip)?;
+            }
+            walk.collect::<Result<Vec<_>, _>>()?
+        },
+    };
+
+    let mut scan = Scan { repository, old: &old, shaped: &shaped, trees: HashSet::new(), blobs: HashSet::new(), missing: 0, found: BTreeMap::new() };
+    for commit in commits {
+        let tree = repository.find_commit(commit)?.tree()?;
+        scan.tree(&tree, "")?;
+    }
+    // Blobless clones only have the files of the branch they checked out
+    if scan.missing > 0 {
+        warn!("{} files weren't downloaded, so they weren't searched for old emails", scan.missing);
+    }
+
+    Ok(scan.found.into_iter().map(|((email, path), blob)| LeakedEmail { email, path, blob }).collect())
+}
+
+struct Scan<'a> {
+    repository: &'a Repository,
+    old: &'a OldEmails,
+    shaped: &'a Regex,
+    /// Trees and blobs already searched, since most are shared between commits
+    trees: HashSet<Oid>,
+    blobs: HashSet<Oid>,
+    /// Blobs that aren't in the clone
+    missing: usize,
+    /// The blob every email was first found in at every path
+    found: BTreeMap<(String, String), String>,
+}
+
+impl Scan<'_> {
+    fn tree(&mut self, tree: &Tree<'_>, prefix: &str) -> Result<()> {
+        if !self.trees.insert(tree.id()) {
+            return Ok(());
+        }
+        for entry in tree.iter() {
+            let path = format!("{prefix}{}", String::from_utf8_lossy(entry.name_bytes()));
+            match entry.kind() {
+                Some(ObjectType::Tree) => {
+                    let subtree = self.repository.find_tree(entry.id())?;
+                    self.tree(&subtree, &format!("{path}/"))?;
+                },
+                // Submodules are commits of another repository, which is scanned on its own
+                Some(ObjectType::Blob) if self.blobs.insert(entry.id()) => self.blob(entry.id(), path)?,
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
+    fn blob(&mut self, id: Oid, path: String) -> Result<()> {
+        let blob = match self.repository.find_blob(id) {
+            Ok(blob) => blob,
+            Err(e) if e.code() == ErrorCode::NotFound => {
+                self.missing += 1;
+                return Ok(());
+            },
+            Err(e) => return Err(e.into()),
+        };
+        if blob.is_binary() || blob.size() > MAX_BLOB_BYTES {
+            return Ok(());
+        }
+
+        let text = String::from_utf8_lossy(blob.content());
+        for email in self.shaped.find_iter(&text).map(|m| m.as_str()) {
+            if self.old.matches(email) {
+                self.found.entry((email.to_string(), path.clone())).or_insert_with(|| id.to_string());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,20 @@
+use std::path::Path;
+
+use crate::git::{git, git_dir};
+
+
+/// Whether the checked out branch stores any files in Git LFS, judging by its `.gitattributes`.
+/// A bare clone has nothing checked out, so its default branch's is read instead
+pub fn uses_lfs(repo_dir: &Path) -> bool {
+    let attributes = match git_dir(repo_dir) == repo_dir {
+        true => git(repo_dir, &["show", "HEAD:.gitattributes"]).ok()
+            .map(|output| String::from_utf8_lossy(&output.stdout).into_owned()),
+        false => std::fs::read_to_string(repo_dir.join(".gitattributes")).ok(),
+    };
+    attributes.is_some_and(|attributes| attributes.lines().any(|l| !l.trim_start().starts_with('#') && l.contains("filter=lfs")))
+}
+
+/// Whether the git-lfs extension is installed
+pub fn installed(repo_dir: &Path) -> bool {
+    git(repo_dir, &["lfs", "version"]).is_ok()
+}
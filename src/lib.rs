@@ -0,0 +1,109 @@
+//! Clean emails and names from the history of a list of git repositories.
+//!
+//! The [`Cleaner`] clones every configured repository, backs it up, and rewrites its history with
+//! git-filter-repo. Once the result has been reviewed, [`Cleaner::push`] force pushes it back.
+//!
+//! Each phase is a [`Pipeline`] of [`Step`]s, assembled from the config and [`CleanerOptions`].
+//! A pipeline can also be put together by hand, to leave steps out or add new ones.
+
+mod allowlist;
+mod attribution;
+mod backend;
+mod backup;
+mod cache;
+mod check;
+mod cleaner;
+mod config;
+mod credentials;
+mod diff;
+mod discovery;
+mod doctor;
+mod error;
+mod forge;
+mod gc;
+mod git;
+mod gitea;
+mod github;
+mod gitlab;
+mod history;
+mod hooks;
+mod import;
+mod leaks;
+mod lfs;
+mod manifest;
+mod migration;
+mod lock;
+mod notify;
+mod options;
+mod pipeline;
+mod progress;
+mod reference;
+mod refresh;
+mod release;
+mod retry;
+mod rewrite;
+mod scheduler;
+mod schema;
+mod sign;
+mod signatures;
+mod ssh;
+mod steps;
+mod submodule;
+mod summary;
+mod tags;
+mod throttle;
+#[cfg(feature = "otlp")]
+mod telemetry;
+#[cfg(feature = "tui")]
+mod tui;
+mod verify;
+mod workdir;
+
+pub use allowlist::{Allowlist, DisallowedEmail};
+pub use attribution::{IdentityStats, MergeReport};
+pub use backup::{BackupEncryption, BackupManifest};
+pub use cache::{RewriteCache, RewriteScope};
+pub use check::{check_config, has_errors, Diagnostic, Severity};
+pub use cleaner::{Cleaner, CleanerOptions, Dependencies, RepoJob};
+pub use config::{AnonymizeDates, CloneStrategy, Config, DateGranularity, DateMode, Field, IdentityMerge, IdentityRule, NameSubstitution, PruneEmpty, Releases, ReplaceRefs, RepositoryConfig, Republish, Role, Substitution, UserCallbacks};
+pub use credentials::{install_routes as install_credential_routes, CredentialRoute};
+pub use diff::{ChangedCommit, HistoryDiff};
+pub use discovery::{Discovery, DiscoveryCache, ListedRepository, Visibility};
+pub use doctor::{run as doctor, Finding};
+pub use error::{AuthError, CloneError, FailureKind, PushRejected, RewriteError};
+pub use forge::{Forge, HostToken, Provider, PullRequest, Release, RepositoryInfo};
+pub use git::{install_proxy, GitCommandError, TimeoutError};
+pub use gitea::{Gitea, GiteaConfig};
+pub use github::{GitHub, GitHubApp, GitHubAppConfig};
+pub use gitlab::GitLabConfig;
+pub use history::{RunHistory, RunRecord, RunRepository};
+pub use hooks::{Hook, Hooks};
+pub use import::read_substitutions_csv;
+pub use leaks::LeakedEmail;
+pub use lock::RunLock;
+pub use manifest::{Manifest, ManifestEntry};
+pub use notify::{IssueTemplate, NotifyConfig};
+pub use options::{Backend, BlobScan, GcMode, PushWindow, RefreshPolicy};
+pub use pipeline::{Flow, Pipeline, Step, StepContext};
+pub use progress::{Phase, ProgressWriter, RepoProgress, RunProgress};
+pub use reference::ReferenceCache;
+pub use release::MovedTag;
+pub use retry::{RetryPolicy, Timeouts};
+pub use rewrite::{Plan, RewriteOutcome};
+pub use scheduler::Scheduler;
+pub use schema::schema as config_schema;
+pub use signatures::InvalidatedSignature;
+pub use ssh::{HostKeyChecking, HostKeyError, SshHost, SshSettings};
+pub use steps::{
+    BackupStep, CacheStep, CloneStep, EnforceStep, GcStep, HookStep, ManifestCheckStep, NotifyStep, PlanStep, PreflightStep,
+    PreviewStep, PushCheckStep, PushStep, RecordStep, RewriteStep, SignStep,
+};
+pub use summary::{RepoReport, RepoStatus, Summary};
+pub use tags::{TagKind, TagReport};
+pub use throttle::{PushSchedule, PushThrottle};
+#[cfg(feature = "otlp")]
+pub use telemetry::{Span, Telemetry};
+#[cfg(feature = "tui")]
+pub use tui::{Dashboard, DashboardWriter, Decision};
+pub use verify::PreservedIdentityError;
+pub use workdir::{collect as collect_workdir_garbage, WorkdirGarbage};
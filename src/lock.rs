@@ -0,0 +1,78 @@
+use std::{fs::OpenOptions, io::{ErrorKind, Write}, path::{Path, PathBuf}};
+
+use eyre::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+
+/// Who holds a [`RunLock`], written into the lock file
+#[derive(Deserialize, Serialize, Debug)]
+struct Holder {
+    pid: u32,
+    hostname: String,
+    started: String,
+}
+
+/// An advisory lock on a working directory, so two runs never work on the same repositories at once.
+/// It is released when dropped.
+#[derive(Debug)]
+pub struct RunLock {
+    path: PathBuf,
+}
+
+impl RunLock {
+    /// Lock `dir`, creating it if needed. With `force`, a lock left behind by a run that died is removed first.
+    pub fn acquire(dir: &Path, force: bool) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .wrap_err(format!("Unable to create working directory {dir:?}"))?;
+        let path = dir.join(".repo-cleaner.lock");
+
+        if force {
+            match std::fs::remove_file(&path) {
+                Ok(()) => warn!("Removed the lock on {dir:?}"),
+                Err(e) if e.kind() == ErrorKind::NotFound => {},
+                Err(e) => return Err(e).wrap_err(format!("Unable to remove lock file {path:?}")),
+            }
+        }
+
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                let holder = std::fs::read_to_string(&path).ok()
+                    .and_then(|text| serde_json::from_str::<Holder>(&text).ok())
+                    .map_or("an unknown process".to_string(), |h| format!("process {} on {}, since {}", h.pid, h.hostname, h.started));
+                bail!("{dir:?} is locked by {holder}. If that run is no longer going, rerun with --force-unlock");
+            },
+            Err(e) => return Err(e).wrap_err(format!("Unable to create lock file {path:?}")),
+        };
+
+        let holder = Holder {
+            pid: std::process::id(),
+            hostname: hostname(),
+            started: chrono::Utc::now().to_rfc3339(),
+        };
+        file.write_all(serde_json::to_string(&holder)?.as_bytes())
+            .wrap_err(format!("Unable to write lock file {path:?}"))?;
+
+        debug!("Locked {dir:?}");
+        Ok(Self { path })
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("Unable to remove lock file {:?}: {e}", self.path);
+        }
+    }
+}
+
+fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "an unknown host".to_string())
+}
@@ -1,315 +1,768 @@
-use std::{collections::HashMap, fs::File, path::{Path, PathBuf}, process::Command, time::Duration};
-
-use clap::{command, Parser};
-use eyre::{Context, Result};
-use git2::{ErrorCode, Repository};
-use git2_credentials::CredentialHandler;
-use indicatif::ProgressBar;
-use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, io::Write, path::Path, process::ExitCode, sync::Mutex, time::Instant};
+
+use clap::CommandFactory;
+use eyre::{bail, eyre, Result};
+use indicatif::{HumanBytes, MultiProgress, ProgressDrawTarget};
+use repo_cleaner::{check_config, collect_workdir_garbage, doctor, Allowlist, config_schema, has_errors, install_credential_routes, install_proxy, read_substitutions_csv, Cleaner, CleanerOptions, Config, Dependencies, DiscoveryCache, FailureKind, Finding, GitHub, GitHubApp, Gitea, ProgressWriter, Provider, PushSchedule, ReferenceCache, RepoProgress, RepoReport, RepositoryConfig, RetryPolicy, RunHistory, RunLock, RunRecord, Timeouts, RunProgress, Scheduler, Severity, SshSettings, Summary};
+#[cfg(feature = "tui")]
+use repo_cleaner::{Dashboard, Decision};
 use tracing::{error, info, level_filters::LevelFilter, warn};
 
 
+include!("cli.rs");
 
-/// Simple tool to clean emails from the patches of a given list of git repositories.
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-struct Args {
-
-    /// The configuration file to load from.
-    /// This should be a JSON file in the following format:
-    /// {
-    ///     "repositories": ["Org/reponame"...],
-    ///     "email_substitutions": {
-    ///         "email@example.com": "another_email@example.com"
-    ///      },
-    ///     "name_substitutions": {
-    ///         "Some Name Regex": "New Name"
-    ///     }
-    /// }
-    config: PathBuf,
-    /// Set this flag after running the command the first time. It will force push every repository that was previously modified.
-    #[arg(long)]
-    commit: bool,
-    /// Set this flag to sign all commits with your default GPG signing key.
-    /// WARNING: This will sign *every* commit, including those not made by you! This only works on single-branch repositories.
-    #[arg(long)]
-    sign: bool,
-}
+impl Args {
+    /// The most detailed level logged, from --quiet and --verbose
+    fn log_level(&self) -> LevelFilter {
+        match (self.quiet, self.verbose) {
+            (true, _) => LevelFilter::WARN,
+            (false, 0) => LevelFilter::INFO,
+            (false, 1) => LevelFilter::DEBUG,
+            (false, _) => LevelFilter::TRACE,
+        }
+    }
 
+    fn cleaner_options(&self, config: &Config) -> Result<CleanerOptions> {
+        // A GitHub App takes precedence over a token, and tokens routed to an owner over both
+        let github = match (&config.github_app, &self.github_token) {
+            (Some(app), _) => Some(GitHub::app(GitHubApp::load(app)?).with_routes(&config.credentials)),
+            (None, Some(token)) => Some(GitHub::new(token).with_routes(&config.credentials)),
+            (None, None) => GitHub::from_routes(&config.credentials),
+        };
+        let gitea = config.gitea.as_ref().map(Gitea::new);
+        // Only the code hosts repositories are actually on need to be set up
+        let uses = |provider| config.repositories.iter().any(|r| r.local_path().is_none() && r.provider == provider)
+            || (provider == Provider::Gitea && gitea.is_some());
+        let has_github_token = |r: &RepositoryConfig| github.as_ref().is_some_and(|g| g.has_token(r.name.split('/').next().unwrap_or_default()));
+        let missing_token = config.repositories.iter().any(|r| r.local_path().is_none() && r.provider == Provider::GitHub && !has_github_token(r));
+        if missing_token && (self.github_create_dest || self.block_if_open_prs) {
+            bail!("--github-create-dest and --block-if-open-prs need --github-token, a \"github_app\" in the config, or a token in \"credentials\" for the owner of every GitHub repository");
+        }
+        if (uses(Provider::AzureDevOps) || uses(Provider::Bitbucket) || uses(Provider::GitLab)) && (self.github_create_dest || self.block_if_open_prs) {
+            bail!("--github-create-dest and --block-if-open-prs only work with GitHub and Gitea repositories");
+        }
+        if gitea.is_none() && uses(Provider::Gitea) {
+            bail!("Repositories with \"provider\": \"gitea\" need \"gitea\" in the config");
+        }
 
-#[derive(Deserialize, Serialize, Debug)]
-struct Substitution {
-    new_email: String,
-    new_author: String,
+        Ok(CleanerOptions {
+            sign: self.sign,
+            anonymize: self.anonymize.then(|| self.anonymize_salt.clone()).flatten(),
+            retry: RetryPolicy {
+                retries: self.retries,
+                delay: Duration::from_secs(self.retry_delay),
+            },
+            timeouts: Timeouts {
+                clone: self.clone_timeout,
+                pull: self.pull_timeout,
+                filter_repo: self.filter_repo_timeout,
+                push: self.push_timeout,
+            },
+            backend: self.backend,
+            gc: self.gc,
+            // Every audit has to see what the remote has now, not what the last cycle rewrote
+            refresh: self.refresh.unwrap_or(if self.watch { RefreshPolicy::Reset } else { RefreshPolicy::Pull }),
+            mirror_to_org: self.mirror_to_org.clone(),
+            github,
+            gitea,
+            create_destination: self.github_create_dest,
+            archive_original: self.archive_original,
+            block_if_open_prs: self.block_if_open_prs,
+            offline: self.offline,
+            skip_backup: self.no_backup,
+            full_rewrite: self.no_cache,
+            rewrite_unmatched: self.rewrite_unmatched,
+            keep_original: self.keep_original,
+            push_originals_to: self.push_originals_to.clone(),
+            scan_blobs: self.scan_blobs,
+            #[cfg(feature = "otlp")]
+            telemetry: self.otlp_endpoint.as_deref().map(repo_cleaner::Telemetry::new).transpose()?.map(std::sync::Arc::new),
+            blobless: self.blobless,
+            reference_cache: self.reference_cache.as_deref().map(ReferenceCache::new).transpose()?,
+            verify_backup: self.verify_backup,
+            age_identity: self.age_identity.clone(),
+            preview_push: self.preview,
+            push_schedule: PushSchedule {
+                delay: self.push_delay,
+                rate: self.push_rate,
+                window: self.push_window,
+            },
+            proxy: self.proxy.clone(),
+            ssh: SshSettings {
+                default_key: self.ssh_key.clone(),
+                known_hosts: config.known_hosts.clone(),
+                strict_host_key_checking: self.strict_host_key_checking,
+                hosts: config.ssh.clone(),
+            },
+        })
+    }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-struct Config {
-    repositories: Vec<String>,
-    email_substitutions: HashMap<String, String>,
-    name_substitutions: HashMap<String, String>,
+/// Exit code used when at least one repository was skipped or failed.
+/// Fatal errors that stop the whole run exit with 1.
+const EXIT_PARTIAL_FAILURE: u8 = 2;
 
-}
-
-fn main() -> Result<()> {
+fn main() -> Result<ExitCode> {
     color_eyre::install()?;
 
+    // Parse args
+    let args = Args::parse();
+
+    // Log lines are printed above the progress bars rather than through them
+    let multi = MultiProgress::new();
+    if args.quiet {
+        multi.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    let writer = ProgressWriter(multi.clone());
+
+    // The dashboard shows log lines next to the repository they are about instead
+    #[cfg(feature = "tui")]
+    let dashboard = args.tui.then(Dashboard::new);
+    #[cfg(feature = "tui")]
+    let dashboard_writer = dashboard.as_ref().map(|dashboard| dashboard.writer());
+
     tracing_subscriber::fmt()
+        .with_writer(move || -> Box<dyn Write> {
+            #[cfg(feature = "tui")]
+            if let Some(writer) = &dashboard_writer {
+                return Box::new(writer.clone());
+            }
+            Box::new(writer.clone())
+        })
         .with_env_filter(tracing_subscriber::EnvFilter::builder()
-            .with_default_directive(LevelFilter::INFO.into())
-            .parse("").unwrap()
+            .with_default_directive(args.log_level().into())
+            .from_env_lossy()
         )
         .try_init().unwrap();
 
-    // Parse args
-    let args = Args::parse();
-    
+    match &args.command {
+        Some(Command::CheckConfig { config }) => return check_config_command(config),
+        Some(Command::ImportSubstitutions { csv }) => {
+            let substitutions = read_substitutions_csv(csv)?.into_iter().collect::<BTreeMap<_, _>>();
+            println!("{}", serde_json::to_string_pretty(&serde_json::json!({ "email_substitutions": substitutions }))?);
+            return Ok(ExitCode::SUCCESS);
+        },
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(*shell, &mut Args::command(), env!("CARGO_BIN_NAME"), &mut std::io::stdout());
+            return Ok(ExitCode::SUCCESS);
+        },
+        Some(Command::Schema) => {
+            println!("{}", serde_json::to_string_pretty(&config_schema())?);
+            return Ok(ExitCode::SUCCESS);
+        },
+        Some(Command::Man) => {
+            print!("{}", include_str!(concat!(env!("OUT_DIR"), "/repo_cleaner.1")));
+            return Ok(ExitCode::SUCCESS);
+        },
+        Some(Command::GcWorkdir { config, dry_run, force_unlock }) => return gc_workdir_command(config, *dry_run, *force_unlock),
+        Some(Command::History { command }) => return history_command(command.as_ref()),
+        Some(Command::Restore { .. } | Command::Rollback { .. } | Command::VerifyBackup { .. } | Command::Diff { .. } | Command::Enforce { .. } | Command::Doctor { .. }) | None => {},
+    }
+
+    let config = match &args.command {
+        Some(Command::Restore { config, .. } | Command::Rollback { config, .. } | Command::VerifyBackup { config, .. } | Command::Diff { config, .. } | Command::Enforce { config, .. } | Command::Doctor { config, .. }) => config.as_path(),
+        _ => args.config.as_deref().expect("clap requires a config without a subcommand"),
+    };
+
+    // Refuse to touch any repository with a broken config
+    let diagnostics = check_config(config)?;
+    for d in &diagnostics {
+        match d.severity {
+            Severity::Error => error!("{d}"),
+            Severity::Warning => warn!("{d}"),
+        }
+    }
+    if has_errors(&diagnostics) {
+        bail!("The configuration file {config:?} has errors. Run `check-config` for details");
+    }
 
     // Load config file
-    let conf: Config = serde_json::from_reader(
-        File::open(args.config.clone())
-            .wrap_err(format!("Unable to open configuration file {:?}", args.config))?
-    ).wrap_err("Error reading configuration file")?;
+    let mut conf = Config::load(config)?;
 
     // Construct the base path
     let base = std::env::current_dir().unwrap()
         .join("cleaner");
-    
-    // The path repos will be put in
-    let repos = base.join("repos");
-
-    // The path backups will be put in
-    let backups = base.join("backups");
-
-    // Dump the substitutions to a json map mapping old email to new email
-    let emails = serde_json::to_string(&conf.email_substitutions).unwrap();
-    let names = serde_json::to_string(&conf.name_substitutions).unwrap();
-    
-    // Create python cleaner for git-filter-repo
-    let email_cleaner = format!("return email if email.decode() not in \"{}\" else {}[email.decode()].encode()",
-        conf.email_substitutions.keys()
-            .map(|v| v.clone()).collect::<Vec<String>>()
-            .join(","),
-        emails);
-    
-    let name_cleaner = format!(r#"for rx in [{}]:
-    print(rx, name)
-    if re.search(rx, name.decode()):
-        return {}[rx].encode()
-return name"#,
-        conf.name_substitutions.keys()
-            .map(|v| format!(r#""{v}""#)).collect::<Vec<String>>()
-            .join(","),
-        names);
-        
-    
-
-
-    // If not commiting, pull each repo and backup
-    if !args.commit {
-        info!("Processing repositories");
-        // Process each repository
-        for repo in conf.repositories {
-
-            let repo_dir = repos.join(repo.clone());
-            let repo_dir = repo_dir.as_path();
-
-            let spin = ProgressBar::new_spinner()
-                .with_message(format!("Processing {repo}"));
-            spin.enable_steady_tick(Duration::from_millis(100));
-
-            // Construct the repo URL
-            let url = format!("git+ssh://git@github.com/{}.git", repo);
-
-            // Load git credential options
-            let mut cb = git2::RemoteCallbacks::new();
-            let git_config = git2::Config::open_default().unwrap();
-            let mut ch = CredentialHandler::new(git_config);
-            cb.credentials(move |url, username, allowed| ch.try_next_credential(url, username, allowed));
-            
-            // Set fetch options
-            let mut fo = git2::FetchOptions::new();
-            fo.remote_callbacks(cb)
-                .download_tags(git2::AutotagOption::All)
-                .update_fetchhead(true);
-
-            // Create clone dir
-            std::fs::create_dir_all(repo_dir).unwrap();
-
-            // Clone the repository
-            let repository = match git2::build::RepoBuilder::new()
-                    .fetch_options(fo)
-                    .clone(&url, repo_dir) {
-                Ok(r) => r,
-                Err(e) => {
-
-                    if e.code() != ErrorCode::Exists {
-                        warn!("Received error while cloning {repo}:\n{e}");
-                        warn!("Skipping cloning {url}");
-                        continue;
-                    }
 
-                    // If it exists, just open the repo
-                    match Repository::open(repos.join(repo.clone()).as_path()) {
-                        Ok(r) => r,
-                        Err(ne) => {
-                            warn!("Repository {repo} already exists.");
-                            warn!("Received error opening existing repo: \n{ne}");
-                            warn!("Skipping cloning {url}");
-                            continue;
-                        },
-                    }
-                },
-            };
-            
-            println!("{:?}", repository.remotes().map(|v| v.iter().filter_map(|v| v.map(|v| v.to_owned())).collect::<Vec<_>>()));
-
-            // It's at this point that we need to drop into raw git commands, as the configuration for credential options gets waaaaay to complex
-            // at this point
-
-
-            info!("Fetching all branches...");
-
-            // Fetch all branches
-            Command::new("git")
-                .args(["pull", "--all"])
-                .current_dir(repo_dir)
-                .output()
-                .expect("if one git command fails, it's likely every git command will fail");
-        
-            
-            info!("Backing up repository");
-
-            // Create backup directory
-            std::fs::create_dir_all(backups.join(repo.split('/').next().unwrap())).unwrap();
-
-            // Backup to tar
-            let backup_file = File::create(backups.join(repo.clone()).with_extension("tar"))?;
-            let mut backup_tar = tar::Builder::new(backup_file);
-            backup_tar.append_dir_all(".", repos.join(repo.clone()))?;
-            drop(backup_tar);
-
-            let mut branches = 0;
-            // Iterate over each branch to clean out the name for each branch
-            for branch in repository.branches(None).unwrap()
-                .filter_map(|v| v.ok())
-                .filter_map(|v| v.0.name().ok().and_then(|v| v.map(|v| v.to_string()))) {
-
-                
-
-                info!("Backing up branch {branch}");
-
-                std::fs::create_dir_all(backups.join(repo.clone()).join(branch.clone()).with_extension("tar").parent().unwrap_or(Path::new(""))).unwrap();
-                let backup_file = File::create(backups.join(repo.clone()).join(branch.clone()).with_extension("tar"))?;
-                let mut backup_tar = tar::Builder::new(backup_file);
-                backup_tar.append_dir_all(".", repos.join(repo.clone()))?;
-                drop(backup_tar);
-
-
-                info!("Cleaning author from branch {branch}");
-
-                Command::new("git")
-                    .args(["checkout", branch.split("/").last().unwrap()])
-                    .current_dir(repo_dir)
-                    .output()
-                    .expect("if one git command fails, it's likely every git command will fail");
-
-                Command::new("git")
-                    .args(["filter-repo", "--force", "--partial", "--sdr", "--name-callback", &name_cleaner])
-                    .current_dir(repo_dir)
-                    .output()
-                    .expect("if one git command fails, it's likely every git command will fail");
-                    
-                info!("Cleaning email from branch {branch}");
-                
-                Command::new("git")
-                    .args(["filter-repo", "--force", "--partial", "--sdr", "--email-callback", &email_cleaner])
-                    .current_dir(repo_dir)
-                    .output()
-                    .expect("if one git command fails, it's likely every git command will fail");
-                
-                
-                branches += 1;
-            }
+    // Held until the run ends, so another run can't work on the same repositories meanwhile
+    let _lock = RunLock::acquire(&base, args.force_unlock)?;
 
-            
-
-            info!("Running garbage collection on {repo}");
-
-            // Run git GC
-            Command::new("git")
-                .args(["git", "gc", "--prune=now", "--aggressive"])
-                .current_dir(repo_dir)
-                .output()
-                .expect("if one git command fails, it's likely every git command will fail");
-
-            if args.sign && branches == 1 {
-                info!("Re-signing all commits for {repo}");
-            
-                Command::new("git")
-                    .args(["rebase", "--exec", "git commit --amend --no-edit -n -S", "--root"])
-                    .current_dir(repo_dir)
-                    .output()
-                    .expect("if one git command fails, it's likely every git command will fail");
-            
-                Command::new("git")
-                    .args(["rebase", "--continue"])
-                    .current_dir(repo_dir)
-                    .output()
-                    .expect("if one git command fails, it's likely every git command will fail");
-                
-                Command::new("git")
-                    .args(["rebase", "--committer-date-is-author-date", "--root"])
-                    .current_dir(repo_dir)
-                    .output()
-                    .expect("if one git command fails, it's likely every git command will fail");
-            
-                Command::new("git")
-                    .args(["rebase", "--continue"])
-                    .current_dir(repo_dir)
-                    .output()
-                    .expect("if one git command fails, it's likely every git command will fail");
+    let mut options = args.cleaner_options(&conf)?;
+    options.timeouts.install();
+    if let Some(proxy) = &options.proxy {
+        install_proxy(proxy);
+    }
+    if !args.offline {
+        discover(&options, &mut conf, &base, args.refresh_discovery)?;
+    }
+    let rest = select_batch(&args, &mut conf.repositories)?;
+    if let Some(
+        Command::Restore { age_identity: Some(identity), .. }
+        | Command::Rollback { age_identity: Some(identity), .. }
+        | Command::VerifyBackup { age_identity: Some(identity), .. }
+        | Command::Diff { age_identity: Some(identity), .. }
+    ) = &args.command {
+        options.age_identity = Some(identity.clone());
+    }
+    if let Some(Command::Doctor { sign, ssh_key, .. }) = &args.command {
+        options.sign = *sign;
+        options.ssh.default_key = ssh_key.clone();
+    }
+    options.ssh.install(&base)?;
+    if let Some(github) = options.github.as_ref().filter(|g| g.is_app()) {
+        github.install_credential_helper();
+    }
+    for (provider, token) in [(Provider::AzureDevOps, &conf.azure_devops), (Provider::Bitbucket, &conf.bitbucket)] {
+        if let Some(token) = token {
+            token.install(provider);
+        }
+    }
+    if let Some(gitlab) = &conf.gitlab {
+        gitlab.install();
+    }
+    install_credential_routes(&conf.credentials);
+
+    let cleaner = Cleaner::new(&conf, &base, options);
+
+    if let Some(Command::Restore { repository, .. }) = &args.command {
+        let entry = conf.repositories.iter().find(|r| &r.name == repository)
+            .ok_or_else(|| eyre!("{repository} is not in {config:?}"))?;
+        cleaner.restore(&cleaner.job(entry))?;
+        info!("Restored {repository}. Run without --commit to clean it again");
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Some(Command::Rollback { repository, .. }) = &args.command {
+        let repositories = match repository {
+            Some(repository) => vec![conf.repositories.iter().find(|r| &r.name == repository)
+                .ok_or_else(|| eyre!("{repository} is not in {config:?}"))?.clone()],
+            None => conf.repositories.clone(),
+        };
+        return rollback_command(&cleaner, &repositories, repository.is_some());
+    }
+    if let Some(Command::VerifyBackup { .. }) = &args.command {
+        return verify_backup_command(&cleaner, &conf.repositories);
+    }
+    if let Some(Command::Diff { repository, .. }) = &args.command {
+        let entry = conf.repositories.iter().find(|r| &r.name == repository)
+            .ok_or_else(|| eyre!("{repository} is not in {config:?}"))?;
+        return diff_command(&cleaner, entry);
+    }
+    if let Some(Command::Doctor { .. }) = &args.command {
+        return doctor_command(&cleaner, &conf.repositories);
+    }
+
+    // A missing tool or broken credential is better found before any repository is touched
+    let cleaning = if args.watch { args.watch_action != WatchAction::Alert } else { args.command.is_none() && !args.commit && !args.dry_run };
+    if cleaning && !args.no_doctor {
+        let findings = doctor(&cleaner, &conf.repositories);
+        for finding in &findings {
+            match finding.severity {
+                Some(Severity::Error) => error!("{finding}"),
+                Some(Severity::Warning) => warn!("{finding}"),
+                None => {},
             }
+        }
+        if findings.iter().any(Finding::is_error) {
+            bail!("Pre-flight checks failed, so nothing was touched. Fix the errors above, or pass --no-doctor to start anyway");
+        }
+    }
+
+    info!("Starting run {}", cleaner.run_id());
+    if args.watch {
+        return watch(&args, &cleaner, &conf, &multi, &base);
+    }
+
+    let allowlist = match &args.command {
+        Some(Command::Enforce { allowlist, .. }) => Some(Allowlist::load(allowlist)?),
+        _ => None,
+    };
+    let phase = match (&allowlist, args.commit, args.preview, args.dry_run) {
+        (Some(allowlist), ..) => RunPhase::Enforce(allowlist),
+        (None, true, true, _) => RunPhase::Preview,
+        (None, true, false, _) => RunPhase::Commit,
+        (None, false, _, true) => RunPhase::Plan,
+        (None, false, _, false) => RunPhase::Clean,
+    };
+    #[cfg(feature = "tui")]
+    let summary = match &dashboard {
+        Some(dashboard) => run_phase_tui(phase, &cleaner, &conf.repositories, dashboard)?,
+        None => run_phase(phase, &cleaner, &conf.repositories, &multi, args.fail_fast, args.jobs),
+    };
+    #[cfg(not(feature = "tui"))]
+    let summary = run_phase(phase, &cleaner, &conf.repositories, &multi, args.fail_fast, args.jobs);
+
+    finish_summary(&summary, &cleaner, &base)?;
+    if !args.offline && !args.dry_run && !args.preview {
+        conf.notify.send(&summary);
+    }
+    if let Some((last, remaining)) = rest {
+        info!("{remaining} more repositories are left. Continue with --start-after {last}");
+    }
+
+    if summary.has_failures() {
+        return Ok(ExitCode::from(EXIT_PARTIAL_FAILURE));
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+
+/// A pass over every repository
+#[derive(Debug, Clone, Copy)]
+enum RunPhase<'a> {
+    /// Find what cleaning would change, without changing anything
+    Plan,
+    /// Clone, back up, and rewrite
+    Clean,
+    /// Find which refs pushing would move, without pushing
+    Preview,
+    /// Force push what the clean phase rewrote
+    Commit,
+    /// Check every email in the history against an allowlist
+    Enforce(&'a Allowlist),
+}
 
-            if branches != 1 {
-                warn!("Unable to re-sign history if more than one branch. Repo has {branches} branches");
+impl RunPhase<'_> {
+    fn name(self) -> &'static str {
+        match self {
+            RunPhase::Plan => "plan",
+            RunPhase::Clean => "clean",
+            RunPhase::Preview => "preview",
+            RunPhase::Commit => "commit",
+            RunPhase::Enforce(_) => "enforce",
+        }
+    }
+}
+
+/// Add every repository of the configured GitHub and Gitea organizations that discovery selects
+/// to `conf`, after the repositories it lists itself. Repositories that are already listed keep
+/// their entry. Listings are kept in `base`, and reused unless `refresh` is set.
+fn discover(options: &CleanerOptions, conf: &mut Config, base: &Path, refresh: bool) -> Result<()> {
+    let discovery = conf.discovery.clone();
+    if discovery.github_organizations.is_empty() && options.gitea.is_none() {
+        return Ok(());
+    }
+    let ttl = if refresh { Duration::ZERO } else { discovery.ttl() };
+    let mut cache = DiscoveryCache::load(base);
+
+    let mut add = |discovered: Vec<String>, provider: Provider, host: &str| {
+        let count = discovered.len();
+        for name in discovered {
+            if !conf.repositories.iter().any(|r| r.name == name && r.provider == provider) {
+                conf.repositories.push(RepositoryConfig { provider, ..RepositoryConfig::new(&name) });
             }
+        }
+        info!("Found {count} repositories on {host}");
+    };
+    if !discovery.github_organizations.is_empty() {
+        let github = options.github.as_ref()
+            .ok_or_else(|| eyre!("Discovering the repositories of \"github_organizations\" needs --github-token, a \"github_app\" in the config, or a token in \"credentials\" for each organization"))?;
+        add(github.discover(&discovery, &mut cache, ttl)?, Provider::GitHub, "GitHub");
+    }
+    if let Some(gitea) = &options.gitea {
+        add(gitea.discover(&discovery, &mut cache, ttl)?, Provider::Gitea, gitea.host());
+    }
+    cache.save()
+}
+
+/// Narrow `repositories` down to the batch --shard, --start-after, and --limit select, in that
+/// order. Returns the last repository of the batch and how many are left after it, if any are.
+fn select_batch(args: &Args, repositories: &mut Vec<RepositoryConfig>) -> Result<Option<(String, usize)>> {
+    if let Some((shard, shards)) = args.shard {
+        let all = std::mem::take(repositories);
+        repositories.extend(all.into_iter().skip(shard - 1).step_by(shards));
+    }
+
+    if let Some(start_after) = &args.start_after {
+        let position = repositories.iter().position(|r| &r.name == start_after)
+            .ok_or_else(|| eyre!("--start-after {start_after} is not one of the repositories to process"))?;
+        repositories.drain(..=position);
+    }
+
+    let Some(limit) = args.limit.filter(|limit| limit.get() < repositories.len()) else {
+        return Ok(None);
+    };
+    let remaining = repositories.split_off(limit.get()).len();
+    let last = repositories.last().map(|r| r.name.clone()).unwrap_or_default();
+    info!("Processing {limit} repositories, up to {last}");
+    Ok(Some((last, remaining)))
+}
+
+/// Run `phase` over `repositories`, returning what happened to each
+fn run_phase(phase: RunPhase, cleaner: &Cleaner, repositories: &[RepositoryConfig], multi: &MultiProgress, fail_fast: bool, jobs: NonZeroUsize) -> Summary {
+    let mut summary = Summary::new(phase.name());
+
+    let (scheduler, failures) = phase_order(phase, cleaner, repositories, jobs, fail_fast);
+    if let (true, Some((repo, e))) = (fail_fast, failures.first()) {
+        error!("Stopping before {repo} because --fail-fast is set");
+        let mut report = RepoReport::new(repo);
+        report.fail(format!("{e:#}"));
+        report.failure = Some(FailureKind::of(e));
+        summary.repositories.push(report);
+        summary.skip_remaining(repositories.iter().map(|r| &r.name).filter(|name| *name != repo), "Not attempted due to --fail-fast");
+        return summary;
+    }
+    let verb = match phase {
+        RunPhase::Plan | RunPhase::Clean => "Cleaning",
+        RunPhase::Preview | RunPhase::Commit => "Pushing",
+        RunPhase::Enforce(_) => "Checking",
+    };
+    let progress = RunProgress::new(multi, scheduler.repositories().len(), verb);
+
+    let reports = Mutex::new(Vec::new());
+    let skipped = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.get() {
+            scope.spawn(|| {
+                while let Some((i, entry)) = scheduler.next() {
+                    let repo = &entry.name;
+
+                    let repo_progress = progress.start(repo);
+                    let report = process(phase, cleaner, entry, &repo_progress);
+                    let succeeded = report.succeeded();
+                    reports.lock().expect("reports are never poisoned").push((i, report));
+
+                    progress.finish(repo_progress);
+                    scheduler.finish(i);
+
+                    if fail_fast && !succeeded {
+                        error!("Stopping after {repo} because --fail-fast is set");
+                        let remaining = scheduler.stop().into_iter().map(|r| r.name.clone());
+                        skipped.lock().expect("skipped repositories are never poisoned").extend(remaining);
+                    }
+                }
+            });
+        }
+    });
+
+    // Repositories finish in whatever order they take, but are reported in the order they started
+    let mut reports = reports.into_inner().expect("reports are never poisoned");
+    reports.sort_by_key(|(i, _)| *i);
+    summary.repositories.extend(reports.into_iter().map(|(_, report)| report));
+    summary.skip_remaining(skipped.into_inner().expect("skipped repositories are never poisoned"), "Not attempted due to --fail-fast");
 
-            spin.finish_with_message(format!("Finished processing {repo}"));
+    progress.clear();
+    summary
+}
+
+/// Run `phase` over `repositories` like [`run_phase`], on the dashboard. A repository that fails
+/// is retried or skipped as the user decides.
+#[cfg(feature = "tui")]
+fn run_phase_tui(phase: RunPhase, cleaner: &Cleaner, repositories: &[RepositoryConfig], dashboard: &std::sync::Arc<Dashboard>) -> Result<Summary> {
+    let mut summary = Summary::new(phase.name());
+
+    let (scheduler, _) = phase_order(phase, cleaner, repositories, NonZeroUsize::MIN, false);
+    let repositories = scheduler.repositories();
+    let names = repositories.iter().map(|r| r.name.clone()).collect::<Vec<_>>();
+    dashboard.show(&format!("repo_cleaner {}", phase.name()), &names)?;
+
+    for (i, entry) in repositories.iter().enumerate() {
+        if dashboard.quit_requested() {
+            summary.skip_remaining(&names[i..], "Not attempted because the dashboard was quit");
+            break;
         }
-    } else {
-        
-        info!("Force pushing every changed repository.");
 
-        for repo in conf.repositories {
+        let report = loop {
+            let report = process(phase, cleaner, entry, &dashboard.start(i));
+            if dashboard.finish(i, &report) != Decision::Retry {
+                break report;
+            }
+            info!("Retrying {}", entry.name);
+        };
+        summary.repositories.push(report);
+    }
 
-            let repo_dir = repos.join(repo.clone());
-            let repo_dir = repo_dir.as_path();
+    dashboard.wait();
+    dashboard.close();
+    Ok(summary)
+}
 
-            info!("Force pushing {repo}");
+/// The order `phase` processes `repositories` in, and which have to wait for which, along with any
+/// repositories whose submodules couldn't be found
+fn phase_order(phase: RunPhase, cleaner: &Cleaner, repositories: &[RepositoryConfig], jobs: NonZeroUsize, fail_fast: bool) -> (Scheduler, Vec<(String, eyre::Report)>) {
+    let dependencies = match phase {
+        RunPhase::Plan | RunPhase::Clean => {
+            info!("Processing repositories");
+            // Repositories used as submodules are rewritten before the repositories that use them
+            cleaner.dependencies(repositories, jobs, fail_fast)
+        },
+        RunPhase::Preview | RunPhase::Commit => {
+            info!("Force pushing every changed repository.");
+            Dependencies::default()
+        },
+        RunPhase::Enforce(_) => {
+            info!("Checking every repository's history against the allowlist");
+            Dependencies::default()
+        },
+    };
+    for (repo, e) in &dependencies.failures {
+        warn!("{e:#}, so {repo} may be rewritten before repositories it uses as submodules");
+    }
+    (Scheduler::new(repositories, &dependencies.found, &cleaner.config().concurrency), dependencies.failures)
+}
 
-            // Run fsck
-            let fsck = Command::new("git")
-                .args(["fsck", "--full", "--strict"])
-                .current_dir(repo_dir)
-                .output()
-                .expect("if one git command fails, it's likely every git command will fail").status;
+/// Run `phase` on a single repository, recording any error as its failure
+fn process(phase: RunPhase, cleaner: &Cleaner, entry: &RepositoryConfig, progress: &RepoProgress) -> RepoReport {
+    let repo = &entry.name;
+    let mut report = RepoReport::new(repo);
+    let started = Instant::now();
+    let job = cleaner.job(entry);
+    let result = match phase {
+        RunPhase::Plan => cleaner.plan(&job, &mut report, progress),
+        RunPhase::Clean => cleaner.clean(&job, &mut report, progress),
+        RunPhase::Preview | RunPhase::Commit => cleaner.push(&job, &mut report),
+        RunPhase::Enforce(allowlist) => cleaner.enforce(&job, allowlist, &mut report, progress),
+    };
+    if let Err(e) = result {
+        error!("Failed to process {repo}: {e:?}");
+        report.fail(format!("{e:#}"));
+        report.failure = Some(FailureKind::of(&e));
+    }
+    report.seconds = started.elapsed().as_secs_f64();
+    report
+}
 
-            if !fsck.success() {
-                error!("FSCK failed on repository {repo}. This repository will not be force pushed, as there is a chance the repo cleaning may have damaged the commit history. Manual review and pushing may be required. Use the command `git push --all --force` once you have confirmed that the repository's history is intact, or have fixed the issues.");
+/// Audit every repository every `interval`, forever. Repositories with commits the rules still
+/// match are reported, and cleaned or cleaned and pushed if `--watch-action` says so.
+fn watch(args: &Args, cleaner: &Cleaner, conf: &Config, multi: &MultiProgress, base: &Path) -> Result<ExitCode> {
+    loop {
+        info!("Auditing every repository for identities the rules match");
+        let mut audit = run_phase(RunPhase::Plan, cleaner, &conf.repositories, multi, false, args.jobs);
+        audit.phase = "audit".to_string();
+        finish_summary(&audit, cleaner, base)?;
+
+        let dirty = conf.repositories.iter()
+            .filter(|r| audit.repositories.iter().any(|report| report.repository == r.name && report.rule_matches.values().any(|n| *n > 0)))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if dirty.is_empty() {
+            info!("No repository has commits the rules match");
+        } else {
+            warn!("Rules still match commits in {}", dirty.iter().map(|r| r.name.as_str()).collect::<Vec<_>>().join(", "));
+            if !args.offline {
+                conf.notify.send(&audit);
+            }
+
+            if args.watch_action != WatchAction::Alert {
+                let cleaned = run_phase(RunPhase::Clean, cleaner, &dirty, multi, false, args.jobs);
+                finish_summary(&cleaned, cleaner, base)?;
+                if !args.offline {
+                    conf.notify.send(&cleaned);
+                }
+
+                // Rules that map a name onto one they match again keep matching, but change nothing
+                let changed = dirty.iter()
+                    .filter(|r| cleaned.repositories.iter().any(|report| report.repository == r.name && report.succeeded() && report.commits_changed > 0))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                if args.watch_action == WatchAction::Push && !changed.is_empty() {
+                    let pushed = run_phase(RunPhase::Commit, cleaner, &changed, multi, false, args.jobs);
+                    finish_summary(&pushed, cleaner, base)?;
+                    if !args.offline {
+                        conf.notify.send(&pushed);
+                    }
+                }
             }
-            
-            Command::new("git")
-                .args(["push", "--all", "--force"])
-                .current_dir(repo_dir)
-                .output()
-                .expect("if one git command fails, it's likely every git command will fail");
-            
-            
         }
+
+        info!("Auditing again in {}", format_interval(args.interval));
+        std::thread::sleep(args.interval);
+    }
+}
+
+fn format_interval(interval: Duration) -> String {
+    let seconds = interval.as_secs();
+    match seconds {
+        s if s % (24 * 60 * 60) == 0 => format!("{}d", s / (24 * 60 * 60)),
+        s if s % (60 * 60) == 0 => format!("{}h", s / (60 * 60)),
+        s if s % 60 == 0 => format!("{}m", s / 60),
+        s => format!("{s}s"),
+    }
+}
+
+/// Print the summary table, write it next to the repositories as JSON, and export the phase's
+/// telemetry
+#[cfg_attr(not(feature = "otlp"), allow(unused_variables))]
+fn finish_summary(summary: &Summary, cleaner: &Cleaner, base: &Path) -> Result<()> {
+    println!("{summary}");
+    #[cfg(feature = "otlp")]
+    if let Some(telemetry) = cleaner.telemetry() {
+        telemetry.export(summary);
     }
-    
 
+    std::fs::create_dir_all(base)?;
+    let path = base.join(format!("{}-summary.json", summary.phase));
+    summary.write(&path)?;
+    info!("Wrote summary to {path:?}");
+    RunHistory::append(base, &RunRecord::new(cleaner, summary)?)?;
 
     Ok(())
 }
+
+/// List every run in the working directory, or every phase of one with `history show`
+fn history_command(command: Option<&HistoryCommand>) -> Result<ExitCode> {
+    let history = RunHistory::load(&std::env::current_dir()?.join("cleaner"))?;
+    let Some(HistoryCommand::Show { id }) = command else {
+        if history.runs.is_empty() {
+            println!("Nothing has run in this working directory yet");
+        } else {
+            print!("{history}");
+        }
+        return Ok(ExitCode::SUCCESS);
+    };
+
+    let phases = history.run(id);
+    if phases.is_empty() {
+        error!("There is no run {id} in the history");
+        return Ok(ExitCode::FAILURE);
+    }
+    for (i, phase) in phases.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        print!("{phase}");
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Print every problem with a configuration file, failing if any of them are errors
+fn check_config_command(config: &Path) -> Result<ExitCode> {
+    let diagnostics = check_config(config)?;
+
+    for d in &diagnostics {
+        println!("{d}");
+    }
+
+    if has_errors(&diagnostics) {
+        return Ok(ExitCode::FAILURE);
+    }
+
+    println!("{config:?} is valid");
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Print the outcome of every pre-flight check, failing if any of them found an error
+fn doctor_command(cleaner: &Cleaner, repositories: &[RepositoryConfig]) -> Result<ExitCode> {
+    let findings = doctor(cleaner, repositories);
+
+    for finding in &findings {
+        println!("{finding}");
+    }
+
+    if findings.iter().any(Finding::is_error) {
+        return Ok(ExitCode::FAILURE);
+    }
+
+    println!("Everything cleaning needs works");
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Roll back every repository of `repositories` left partway through changing, saying so of each
+/// one that wasn't if it was asked for by name
+fn rollback_command(cleaner: &Cleaner, repositories: &[RepositoryConfig], named: bool) -> Result<ExitCode> {
+    let mut rolled_back = 0;
+    for repository in repositories {
+        match cleaner.rollback(&cleaner.job(repository))? {
+            Some(step) => {
+                info!("Rolled {} back from the {step} step. Run without --commit to clean it again", repository.name);
+                rolled_back += 1;
+            },
+            None if named => info!("{} wasn't left partway through anything. Use `restore` to undo a rewrite that finished", repository.name),
+            None => {},
+        }
+    }
+
+    if !named && rolled_back == 0 {
+        info!("No repository was left partway through anything");
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Verify the backup of every repository against its manifest, failing if any doesn't match
+fn verify_backup_command(cleaner: &Cleaner, repositories: &[RepositoryConfig]) -> Result<ExitCode> {
+    let mut failed = false;
+    for repository in repositories {
+        match cleaner.verify_backup(&cleaner.job(repository)) {
+            Ok(()) => println!("ok: {}", repository.name),
+            Err(e) => {
+                println!("error: {}: {e:#}", repository.name);
+                failed = true;
+            },
+        }
+    }
+
+    if failed {
+        return Ok(ExitCode::FAILURE);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Compare the backup of `repository` with its rewrite, failing if any commit's files changed
+/// without a callback that could have changed them
+fn diff_command(cleaner: &Cleaner, repository: &RepositoryConfig) -> Result<ExitCode> {
+    let diff = cleaner.diff(&cleaner.job(repository))?;
+    print!("{diff}");
+    if diff.changed.is_empty() {
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let callbacks = &cleaner.config().callbacks;
+    if callbacks.commit_callback.is_some() || callbacks.filename_callback.is_some() {
+        warn!("{} commits of {} had their files changed, which the commit or filename callback in the config may have meant to", diff.changed.len(), repository.name);
+        return Ok(ExitCode::SUCCESS);
+    }
+    error!("{} commits of {} had their files changed by the rewrite, which should only change names, emails, and messages", diff.changed.len(), repository.name);
+    Ok(ExitCode::FAILURE)
+}
+
+/// Remove what the repositories of `config` no longer need from the working directory
+fn gc_workdir_command(config: &Path, dry_run: bool, force_unlock: bool) -> Result<ExitCode> {
+    let conf = Config::load(config)?;
+    let base = std::env::current_dir()?.join("cleaner");
+    let _lock = RunLock::acquire(&base, force_unlock)?;
+
+    let garbage = collect_workdir_garbage(&base, &conf, dry_run)?;
+    let verb = if dry_run { "Would reclaim" } else { "Reclaimed" };
+    println!("{verb} {} from {} paths in {base:?}", HumanBytes(garbage.reclaimed), garbage.removed.len());
+    Ok(ExitCode::SUCCESS)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_interval_reads_units() {
+        assert_eq!(parse_interval("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_interval("2h"), Ok(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(parse_interval("7d"), Ok(Duration::from_secs(7 * 24 * 60 * 60)));
+    }
+
+    #[test]
+    fn parse_interval_rejects_zero_and_unknown_units() {
+        assert!(parse_interval("0m").is_err());
+        assert!(parse_interval("5x").is_err());
+        assert!(parse_interval("m").is_err());
+    }
+
+    #[test]
+    fn parse_interval_rejects_missing_units_and_overflow() {
+        assert!(parse_interval("30").is_err());
+        assert!(parse_interval("999999999999999999d").is_err());
+    }
+
+    #[test]
+    fn parse_shard_reads_index_and_count() {
+        assert_eq!(parse_shard("2/4"), Ok((2, 4)));
+        assert_eq!(parse_shard("3/3"), Ok((3, 3)));
+    }
+
+    #[test]
+    fn parse_shard_rejects_out_of_range_shards() {
+        assert!(parse_shard("0/3").is_err());
+        assert!(parse_shard("4/3").is_err());
+        assert!(parse_shard("2").is_err());
+        assert!(parse_shard("a/3").is_err());
+    }
+}
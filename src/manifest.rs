@@ -0,0 +1,153 @@
+use std::{collections::BTreeMap, fs::OpenOptions, io::{ErrorKind, Write}, path::{Path, PathBuf}, sync::Mutex};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use eyre::{bail, eyre, Context, Result};
+use ring::{hmac, rand::{SecureRandom, SystemRandom}};
+use serde::{Deserialize, Serialize};
+
+
+/// Held while a manifest is read, changed, and written back, since repositories are cleaned on
+/// several threads at once
+static UPDATE: Mutex<()> = Mutex::new(());
+
+/// Held while the signing key is read or generated, so threads loading the manifest of a fresh
+/// working directory at once agree on a single key
+static KEY: Mutex<()> = Mutex::new(());
+
+/// What the clean phase left a repository as, which is exactly what the commit phase may push
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// Every branch to push, and the commit it has to be at
+    pub branches: BTreeMap<String, String>,
+    pub cleaned_at: String,
+    /// Whether no rule matched anything, so the repository was left as it is and has nothing to
+    /// push back to where it came from
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unchanged: bool,
+}
+
+/// The repositories the clean phase rewrote successfully, kept next to them.
+///
+/// The file is signed with a key that only lives in the working directory, so a manifest that was
+/// edited by hand or copied from another working directory is refused rather than trusted.
+#[derive(Debug)]
+pub struct Manifest {
+    path: PathBuf,
+    key: hmac::Key,
+    repositories: BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct SignedManifest {
+    repositories: BTreeMap<String, ManifestEntry>,
+    signature: String,
+}
+
+impl Manifest {
+    /// Load the manifest in `dir`, creating its key if this is the first run there
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join("manifest.json");
+        let key = load_key(&dir.join("manifest.key"))?;
+
+        let repositories = match std::fs::read_to_string(&path) {
+            Ok(text) => {
+                let signed: SignedManifest = serde_json::from_str(&text)
+                    .wrap_err(format!("Unable to read manifest {path:?}"))?;
+                let signature = STANDARD.decode(&signed.signature)
+                    .wrap_err(format!("Manifest {path:?} has an invalid signature"))?;
+                hmac::verify(&key, serde_json::to_string(&signed.repositories)?.as_bytes(), &signature)
+                    .map_err(|_| eyre!("Manifest {path:?} was not written by this working directory's clean phase. Run without --commit again"))?;
+                signed.repositories
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e).wrap_err(format!("Unable to read manifest {path:?}")),
+        };
+
+        Ok(Self { path, key, repositories })
+    }
+
+    /// Every repository recorded, by name
+    pub fn repositories(&self) -> impl Iterator<Item = &str> {
+        self.repositories.keys().map(String::as_str)
+    }
+
+    pub fn get(&self, repository: &str) -> Option<&ManifestEntry> {
+        self.repositories.get(repository)
+    }
+
+    /// Record that `repository` was cleaned, leaving `branches` at their commits
+    pub fn record(&mut self, repository: &str, branches: BTreeMap<String, String>) -> Result<()> {
+        self.insert(repository, branches, false)
+    }
+
+    /// Record that `repository` was left as it is, with `branches` at their commits, since no rule
+    /// matched anything in it
+    pub fn record_unchanged(&mut self, repository: &str, branches: BTreeMap<String, String>) -> Result<()> {
+        self.insert(repository, branches, true)
+    }
+
+    fn insert(&mut self, repository: &str, branches: BTreeMap<String, String>, unchanged: bool) -> Result<()> {
+        let entry = ManifestEntry { branches, cleaned_at: chrono::Utc::now().to_rfc3339(), unchanged };
+        self.update(|repositories| {
+            repositories.insert(repository.to_string(), entry);
+            true
+        })
+    }
+
+    /// Forget `repository`, so it can't be pushed until it is cleaned again
+    pub fn remove(&mut self, repository: &str) -> Result<()> {
+        self.update(|repositories| repositories.remove(repository).is_some())
+    }
+
+    /// Apply `change` to the manifest as it is on disk now, which other threads may have changed
+    /// since it was loaded, and save it if `change` returns true
+    fn update(&mut self, change: impl FnOnce(&mut BTreeMap<String, ManifestEntry>) -> bool) -> Result<()> {
+        let _update = UPDATE.lock().expect("manifest updates are never poisoned");
+        self.repositories = Self::load(self.path.parent().unwrap_or(Path::new(".")))?.repositories;
+        if change(&mut self.repositories) {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> Result<()> {
+        let signature = hmac::sign(&self.key, serde_json::to_string(&self.repositories)?.as_bytes());
+        let signed = SignedManifest {
+            repositories: self.repositories.clone(),
+            signature: STANDARD.encode(signature.as_ref()),
+        };
+        std::fs::write(&self.path, serde_json::to_string_pretty(&signed)?)
+            .wrap_err(format!("Unable to write manifest {:?}", self.path))
+    }
+}
+
+/// Read the manifest signing key at `path`, generating it if it doesn't exist yet
+fn load_key(path: &Path) -> Result<hmac::Key> {
+    let _key = KEY.lock().expect("manifest key loads are never poisoned");
+    match std::fs::read(path) {
+        Ok(bytes) if bytes.is_empty() => bail!("Manifest key {path:?} is empty. Delete it, and run without --commit again"),
+        Ok(bytes) => return Ok(hmac::Key::new(hmac::HMAC_SHA256, &bytes)),
+        Err(e) if e.kind() == ErrorKind::NotFound => {},
+        Err(e) => return Err(e).wrap_err(format!("Unable to read manifest key {path:?}")),
+    }
+
+    let mut bytes = [0; 32];
+    SystemRandom::new().fill(&mut bytes)
+        .map_err(|_| eyre!("Unable to generate a manifest key"))?;
+
+    // Written beside the key and renamed into place, so a half-written key is never read
+    let partial = path.with_extension("key.partial");
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&partial)
+        .wrap_err(format!("Unable to create manifest key {partial:?}"))?;
+    file.write_all(&bytes)
+        .and_then(|()| file.sync_all())
+        .wrap_err(format!("Unable to write manifest key {partial:?}"))?;
+    std::fs::rename(&partial, path)
+        .wrap_err(format!("Unable to create manifest key {path:?}"))?;
+
+    Ok(hmac::Key::new(hmac::HMAC_SHA256, &bytes))
+}
@@ -0,0 +1,58 @@
+use std::{collections::BTreeMap, fmt::Write, path::Path};
+
+use eyre::{Context, Result};
+
+
+/// Write `MIGRATION.md` into `dir`, telling collaborators how to move their clones and in-flight
+/// branches of `repository` onto its rewritten history, along with the full map of old to new commits.
+///
+/// `branches` maps every rewritten branch to its commit before and after the rewrite, and `url` is
+/// where the cleaned history is pushed.
+pub fn write(dir: &Path, repository: &str, url: &str, branches: &BTreeMap<String, (String, String)>, commit_map: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir)
+        .wrap_err(format!("Unable to create migration directory {dir:?}"))?;
+
+    let moved = branches.iter()
+        .filter(|(_, (old, new))| old != new)
+        .collect::<Vec<_>>();
+    let url = url.strip_prefix("git+").unwrap_or(url);
+
+    let mut text = format!("# The history of {repository} has been rewritten\n\n");
+    text += &format!("Author and committer names and emails in the history of `{url}` were cleaned up, which gave the affected commits new ids. Existing clones, forks, and branches still point at the old commits, and pushing them would bring the old history back.\n\n");
+
+    if moved.is_empty() {
+        text += "No branch moved, so existing clones need no changes.\n";
+    } else {
+        text += "## Clones without local work\n\nClone the repository again, or reset each branch of an existing clone. This discards anything that isn't pushed elsewhere.\n\n";
+        text += "```sh\ngit fetch origin\n";
+        for (branch, _) in &moved {
+            let _ = writeln!(text, "git checkout {branch} && git reset --hard origin/{branch}");
+        }
+        text += "```\n\n";
+
+        text += "## Branches that aren't pushed yet\n\nReplay only your own commits onto the rewritten branch they started from. For a branch `my-work` based on";
+        let (branch, (old, _)) = moved.iter()
+            .find(|(branch, _)| ["main", "master"].contains(&branch.as_str()))
+            .unwrap_or(&moved[0]);
+        let _ = write!(text, " `{branch}`:\n\n```sh\ngit fetch origin\ngit rebase --onto origin/{branch} {old} my-work\n```\n\n");
+        text += "Use the old commit of whichever branch it started from, as listed below. Then check that `git log origin/BRANCH..my-work` only lists your own commits before pushing it.\n\n";
+
+        text += "## Branches that moved\n\n| Branch | Old commit | New commit |\n| --- | --- | --- |\n";
+        for (branch, (old, new)) in &moved {
+            let _ = writeln!(text, "| `{branch}` | `{old}` | `{new}` |");
+        }
+        text += "\nEvery rewritten commit is listed in `commit-map.txt` next to this file, as `old new` pairs.\n";
+    }
+
+    let path = dir.join("MIGRATION.md");
+    std::fs::write(&path, text)
+        .wrap_err(format!("Unable to write migration instructions to {path:?}"))?;
+
+    // Nothing was rewritten if filter-repo never ran
+    if commit_map.exists() {
+        std::fs::copy(commit_map, dir.join("commit-map.txt"))
+            .wrap_err(format!("Unable to copy commit map into {dir:?}"))?;
+    }
+
+    Ok(())
+}
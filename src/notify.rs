@@ -0,0 +1,145 @@
+use eyre::{Context, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::{git::http_agent, summary::{RepoStatus, Summary}};
+
+
+/// Where to send a run summary once a phase finishes
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone)]
+#[schemars(deny_unknown_fields)]
+pub struct NotifyConfig {
+    /// Generic webhook that receives the summary as a JSON POST
+    pub webhook_url: Option<String>,
+    /// Slack incoming webhook that receives a human readable message
+    pub slack_webhook_url: Option<String>,
+    /// Issue opened on every repository after it is pushed, telling collaborators their clones are out of date
+    pub github_issue: Option<IssueTemplate>,
+}
+
+/// The title and body of the issue opened after pushing a repository.
+///
+/// `{repository}`, `{branch}`, `{old_head}`, `{new_head}`, and `{url}` are replaced with the
+/// repository, its checked out branch, that branch's commit before and after rewriting, and where
+/// the cleaned history was pushed.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[schemars(deny_unknown_fields)]
+#[serde(default)]
+pub struct IssueTemplate {
+    pub title: String,
+    pub body: String,
+}
+
+impl Default for IssueTemplate {
+    fn default() -> Self {
+        Self {
+            title: "The history of {repository} has been rewritten".to_string(),
+            body: "The history of {repository} was rewritten to clean up author names and emails, so existing clones no longer match it.\n\n\
+                `{branch}` moved from {old_head} to {new_head}.\n\n\
+                Clone it again:\n\n    git clone {url}\n\n\
+                Or reset an existing clone, which discards any local work that isn't saved elsewhere:\n\n    git fetch origin\n    git reset --hard origin/{branch}\n".to_string(),
+        }
+    }
+}
+
+impl IssueTemplate {
+    /// The title and body with every placeholder replaced by its value in `values`
+    pub fn render(&self, values: &[(&str, &str)]) -> (String, String) {
+        let fill = |template: &str| values.iter()
+            .fold(template.to_string(), |text, (key, value)| text.replace(&format!("{{{key}}}"), value));
+        (fill(&self.title), fill(&self.body))
+    }
+}
+
+impl NotifyConfig {
+    /// Post the summary to every configured destination.
+    /// Delivery failures are logged rather than returned, so a broken webhook never fails the run.
+    pub fn send(&self, summary: &Summary) {
+        if let Some(url) = &self.webhook_url {
+            match post(url, &webhook_payload(summary)) {
+                Ok(()) => info!("Sent run summary to webhook"),
+                Err(e) => warn!("Unable to send run summary to webhook: {e:#}"),
+            }
+        }
+
+        if let Some(url) = &self.slack_webhook_url {
+            match post(url, &json!({ "text": slack_message(summary) })) {
+                Ok(()) => info!("Sent run summary to Slack"),
+                Err(e) => warn!("Unable to send run summary to Slack: {e:#}"),
+            }
+        }
+    }
+}
+
+/// Repositories that were cleaned successfully and still need to be pushed with `--commit`
+fn awaiting_commit(summary: &Summary) -> Vec<&str> {
+    if summary.phase != "clean" {
+        return Vec::new();
+    }
+
+    summary.repositories.iter()
+        .filter(|r| r.status == RepoStatus::Succeeded)
+        .map(|r| r.repository.as_str())
+        .collect()
+}
+
+fn webhook_payload(summary: &Summary) -> serde_json::Value {
+    let failures = summary.repositories.iter()
+        .filter(|r| !r.succeeded())
+        .collect::<Vec<_>>();
+
+    json!({
+        "phase": summary.phase,
+        "processed": summary.repositories.len(),
+        "failures": failures,
+        "awaiting_commit": awaiting_commit(summary),
+        "repositories": summary.repositories,
+    })
+}
+
+fn slack_message(summary: &Summary) -> String {
+    let count = |status| summary.repositories.iter().filter(|r| r.status == status).count();
+
+    let mut message = format!(
+        "repo_cleaner finished the {} phase: {} processed, {} succeeded, {} clean, {} skipped, {} failed",
+        summary.phase,
+        summary.repositories.len(),
+        count(RepoStatus::Succeeded),
+        count(RepoStatus::Clean),
+        count(RepoStatus::Skipped),
+        count(RepoStatus::Failed),
+    );
+
+    for r in summary.repositories.iter().filter(|r| !r.succeeded()) {
+        message += &format!("\n• {} {}: {}", r.repository, r.status, r.reason.as_deref().unwrap_or("no reason given"));
+    }
+
+    // An audit is only worth reading for the repositories that still have matching commits
+    if summary.phase == "audit" {
+        for r in &summary.repositories {
+            let matched = r.rule_matches.values().filter(|n| **n > 0).count();
+            if matched > 0 {
+                message += &format!("\n• {} still has commits matching {matched} rules", r.repository);
+            }
+            if !r.leaked_emails.is_empty() {
+                message += &format!("\n• {} has {} old emails in its file contents", r.repository, r.leaked_emails.len());
+            }
+        }
+    }
+
+    let awaiting = awaiting_commit(summary);
+    if !awaiting.is_empty() {
+        message += &format!("\nAwaiting `--commit`: {}", awaiting.join(", "));
+    }
+
+    message
+}
+
+fn post(url: &str, body: &serde_json::Value) -> Result<()> {
+    http_agent().post(url)
+        .send_json(body)
+        .wrap_err(format!("POST to {url} failed"))?;
+    Ok(())
+}
@@ -0,0 +1,139 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use chrono::NaiveTime;
+use clap::ValueEnum;
+use serde::Serialize;
+
+
+/// Which git implementation clones and fetches repositories
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// libgit2, through git2
+    #[default]
+    Libgit2,
+    /// gitoxide, which resolves packs in parallel and is much faster on large repositories
+    #[cfg(feature = "gix")]
+    Gix,
+}
+
+/// How much of a repository's file contents to search for old emails
+#[derive(ValueEnum, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BlobScan {
+    /// The files at the tip of every branch and tag
+    Tree,
+    /// Every version of every file reachable from any branch or tag, which is slow on large repositories
+    History,
+}
+
+/// How hard to collect garbage after rewriting
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GcMode {
+    /// Leave the repository as filter-repo left it
+    Off,
+    /// Prune every object the rewrite left unreachable
+    #[default]
+    Normal,
+    /// Also recompute every delta, which packs much tighter but is slow on large repositories
+    Aggressive,
+}
+
+/// What to do with a clone an earlier run left behind
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RefreshPolicy {
+    /// Fetch, and fast-forward the checked out branch, failing if it was rewritten since
+    #[default]
+    Pull,
+    /// Use the clone as it is, without fetching anything
+    Reuse,
+    /// Fetch, and reset every branch and tag to what the remote has, deleting any it doesn't
+    Reset,
+    /// Delete the clone and clone the repository again
+    Reclone,
+}
+
+/// The hours of the day pushes may start in, in local time, like `22:00-05:00`. Windows that end
+/// before they start run past midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PushWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl PushWindow {
+    /// Whether a push may start at `time`
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// How long after `time` the window next opens, which is nothing if it is open
+    pub fn until_open(&self, time: NaiveTime) -> Duration {
+        if self.contains(time) {
+            return Duration::ZERO;
+        }
+        let wait = self.start - time;
+        // The window opens tomorrow if its start has passed today
+        let wait = if wait < chrono::TimeDelta::zero() { wait + chrono::TimeDelta::days(1) } else { wait };
+        wait.to_std().unwrap_or_default()
+    }
+}
+
+impl FromStr for PushWindow {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (start, end) = text.split_once('-')
+            .ok_or_else(|| format!("{text} is not a window like 22:00-05:00"))?;
+        let time = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M")
+            .map_err(|_| format!("{time} is not a time like 22:00"));
+        let window = Self { start: time(start)?, end: time(end)? };
+        if window.start == window.end {
+            return Err("The window has to start and end at different times".to_string());
+        }
+        Ok(window)
+    }
+}
+
+impl fmt::Display for PushWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start.format("%H:%M"), self.end.format("%H:%M"))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn push_window_parses_and_displays() {
+        let window: PushWindow = "22:00 - 05:30".parse().unwrap();
+        assert_eq!(window, PushWindow { start: at("22:00"), end: at("05:30") });
+        assert_eq!(window.to_string(), "22:00-05:30");
+    }
+
+    #[test]
+    fn push_window_rejects_malformed_windows() {
+        assert!("22:00".parse::<PushWindow>().is_err());
+        assert!("25:00-05:00".parse::<PushWindow>().is_err());
+        assert!("10:00-10:00".parse::<PushWindow>().is_err());
+    }
+
+    #[test]
+    fn push_window_runs_past_midnight() {
+        let window: PushWindow = "22:00-05:00".parse().unwrap();
+        assert!(window.contains(at("23:30")));
+        assert!(window.contains(at("04:59")));
+        assert!(!window.contains(at("05:00")));
+        assert_eq!(window.until_open(at("21:00")), Duration::from_secs(60 * 60));
+        assert_eq!(window.until_open(at("01:00")), Duration::ZERO);
+    }
+}
@@ -0,0 +1,250 @@
+use std::{fmt, path::Path};
+
+use eyre::{eyre, Context, Result};
+use git2::{Oid, Repository};
+use tracing::{debug, info};
+
+use crate::{
+    allowlist::Allowlist,
+    cache::RewriteScope,
+    cleaner::{Cleaner, CleanerOptions, RepoJob},
+    config::Config,
+    git::git_dir,
+    hooks::Hook,
+    options::GcMode,
+    progress::RepoProgress,
+    rewrite::{Callbacks, RewriteOutcome},
+    steps::{
+        BackupStep, CacheStep, CloneStep, DefaultBranchStep, EnforceStep, GcStep, HookStep, LeakScanStep, ManifestCheckStep, NotifyStep, PlanStep,
+        PreflightStep, PreviewStep, PushCheckStep, PushStep, RecordStep, ReleaseStep, ReleaseTagStep, RewriteStep, ScanStep, SignStep,
+    },
+    summary::RepoReport,
+};
+
+
+/// Whether a pipeline carries on after a step
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    /// Leave the rest of the steps out. The step has already recorded why in the report
+    Stop,
+}
+
+/// One stage of processing a repository, like cloning, rewriting, or pushing it
+pub trait Step: fmt::Debug + Send + Sync {
+    /// A short name for logs, like `clone`
+    fn name(&self) -> &'static str;
+
+    /// Process the repository in `cx`, passing anything later steps need along in it
+    fn run(&self, cleaner: &Cleaner, cx: &mut StepContext<'_>) -> Result<Flow>;
+
+    /// Whether the step changes the clone in a way that leaves it in an unknown state if it fails or
+    /// is interrupted partway. A marker is left in the clone while such a step runs, so later runs
+    /// know to roll it back.
+    fn changes_clone(&self) -> bool {
+        false
+    }
+}
+
+/// What the marker a step that changes the clone leaves in its git directory starts with, followed
+/// by the step's name
+const INCOMPLETE: &str = "repo_cleaner-incomplete-";
+
+/// The step an earlier run failed or was interrupted partway through, leaving the clone at
+/// `repo_dir` in an unknown state, if any
+pub(crate) fn incomplete_step(repo_dir: &Path) -> Option<String> {
+    std::fs::read_dir(git_dir(repo_dir)).ok()?
+        .filter_map(|entry| entry.ok())
+        .find_map(|entry| entry.file_name().to_str()?.strip_prefix(INCOMPLETE).map(str::to_string))
+}
+
+/// Everything the steps of a pipeline share about the repository they are processing
+pub struct StepContext<'a> {
+    pub job: &'a RepoJob,
+    pub report: &'a mut RepoReport,
+    pub progress: &'a RepoProgress,
+    /// The clone, once the clone step has opened it
+    pub repository: Option<Repository>,
+    /// Where HEAD pointed before rewriting
+    pub old_head: Option<String>,
+    /// The callbacks the rewrite uses, and the hash of everything it depends on
+    pub(crate) callbacks: Option<(Callbacks, String)>,
+    /// How much of the history has to be rewritten, and the commits that don't need it
+    pub scope: RewriteScope,
+    pub clean_tips: Vec<Oid>,
+    /// What the rewrite changed
+    pub outcome: Option<RewriteOutcome>,
+    /// The `git push` arguments the refs to push are found from, and previewed with
+    pub push_args: Vec<String>,
+}
+
+impl<'a> StepContext<'a> {
+    pub fn new(job: &'a RepoJob, report: &'a mut RepoReport, progress: &'a RepoProgress) -> Self {
+        Self {
+            job,
+            report,
+            progress,
+            repository: None,
+            old_head: None,
+            callbacks: None,
+            scope: RewriteScope::Full,
+            clean_tips: Vec::new(),
+            outcome: None,
+            push_args: Vec::new(),
+        }
+    }
+
+    /// The clone, which a step before this one has to have opened
+    pub fn repository(&self) -> Result<&Repository> {
+        self.repository.as_ref().ok_or_else(|| eyre!("{} has to be cloned before this step", self.job.repository))
+    }
+}
+
+/// The steps a repository goes through in a phase, run in order until one fails or stops it
+#[derive(Debug, Default)]
+pub struct Pipeline {
+    steps: Vec<Box<dyn Step>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `step` to the end
+    pub fn step(mut self, step: impl Step + 'static) -> Self {
+        self.steps.push(Box::new(step));
+        self
+    }
+
+    /// Add `step` to the end, if `condition` holds
+    pub fn step_if(self, condition: bool, step: impl Step + 'static) -> Self {
+        if condition { self.step(step) } else { self }
+    }
+
+    /// The names of the steps, in order
+    pub fn names(&self) -> Vec<&'static str> {
+        self.steps.iter().map(|s| s.name()).collect()
+    }
+
+    /// The steps that clone, back up, and rewrite a repository, leaving out whichever `config` and
+    /// `options` turn off
+    pub fn clean(config: &Config, options: &CleanerOptions) -> Self {
+        if options.skip_backup {
+            info!("Not backing up repositories, since --no-backup is set");
+        }
+        let hooks = |hook| has_hook(config, hook);
+
+        Self::new()
+            .step(PreflightStep)
+            .step(CloneStep { lfs: true })
+            .step(CacheStep)
+            .step_if(!options.rewrite_unmatched, ScanStep)
+            .step_if(!options.skip_backup, BackupStep)
+            .step_if(hooks(Hook::PreRewrite), HookStep(Hook::PreRewrite))
+            .step(RewriteStep)
+            .step_if(config.releases.is_some(), ReleaseTagStep)
+            .step_if(options.gc != GcMode::Off, GcStep)
+            .step_if(options.sign, SignStep)
+            .step_if(hooks(Hook::PostRewrite), HookStep(Hook::PostRewrite))
+            .step(RecordStep)
+            .step_if(options.scan_blobs.is_some(), LeakScanStep)
+    }
+
+    /// The steps that find what cleaning a repository would change, without changing anything
+    pub fn plan(options: &CleanerOptions) -> Self {
+        Self::new()
+            .step(CloneStep { lfs: false })
+            .step(PlanStep)
+            .step_if(options.scan_blobs.is_some(), LeakScanStep)
+    }
+
+    /// The steps that check a repository's history against `allowlist`
+    pub fn enforce(allowlist: Allowlist) -> Self {
+        Self::new()
+            .step(CloneStep { lfs: false })
+            .step(EnforceStep(allowlist))
+    }
+
+    /// The steps that check a cleaned repository and push it, or only preview the push
+    pub fn push(config: &Config, options: &CleanerOptions) -> Self {
+        let hooks = |hook| has_hook(config, hook);
+        let pipeline = Self::new()
+            .step(ManifestCheckStep)
+            // Pull requests may have been opened since the clean phase
+            .step(PreflightStep)
+            .step(PushCheckStep);
+        if options.preview_push {
+            return pipeline.step(PreviewStep);
+        }
+
+        pipeline
+            .step_if(hooks(Hook::PrePush), HookStep(Hook::PrePush))
+            .step(PushStep)
+            .step(DefaultBranchStep)
+            .step_if(config.releases.is_some(), ReleaseStep)
+            .step_if(hooks(Hook::PostPush), HookStep(Hook::PostPush))
+            .step(NotifyStep)
+    }
+
+    /// Run every step on `job` in order, recording what happened in `report` and showing each
+    /// phase on `progress`
+    pub fn run(&self, cleaner: &Cleaner, job: &RepoJob, report: &mut RepoReport, progress: &RepoProgress) -> Result<()> {
+        #[cfg(feature = "otlp")]
+        let telemetry = cleaner.telemetry();
+        #[cfg(feature = "otlp")]
+        let mut span = telemetry.map(|telemetry| telemetry.span(&job.repository, None));
+
+        let mut cx = StepContext::new(job, report, progress);
+        let mut result = Ok(());
+        for step in &self.steps {
+            debug!("Running the {} step on {}", step.name(), job.repository);
+            #[cfg(feature = "otlp")]
+            let step_span = telemetry.map(|telemetry| telemetry.span(step.name(), span.as_ref()));
+            let marker = step.changes_clone().then(|| git_dir(&job.dir).join(format!("{INCOMPLETE}{}", step.name())));
+            if let Some(marker) = &marker {
+                if let Err(e) = std::fs::write(marker, "") {
+                    result = Err(e).wrap_err(format!("Unable to mark {} as being changed by the {} step", job.repository, step.name()));
+                    break;
+                }
+            }
+            let flow = step.run(cleaner, &mut cx);
+            // A step that failed leaves its marker, since it may have stopped partway
+            if let (Some(marker), Ok(_)) = (&marker, &flow) {
+                let _ = std::fs::remove_file(marker);
+            }
+            #[cfg(feature = "otlp")]
+            if let Some(step_span) = step_span {
+                step_span.finish(flow.as_ref().err());
+            }
+
+            match flow {
+                Ok(Flow::Continue) => {},
+                Ok(Flow::Stop) => {
+                    debug!("The {} step stopped {}", step.name(), job.repository);
+                    break;
+                },
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                },
+            }
+        }
+
+        #[cfg(feature = "otlp")]
+        if let Some(mut span) = span.take() {
+            span.attribute("repo_cleaner.repository", &job.repository);
+            // A failure is only recorded in the report once it is returned
+            let status = if result.is_err() { "failed".to_string() } else { cx.report.status.to_string() };
+            span.attribute("repo_cleaner.status", &status);
+            span.count("repo_cleaner.commits_changed", cx.report.commits_changed as u64);
+            span.finish(result.as_ref().err());
+        }
+        result
+    }
+}
+
+/// Whether `hook` is configured for every repository, or for any one of them
+fn has_hook(config: &Config, hook: Hook) -> bool {
+    config.hooks.command(hook).is_some() || config.repositories.iter().any(|r| r.hooks.command(hook).is_some())
+}
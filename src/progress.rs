@@ -0,0 +1,147 @@
+use std::{fmt, io::{self, Write}, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use indicatif::{HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressStyle};
+
+
+/// A step in cleaning a single repository, in the order they happen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Clone,
+    Fetch,
+    Backup,
+    Rewrite,
+    Gc,
+    Sign,
+}
+
+impl Phase {
+    const ALL: [Phase; 6] = [Phase::Clone, Phase::Fetch, Phase::Backup, Phase::Rewrite, Phase::Gc, Phase::Sign];
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::Clone => write!(f, "cloning"),
+            Phase::Fetch => write!(f, "fetching"),
+            Phase::Backup => write!(f, "backing up"),
+            Phase::Rewrite => write!(f, "rewriting"),
+            Phase::Gc => write!(f, "collecting garbage"),
+            Phase::Sign => write!(f, "signing"),
+        }
+    }
+}
+
+/// Progress of a whole run: an overall bar counting repositories, above a bar for each repository being processed
+#[derive(Debug, Clone)]
+pub struct RunProgress {
+    multi: MultiProgress,
+    overall: ProgressBar,
+}
+
+impl RunProgress {
+    /// Add an overall bar for `total` repositories to `multi`
+    pub fn new(multi: &MultiProgress, total: usize, message: &str) -> Self {
+        let overall = multi.add(ProgressBar::new(total as u64)
+            .with_style(ProgressStyle::with_template("{msg} [{bar:30}] {pos}/{len} repositories ({elapsed})")
+                .unwrap()
+                .progress_chars("=> "))
+            .with_message(message.to_string()));
+        overall.enable_steady_tick(Duration::from_millis(250));
+
+        Self { multi: multi.clone(), overall }
+    }
+
+    /// Add a bar for a repository that is about to be processed
+    pub fn start(&self, repository: &str) -> RepoProgress {
+        let bar = self.multi.insert_after(&self.overall, ProgressBar::new(Phase::ALL.len() as u64)
+            .with_style(ProgressStyle::with_template("  {spinner} {prefix} [{bar:12}] {msg}")
+                .unwrap()
+                .progress_chars("=> "))
+            .with_prefix(repository.to_string()));
+        bar.enable_steady_tick(Duration::from_millis(100));
+
+        RepoProgress { bar, phase_started: Arc::new(Mutex::new(Instant::now())) }
+    }
+
+    /// Count a repository as done, removing its bar
+    pub fn finish(&self, repo: RepoProgress) {
+        repo.bar.finish_and_clear();
+        self.multi.remove(&repo.bar);
+        self.overall.inc(1);
+    }
+
+    /// Clear every bar, leaving the terminal to the summary
+    pub fn clear(&self) {
+        self.overall.finish_and_clear();
+    }
+}
+
+/// Progress of a single repository through the phases of cleaning it
+#[derive(Debug, Clone)]
+pub struct RepoProgress {
+    bar: ProgressBar,
+    /// When the current phase started, to work out transfer rates from
+    phase_started: Arc<Mutex<Instant>>,
+}
+
+impl RepoProgress {
+    /// Progress that is never shown
+    pub fn hidden() -> Self {
+        Self { bar: ProgressBar::hidden(), phase_started: Arc::new(Mutex::new(Instant::now())) }
+    }
+
+    pub fn phase(&self, phase: Phase) {
+        *self.phase_started.lock().expect("phase start is never poisoned") = Instant::now();
+        let position = Phase::ALL.iter().position(|p| *p == phase).unwrap_or_default();
+        self.bar.set_position(position as u64);
+        self.bar.set_message(phase.to_string());
+    }
+
+    /// What the bar currently says, for the dashboard
+    #[cfg(feature = "tui")]
+    pub(crate) fn status(&self) -> String {
+        self.bar.message()
+    }
+
+    /// Show how much of a clone or fetch has been received, how fast, and how long the rest should
+    /// take, then how many deltas have been resolved once every object has arrived
+    pub fn transfer(&self, phase: Phase, stats: &git2::Progress<'_>) {
+        let (received, total) = (stats.received_objects(), stats.total_objects());
+        if total > 0 && received == total && stats.total_deltas() > 0 {
+            self.bar.set_message(format!("{phase}, resolving deltas {}/{}", stats.indexed_deltas(), stats.total_deltas()));
+            return;
+        }
+
+        let elapsed = self.phase_started.lock().expect("phase start is never poisoned").elapsed();
+        let bytes = stats.received_bytes() as u64;
+        let mut message = format!("{phase} {received}/{total} objects, {}", HumanBytes(bytes));
+        if elapsed >= Duration::from_secs(1) {
+            message += &format!(" at {}/s", HumanBytes((bytes as f64 / elapsed.as_secs_f64()) as u64));
+        }
+        // Objects vary in size, but their count is all that is known up front
+        if received > 0 && received < total {
+            let left = elapsed.mul_f64((total - received) as f64 / received as f64);
+            message += &format!(", {} left", HumanDuration(left));
+        }
+        self.bar.set_message(message);
+    }
+
+    /// Show how much of a push has been sent
+    pub fn upload(&self, current: usize, total: usize, bytes: usize) {
+        self.bar.set_message(format!("pushing {current}/{total} objects, {}", HumanBytes(bytes as u64)));
+    }
+}
+
+/// Writes log lines above the progress bars instead of through them
+#[derive(Debug, Clone)]
+pub struct ProgressWriter(pub MultiProgress);
+
+impl Write for ProgressWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.suspend(|| io::stderr().write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
@@ -0,0 +1,66 @@
+use std::{fs::OpenOptions, path::{Path, PathBuf}, time::Duration};
+
+use eyre::{Context, Result};
+use git2::Repository;
+use tracing::debug;
+
+use crate::git::git_with;
+
+
+/// A bare repository holding the objects of every repository cloned with it, which new clones
+/// borrow objects from rather than downloading and storing their own copies. Forks and mirrors of
+/// the same history share almost all of theirs.
+///
+/// Objects are never pruned from it, since the clones borrowing them, and their backups, rely on
+/// them staying there.
+#[derive(Debug, Clone)]
+pub struct ReferenceCache {
+    dir: PathBuf,
+}
+
+impl ReferenceCache {
+    /// Use the cache in `dir`, which is created when the first repository is added to it
+    pub fn new(dir: &Path) -> Result<Self> {
+        // Clones record where they borrow from, so it has to be found from wherever they are
+        let dir = std::path::absolute(dir)
+            .wrap_err(format!("Unable to find reference cache {dir:?}"))?;
+        Ok(Self { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Fetch every branch and tag of `url` into the cache, under `refs/cache/<key>/`, creating
+    /// the cache first if this is the first repository added to it. Other threads and runs using
+    /// the same cache wait until this is done.
+    pub(crate) fn add(&self, key: &str, url: &str, timeout: Option<Duration>, env: &[(&str, String)]) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)
+            .wrap_err(format!("Unable to create reference cache {:?}", self.dir))?;
+        let lock_path = self.dir.join("cache.lock");
+        let lock = OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)
+            .wrap_err(format!("Unable to open {lock_path:?}"))?;
+        lock.lock()
+            .wrap_err(format!("Unable to lock reference cache {:?}", self.dir))?;
+
+        if Repository::open_bare(&self.dir).is_err() {
+            let repository = Repository::init_bare(&self.dir)
+                .wrap_err(format!("Unable to create reference cache {:?}", self.dir))?;
+            let mut config = repository.config()?;
+            config.set_i32("gc.auto", 0)?;
+            config.set_str("gc.pruneExpire", "never")?;
+        }
+
+        debug!("Adding {url} to the reference cache");
+        let heads = format!("+refs/heads/*:refs/cache/{key}/heads/*");
+        let tags = format!("+refs/tags/*:refs/cache/{key}/tags/*");
+        // Run from here, so local paths resolve the same as they do for clones
+        let git_dir = self.dir.to_string_lossy();
+        git_with(Path::new("."), &["--git-dir", &git_dir, "fetch", "--quiet", "--no-tags", url, &heads, &tags], timeout, env)
+            .wrap_err(format!("Unable to add {url} to the reference cache"))?;
+
+        // Closing the file releases the lock
+        drop(lock);
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::BTreeMap;
+
+use eyre::Result;
+use git2::Repository;
+use tracing::debug;
+
+use crate::git::{default_branch, tracked_name};
+
+
+/// The refspec that force-fetches every tag, so tags an earlier run rewrote go back to the remote's
+pub(crate) const TAGS_REFSPEC: &str = "+refs/tags/*:refs/tags/*";
+
+/// Point every local branch of `repository` at its remote-tracking branch, creating any that are
+/// missing and deleting any the remote doesn't have. HEAD moves to the default branch if the
+/// remote dropped the one checked out. Returns the branch checked out, whose working tree is left
+/// for the caller to reset, since only git can fill in the blobs a partial clone is missing.
+pub(crate) fn reset(repository: &Repository) -> Result<Option<String>> {
+    let mut remote = BTreeMap::new();
+    for reference in repository.references_glob("refs/remotes/*")? {
+        let reference = reference?;
+        let (Some(name), Some(target)) = (reference.name(), reference.target()) else {
+            continue;
+        };
+        if let Some(branch) = tracked_name(repository, name) {
+            remote.insert(branch, (name.to_string(), target));
+        }
+    }
+
+    let head = repository.head().ok()
+        .filter(|head| head.is_branch())
+        .and_then(|head| head.shorthand().map(str::to_string));
+    let checkout = head.filter(|name| remote.contains_key(name))
+        .or_else(|| default_branch(repository).filter(|name| remote.contains_key(name)));
+
+    for (name, (upstream, target)) in &remote {
+        let reference = format!("refs/heads/{name}");
+        repository.reference(&reference, *target, true, &format!("reset to {upstream}"))?;
+        repository.find_branch(name, git2::BranchType::Local)?
+            .set_upstream(Some(upstream.strip_prefix("refs/remotes/").unwrap_or(upstream)))?;
+    }
+    if let Some(name) = &checkout {
+        repository.set_head(&format!("refs/heads/{name}"))?;
+    }
+
+    for branch in repository.branches(Some(git2::BranchType::Local))? {
+        let (mut branch, _) = branch?;
+        let Some(name) = branch.name()?.map(str::to_string) else {
+            continue;
+        };
+        if !remote.contains_key(&name) && checkout.is_some() {
+            branch.delete()?;
+            debug!("Deleted {name}, which the remote doesn't have");
+        }
+    }
+
+    Ok(checkout)
+}
@@ -0,0 +1,84 @@
+use std::collections::{HashMap, HashSet};
+
+use eyre::{eyre, Context, Result};
+use git2::{ObjectType, Oid, Repository};
+use serde::Serialize;
+use tracing::{debug, info};
+
+use crate::{config::Releases, sign};
+
+
+/// Where signatures start in a tag's message, which is where git keeps them
+const SIGNATURE_MARKERS: [&str; 2] = ["-----BEGIN PGP SIGNATURE-----", "-----BEGIN SSH SIGNATURE-----"];
+
+/// A release tag that now points at the commit its old commit was rewritten to
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct MovedTag {
+    pub name: String,
+    pub old_commit: String,
+    pub new_commit: String,
+    /// Whether the tag was re-signed
+    pub signed: bool,
+}
+
+/// Point every release tag of `repository` at the commit its commit was rewritten to, as
+/// `commit_map` says. filter-repo moves the tags it rewrites itself, but not those outside the
+/// branches or dates it was limited to, and it drops their signatures. Annotated tags keep their
+/// tagger and message, and are re-signed if `releases` says so.
+pub fn fixup(repository: &Repository, releases: &Releases, commit_map: &HashMap<String, String>) -> Result<Vec<MovedTag>> {
+    let rewritten = commit_map.values().collect::<HashSet<_>>();
+
+    let mut moved = Vec::new();
+    for name in repository.tag_names(None)?.iter().flatten().filter(|name| releases.matches(name)) {
+        let reference = repository.find_reference(&format!("refs/tags/{name}"))?;
+        let commit = reference.peel_to_commit()
+            .wrap_err(format!("Release tag {name} doesn't point to a commit"))?
+            .id().to_string();
+
+        // A tag filter-repo already moved points at a rewritten commit, but has lost its signature
+        let (old_commit, new_commit) = match commit_map.get(&commit) {
+            Some(new) => (commit.clone(), new.clone()),
+            None if rewritten.contains(&commit) => {
+                let old = commit_map.iter().find(|(_, new)| **new == commit).map(|(old, _)| old.clone()).unwrap_or_default();
+                (old, commit)
+            },
+            None => {
+                debug!("Leaving release tag {name} where it is, since {commit} wasn't rewritten");
+                continue;
+            },
+        };
+        let target = Oid::from_str(&new_commit)?;
+
+        let mut signed = false;
+        let tag = reference.target().and_then(|oid| repository.find_tag(oid).ok());
+        let new = match (tag, reference.target()) {
+            (Some(tag), _) => {
+                let message = tag.message().unwrap_or_default();
+                let signature = SIGNATURE_MARKERS.iter().filter_map(|marker| message.find(marker)).min();
+                if tag.target_id() == target && (!releases.sign || signature.is_some()) {
+                    continue;
+                }
+                let message = signature.map_or(message, |start| &message[..start]);
+                let tagger = tag.tagger()
+                    .ok_or_else(|| eyre!("Release tag {name} has no tagger"))?;
+                if releases.sign {
+                    signed = true;
+                    sign::sign_tag(repository, name, target, &tagger, message)?
+                } else {
+                    repository.tag_annotation_create(name, &repository.find_object(target, Some(ObjectType::Commit))?, &tagger, message)?
+                }
+            },
+            (None, Some(current)) if current == target => continue,
+            (None, _) => target,
+        };
+        repository.reference(&format!("refs/tags/{name}"), new, true, "repo_cleaner: move release tag onto the rewritten commit")?;
+        info!("Moved release tag {name} from {old_commit} to {new_commit}{}", if signed { ", re-signed" } else { "" });
+        moved.push(MovedTag { name: name.to_string(), old_commit, new_commit, signed });
+    }
+    Ok(moved)
+}
+
+/// Whether a release's target is a full commit id, rather than a branch that moves with the rewrite
+pub fn targets_commit(target: &str) -> bool {
+    target.len() == 40 && target.bytes().all(|b| b.is_ascii_hexdigit())
+}
@@ -0,0 +1,84 @@
+use std::{thread::sleep, time::Duration};
+
+use eyre::Result;
+use git2::{ErrorClass, ErrorCode};
+use tracing::warn;
+
+use crate::git::GitCommandError;
+
+
+/// How often, and how patiently, to retry network operations
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt
+    pub retries: u32,
+    /// Delay before the first retry. Doubled after every attempt
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Run `op`, retrying with exponential backoff for as long as it fails with a retryable error
+    pub fn run<T>(&self, what: &str, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut attempt = 0;
+        loop {
+            match op() {
+                Ok(v) => return Ok(v),
+                Err(e) if attempt < self.retries && is_retryable(&e) => {
+                    let delay = self.delay.saturating_mul(2u32.saturating_pow(attempt));
+                    attempt += 1;
+                    warn!("{what} failed (attempt {attempt} of {}), retrying in {delay:?}: {e}", self.retries + 1);
+                    sleep(delay);
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// How long each external command may run before it is killed and its repository fails. Commands
+/// without a timeout may run forever.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timeouts {
+    pub clone: Option<Duration>,
+    /// Fetching a repository that was already cloned
+    pub pull: Option<Duration>,
+    pub filter_repo: Option<Duration>,
+    /// Every push (and `git lfs push`) of a repository, separately
+    pub push: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Have libgit2 drop connections that send nothing for as long as a clone, fetch, or push may
+    /// take, which it can't otherwise be interrupted during. Call before any thread clones anything.
+    pub fn install(&self) {
+        if let Some(timeout) = [self.clone, self.pull, self.push].into_iter().flatten().max() {
+            let millis = timeout.as_millis().try_into().unwrap_or(i32::MAX);
+            // SAFETY: libgit2's options are process wide, and nothing is cloned yet to read them
+            if let Err(e) = unsafe { git2::opts::set_server_timeout_in_milliseconds(millis) } {
+                warn!("Unable to set libgit2's server timeout: {e}");
+            }
+        }
+    }
+}
+
+/// Whether an error is a transient network failure, as opposed to a permanent one like an authentication failure
+pub fn is_retryable(e: &eyre::Report) -> bool {
+    if let Some(e) = e.downcast_ref::<git2::Error>() {
+        return is_retryable_git2(e);
+    }
+    if let Some(e) = e.downcast_ref::<GitCommandError>() {
+        return e.is_retryable();
+    }
+    #[cfg(feature = "gix")]
+    if let Some(e) = e.downcast_ref::<crate::backend::GixError>() {
+        return e.is_retryable();
+    }
+    false
+}
+
+fn is_retryable_git2(e: &git2::Error) -> bool {
+    if matches!(e.code(), ErrorCode::Auth | ErrorCode::Certificate | ErrorCode::Exists | ErrorCode::NotFound) {
+        return false;
+    }
+    matches!(e.class(), ErrorClass::Net | ErrorClass::Ssh | ErrorClass::Http | ErrorClass::Os)
+}
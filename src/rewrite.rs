@@ -0,0 +1,627 @@
+use std::{collections::{BTreeMap, HashMap}, path::{Path, PathBuf}, time::Duration};
+
+use eyre::{bail, Context, Result};
+use git2::{BranchType, Oid, Repository};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::{debug, info, warn};
+
+use crate::{attribution::{self, IdentityStats, MergeReport}, config::{AnonymizeDates, Config, DateMode, Field, IdentityMerge, IdentityRule, PruneEmpty, RepositoryConfig, Republish}, git::{self, git, git_dir, notes_refs, GitCommandError, order_branches, output_within, tracked_name, QUARANTINE}, signatures::{self, InvalidatedSignature}, tags::{self, TagReport}};
+
+
+/// The script every generated callback loads its functions from
+const SCRIPT: &str = include_str!("callbacks.py");
+
+/// Loads the script into a global the first time a callback runs, since filter-repo runs the
+/// callback body for every commit. Everything it needs comes from environment variables.
+const LOAD_SCRIPT: &str = r#"global repo_cleaner
+try:
+    repo_cleaner
+except NameError:
+    import os
+    repo_cleaner = {}
+    with open(os.environ['REPO_CLEANER_SCRIPT'], encoding='utf-8') as f:
+        exec(compile(f.read(), f.name, 'exec'), repo_cleaner)"#;
+
+/// Callbacks handed to git-filter-repo.
+///
+/// The callbacks themselves are fixed Python. Every value from the config is written to a JSON rules
+/// file that the script reads, so no email, name, or salt is ever spliced into Python source.
+#[derive(Debug, Clone)]
+pub struct Callbacks {
+    rules: Rules,
+    /// Renames authors and committers, fuzzes their dates if configured, and rewrites submodule pointers
+    pub commit: String,
+    /// Renames taggers, and fuzzes their dates if configured
+    pub tag: String,
+    /// The user's own callbacks that don't need combining with the generated ones, as flag and body
+    pub extra: Vec<(&'static str, String)>,
+    /// filter-repo options from the config, as flag and value
+    pub options: Vec<(&'static str, &'static str)>,
+    /// The id the script counts each rule's matches under, and the rule as it is written in the config
+    rule_names: Vec<(String, String)>,
+    /// Whether rewriting may change commits and tags no rule matches
+    changes_unmatched: bool,
+}
+
+/// Everything the callback script needs to know, as read by `callbacks.py`
+#[derive(Serialize, Debug, Clone)]
+struct Rules {
+    /// Old emails to new emails
+    emails: HashMap<String, String>,
+    /// Old emails to the name that should replace their owner's
+    authors: HashMap<String, String>,
+    /// Name regexes and the name that should replace any match, in the order they are tried
+    names: Vec<(String, String)>,
+    /// Renames for identities matching both a name and an email regex, in order
+    identities: Vec<IdentityRule>,
+    /// Old emails, keyed like `emails`, and names to fold into a canonical identity each, in order
+    merges: Vec<IdentityMerge>,
+    /// The identity that replaces every other
+    republish: Option<Republish>,
+    /// Lowercased GitHub usernames to the username or email their noreply addresses are replaced with
+    noreply: HashMap<String, String>,
+    /// The fields every rule may change, unless it says otherwise
+    fields: Vec<Field>,
+    /// Old emails to the fields their substitution may change, where it differs from `fields`
+    email_fields: HashMap<String, Vec<Field>>,
+    /// Name regexes to the fields they may change, where it differs from `fields`
+    name_fields: HashMap<String, Vec<Field>>,
+    preserved: Vec<String>,
+    case_insensitive_emails: bool,
+    since: Option<i64>,
+    until: Option<i64>,
+    anonymize_salt: Option<String>,
+    anonymize_dates: Option<DateRules>,
+    /// File of `old new` commit pairs that submodule pointers are rewritten with
+    gitlinks: Option<PathBuf>,
+    /// Scripts whose `rewrite(commit)` is called with every commit's metadata
+    plugins: Vec<PathBuf>,
+    strip_message_signatures: bool,
+    /// Trailers added to every rewritten commit's message, before their variables are filled in
+    trailers: Vec<String>,
+    run_id: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct DateRules {
+    window: u64,
+    randomize: bool,
+    /// The secret randomized dates are keyed with
+    salt: Option<String>,
+}
+
+impl Callbacks {
+    /// `anonymize` is the salt to derive pseudonyms from, if matched identities should be replaced by
+    /// pseudonyms rather than by their configured substitutions. `run_id` is filled into trailers.
+    /// Only commits and tags dated within `repo`'s date range are touched. `gitlinks` is a file of
+    /// `old new` commit pairs that submodule pointers are rewritten with.
+    pub fn new(config: &Config, anonymize: Option<&str>, run_id: &str, repo: &RepositoryConfig, gitlinks: Option<&Path>) -> Result<Self> {
+        // With case insensitive matching, the script lowercases the emails it looks up, so the keys must be too
+        let key = |email: &str| if config.case_insensitive_emails { email.to_lowercase() } else { email.to_string() };
+
+        let (since, until) = repo.date_range()?;
+        let rules = Rules {
+            emails: config.email_substitutions.iter()
+                .map(|(old, new)| (key(old), new.new_email().to_string()))
+                .collect(),
+            authors: config.email_substitutions.iter()
+                .filter_map(|(old, new)| new.new_author().map(|name| (key(old), name.to_string())))
+                .collect(),
+            names: config.ordered_name_substitutions().into_iter()
+                .map(|(pattern, new)| (pattern.clone(), new.new_name().to_string()))
+                .collect(),
+            identities: config.identity_substitutions.clone(),
+            merges: config.merge_identities.iter()
+                .map(|merge| IdentityMerge { emails: merge.emails.iter().map(|e| key(e)).collect(), ..merge.clone() })
+                .collect(),
+            republish: config.republish.clone(),
+            noreply: config.github_noreply_substitutions.iter()
+                .map(|(old, new)| (old.to_lowercase(), new.clone()))
+                .collect(),
+            fields: Field::of(&config.apply_to),
+            email_fields: config.email_substitutions.iter()
+                .filter_map(|(old, new)| new.fields().map(|fields| (key(old), fields)))
+                .collect(),
+            name_fields: config.name_substitutions.iter()
+                .filter_map(|(pattern, new)| new.fields().map(|fields| (pattern.clone(), fields.to_vec())))
+                .collect(),
+            preserved: config.preserve_emails.iter().map(|e| key(e)).collect(),
+            case_insensitive_emails: config.case_insensitive_emails,
+            since,
+            until,
+            anonymize_salt: anonymize.map(str::to_string),
+            anonymize_dates: config.anonymize_dates.as_ref().map(date_rules).transpose()?,
+            gitlinks: gitlinks.map(Path::to_path_buf),
+            // filter-repo runs inside the repository, so relative paths would no longer resolve
+            plugins: config.plugins.iter().map(std::path::absolute).collect::<Result<_, _>>()?,
+            strip_message_signatures: config.strip_message_signatures,
+            trailers: config.trailers.clone(),
+            run_id: run_id.to_string(),
+        };
+
+        let user = &config.callbacks;
+
+        let commit = format!("{LOAD_SCRIPT}\nrepo_cleaner['rewrite_commit'](commit)\n{}", user.commit_callback.as_deref().unwrap_or_default());
+        let tag = format!("{LOAD_SCRIPT}\nrepo_cleaner['rewrite_tag'](tag)\n{}", user.tag_callback.as_deref().unwrap_or_default());
+
+        let extra = user.passthrough().into_iter()
+            .map(|(flag, body)| (flag, body.to_string()))
+            .collect();
+
+        let options = [
+            config.prune_empty.map(|p| ("--prune-empty", p.as_arg())),
+            config.replace_refs.map(|r| ("--replace-refs", r.as_arg())),
+        ].into_iter().flatten().collect();
+
+        let rule_names = config.republish.iter()
+            .map(|republish| ("republish".to_string(), format!("republish ({})", republish.identity())))
+            .chain(config.merge_identities.iter().enumerate()
+                .map(|(i, merge)| (format!("merge:{i}"), format!("merge_identities.{i} ({})", merge.identity()))))
+            .chain(config.email_substitutions.keys()
+                .map(|old| (format!("email:{}", key(old)), format!("email_substitutions.{old}"))))
+            .chain(config.github_noreply_substitutions.keys()
+                .map(|user| (format!("noreply:{}", user.to_lowercase()), format!("github_noreply_substitutions.{user}"))))
+            .chain(config.identity_substitutions.iter().enumerate()
+                .map(|(i, rule)| (format!("identity:{i}"), format!("identity_substitutions.{i} ({} <{}>)", rule.name, rule.email))))
+            .chain(config.ordered_name_substitutions().into_iter()
+                .map(|(pattern, _)| (format!("name:{pattern}"), format!("name_substitutions.{pattern}"))))
+            .chain(rules.plugins.iter()
+                .zip(&config.plugins)
+                .map(|(path, listed)| (format!("plugin:{}", path.display()), format!("plugins.{}", listed.display()))))
+            .chain(config.strip_message_signatures
+                .then(|| ("signature".to_string(), "strip_message_signatures".to_string())))
+            .chain((!config.trailers.is_empty())
+                .then(|| ("trailers".to_string(), "trailers".to_string())))
+            .collect();
+
+        // Submodule pointers, the user's own callbacks, and pruning every empty commit all reach
+        // past what the rules match
+        let changes_unmatched = gitlinks.is_some()
+            || !user.is_empty()
+            || config.prune_empty == Some(PruneEmpty::Always);
+
+        Ok(Self { rules, commit, tag, extra, options, rule_names, changes_unmatched })
+    }
+
+    /// Write the rules and the script into `dir`, returning the environment variables that point filter-repo's callbacks at them
+    fn write(&self, dir: &Path) -> Result<[(&'static str, PathBuf); 3]> {
+        let rules = dir.join("repo-cleaner-rules.json");
+        std::fs::write(&rules, serde_json::to_string(&self.rules)?)
+            .wrap_err(format!("Unable to write rewrite rules to {rules:?}"))?;
+
+        let script = dir.join("repo-cleaner-callbacks.py");
+        std::fs::write(&script, SCRIPT)
+            .wrap_err(format!("Unable to write callback script to {script:?}"))?;
+
+        // filter-repo runs inside the repository, so relative paths would no longer resolve
+        Ok([
+            ("REPO_CLEANER_RULES", std::path::absolute(rules)?),
+            ("REPO_CLEANER_SCRIPT", std::path::absolute(script)?),
+            ("REPO_CLEANER_STATS", std::path::absolute(dir.join("repo-cleaner-stats.json"))?),
+        ])
+    }
+
+    /// Whether rewriting may change commits and tags no rule matches, so that a repository nothing
+    /// matched in could still change
+    pub fn changes_unmatched(&self) -> bool {
+        self.changes_unmatched
+    }
+
+    /// How many commits each configured rule matched, and how many tags any rule matched, as
+    /// counted by the script in `path`. Rules that matched nothing are included, so they stand out.
+    fn read_matches(&self, path: &Path) -> Result<(BTreeMap<String, usize>, usize)> {
+        // The script only writes counts once it has seen a commit
+        let counts: HashMap<String, usize> = match std::fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text)
+                .wrap_err(format!("Unable to read rule matches from {path:?}"))?,
+            Err(_) => HashMap::new(),
+        };
+
+        let rule_matches = self.rule_names.iter()
+            .map(|(id, name)| (name.clone(), counts.get(id).copied().unwrap_or_default()))
+            .collect();
+        Ok((rule_matches, counts.get("tags").copied().unwrap_or_default()))
+    }
+
+    /// A hash of everything that decides what rewriting `repo` with these callbacks produces, so a
+    /// later run can tell whether an earlier rewrite is still what it would produce
+    pub fn fingerprint(&self, repo: &RepositoryConfig) -> Result<String> {
+        // Going through a Value sorts every map, so the same rules always hash the same
+        let mut rules = serde_json::to_value(&self.rules)?;
+        // What the submodules were rewritten to matters, not where that was written
+        rules["gitlinks"] = match &self.rules.gitlinks {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .wrap_err(format!("Unable to read submodule commit map {path:?}"))?;
+                let mut lines = text.lines().collect::<Vec<_>>();
+                lines.sort_unstable();
+                json!(lines)
+            },
+            None => Value::Null,
+        };
+        // So do the plugins' contents, wherever they are
+        rules["plugins"] = self.rules.plugins.iter()
+            .map(|path| std::fs::read_to_string(path).wrap_err(format!("Unable to read plugin {path:?}")))
+            .collect::<Result<Vec<_>>>()?
+            .into();
+        // Which run rewrote a commit ends up in its trailers, but shouldn't make the next run redo it
+        rules["run_id"] = Value::Null;
+
+        let inputs = json!({
+            "rules": rules,
+            "script": SCRIPT,
+            "commit": self.commit,
+            "tag": self.tag,
+            "extra": self.extra,
+            "options": self.options,
+            "start_commit": repo.start_commit,
+            "branches": repo.branches,
+        });
+        let digest = ring::digest::digest(&ring::digest::SHA256, serde_json::to_string(&inputs)?.as_bytes());
+        Ok(digest.as_ref().iter().map(|b| format!("{b:02x}")).collect())
+    }
+
+    /// Arguments for the filter-repo pass that rewrites names and emails
+    fn args(&self) -> Vec<&str> {
+        let mut args = vec!["--commit-callback", &self.commit, "--tag-callback", &self.tag];
+        for (flag, body) in &self.extra {
+            args.extend([*flag, body.as_str()]);
+        }
+        for (flag, value) in &self.options {
+            args.extend([*flag, *value]);
+        }
+        args
+    }
+}
+
+/// What rewriting a repository's history changed
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct RewriteOutcome {
+    /// Number of branches that were rewritten
+    pub branches: usize,
+    /// Number of commits that were given a new id
+    pub commits_changed: usize,
+    /// Signed commits that were given a new id, so their signatures no longer verify
+    pub invalidated_signatures: Vec<InvalidatedSignature>,
+    /// How many commits each configured rule matched
+    pub rule_matches: BTreeMap<String, usize>,
+    /// Every selected branch, and the commit it was at before rewriting
+    pub original_branches: BTreeMap<String, String>,
+    /// How many commits each author has on the selected branches before and after rewriting
+    pub attribution: Vec<IdentityStats>,
+    /// Every tag, and who tagged the annotated ones before and after rewriting
+    pub tags: Vec<TagReport>,
+    /// How many commits each `merge_identities` entry folded into its canonical identity
+    pub merges: Vec<MergeReport>,
+}
+
+/// What rewriting a repository would change, found by a dry run
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Plan {
+    /// Number of branches that would be rewritten
+    pub branches: usize,
+    /// How many commits each configured rule would match
+    pub rule_matches: BTreeMap<String, usize>,
+    /// How many tags any rule would match
+    pub tags: usize,
+}
+
+impl Plan {
+    /// Whether no rule would match any commit or tag. "trailers" are added to every commit, so they
+    /// alone never make a repository worth rewriting
+    pub fn matches_nothing(&self) -> bool {
+        self.tags == 0 && self.rule_matches.iter().all(|(rule, &n)| n == 0 || rule == "trailers")
+    }
+}
+
+/// The date rules for `dates`, refusing to randomize without a salt to key the offsets with
+fn date_rules(dates: &AnonymizeDates) -> Result<DateRules> {
+    let randomize = dates.mode == DateMode::Randomize;
+    if randomize && dates.salt.as_deref().is_none_or(str::is_empty) {
+        bail!("\"anonymize_dates\" needs a \"salt\" to randomize dates with");
+    }
+    Ok(DateRules { window: dates.granularity.seconds(), randomize, salt: dates.salt.clone() })
+}
+
+/// Rewrite every branch of `repository` that `repo` selects, in a single git-filter-repo run.
+/// With a start commit, only that commit and its descendants are rewritten, and commits reachable
+/// from `clean_tips`, which an earlier run already rewrote with the same callbacks, are left alone.
+///
+/// Remote branches without a local branch get one first, so every selected branch is rewritten
+/// and pushed. The whole repository should already be backed up. git-filter-repo is killed if it
+/// runs for longer than `timeout`.
+pub fn rewrite(repository: &Repository, repo_dir: &Path, callbacks: &Callbacks, repo: &RepositoryConfig, clean_tips: &[Oid], timeout: Option<Duration>) -> Result<RewriteOutcome> {
+    // Remember where every ref pointed before rewriting, so we can count the changed commits afterwards
+    let old_tips = repository.references()?
+        .filter_map(|r| r.ok())
+        .filter_map(|r| r.target())
+        .collect::<Vec<_>>();
+    let signed = signatures::signed_commits(repository)?;
+    let original_branches: BTreeMap<String, String> = local_branches(repository, repo)?.into_iter()
+        .filter_map(|name| {
+            let tip = repository.find_branch(&name, BranchType::Local).ok()?.get().target()?;
+            Some((name, tip.to_string()))
+        })
+        .collect();
+    let tips = original_branches.values().filter_map(|tip| Oid::from_str(tip).ok()).collect::<Vec<_>>();
+    let authors = attribution::authors(repository, &tips)?;
+    let tags = tags::inventory(repository)?;
+
+    let (branches, rule_matches, _) = filter_repo(repository, repo_dir, callbacks, repo, clean_tips, false, timeout)?;
+
+    // Bring the working tree in line with the rewritten checked out branch
+    if !repository.is_bare() {
+        git(repo_dir, &["reset", "--hard", "--quiet"])?;
+    }
+
+    let commit_map = read_commit_map(repo_dir)?;
+    remap_notes(repo_dir, &commit_map)?;
+    if clean_tips.is_empty() {
+        write_commit_map(repo_dir, &commit_map)?;
+    } else {
+        // Only the new commits were rewritten, so the earlier run's map still covers the rest
+        let mut full_map = read_saved_commit_map(repo_dir);
+        full_map.extend(commit_map.clone());
+        write_commit_map(repo_dir, &full_map)?;
+    }
+
+    let attribution = attribution::compare(repository, &authors, &commit_map)?;
+    let canonical = callbacks.rules.merges.iter().map(IdentityMerge::identity).collect::<Vec<_>>();
+    Ok(RewriteOutcome {
+        branches: branches.len(),
+        commits_changed: count_new_commits(repo_dir, &old_tips)?,
+        invalidated_signatures: signatures::invalidated(&signed, &commit_map),
+        rule_matches,
+        original_branches,
+        merges: attribution::merges(&attribution, &canonical),
+        attribution,
+        tags: tags::compare(repository, tags)?,
+    })
+}
+
+/// Keep the tips of every branch `repo` selects, and of every tag, under
+/// `refs/pre-clean/<today>/`, so the history from before rewriting stays around for diffing and
+/// restoring. Refs already kept today are left alone, so they hold what there was before the day's
+/// first rewrite. Returns the prefix they were kept under.
+pub(crate) fn quarantine(repository: &Repository, repo: &RepositoryConfig) -> Result<String> {
+    let prefix = format!("{QUARANTINE}{}/", chrono::Utc::now().format("%Y-%m-%d"));
+    let mut kept = local_branches(repository, repo)?.into_iter()
+        .map(|branch| (format!("refs/heads/{branch}"), format!("{prefix}heads/{branch}")))
+        .collect::<Vec<_>>();
+    kept.extend(repository.tag_names(None)?.iter().flatten()
+        .map(|tag| (format!("refs/tags/{tag}"), format!("{prefix}tags/{tag}"))));
+
+    for (name, quarantined) in kept {
+        let Some(target) = repository.find_reference(&name)?.target() else {
+            continue;
+        };
+        if repository.find_reference(&quarantined).is_err() {
+            repository.reference(&quarantined, target, false, &format!("keep {name} from before rewriting"))?;
+        }
+    }
+    Ok(prefix)
+}
+
+/// Find what [`rewrite`] would change, leaving out the commits reachable from `clean_tips`, by
+/// running git-filter-repo without letting it touch the repository.
+/// git-filter-repo is killed if it runs for longer than `timeout`.
+pub fn plan(repository: &Repository, repo_dir: &Path, callbacks: &Callbacks, repo: &RepositoryConfig, clean_tips: &[Oid], timeout: Option<Duration>) -> Result<Plan> {
+    let (branches, rule_matches, tags) = filter_repo(repository, repo_dir, callbacks, repo, clean_tips, true, timeout)?;
+    Ok(Plan { branches: branches.len(), rule_matches, tags })
+}
+
+/// Run git-filter-repo over every branch `repo` selects, except for the commits reachable from
+/// `clean_tips`, returning those branches, how many commits each rule matched, and how many tags
+/// any rule matched
+fn filter_repo(repository: &Repository, repo_dir: &Path, callbacks: &Callbacks, repo: &RepositoryConfig, clean_tips: &[Oid], dry_run: bool, timeout: Option<Duration>) -> Result<(Vec<String>, BTreeMap<String, usize>, usize)> {
+    // Resolve the start commit up front, so a typo fails the repository instead of silently rewriting nothing
+    let mut exclude = match &repo.start_commit {
+        Some(start) => {
+            let commit = repository.revparse_single(start)
+                .and_then(|o| o.peel_to_commit())
+                .wrap_err(format!("Start commit {start} not found"))?;
+            // Excluding the start commit's parents (rather than `start^`) also works for root commits
+            vec![format!("^{}^@", commit.id())]
+        },
+        None => Vec::new(),
+    };
+    exclude.extend(clean_tips.iter().map(|tip| format!("^{tip}")));
+
+    let branches = local_branches(repository, repo)?;
+    if dry_run {
+        info!("Planning the rewrite of branches {}", branches.join(", "));
+    } else {
+        info!("Cleaning authors and emails from branches {}", branches.join(", "));
+    }
+
+    // The rules may hold the anonymization salt, so they only exist while filter-repo runs,
+    // and never end up in a backup
+    let env = callbacks.write(&git_dir(repo_dir))?;
+
+    let mut filter = git::command("git");
+    filter.args(["filter-repo", "--force", "--partial", "--sdr"])
+        .args(callbacks.args())
+        .envs(env.iter().map(|(k, v)| (k, v)));
+    // Without a start commit or a branch selection, every ref is rewritten, apart from the history
+    // kept from before earlier rewrites
+    let quarantined = repository.references_glob(&format!("{QUARANTINE}*"))?.next().is_some();
+    if !exclude.is_empty() || !repo.branches.is_empty() {
+        filter.arg("--refs")
+            .args(branches.iter().map(|b| format!("refs/heads/{b}")))
+            // Notes commits are only reachable from their own refs, and carry identities too
+            .args(notes_refs(repository)?);
+        // Tags would otherwise be rewritten too, so those on commits added since the last run have to be listed
+        if !clean_tips.is_empty() && repo.branches.is_empty() {
+            filter.args(repository.tag_names(None)?.iter().flatten().map(|t| format!("refs/tags/{t}")));
+        }
+        filter.args(&exclude);
+    } else if quarantined {
+        filter.args(["--refs", &format!("--exclude={QUARANTINE}*"), "--all"]);
+    }
+    if dry_run {
+        filter.arg("--dry-run");
+    }
+    debug!("Running git-filter-repo in {repo_dir:?}");
+    let ran = output_within(filter.current_dir(repo_dir), timeout);
+
+    let matches = callbacks.read_matches(&env[2].1);
+    for (_, path) in env {
+        let _ = std::fs::remove_file(path);
+    }
+    let output = ran?;
+    // The callbacks are whole scripts, so only the subcommand is named
+    if !output.status.success() {
+        return Err(GitCommandError {
+            args: "filter-repo".to_string(),
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        }.into());
+    }
+
+    let (rule_matches, tags) = matches?;
+    Ok((branches, rule_matches, tags))
+}
+
+/// The local branches `repo` selects, the remote's default branch first. Every selected remote
+/// branch gets a local branch if it doesn't have one yet, and one that's only behind it is
+/// fast-forwarded, so the rewrite covers what the remote has rather than a stale copy.
+pub(crate) fn local_branches(repository: &Repository, repo: &RepositoryConfig) -> Result<Vec<String>> {
+    let head = repository.head().ok().filter(|h| h.is_branch()).and_then(|h| h.shorthand().map(str::to_string));
+    for branch in repository.branches(Some(BranchType::Remote))?.filter_map(|b| b.ok()) {
+        let reference = branch.0.get();
+        let (Some(name), Some(target)) = (reference.name(), reference.target()) else {
+            continue;
+        };
+        // A remote's `HEAD` is only a pointer to its default branch
+        let Some(local) = tracked_name(repository, name) else {
+            continue;
+        };
+        if !repo.selects_branch(&local) {
+            continue;
+        }
+
+        let Ok(existing) = repository.find_branch(&local, BranchType::Local) else {
+            let mut created = repository.branch(&local, &repository.find_commit(target)?, false)?;
+            created.set_upstream(reference.shorthand())?;
+            continue;
+        };
+        // The checked out branch was fast-forwarded when fetching
+        let Some(current) = existing.get().target().filter(|_| head.as_deref() != Some(local.as_str())) else {
+            continue;
+        };
+        if current == target || repository.graph_descendant_of(current, target)? {
+            continue;
+        }
+        if repository.graph_descendant_of(target, current)? {
+            existing.into_reference().set_target(target, &format!("fast-forward to {name}"))?;
+            debug!("Fast-forwarded {local} to {name}");
+        } else {
+            warn!("{local} has diverged from {name}, so the local branch is rewritten as it is");
+        }
+    }
+
+    let mut branches = repository.branches(Some(BranchType::Local))?
+        .filter_map(|b| b.ok())
+        .filter_map(|b| b.0.name().ok().flatten().map(str::to_string))
+        .filter(|name| repo.selects_branch(name))
+        .collect::<Vec<_>>();
+    order_branches(repository, &mut branches);
+
+    Ok(branches)
+}
+
+/// Where the map from every original commit to its rewritten commit is kept, for repositories that use this one as a submodule
+pub(crate) fn commit_map_path(repo_dir: &Path) -> PathBuf {
+    git_dir(repo_dir).join("repo-cleaner-commit-map")
+}
+
+/// Read the commit-map git-filter-repo wrote, mapping every original commit to its rewritten commit
+fn read_commit_map(repo_dir: &Path) -> Result<HashMap<String, String>> {
+    let path = git_dir(repo_dir).join("filter-repo").join("commit-map");
+    let Ok(text) = std::fs::read_to_string(&path) else {
+        // filter-repo didn't run, so nothing was rewritten
+        return Ok(HashMap::new());
+    };
+
+    // The first line is a header
+    Ok(text.lines().skip(1)
+        .filter_map(|l| l.split_once(' '))
+        .map(|(old, new)| (old.trim().to_string(), new.trim().to_string()))
+        .filter(|(old, new)| old != new)
+        .collect())
+}
+
+/// The commit map an earlier rewrite left, or an empty one if there is none
+pub(crate) fn read_saved_commit_map(repo_dir: &Path) -> HashMap<String, String> {
+    std::fs::read_to_string(commit_map_path(repo_dir)).unwrap_or_default()
+        .lines()
+        .filter_map(|l| l.split_once(' '))
+        .map(|(old, new)| (old.to_string(), new.to_string()))
+        .collect()
+}
+
+/// Write the commit map, leaving out pruned commits since nothing can point to them anymore
+fn write_commit_map(repo_dir: &Path, map: &HashMap<String, String>) -> Result<()> {
+    let pruned = Oid::zero().to_string();
+    let text = map.iter()
+        .filter(|(_, new)| **new != pruned)
+        .map(|(old, new)| format!("{old} {new}\n"))
+        .collect::<String>();
+
+    let path = commit_map_path(repo_dir);
+    std::fs::write(&path, text)
+        .wrap_err(format!("Unable to write commit map to {path:?}"))
+}
+
+/// Move every note on a commit in `commit_map` onto the commit it was rewritten to, since notes
+/// are kept by the id of the commit they annotate. Notes on pruned commits are dropped. Each notes
+/// ref gets a single new commit, by whoever made its last one.
+fn remap_notes(repo_dir: &Path, commit_map: &HashMap<String, String>) -> Result<()> {
+    let repository = Repository::open(repo_dir)?;
+    let pruned = Oid::zero().to_string();
+
+    for name in notes_refs(&repository)? {
+        let notes = repository.notes(Some(&name))?
+            .collect::<Result<Vec<_>, _>>()?;
+        if !notes.iter().any(|(_, annotated)| commit_map.contains_key(&annotated.to_string())) {
+            continue;
+        }
+
+        // A flat tree, which git reads as well as the fanned out trees it writes itself
+        let mut tree = repository.treebuilder(None)?;
+        let mut moved = 0;
+        for (note, annotated) in notes {
+            let annotated = match commit_map.get(&annotated.to_string()) {
+                Some(new) if *new == pruned => continue,
+                Some(new) => {
+                    moved += 1;
+                    new.clone()
+                },
+                None => annotated.to_string(),
+            };
+            tree.insert(&annotated, note, 0o100644)?;
+        }
+        let tree = repository.find_tree(tree.write()?)?;
+
+        let parent = repository.find_reference(&name)?.peel_to_commit()?;
+        repository.commit(Some(&name), &parent.author(), &parent.committer(), "Notes moved onto the rewritten commits\n", &tree, &[&parent])
+            .wrap_err(format!("Unable to rewrite {name}"))?;
+        info!("Moved {moved} notes of {name} onto the rewritten commits");
+    }
+    Ok(())
+}
+
+/// Count the commits reachable from the local branches that were not reachable from `old_tips`
+fn count_new_commits(repo_dir: &Path, old_tips: &[Oid]) -> Result<usize> {
+    let repository = Repository::open(repo_dir)?;
+
+    let mut walk = repository.revwalk()?;
+    walk.push_glob("refs/heads/*")?;
+    for tip in old_tips {
+        // Old tips may have been pruned already, in which case there is nothing to hide
+        let _ = walk.hide(*tip);
+    }
+
+    Ok(walk.count())
+}
@@ -0,0 +1,97 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, sync::{Condvar, Mutex}};
+
+use crate::{config::RepositoryConfig, forge::Provider, submodule};
+
+
+/// Hands repositories out to the threads processing them, in order. A repository waits for the
+/// repositories it uses as submodules to finish first, and for a free slot if its provider is at
+/// its concurrency limit.
+#[derive(Debug)]
+pub struct Scheduler {
+    repositories: Vec<RepositoryConfig>,
+    /// The earlier repositories each one has to wait for, by index
+    waits_for: Vec<Vec<usize>>,
+    limits: BTreeMap<Provider, usize>,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Indexes of the repositories not handed out yet
+    pending: Vec<usize>,
+    finished: HashSet<usize>,
+    running: HashMap<Provider, usize>,
+    stopped: bool,
+}
+
+impl Scheduler {
+    /// Schedule `repositories`, ordered so that repositories used as submodules by others, as listed
+    /// in `dependencies`, come first. `limits` caps how many of each provider's run at once.
+    pub fn new(repositories: &[RepositoryConfig], dependencies: &HashMap<String, Vec<String>>, limits: &BTreeMap<Provider, usize>) -> Self {
+        let repositories = submodule::dependency_order(repositories, dependencies);
+
+        // Only earlier repositories are waited for, so a cycle of submodules can't deadlock
+        let waits_for = repositories.iter().enumerate()
+            .map(|(i, repo)| dependencies.get(&repo.name).into_iter().flatten()
+                .filter_map(|dep| repositories[..i].iter().position(|r| &r.name == dep))
+                .collect())
+            .collect();
+
+        let state = State { pending: (0..repositories.len()).collect(), ..State::default() };
+        Self { repositories, waits_for, limits: limits.clone(), state: Mutex::new(state), changed: Condvar::new() }
+    }
+
+    /// Every repository, in the order they are handed out
+    pub fn repositories(&self) -> &[RepositoryConfig] {
+        &self.repositories
+    }
+
+    /// The provider whose limit applies to `repo`, if any does
+    fn limited(&self, repo: &RepositoryConfig) -> Option<Provider> {
+        Some(repo.provider).filter(|p| repo.local_path().is_none() && self.limits.contains_key(p))
+    }
+
+    /// The next repository to process and its index, waiting until one may start. `None` once every
+    /// repository has been handed out, or [`Scheduler::stop`] was called.
+    pub fn next(&self) -> Option<(usize, &RepositoryConfig)> {
+        let mut state = self.state.lock().expect("scheduler is never poisoned");
+        loop {
+            if state.stopped || state.pending.is_empty() {
+                return None;
+            }
+
+            let ready = state.pending.iter().position(|&i| {
+                let free = self.limited(&self.repositories[i])
+                    .is_none_or(|p| state.running.get(&p).copied().unwrap_or_default() < self.limits[&p]);
+                free && self.waits_for[i].iter().all(|dep| state.finished.contains(dep))
+            });
+            if let Some(position) = ready {
+                let i = state.pending.remove(position);
+                if let Some(provider) = self.limited(&self.repositories[i]) {
+                    *state.running.entry(provider).or_default() += 1;
+                }
+                return Some((i, &self.repositories[i]));
+            }
+            state = self.changed.wait(state).expect("scheduler is never poisoned");
+        }
+    }
+
+    /// Record that the repository at `index` is done with, whether it succeeded or not
+    pub fn finish(&self, index: usize) {
+        let mut state = self.state.lock().expect("scheduler is never poisoned");
+        if let Some(provider) = self.limited(&self.repositories[index]) {
+            *state.running.entry(provider).or_default() -= 1;
+        }
+        state.finished.insert(index);
+        self.changed.notify_all();
+    }
+
+    /// Hand out no more repositories, returning the ones that weren't
+    pub fn stop(&self) -> Vec<&RepositoryConfig> {
+        let mut state = self.state.lock().expect("scheduler is never poisoned");
+        state.stopped = true;
+        self.changed.notify_all();
+        std::mem::take(&mut state.pending).into_iter().map(|i| &self.repositories[i]).collect()
+    }
+}
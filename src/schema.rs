@@ -0,0 +1,20 @@
+use schemars::schema_for;
+use serde_json::{json, Value};
+
+use crate::Config;
+
+
+/// A JSON Schema for the configuration file, generated from [`Config`] and the types it refers to,
+/// with their doc comments as descriptions. Unknown keys are rejected, as `check-config` reports them too.
+pub fn schema() -> Value {
+    let mut schema = schema_for!(Config);
+    schema.insert("title".to_string(), json!("repo_cleaner configuration"));
+    // Editors find the schema of a file through "$schema", which the tool itself has no use for
+    if let Some(properties) = schema.get_mut("properties").and_then(Value::as_object_mut) {
+        properties.insert("$schema".to_string(), json!({
+            "type": "string",
+            "description": "Where editors find this schema. Ignored by repo_cleaner",
+        }));
+    }
+    schema.to_value()
+}
@@ -0,0 +1,112 @@
+use std::{collections::HashMap, io::Write, path::Path, process::{Command, Stdio}};
+
+use eyre::{bail, eyre, Context, Result};
+use git2::{ObjectType, Oid, Repository, Signature, Sort};
+use tracing::info;
+
+
+/// Re-sign every commit on every local branch, keeping each commit's author, committer, and dates exactly as they were.
+///
+/// Commits are recreated with git2 rather than through a rebase, so nothing is re-stamped with the
+/// current user or time, and branches that share history keep sharing it. Returns the number of
+/// commits re-signed.
+pub fn resign_preserving(repo_dir: &Path) -> Result<usize> {
+    let repository = Repository::open(repo_dir)?;
+    let (program, key) = signing_key(&repository)?;
+
+    let mut walk = repository.revwalk()?;
+    walk.push_glob("refs/heads/*")?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+
+    // Maps every original commit to its re-signed replacement
+    let mut rewritten: HashMap<Oid, Oid> = HashMap::new();
+    for oid in walk {
+        let oid = oid?;
+        let commit = repository.find_commit(oid)?;
+
+        let parents = commit.parent_ids()
+            .map(|p| repository.find_commit(rewritten.get(&p).copied().unwrap_or(p)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let parents = parents.iter().collect::<Vec<_>>();
+
+        let message = commit.message_raw()
+            .ok_or_else(|| eyre!("Commit {oid} has a message that isn't valid UTF-8"))?;
+        let buffer = repository.commit_create_buffer(&commit.author(), &commit.committer(), message, &commit.tree()?, &parents)?;
+        let buffer = buffer.as_str().expect("commit buffer built from UTF-8 parts");
+
+        let signature = gpg_sign(&program, key.as_deref(), buffer)
+            .wrap_err(format!("Unable to sign commit {oid}"))?;
+        let new = repository.commit_signed(buffer, &signature, Some("gpgsig"))?;
+        rewritten.insert(oid, new);
+    }
+
+    // Point every branch at its re-signed tip
+    for branch in repository.branches(Some(git2::BranchType::Local))? {
+        let (branch, _) = branch?;
+        let mut reference = branch.into_reference();
+        if let Some(new) = reference.target().and_then(|t| rewritten.get(&t)) {
+            info!("Re-signed {}", reference.shorthand().unwrap_or_default());
+            reference.set_target(*new, "repo_cleaner: re-sign preserving committer")?;
+        }
+    }
+
+    Ok(rewritten.len())
+}
+
+/// Create an annotated tag object named `name` on the commit `target`, with `tagger` and
+/// `message`, signed with the default GPG signing key like `git tag -s` does. Returns the tag
+/// object, which no ref points to yet.
+pub fn sign_tag(repository: &Repository, name: &str, target: Oid, tagger: &Signature, message: &str) -> Result<Oid> {
+    let (program, key) = signing_key(repository)?;
+
+    let offset = tagger.when().offset_minutes();
+    let sign = if offset < 0 { '-' } else { '+' };
+    let mut payload = format!("object {target}\ntype commit\ntag {name}\ntagger ").into_bytes();
+    payload.extend_from_slice(tagger.name_bytes());
+    payload.extend_from_slice(b" <");
+    payload.extend_from_slice(tagger.email_bytes());
+    payload.extend_from_slice(format!("> {} {sign}{:02}{:02}\n\n", tagger.when().seconds(), offset.abs() / 60, offset.abs() % 60).as_bytes());
+    payload.extend_from_slice(message.as_bytes());
+    if !message.ends_with('\n') {
+        payload.push(b'\n');
+    }
+
+    let payload = String::from_utf8(payload)
+        .map_err(|_| eyre!("The tagger of {name} isn't valid UTF-8"))?;
+    let signature = gpg_sign(&program, key.as_deref(), &payload)
+        .wrap_err(format!("Unable to sign tag {name}"))?;
+    // A tag's signature is part of its message, rather than a header like a commit's
+    Ok(repository.odb()?.write(ObjectType::Tag, format!("{payload}{signature}").as_bytes())?)
+}
+
+/// The GPG program and signing key git is configured to sign with in `repository`
+fn signing_key(repository: &Repository) -> Result<(String, Option<String>)> {
+    let config = repository.config()?;
+    let program = config.get_string("gpg.program").unwrap_or_else(|_| "gpg".to_string());
+    Ok((program, config.get_string("user.signingkey").ok()))
+}
+
+/// Produce an ASCII armored detached signature of `payload`, the same way git does
+fn gpg_sign(program: &str, key: Option<&str>, payload: &str) -> Result<String> {
+    let mut command = Command::new(program);
+    command.args(["--status-fd=2", "-bsa"]);
+    if let Some(key) = key {
+        command.args(["-u", key]);
+    }
+
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .wrap_err(format!("Unable to run {program}"))?;
+
+    child.stdin.take().expect("stdin is piped").write_all(payload.as_bytes())?;
+    let output = child.wait_with_output()?;
+
+    if !output.status.success() {
+        bail!("{program} exited with {}: {}", output.status, String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    Ok(String::from_utf8(output.stdout)?)
+}
@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+
+use eyre::Result;
+use git2::{Oid, Repository};
+use serde::Serialize;
+
+use crate::git::is_quarantined;
+
+
+/// A commit that was signed before the rewrite, and whose signature no longer matches its rewritten self
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct InvalidatedSignature {
+    /// The signed commit, as it was before rewriting
+    pub commit: String,
+    /// What it was rewritten to
+    pub rewritten: String,
+    /// `Name <email>` of the author
+    pub author: String,
+    /// First line of the message
+    pub subject: String,
+}
+
+/// Every signed commit reachable from any ref, with its author and subject, recorded before rewriting
+/// since filter-repo drops the original objects
+pub fn signed_commits(repository: &Repository) -> Result<HashMap<Oid, (String, String)>> {
+    let mut walk = repository.revwalk()?;
+    for reference in repository.references()?.filter_map(|r| r.ok()).filter(|r| !r.name().is_some_and(is_quarantined)) {
+        if let Ok(commit) = reference.peel_to_commit() {
+            walk.push(commit.id())?;
+        }
+    }
+
+    let mut signed = HashMap::new();
+    for oid in walk {
+        let oid = oid?;
+        // Only signed commits have a signature to extract
+        if repository.extract_signature(&oid, None).is_err() {
+            continue;
+        }
+        let commit = repository.find_commit(oid)?;
+        let author = commit.author();
+        signed.insert(oid, (
+            format!("{} <{}>", String::from_utf8_lossy(author.name_bytes()), String::from_utf8_lossy(author.email_bytes())),
+            String::from_utf8_lossy(commit.summary_bytes().unwrap_or_default()).into_owned(),
+        ));
+    }
+    Ok(signed)
+}
+
+/// The commits of `signed` that `commit_map` gives a new id, since a signature only ever covers the
+/// exact commit it was made for. Pruned commits are left out, as nothing points to them anymore.
+pub fn invalidated(signed: &HashMap<Oid, (String, String)>, commit_map: &HashMap<String, String>) -> Vec<InvalidatedSignature> {
+    let pruned = Oid::zero().to_string();
+    let mut invalidated = signed.iter()
+        .filter_map(|(oid, (author, subject))| {
+            let commit = oid.to_string();
+            let rewritten = commit_map.get(&commit).filter(|new| **new != pruned)?.clone();
+            Some(InvalidatedSignature { commit, rewritten, author: author.clone(), subject: subject.clone() })
+        })
+        .collect::<Vec<_>>();
+    invalidated.sort_by(|a, b| a.commit.cmp(&b.commit));
+    invalidated
+}
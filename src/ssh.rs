@@ -0,0 +1,429 @@
+use std::{collections::BTreeMap, fs::OpenOptions, io::Write, path::{Path, PathBuf}};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use eyre::{Context, Result};
+use ring::hmac;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::git;
+
+
+/// How ssh treats a host key it hasn't seen before
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostKeyChecking {
+    /// Refuse unknown hosts
+    Yes,
+    /// Accept and remember unknown hosts, but refuse hosts whose key changed
+    AcceptNew,
+    /// Accept any key. Only for throwaway environments
+    No,
+}
+
+impl HostKeyChecking {
+    fn option(self) -> &'static str {
+        match self {
+            HostKeyChecking::Yes => "yes",
+            HostKeyChecking::AcceptNew => "accept-new",
+            HostKeyChecking::No => "no",
+        }
+    }
+}
+
+/// How to connect to a single ssh host
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Default, PartialEq, Eq)]
+#[schemars(deny_unknown_fields)]
+pub struct SshHost {
+    /// Private key to authenticate with, instead of the agent and default keys
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<PathBuf>,
+    /// User to log in as, instead of the one in the URL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// known_hosts file to check host keys against, instead of `~/.ssh/known_hosts`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub known_hosts: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_key_checking: Option<HostKeyChecking>,
+}
+
+/// Ssh settings for every host, from `--ssh-key`, `--strict-host-key-checking`, and the config's
+/// `ssh` block and `known_hosts`
+#[derive(Debug, Clone, Default)]
+pub struct SshSettings {
+    /// Key used for every host without a key of its own
+    pub default_key: Option<PathBuf>,
+    /// known_hosts file used for every host without one of its own
+    pub known_hosts: Option<PathBuf>,
+    /// Refuse every host whose key isn't already known, whatever each host's `host_key_checking` says
+    pub strict_host_key_checking: bool,
+    pub hosts: BTreeMap<String, SshHost>,
+}
+
+impl SshSettings {
+    pub fn is_empty(&self) -> bool {
+        self.default_key.is_none() && self.known_hosts.is_none() && !self.strict_host_key_checking && self.hosts.is_empty()
+    }
+
+    /// The settings for `host`, with the defaults filled in
+    pub fn host(&self, host: &str) -> SshHost {
+        let mut settings = self.hosts.get(host).cloned().unwrap_or_default();
+        // ssh expands `~` itself, but libgit2 doesn't
+        settings.key = settings.key.or_else(|| self.default_key.clone()).map(|key| expand_home(&key));
+        // ssh runs inside each clone, so a relative path would be looked for there
+        settings.known_hosts = settings.known_hosts.or_else(|| self.known_hosts.clone())
+            .map(|file| expand_home(&file))
+            .map(|file| std::path::absolute(&file).unwrap_or(file));
+        if self.strict_host_key_checking {
+            settings.host_key_checking = Some(HostKeyChecking::Yes);
+        }
+        settings
+    }
+
+    /// `url` with the user and port configured for its host. URLs that aren't ssh are returned as they are.
+    pub fn url(&self, url: &str) -> String {
+        let Some(parts) = SshUrl::parse(url) else {
+            return url.to_string();
+        };
+
+        let settings = self.host(parts.host);
+        let mut url = format!("{}://", parts.scheme);
+        if let Some(user) = settings.user.as_deref().or(parts.user) {
+            url += &format!("{user}@");
+        }
+        url += parts.host;
+        if let Some(port) = settings.port.or(parts.port) {
+            url += &format!(":{port}");
+        }
+        url + "/" + parts.path
+    }
+
+    /// The settings for the host of `url`, or `None` if it isn't an ssh URL
+    pub fn for_url(&self, url: &str) -> Option<SshHost> {
+        SshUrl::parse(url).map(|parts| self.host(parts.host))
+    }
+
+    /// How libgit2 has to check the host key of `url`, which it can't be pointed at a known_hosts
+    /// file for: against the file pinned for its host, or `~/.ssh/known_hosts` if it has to be
+    /// checked strictly. `None` leaves it to libgit2, as do URLs that aren't ssh.
+    pub(crate) fn host_key_check(&self, url: &str) -> Option<HostKeyCheck> {
+        let parts = SshUrl::parse(url)?;
+        let settings = self.host(parts.host);
+        let strict = matches!(settings.host_key_checking, Some(HostKeyChecking::Yes | HostKeyChecking::AcceptNew));
+        let file = settings.known_hosts
+            .or_else(|| strict.then(|| expand_home(Path::new("~/.ssh/known_hosts"))))?;
+        // known_hosts names hosts on other ports than ssh's with their port
+        let (name, keyscan) = match settings.port.or(parts.port).filter(|port| *port != 22) {
+            Some(port) => (format!("[{}]:{port}", parts.host), format!("ssh-keyscan -p {port} {}", parts.host)),
+            None => (parts.host.to_string(), format!("ssh-keyscan {}", parts.host)),
+        };
+        Some(HostKeyCheck { name, keyscan, file, accept_new: settings.host_key_checking == Some(HostKeyChecking::AcceptNew) })
+    }
+
+    /// Write these settings as an ssh config in `dir`, and point every git subprocess at it through `GIT_SSH_COMMAND`.
+    /// Does nothing if nothing is configured, leaving ssh to the user's own config.
+    pub fn install(&self, dir: &Path) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        let mut text = String::new();
+        for host in self.hosts.keys() {
+            text += &format!("Host {host}\n");
+            text += &host_options(&self.host(host));
+        }
+        // Every other host still gets the defaults
+        text += "Host *\n";
+        text += &host_options(&self.host("*"));
+        // Anything not set here falls back to the user's own config
+        text += "Include ~/.ssh/config\n";
+
+        std::fs::create_dir_all(dir)?;
+        let path = std::path::absolute(dir.join("ssh_config"))?;
+        std::fs::write(&path, text)
+            .wrap_err(format!("Unable to write ssh config to {path:?}"))?;
+
+        let command = format!("ssh -F {}", shell_quote(&path));
+        debug!("Setting GIT_SSH_COMMAND to {command}");
+        // Set once at startup, so it reaches git, git-lfs, and hooks alike
+        git::set_env("GIT_SSH_COMMAND", &command);
+
+        Ok(())
+    }
+}
+
+/// A host key that doesn't match a known_hosts file
+#[derive(Debug, thiserror::Error)]
+pub enum HostKeyError {
+    #[error("The ssh host key of {host} isn't in {file:?}, so it can't be verified. Check its fingerprint, then add it with `{keyscan} >> {file:?}`")]
+    Unknown { host: String, file: PathBuf, keyscan: String },
+    #[error("The ssh host key of {host} doesn't match the one in {file:?}. Someone may be intercepting the connection. If the key was changed on purpose, replace it in {file:?}")]
+    Changed { host: String, file: PathBuf },
+    #[error("Unable to read known_hosts file {file:?}: {message}")]
+    Unreadable { file: PathBuf, message: String },
+}
+
+/// Checks a host's key against a known_hosts file, for libgit2
+#[derive(Debug, Clone)]
+pub(crate) struct HostKeyCheck {
+    /// The host as known_hosts names it, like `github.com` or `[git.example.com]:2222`
+    name: String,
+    /// The command that prints the host's keys
+    keyscan: String,
+    file: PathBuf,
+    /// Add the keys of hosts that aren't in the file yet, rather than refusing them
+    accept_new: bool,
+}
+
+impl HostKeyCheck {
+    /// Check that `key`, of the type called `key_type` in known_hosts, is the one the file has for
+    /// the host. Keys of other types than the host has in the file count as unknown.
+    pub(crate) fn verify(&self, key_type: &str, key: &[u8]) -> Result<(), HostKeyError> {
+        let text = match std::fs::read_to_string(&self.file) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(HostKeyError::Unreadable { file: self.file.clone(), message: e.to_string() }),
+        };
+
+        let mut changed = false;
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            // Certificate authorities and revoked keys are left to ssh itself
+            if line.starts_with('@') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (Some(hosts), Some(known_type), Some(known_key)) = (fields.next(), fields.next(), fields.next()) else {
+                continue;
+            };
+            if known_type != key_type || !hosts_match(hosts, &self.name) {
+                continue;
+            }
+            if STANDARD.decode(known_key).is_ok_and(|known_key| known_key == key) {
+                return Ok(());
+            }
+            changed = true;
+        }
+
+        if changed {
+            return Err(HostKeyError::Changed { host: self.name.clone(), file: self.file.clone() });
+        }
+        if !self.accept_new {
+            return Err(HostKeyError::Unknown { host: self.name.clone(), file: self.file.clone(), keyscan: self.keyscan.clone() });
+        }
+
+        info!("Adding the ssh host key of {} to {:?}", self.name, self.file);
+        let mut line = format!("{} {key_type} {}\n", self.name, STANDARD.encode(key));
+        // Don't run the key onto the end of a last line without a newline
+        if !text.is_empty() && !text.ends_with('\n') {
+            line.insert(0, '\n');
+        }
+        OpenOptions::new().create(true).append(true).open(&self.file)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .map_err(|e| HostKeyError::Unreadable { file: self.file.clone(), message: e.to_string() })
+    }
+}
+
+/// Whether the comma separated `patterns` of a known_hosts line name `host`. Patterns may be
+/// hashed, have `*` and `?` wildcards, or start with `!` to exclude hosts.
+fn hosts_match(patterns: &str, host: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns.split(',') {
+        if let Some(hashed) = pattern.strip_prefix("|1|") {
+            let Some((salt, hash)) = hashed.split_once('|') else { continue };
+            let (Ok(salt), Ok(hash)) = (STANDARD.decode(salt), STANDARD.decode(hash)) else { continue };
+            let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, &salt);
+            matched |= hmac::verify(&key, host.as_bytes(), &hash).is_ok();
+            continue;
+        }
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        if wildcard_match(pattern.as_bytes(), host.as_bytes()) {
+            if negated {
+                return false;
+            }
+            matched = true;
+        }
+    }
+    matched
+}
+
+/// Whether `text` matches `pattern`, where `*` matches anything and `?` any one character. Unlike
+/// a glob, brackets are themselves, as in `[host]:port`.
+fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.split_first(), text.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => wildcard_match(rest, text) || text.split_first().is_some_and(|(_, text)| wildcard_match(pattern, text)),
+        (Some((b'?', rest)), Some((_, text))) => wildcard_match(rest, text),
+        (Some((p, rest)), Some((t, text))) => p.eq_ignore_ascii_case(t) && wildcard_match(rest, text),
+        _ => false,
+    }
+}
+
+/// `path` as a single word for the shell `GIT_SSH_COMMAND` runs in, whatever quotes it contains
+pub(crate) fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.display().to_string().replace('\'', r"'\''"))
+}
+
+pub(crate) fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// The parts of a `scheme://user@host:port/path` ssh URL
+struct SshUrl<'a> {
+    scheme: &'a str,
+    user: Option<&'a str>,
+    host: &'a str,
+    port: Option<u16>,
+    path: &'a str,
+}
+
+impl<'a> SshUrl<'a> {
+    fn parse(url: &'a str) -> Option<Self> {
+        let (scheme, rest) = url.split_once("://").filter(|(scheme, _)| scheme.ends_with("ssh"))?;
+        let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let (user, host) = match authority.rsplit_once('@') {
+            Some((user, host)) => (Some(user), host),
+            None => (None, authority),
+        };
+        let (host, port) = match host.split_once(':') {
+            Some((host, port)) => (host, port.parse().ok()),
+            None => (host, None),
+        };
+        Some(Self { scheme, user, host, port, path })
+    }
+}
+
+fn host_options(settings: &SshHost) -> String {
+    let mut text = String::new();
+    if let Some(key) = &settings.key {
+        text += &format!("    IdentityFile \"{}\"\n    IdentitiesOnly yes\n", key.display());
+    }
+    if let Some(user) = &settings.user {
+        text += &format!("    User {user}\n");
+    }
+    if let Some(port) = settings.port {
+        text += &format!("    Port {port}\n");
+    }
+    if let Some(known_hosts) = &settings.known_hosts {
+        text += &format!("    UserKnownHostsFile \"{}\"\n", known_hosts.display());
+    }
+    if let Some(checking) = settings.host_key_checking {
+        text += &format!("    StrictHostKeyChecking {}\n", checking.option());
+    }
+    text
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const KEY: &[u8] = b"the host key";
+    const OTHER_KEY: &[u8] = b"another host key";
+
+    /// A check of `git.example.com` against a known_hosts file holding `lines`
+    fn check(dir: &TempDir, lines: &[String], accept_new: bool) -> HostKeyCheck {
+        let file = dir.path().join("known_hosts");
+        std::fs::write(&file, lines.join("\n")).unwrap();
+        HostKeyCheck { name: "git.example.com".to_string(), keyscan: "ssh-keyscan git.example.com".to_string(), file, accept_new }
+    }
+
+    fn line(hosts: &str, key: &[u8]) -> String {
+        format!("{hosts} ssh-ed25519 {}", STANDARD.encode(key))
+    }
+
+    /// `host` hashed the way `ssh-keygen -H` does
+    fn hashed(host: &str) -> String {
+        let salt = b"0123456789abcdefghij";
+        let hash = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, salt), host.as_bytes());
+        format!("|1|{}|{}", STANDARD.encode(salt), STANDARD.encode(hash.as_ref()))
+    }
+
+    #[test]
+    fn shell_quote_keeps_paths_one_word() {
+        assert_eq!(shell_quote(Path::new("/srv/clean up/ssh_config")), "'/srv/clean up/ssh_config'");
+        let path = Path::new("/home/o'brien/ssh_config");
+        assert_eq!(shell_quote(path), r"'/home/o'\''brien/ssh_config'");
+        let echoed = std::process::Command::new("sh").arg("-c").arg(format!("printf %s {}", shell_quote(path))).output().unwrap();
+        assert_eq!(echoed.stdout, path.as_os_str().as_encoded_bytes());
+    }
+
+    #[test]
+    fn matching_plain_keys_are_accepted() {
+        let dir = TempDir::new().unwrap();
+        let check = check(&dir, &["# comment".to_string(), line("other.example.com,git.example.com", KEY)], false);
+        assert!(check.verify("ssh-ed25519", KEY).is_ok());
+        // Keys of another type than the file has count as unknown, not changed
+        assert!(matches!(check.verify("ssh-rsa", KEY), Err(HostKeyError::Unknown { .. })));
+    }
+
+    #[test]
+    fn matching_hashed_keys_are_accepted() {
+        let dir = TempDir::new().unwrap();
+        assert!(check(&dir, &[line(&hashed("git.example.com"), KEY)], false).verify("ssh-ed25519", KEY).is_ok());
+        let other = check(&dir, &[line(&hashed("other.example.com"), KEY)], false);
+        assert!(matches!(other.verify("ssh-ed25519", KEY), Err(HostKeyError::Unknown { .. })));
+    }
+
+    #[test]
+    fn changed_keys_are_refused_even_with_accept_new() {
+        let dir = TempDir::new().unwrap();
+        let lines = [line("git.example.com", OTHER_KEY)];
+        let check = check(&dir, &lines, true);
+        assert!(matches!(check.verify("ssh-ed25519", KEY), Err(HostKeyError::Changed { .. })));
+        assert_eq!(std::fs::read_to_string(&check.file).unwrap(), lines.join("\n"));
+    }
+
+    #[test]
+    fn negated_patterns_exclude_hosts() {
+        assert!(hosts_match("*.example.com", "git.example.com"));
+        assert!(!hosts_match("*.example.com,!git.example.com", "git.example.com"));
+        assert!(!hosts_match("!git.example.com,*.example.com", "git.example.com"));
+        assert!(hosts_match("*.example.com,!other.example.com", "git.example.com"));
+
+        let dir = TempDir::new().unwrap();
+        let check = check(&dir, &[line("*.example.com,!git.example.com", KEY)], false);
+        assert!(matches!(check.verify("ssh-ed25519", KEY), Err(HostKeyError::Unknown { .. })));
+    }
+
+    #[test]
+    fn hosts_on_other_ports_are_named_with_their_port() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("known_hosts");
+        std::fs::write(&file, [line("git.example.com", OTHER_KEY), line("[git.example.com]:2222", KEY)].join("\n")).unwrap();
+        let host = SshHost { port: Some(2222), known_hosts: Some(file), ..SshHost::default() };
+        let settings = SshSettings { hosts: BTreeMap::from([("git.example.com".to_string(), host)]), ..SshSettings::default() };
+
+        let check = settings.host_key_check("ssh://git@git.example.com/org/project.git").unwrap();
+        assert_eq!(check.name, "[git.example.com]:2222");
+        assert_eq!(check.keyscan, "ssh-keyscan -p 2222 git.example.com");
+        assert!(check.verify("ssh-ed25519", KEY).is_ok());
+        assert!(wildcard_match(b"[git.example.com]:22?2", b"[git.example.com]:2222"));
+        assert!(!wildcard_match(b"[git.example.com]:22", b"[git.example.com]:2222"));
+    }
+
+    #[test]
+    fn accept_new_appends_unknown_keys() {
+        let dir = TempDir::new().unwrap();
+        let refusing = check(&dir, &[line("other.example.com", OTHER_KEY)], false);
+        assert!(matches!(refusing.verify("ssh-ed25519", KEY), Err(HostKeyError::Unknown { .. })));
+
+        let check = HostKeyCheck { accept_new: true, ..refusing };
+        assert!(check.verify("ssh-ed25519", KEY).is_ok());
+        let text = std::fs::read_to_string(&check.file).unwrap();
+        assert_eq!(text, format!("{}\n{}\n", line("other.example.com", OTHER_KEY), line("git.example.com", KEY)));
+        // The key is known from then on
+        assert!(HostKeyCheck { accept_new: false, ..check }.verify("ssh-ed25519", KEY).is_ok());
+    }
+}
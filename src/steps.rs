@@ -0,0 +1,757 @@
+use std::collections::HashSet;
+
+use eyre::{eyre, Context, Result};
+use git2::Repository;
+use tracing::{error, info, warn};
+
+use crate::{
+    allowlist::Allowlist,
+    attribution,
+    cache::{RewriteCache, RewriteScope},
+    cleaner::{head, old_head_path, Cleaner},
+    error::{FailureKind, RewriteError},
+    git::{self, git, git_with, is_local},
+    hooks::Hook,
+    leaks,
+    manifest::Manifest,
+    migration,
+    options::RefreshPolicy,
+    pipeline::{incomplete_step, Flow, Step, StepContext},
+    progress::Phase,
+    release,
+    rewrite,
+    sign,
+    submodule,
+    verify,
+};
+
+
+/// Record the open pull requests and forks of the original, skipping it if they block processing it
+#[derive(Debug, Clone, Copy)]
+pub struct PreflightStep;
+
+impl Step for PreflightStep {
+    fn name(&self) -> &'static str {
+        "preflight"
+    }
+
+    fn run(&self, cleaner: &Cleaner, cx: &mut StepContext<'_>) -> Result<Flow> {
+        Ok(if cleaner.preflight(cx.job, cx.report) { Flow::Continue } else { Flow::Stop })
+    }
+}
+
+/// Clone the repository, or open an existing clone, and fetch every branch. A repository that
+/// can't be cloned is skipped.
+#[derive(Debug, Clone, Copy)]
+pub struct CloneStep {
+    /// Also fetch every LFS object, so none are missing once the rewritten history is pushed
+    pub lfs: bool,
+}
+
+impl Step for CloneStep {
+    fn name(&self) -> &'static str {
+        "clone"
+    }
+
+    fn run(&self, cleaner: &Cleaner, cx: &mut StepContext<'_>) -> Result<Flow> {
+        let job = cx.job;
+
+        cx.progress.phase(Phase::Clone);
+        let existed = Repository::open(&job.dir).is_ok();
+        let repository = match cleaner.clone_repository(job, cx.progress) {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("Skipping {}: {e}", job.repository);
+                cx.report.skip(format!("{e:#}"));
+                cx.report.failure = Some(FailureKind::of(&e));
+                return Ok(Flow::Stop);
+            },
+        };
+        cx.report.cloned = true;
+
+        cx.progress.phase(Phase::Fetch);
+        if existed && cleaner.options.refresh == RefreshPolicy::Reuse {
+            info!("Using the existing clone of {} as it is", job.repository);
+        } else {
+            cleaner.fetch(job, cx.progress)?;
+            if self.lfs {
+                cleaner.fetch_lfs(job)?;
+            }
+        }
+
+        // Remember where HEAD was, for the hooks that run after rewriting and around pushing
+        cx.old_head = head(&repository).map(|(_, oid)| oid);
+        cx.repository = Some(repository);
+        Ok(Flow::Continue)
+    }
+}
+
+/// Find how much of the history an earlier clean with the same rules already rewrote, stopping
+/// if that was all of it
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStep;
+
+impl Step for CacheStep {
+    fn name(&self) -> &'static str {
+        "cache"
+    }
+
+    fn run(&self, cleaner: &Cleaner, cx: &mut StepContext<'_>) -> Result<Flow> {
+        let job = cx.job;
+        let repository = cx.repository()?;
+
+        let callbacks = cleaner.callbacks(job, repository)?;
+        let fingerprint = cleaner.fingerprint(job, &callbacks)?;
+        let (scope, clean_tips) = match RewriteCache::load(&job.dir) {
+            Some(cache) if !cleaner.options.full_rewrite => {
+                // Make sure every selected remote branch has a local branch to compare
+                rewrite::local_branches(repository, &job.config)?;
+                cache.scope(repository, &fingerprint, &cleaner.branch_tips(job)?)
+            },
+            _ => (RewriteScope::Full, Vec::new()),
+        };
+        cx.report.rewrite_scope = Some(scope);
+        cx.callbacks = Some((callbacks, fingerprint));
+        cx.scope = scope;
+        cx.clean_tips = clean_tips;
+
+        if scope == RewriteScope::Unchanged {
+            info!("{} hasn't changed since it was last cleaned with the same rules, so it doesn't need rewriting", job.repository);
+            Manifest::load(&cleaner.dir)?.record(&job.repository, cleaner.branch_tips(job)?)?;
+            return Ok(Flow::Stop);
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+/// Find whether any rule matches anything in the repository before it is backed up, leaving it
+/// as it is and stopping if none does, since rewriting it would change nothing
+#[derive(Debug, Clone, Copy)]
+pub struct ScanStep;
+
+impl Step for ScanStep {
+    fn name(&self) -> &'static str {
+        "scan"
+    }
+
+    fn run(&self, cleaner: &Cleaner, cx: &mut StepContext<'_>) -> Result<Flow> {
+        let job = cx.job;
+        let repo = &job.repository;
+        // An earlier rewrite of the rest of the history may still need pushing
+        if cx.scope != RewriteScope::Full {
+            return Ok(Flow::Continue);
+        }
+        if cx.callbacks.is_none() {
+            let callbacks = cleaner.callbacks(job, cx.repository()?)?;
+            let fingerprint = cleaner.fingerprint(job, &callbacks)?;
+            cx.callbacks = Some((callbacks, fingerprint));
+        }
+        let (callbacks, _) = cx.callbacks.as_ref().expect("the callbacks were just made");
+        if callbacks.changes_unmatched() {
+            return Ok(Flow::Continue);
+        }
+        let repository = cx.repository()?;
+        // Make sure every selected remote branch has a local branch to compare
+        rewrite::local_branches(repository, &job.config)?;
+        let tips = cleaner.branch_tips(job)?;
+        // So may a clone whose branches moved away from the remote's
+        let matches_remote = tips.iter().all(|(branch, tip)| {
+            repository.refname_to_id(&format!("refs/remotes/origin/{branch}")).is_ok_and(|oid| oid.to_string() == *tip)
+        });
+        if !matches_remote {
+            return Ok(Flow::Continue);
+        }
+
+        cx.progress.phase(Phase::Rewrite);
+        let plan = rewrite::plan(repository, &job.dir, callbacks, &job.config, &cx.clean_tips, cleaner.options.timeouts.filter_repo)?;
+        if !plan.matches_nothing() {
+            return Ok(Flow::Continue);
+        }
+
+        info!("No rule matches anything in {repo}, so it is left as it is");
+        cx.report.rule_matches = plan.rule_matches;
+        cx.report.mark_clean("No rule matches anything in it");
+        Manifest::load(&cleaner.dir)?.record_unchanged(repo, tips)?;
+        Ok(Flow::Stop)
+    }
+}
+
+/// Archive the whole clone before anything is rewritten
+#[derive(Debug, Clone, Copy)]
+pub struct BackupStep;
+
+impl Step for BackupStep {
+    fn name(&self) -> &'static str {
+        "backup"
+    }
+
+    fn run(&self, cleaner: &Cleaner, cx: &mut StepContext<'_>) -> Result<Flow> {
+        cx.progress.phase(Phase::Backup);
+        cx.report.backup_bytes = Some(cleaner.backup(cx.job)?);
+        cx.report.backed_up = true;
+        Ok(Flow::Continue)
+    }
+}
+
+/// Run the hooks configured for `0`, for every repository and then for this one
+#[derive(Debug, Clone, Copy)]
+pub struct HookStep(pub Hook);
+
+impl Step for HookStep {
+    fn name(&self) -> &'static str {
+        self.0.name()
+    }
+
+    fn run(&self, cleaner: &Cleaner, cx: &mut StepContext<'_>) -> Result<Flow> {
+        cleaner.run_hooks(self.0, cx.job, cx.old_head.as_deref())?;
+        Ok(Flow::Continue)
+    }
+}
+
+/// Rewrite names and emails with git-filter-repo, checking protected identities survived
+#[derive(Debug, Clone, Copy)]
+pub struct RewriteStep;
+
+impl Step for RewriteStep {
+    fn name(&self) -> &'static str {
+        "rewrite"
+    }
+
+    fn changes_clone(&self) -> bool {
+        true
+    }
+
+    fn run(&self, cleaner: &Cleaner, cx: &mut StepContext<'_>) -> Result<Flow> {
+        let job = cx.job;
+        let repo = &job.repository;
+        if let Some(old_head) = &cx.old_head {
+            std::fs::write(old_head_path(&job.dir), old_head)?;
+        }
+        // Without the cache step, everything is rewritten
+        if cx.callbacks.is_none() {
+            let callbacks = cleaner.callbacks(job, cx.repository()?)?;
+            let fingerprint = cleaner.fingerprint(job, &callbacks)?;
+            cx.callbacks = Some((callbacks, fingerprint));
+        }
+        let (callbacks, _) = cx.callbacks.as_ref().expect("the callbacks were just made");
+
+        cx.progress.phase(Phase::Rewrite);
+        verify::record(&job.dir, &cleaner.config)?;
+        if cleaner.options.keep_original {
+            let prefix = rewrite::quarantine(cx.repository()?, &job.config)?;
+            info!("Kept the original branches and tags of {repo} under {prefix}");
+        }
+        if cx.scope == RewriteScope::Incremental {
+            info!("Only rewriting the commits added to {repo} since it was last cleaned with the same rules");
+        }
+        let outcome = rewrite::rewrite(cx.repository()?, &job.dir, callbacks, &job.config, &cx.clean_tips, cleaner.options.timeouts.filter_repo)
+            .wrap_err(RewriteError { repository: repo.clone() })?;
+        verify::check(&job.dir, &cleaner.config)?;
+        cx.report.branches_rewritten = outcome.branches;
+        cx.report.commits_changed = outcome.commits_changed;
+        if !outcome.invalidated_signatures.is_empty() {
+            warn!("Rewriting {repo} invalidated the signatures of {} commits, listed in the summary", outcome.invalidated_signatures.len());
+        }
+        cx.report.invalidated_signatures = outcome.invalidated_signatures.clone();
+        cx.report.rule_matches = outcome.rule_matches.clone();
+        cx.report.attribution = outcome.attribution.clone();
+        cx.report.tags = outcome.tags.clone();
+        cx.report.merges = outcome.merges.clone();
+        cx.outcome = Some(outcome);
+        Ok(Flow::Continue)
+    }
+}
+
+/// Move the release tags onto the commits theirs were rewritten to, re-signing them if configured
+#[derive(Debug, Clone, Copy)]
+pub struct ReleaseTagStep;
+
+impl Step for ReleaseTagStep {
+    fn name(&self) -> &'static str {
+        "release-tags"
+    }
+
+    fn changes_clone(&self) -> bool {
+        true
+    }
+
+    fn run(&self, cleaner: &Cleaner, cx: &mut StepContext<'_>) -> Result<Flow> {
+        let Some(releases) = &cleaner.config.releases else {
+            return Ok(Flow::Continue);
+        };
+        let commit_map = rewrite::read_saved_commit_map(&cx.job.dir);
+        cx.report.release_tags = release::fixup(cx.repository()?, releases, &commit_map)
+            .wrap_err("Unable to move the release tags")?;
+        Ok(Flow::Continue)
+    }
+}
+
+/// Collect garbage, dropping the objects the rewrite left behind
+#[derive(Debug, Clone, Copy)]
+pub struct GcStep;
+
+impl Step for GcStep {
+    fn name(&self) -> &'static str {
+        "gc"
+    }
+
+    fn run(&self, cleaner: &Cleaner, cx: &mut StepContext<'_>) -> Result<Flow> {
+        cx.progress.phase(Phase::Gc);
+        cx.report.reclaimed_bytes = cleaner.gc(cx.job);
+        Ok(Flow::Continue)
+    }
+}
+
+/// Re-sign every rewritten commit with the default GPG signing key
+#[derive(Debug, Clone, Copy)]
+pub struct SignStep;
+
+impl Step for SignStep {
+    fn name(&self) -> &'static str {
+        "sign"
+    }
+
+    fn changes_clone(&self) -> bool {
+        true
+    }
+
+    fn run(&self, _cleaner: &Cleaner, cx: &mut StepContext<'_>) -> Result<Flow> {
+        let job = cx.job;
+
+        cx.progress.phase(Phase::Sign);
+        info!("Re-signing all commits for {}, preserving committers", job.repository);
+        let commits = sign::resign_preserving(&job.dir)?;
+        info!("Re-signed {commits} commits");
+        cx.report.signed = true;
+        Ok(Flow::Continue)
+    }
+}
+
+/// Record the cleaned branches in the manifest and the cache, and write instructions for collaborators
+#[derive(Debug, Clone, Copy)]
+pub struct RecordStep;
+
+impl Step for RecordStep {
+    fn name(&self) -> &'static str {
+        "record"
+    }
+
+    fn run(&self, cleaner: &Cleaner, cx: &mut StepContext<'_>) -> Result<Flow> {
+        let job = cx.job;
+        let repo = &job.repository;
+
+        // Only now may the commit phase push it, and only as it is right now
+        let tips = cleaner.branch_tips(job)?;
+        Manifest::load(&cleaner.dir)?.record(repo, tips.clone())?;
+        if let Some((_, fingerprint)) = &cx.callbacks {
+            RewriteCache { fingerprint: fingerprint.clone(), branches: tips.clone() }.save(&job.dir)?;
+        }
+
+        let outcome = cx.outcome.take().unwrap_or_default();
+        let branches = outcome.original_branches.into_iter()
+            .filter_map(|(branch, old)| Some((branch.clone(), (old, tips.get(&branch)?.clone()))))
+            .collect();
+        let migration = cleaner.dir.join("migration").join(job.config.dir_name());
+        migration::write(&migration, repo, job.push_to.as_deref().unwrap_or(&job.url), &branches, &rewrite::commit_map_path(&job.dir))?;
+        info!("Wrote instructions for collaborators of {repo} to {:?}", migration.join("MIGRATION.md"));
+        if !outcome.attribution.is_empty() {
+            attribution::write(&migration, repo, &outcome.attribution)?;
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+/// Find what cleaning would change, by rewriting without touching the repository
+#[derive(Debug, Clone, Copy)]
+pub struct PlanStep;
+
+impl Step for PlanStep {
+    fn name(&self) -> &'static str {
+        "plan"
+    }
+
+    fn run(&self, cleaner: &Cleaner, cx: &mut StepContext<'_>) -> Result<Flow> {
+        let job = cx.job;
+        cx.progress.phase(Phase::Rewrite);
+        // Submodules haven't been rewritten, so there are no new submodule pointers to plan with
+        let callbacks = rewrite::Callbacks::new(&cleaner.config, cleaner.options.anonymize.as_deref(), &cleaner.run_id, &job.config, None)?;
+        let plan = rewrite::plan(cx.repository()?, &job.dir, &callbacks, &job.config, &[], cleaner.options.timeouts.filter_repo)?;
+        cx.report.branches_rewritten = plan.branches;
+        cx.report.rule_matches = plan.rule_matches;
+        Ok(Flow::Continue)
+    }
+}
+
+/// Search file contents for the old emails, which rewriting leaves in place since it only changes
+/// commits and tags
+#[derive(Debug, Clone, Copy)]
+pub struct LeakScanStep;
+
+impl Step for LeakScanStep {
+    fn name(&self) -> &'static str {
+        "scan-blobs"
+    }
+
+    fn run(&self, cleaner: &Cleaner, cx: &mut StepContext<'_>) -> Result<Flow> {
+        let Some(depth) = cleaner.options.scan_blobs else {
+            return Ok(Flow::Continue);
+        };
+        cx.report.leaked_emails = leaks::scan(cx.repository()?, &cleaner.config, depth)?;
+        if !cx.report.leaked_emails.is_empty() {
+            let files = cx.report.leaked_emails.iter().map(|l| &l.path).collect::<HashSet<_>>().len();
+            warn!("{} has old emails in the contents of {files} files, which rewriting commits won't replace", cx.job.repository);
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+/// Check every author and committer email in the history against an allowlist, failing the
+/// repository if any aren't on it
+#[derive(Debug, Clone)]
+pub struct EnforceStep(pub Allowlist);
+
+impl Step for EnforceStep {
+    fn name(&self) -> &'static str {
+        "enforce"
+    }
+
+    fn run(&self, _cleaner: &Cleaner, cx: &mut StepContext<'_>) -> Result<Flow> {
+        cx.report.disallowed_emails = self.0.scan(cx.repository()?)?;
+        if !cx.report.disallowed_emails.is_empty() {
+            let commits = cx.report.disallowed_emails.iter().map(|d| &d.commit).collect::<HashSet<_>>().len();
+            warn!("{} has {commits} commits with emails that aren't allowlisted", cx.job.repository);
+            cx.report.fail(format!("{commits} commits have emails that aren't allowlisted"));
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+/// Make sure the clean phase rewrote the repository successfully, and that nothing moved since
+#[derive(Debug, Clone, Copy)]
+pub struct ManifestCheckStep;
+
+impl Step for ManifestCheckStep {
+    fn name(&self) -> &'static str {
+        "manifest"
+    }
+
+    fn run(&self, cleaner: &Cleaner, cx: &mut StepContext<'_>) -> Result<Flow> {
+        let job = cx.job;
+        let repo = &job.repository;
+
+        let Ok(repository) = Repository::open(&job.dir) else {
+            warn!("{repo} has not been cloned. Run without --commit first.");
+            cx.report.skip("Repository was never cloned");
+            return Ok(Flow::Stop);
+        };
+        // The manifest entry is removed before cleaning starts, but a clone left partway through
+        // changing is never pushed, whatever the manifest says
+        if let Some(step) = incomplete_step(&job.dir) {
+            error!("{repo} was left partway through the {step} step, so it will not be pushed. Run `rollback` to restore it from its backup, then clean it again.");
+            cx.report.fail(format!("Left partway through the {step} step"));
+            return Ok(Flow::Stop);
+        }
+
+        // Only push exactly what the clean phase finished rewriting
+        let Some(cleaned) = Manifest::load(&cleaner.dir)?.get(repo).cloned() else {
+            warn!("{repo} was not cleaned successfully, so it will not be pushed. Run without --commit first.");
+            cx.report.skip("Not cleaned successfully");
+            return Ok(Flow::Stop);
+        };
+        let tips = cleaner.branch_tips(job)?;
+        if tips != cleaned.branches {
+            let changed = cleaned.branches.keys().chain(tips.keys())
+                .find(|branch| tips.get(*branch) != cleaned.branches.get(*branch))
+                .expect("the branches differ");
+            error!("{repo} will not be pushed, since branch {changed} changed after it was cleaned at {}. Run without --commit to clean it again.", cleaned.cleaned_at);
+            cx.report.fail(format!("Branch {changed} changed after cleaning"));
+            return Ok(Flow::Stop);
+        }
+        // A destination of its own still needs the history, even if it is unchanged
+        if cleaned.unchanged && job.push_to.is_none() {
+            info!("{repo} has nothing to push, since no rule matched anything in it");
+            cx.report.mark_clean("No rule matched anything in it");
+            return Ok(Flow::Stop);
+        }
+
+        cx.old_head = std::fs::read_to_string(old_head_path(&job.dir)).ok();
+        cx.repository = Some(repository);
+        Ok(Flow::Continue)
+    }
+}
+
+/// Check the repository can be pushed: that we are online, its history is intact, and no protected
+/// identity was touched. Works out what to push.
+#[derive(Debug, Clone, Copy)]
+pub struct PushCheckStep;
+
+impl Step for PushCheckStep {
+    fn name(&self) -> &'static str {
+        "check"
+    }
+
+    fn run(&self, cleaner: &Cleaner, cx: &mut StepContext<'_>) -> Result<Flow> {
+        let job = cx.job;
+        let repo = &job.repository;
+        let repo_dir = job.dir.as_path();
+
+        let remote = job.push_to.as_deref().unwrap_or("origin");
+        if cleaner.options.offline && !is_local(job.push_to.as_deref().unwrap_or(&job.url)) {
+            warn!("Not pushing {repo}, since we are offline. Run --commit again once online.");
+            cx.report.skip("Offline");
+            return Ok(Flow::Stop);
+        }
+        match &job.push_to {
+            Some(url) => info!("Publishing {repo} to {url}, leaving the original untouched"),
+            None => info!("Force pushing {repo}"),
+        }
+
+        // Run fsck
+        if let Err(e) = git(repo_dir, &["fsck", "--full", "--strict"]) {
+            error!("FSCK failed on repository {repo}. This repository will not be force pushed, as there is a chance the repo cleaning may have damaged the commit history. Manual review and pushing may be required. Use the command `git push --all --force` once you have confirmed that the repository's history is intact, or have fixed the issues.");
+            cx.report.fail(format!("fsck failed: {e}"));
+            return Ok(Flow::Stop);
+        }
+
+        let mut args = vec!["push".to_string(), "--force".to_string(), remote.to_string()];
+        let branches = cleaner.selected_branches(job)?;
+        if !job.config.push_refspecs.is_empty() {
+            args.extend(job.config.push_refspecs.iter().cloned());
+        } else if job.config.branches.is_empty() {
+            args.push("--all".to_string());
+        } else if branches.is_empty() {
+            warn!("None of the configured branches of {repo} exist, so there is nothing to push");
+            cx.report.skip("No configured branches exist");
+            return Ok(Flow::Stop);
+        } else {
+            args.extend(branches);
+        }
+
+        // Signing or manual edits since the clean phase could still have touched a protected identity
+        if let Err(e) = verify::check(repo_dir, &cleaner.config) {
+            error!("{repo} will not be force pushed: {e}");
+            cx.report.fail(format!("{e}"));
+            return Ok(Flow::Stop);
+        }
+
+        cx.push_args = args;
+        Ok(Flow::Continue)
+    }
+}
+
+/// Find which refs pushing would move, from what to what, without pushing anything
+#[derive(Debug, Clone, Copy)]
+pub struct PreviewStep;
+
+impl Step for PreviewStep {
+    fn name(&self) -> &'static str {
+        "preview"
+    }
+
+    fn run(&self, cleaner: &Cleaner, cx: &mut StepContext<'_>) -> Result<Flow> {
+        let job = cx.job;
+        let remote = job.push_to.as_deref().unwrap_or("origin");
+        let args = cx.push_args.iter().map(String::as_str).collect::<Vec<_>>();
+
+        let env = cleaner.git_env(job, job.push_to.as_deref().unwrap_or(&job.url))?;
+        cx.report.ref_updates = git::push_preview(&job.dir, &args, &env)
+            .wrap_err(format!("Unable to preview pushing to {remote}"))?;
+        cx.report.ref_updates.extend(git::push_preview(&job.dir, &["push", "--force", remote, "--tags"], &env)?);
+        if !git::notes_refs(cx.repository()?)?.is_empty() {
+            cx.report.ref_updates.extend(git::push_preview(&job.dir, &["push", "--force", remote, "refs/notes/*:refs/notes/*"], &env)?);
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+/// Force push the rewritten branches, and their LFS objects first
+#[derive(Debug, Clone, Copy)]
+pub struct PushStep;
+
+impl Step for PushStep {
+    fn name(&self) -> &'static str {
+        "push"
+    }
+
+    fn run(&self, cleaner: &Cleaner, cx: &mut StepContext<'_>) -> Result<Flow> {
+        let job = cx.job;
+        let repo = &job.repository;
+        let repo_dir = job.dir.as_path();
+        let remote = job.push_to.as_deref().unwrap_or("origin");
+        let retry = &cleaner.options.retry;
+        let timeout = cleaner.options.timeouts.push;
+
+        let url = job.push_to.as_deref().unwrap_or(&job.url);
+        cleaner.push_throttle.wait(repo);
+        // After waiting, since the wait can outlast a GitHub App token
+        let env = cleaner.git_env(job, url)?;
+
+        if let (true, Some(url)) = (cleaner.options.create_destination, &job.push_to) {
+            if !is_local(url) {
+                cleaner.create_destination(job, url)?;
+            }
+        }
+
+        // LFS objects go first, so the pushed history never points at objects the remote doesn't have
+        if cleaner.lfs_ready(job) {
+            retry.run(&format!("Pushing LFS objects for {repo}"), || git_with(repo_dir, &["lfs", "push", "--all", remote], timeout, &env))
+                .wrap_err("Unable to push LFS objects")?;
+        }
+
+        let mut args = cx.push_args.iter().map(String::as_str).collect::<Vec<_>>();
+        // Tags were rewritten along with the commits they point to, including moved release tags,
+        // and a new destination has none of the original's, so every tag is force pushed
+        args.push("--tags");
+        let repository = cx.repository()?;
+        // The original history is archived before anything replaces it
+        if let Some(archive) = &cleaner.options.push_originals_to {
+            let archive = archive.replace("{repository}", repo).replace("{name}", job.config.short_name());
+            let archive_env = cleaner.git_env(job, &archive)?;
+            let refspecs = git::quarantine_refspecs(repository)?;
+            retry.run(&format!("Pushing the original history of {repo}"), || cleaner.push_refs(repository, &archive, &archive_env, &refspecs, cx.progress))
+                .wrap_err(format!("Unable to push the original history to {archive}"))?;
+        }
+        let mut refspecs = git::push_refspecs(repository, &args)?;
+        // Notes are keyed by the ids of the commits they annotate, so were moved onto the rewritten ones
+        refspecs.extend(git::notes_refspecs(repository)?);
+        retry.run(&format!("Pushing {repo}"), || cleaner.push_refs(repository, url, &env, &refspecs, cx.progress))?;
+        cx.report.pushed = true;
+        Ok(Flow::Continue)
+    }
+}
+
+/// Point the remote's HEAD at the repository's `default_branch`, or else the original's default
+/// branch, since a mirror or a rewrite that renamed branches can leave it at one that is gone
+#[derive(Debug, Clone, Copy)]
+pub struct DefaultBranchStep;
+
+impl Step for DefaultBranchStep {
+    fn name(&self) -> &'static str {
+        "default-branch"
+    }
+
+    fn run(&self, cleaner: &Cleaner, cx: &mut StepContext<'_>) -> Result<Flow> {
+        let job = cx.job;
+        let repo = &job.repository;
+        let wanted = match &job.config.default_branch {
+            Some(branch) => branch.clone(),
+            None => match git::default_branch(cx.repository()?) {
+                Some(branch) => branch,
+                None => return Ok(Flow::Continue),
+            },
+        };
+
+        let url = job.push_to.as_deref().unwrap_or(&job.url);
+        let (head, branches) = git::remote_branches(&job.dir, &cleaner.options.ssh.url(url), cleaner.options.timeouts.push, &cleaner.git_env(job, url)?)
+            .wrap_err(format!("Unable to look up the default branch of {url}"))?;
+        let current = head.as_deref().unwrap_or("a branch that doesn't exist");
+        if head.as_ref() == Some(&wanted) {
+            return Ok(Flow::Continue);
+        }
+        if !branches.contains(&wanted) {
+            warn!("{repo} should have {wanted} as its default branch, but it wasn't pushed, so HEAD is left at {current}");
+            return Ok(Flow::Continue);
+        }
+
+        if is_local(url) {
+            let destination = Repository::open(url.strip_prefix("file://").unwrap_or(url))
+                .wrap_err(format!("Unable to open {url}"))?;
+            // Moving a working tree's HEAD would leave its files belonging to another branch
+            if !destination.is_bare() {
+                warn!("{url} has a working tree, so its HEAD is left at {current} rather than moved to {wanted}");
+                return Ok(Flow::Continue);
+            }
+            destination.set_head(&format!("refs/heads/{wanted}"))?;
+        } else {
+            let forge = match cleaner.forge(job) {
+                Ok(forge) => forge,
+                Err(e) => {
+                    warn!("The default branch of {repo} is {current} rather than {wanted}, and can't be changed: {e}. Change it by hand");
+                    return Ok(Flow::Continue);
+                },
+            };
+            let name = match &job.push_to {
+                Some(url) => submodule::repository_name(url, repo).ok_or_else(|| eyre!("{url} does not name a {} repository", job.config.provider))?,
+                None => repo.clone(),
+            };
+            forge.set_default_branch(&name, &wanted)?;
+        }
+        info!("Made {wanted} the default branch of {repo}, instead of {current}");
+        cx.report.default_branch = Some(wanted);
+        Ok(Flow::Continue)
+    }
+}
+
+/// Point the releases made from release tags at the commits the tags were moved to, where they
+/// name a commit rather than a branch
+#[derive(Debug, Clone, Copy)]
+pub struct ReleaseStep;
+
+impl Step for ReleaseStep {
+    fn name(&self) -> &'static str {
+        "releases"
+    }
+
+    fn run(&self, cleaner: &Cleaner, cx: &mut StepContext<'_>) -> Result<Flow> {
+        let job = cx.job;
+        let repo = &job.repository;
+        let Some(releases) = &cleaner.config.releases else {
+            return Ok(Flow::Continue);
+        };
+        // Local repositories have no releases
+        if is_local(job.push_to.as_deref().unwrap_or(&job.url)) {
+            return Ok(Flow::Continue);
+        }
+        let forge = match cleaner.forge(job) {
+            Ok(forge) => forge,
+            Err(e) => {
+                warn!("The releases of {repo} can't be moved onto the rewritten commits: {e}. Move them by hand");
+                return Ok(Flow::Continue);
+            },
+        };
+        let name = match &job.push_to {
+            Some(url) => submodule::repository_name(url, repo).ok_or_else(|| eyre!("{url} does not name a {} repository", job.config.provider))?,
+            None => repo.clone(),
+        };
+
+        let repository = cx.repository()?;
+        let mut moved = Vec::new();
+        for found in forge.releases(&name)? {
+            if !releases.matches(&found.tag_name) || !release::targets_commit(&found.target_commitish) {
+                continue;
+            }
+            let Ok(commit) = repository.revparse_single(&format!("refs/tags/{}^{{commit}}", found.tag_name)) else {
+                continue;
+            };
+            let commit = commit.id().to_string();
+            if found.target_commitish != commit {
+                forge.set_release_target(&name, found.id, &commit)?;
+                info!("Moved release {} of {repo} from {} to {commit}", found.tag_name, found.target_commitish);
+                moved.push(found.tag_name);
+            }
+        }
+        cx.report.releases_moved = moved;
+        Ok(Flow::Continue)
+    }
+}
+
+/// Tell collaborators about the rewrite, and archive the original once it is published elsewhere
+#[derive(Debug, Clone, Copy)]
+pub struct NotifyStep;
+
+impl Step for NotifyStep {
+    fn name(&self) -> &'static str {
+        "notify"
+    }
+
+    fn run(&self, cleaner: &Cleaner, cx: &mut StepContext<'_>) -> Result<Flow> {
+        let job = cx.job;
+        cleaner.open_issue(job, cx.old_head.as_deref());
+
+        // The original is only archived once nothing can fail anymore
+        if cleaner.options.archive_original && job.push_to.is_some() && job.config.local_path().is_none() {
+            cleaner.forge(job)?.archive(&job.repository)?;
+        }
+        Ok(Flow::Continue)
+    }
+}
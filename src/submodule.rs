@@ -0,0 +1,155 @@
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use eyre::{Context, Result};
+use git2::Repository;
+use tracing::warn;
+
+use crate::{config::RepositoryConfig, git::git_dir, rewrite};
+
+
+/// The configured repositories that `repository` (called `name`) uses as submodules
+pub fn dependencies(repository: &Repository, name: &str, configured: &[RepositoryConfig]) -> Vec<String> {
+    dependencies_of(&urls(repository), name, configured)
+}
+
+/// The URL of every submodule of `repository`
+pub(crate) fn urls(repository: &Repository) -> Vec<String> {
+    let Ok(submodules) = repository.submodules() else {
+        return Vec::new();
+    };
+    submodules.iter().filter_map(|s| s.url()).map(str::to_string).collect()
+}
+
+/// The URL of every submodule in the `.gitmodules` file at `path`
+pub(crate) fn gitmodules_urls(path: &Path) -> Result<Vec<String>> {
+    let config = git2::Config::open(path).wrap_err(format!("Unable to read {path:?}"))?;
+    let mut urls = Vec::new();
+    let mut entries = config.entries(Some(r"submodule\..*\.url"))?;
+    while let Some(entry) = entries.next() {
+        urls.extend(entry?.value().map(str::to_string));
+    }
+    Ok(urls)
+}
+
+/// The configured repositories that the submodules at `urls`, of the repository called `name`, are
+pub(crate) fn dependencies_of(urls: &[impl AsRef<str>], name: &str, configured: &[RepositoryConfig]) -> Vec<String> {
+    let superproject = configured.iter().find(|r| r.name == name).and_then(|r| r.local_path());
+    urls.iter()
+        .map(AsRef::as_ref)
+        .filter_map(|url| configured.iter().find(|r| match (r.local_path(), local_path(url, superproject)) {
+            (Some(path), Some(url)) => same_path(path, &url),
+            (None, None) => repository_name(url, name).is_some_and(|dep| r.name.eq_ignore_ascii_case(&dep)),
+            _ => false,
+        }))
+        .map(|r| r.name.clone())
+        .filter(|dep| dep != name)
+        .collect()
+}
+
+/// The path a submodule URL points to, if it is local. Relative URLs are resolved against a local `superproject`.
+fn local_path(url: &str, superproject: Option<&Path>) -> Option<PathBuf> {
+    if let Some(path) = url.strip_prefix("file://") {
+        return Some(PathBuf::from(path));
+    }
+    match superproject {
+        // Relative submodule URLs are relative to the superproject's own URL
+        Some(superproject) if url.starts_with("../") || url.starts_with("./") => Some(superproject.join(url)),
+        _ if Path::new(url).is_absolute() => Some(PathBuf::from(url)),
+        _ => None,
+    }
+}
+
+fn same_path(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// The path of the repository a URL points to, like `Org/reponame` or `group/subgroup/project`.
+/// Relative URLs are resolved against `superproject`, the path of the repository they are in.
+pub(crate) fn repository_name(url: &str, superproject: &str) -> Option<String> {
+    let url = url.trim_end_matches('/');
+    let url = url.strip_suffix(".git").unwrap_or(url);
+
+    // Like git, the first `../` steps out of the superproject's name, and every one after it out of a group
+    if let Some(mut rest) = url.strip_prefix("../") {
+        let mut path = superproject.split('/').collect::<Vec<_>>();
+        path.pop();
+        while let Some(stripped) = rest.strip_prefix("../") {
+            path.pop()?;
+            rest = stripped;
+        }
+        path.push(rest);
+        return Some(path.join("/"));
+    }
+
+    // Handles `https://host/Org/repo`, `ssh://git@host:2222/group/sub/repo`, and `git@host:Org/repo`
+    let path = match url.split_once("://") {
+        Some((_, rest)) => rest.split_once('/')?.1,
+        None => url.split_once(':')?.1,
+    };
+    // Azure DevOps has `v3/` before the org over ssh, and `_git/` before the repository over HTTPS
+    let path = path.strip_prefix("v3/").unwrap_or(path).replacen("/_git/", "/", 1);
+    path.contains('/').then_some(path)
+}
+
+/// Order repositories so every repository comes after the configured repositories it uses as submodules.
+/// Otherwise the config order is kept. Cycles can't be ordered, so they are left as they are.
+pub fn dependency_order(repositories: &[RepositoryConfig], dependencies: &HashMap<String, Vec<String>>) -> Vec<RepositoryConfig> {
+    fn visit<'a>(
+        repo: &'a RepositoryConfig,
+        repositories: &'a [RepositoryConfig],
+        dependencies: &HashMap<String, Vec<String>>,
+        visiting: &mut Vec<&'a str>,
+        ordered: &mut Vec<RepositoryConfig>,
+    ) {
+        if ordered.contains(repo) {
+            return;
+        }
+        if visiting.contains(&repo.name.as_str()) {
+            warn!("Submodules of {} form a cycle, so their pointers may not all be rewritten", repo.name);
+            return;
+        }
+
+        visiting.push(&repo.name);
+        for dep in dependencies.get(&repo.name).into_iter().flatten() {
+            if let Some(dep) = repositories.iter().find(|r| &r.name == dep) {
+                visit(dep, repositories, dependencies, visiting, ordered);
+            }
+        }
+        visiting.pop();
+
+        if !ordered.contains(repo) {
+            ordered.push(repo.clone());
+        }
+    }
+
+    let mut ordered = Vec::new();
+    for repo in repositories {
+        visit(repo, repositories, dependencies, &mut Vec::new(), &mut ordered);
+    }
+    ordered
+}
+
+/// Merge the commit maps of every already rewritten submodule into a single file in `repo_dir`.
+/// Returns `None` if none of the submodules have been rewritten.
+pub fn write_gitlink_map(repo_dir: &Path, submodule_dirs: &[PathBuf]) -> Result<Option<PathBuf>> {
+    let mut text = String::new();
+    for dir in submodule_dirs {
+        if let Ok(map) = std::fs::read_to_string(rewrite::commit_map_path(dir)) {
+            text.push_str(&map);
+        }
+    }
+
+    if text.is_empty() {
+        return Ok(None);
+    }
+
+    let path = git_dir(repo_dir).join("repo-cleaner-gitlink-map");
+    std::fs::write(&path, text)
+        .wrap_err(format!("Unable to write submodule commit map to {path:?}"))?;
+
+    // filter-repo runs inside the repository, so a relative path would no longer resolve
+    Ok(Some(std::path::absolute(path)?))
+}
@@ -0,0 +1,329 @@
+use std::{collections::BTreeMap, fmt, fs::File, path::Path};
+
+use chrono::{DateTime, Utc};
+use eyre::{Context, Result};
+use indicatif::HumanBytes;
+use serde::{Deserialize, Serialize};
+
+use crate::{allowlist::DisallowedEmail, attribution::{IdentityStats, MergeReport}, cache::RewriteScope, error::FailureKind, git::RefUpdate, leaks::LeakedEmail, release::MovedTag, signatures::InvalidatedSignature, tags::{TagKind, TagReport}};
+
+
+/// The final outcome of a single repository
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RepoStatus {
+    Succeeded,
+    /// No rule matched anything in the repository, so it was left as it is
+    Clean,
+    Skipped,
+    Failed,
+}
+
+impl fmt::Display for RepoStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepoStatus::Succeeded => write!(f, "succeeded"),
+            RepoStatus::Clean => write!(f, "clean"),
+            RepoStatus::Skipped => write!(f, "skipped"),
+            RepoStatus::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// Everything that happened to a single repository during a run
+#[derive(Serialize, Debug, Clone)]
+pub struct RepoReport {
+    pub repository: String,
+    pub status: RepoStatus,
+    /// Why the repository was skipped or failed
+    pub reason: Option<String>,
+    /// Whether the error it failed with is worth retrying, needs the config or credentials fixed, or is fatal
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure: Option<FailureKind>,
+    pub cloned: bool,
+    pub backed_up: bool,
+    /// How large the backup taken before rewriting is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backup_bytes: Option<u64>,
+    pub branches_rewritten: usize,
+    pub commits_changed: usize,
+    /// How much of the history the clean phase had to rewrite, given what earlier runs rewrote
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rewrite_scope: Option<RewriteScope>,
+    /// Commits that were signed before the rewrite, whose signatures it broke
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub invalidated_signatures: Vec<InvalidatedSignature>,
+    /// How many commits each configured rule matched
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub rule_matches: BTreeMap<String, usize>,
+    /// How many commits each author has before and after the rewrite
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attribution: Vec<IdentityStats>,
+    /// Every tag, and who tagged the annotated ones before and after the rewrite
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<TagReport>,
+    /// How many commits each `merge_identities` entry folded into its canonical identity
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub merges: Vec<MergeReport>,
+    /// Release tags moved onto the rewritten commits
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub release_tags: Vec<MovedTag>,
+    /// Releases moved onto the rewritten commits, by tag
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub releases_moved: Vec<String>,
+    /// How much garbage collection shrank the repository, if it ran
+    pub reclaimed_bytes: Option<u64>,
+    pub signed: bool,
+    pub pushed: bool,
+    /// The branch pushing made the remote's default, if it had to change it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_branch: Option<String>,
+    /// The refs a previewed push would update
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ref_updates: Vec<RefUpdate>,
+    /// Author and committer emails that aren't on the allowlist being enforced
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub disallowed_emails: Vec<DisallowedEmail>,
+    /// Old emails found in file contents, with --scan-blobs
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub leaked_emails: Vec<LeakedEmail>,
+    /// Open pull requests on the original, if they could be looked up
+    pub open_pull_requests: Option<usize>,
+    /// Forks of the original, if they could be looked up
+    pub forks: Option<u64>,
+    /// How long processing the repository took, in seconds
+    pub seconds: f64,
+}
+
+impl RepoReport {
+    pub fn new(repository: &str) -> Self {
+        Self {
+            repository: repository.to_string(),
+            status: RepoStatus::Succeeded,
+            reason: None,
+            failure: None,
+            cloned: false,
+            backed_up: false,
+            backup_bytes: None,
+            branches_rewritten: 0,
+            commits_changed: 0,
+            rewrite_scope: None,
+            invalidated_signatures: Vec::new(),
+            rule_matches: BTreeMap::new(),
+            attribution: Vec::new(),
+            tags: Vec::new(),
+            merges: Vec::new(),
+            release_tags: Vec::new(),
+            releases_moved: Vec::new(),
+            reclaimed_bytes: None,
+            signed: false,
+            pushed: false,
+            default_branch: None,
+            ref_updates: Vec::new(),
+            disallowed_emails: Vec::new(),
+            leaked_emails: Vec::new(),
+            open_pull_requests: None,
+            forks: None,
+            seconds: 0.0,
+        }
+    }
+
+    /// Mark the repository as skipped
+    pub fn skip(&mut self, reason: impl Into<String>) {
+        self.status = RepoStatus::Skipped;
+        self.reason = Some(reason.into());
+    }
+
+    /// Mark the repository as left as it is, since nothing in it needed cleaning
+    pub fn mark_clean(&mut self, reason: impl Into<String>) {
+        self.status = RepoStatus::Clean;
+        self.reason = Some(reason.into());
+    }
+
+    /// Mark the repository as failed
+    pub fn fail(&mut self, reason: impl Into<String>) {
+        self.status = RepoStatus::Failed;
+        self.reason = Some(reason.into());
+    }
+
+    /// Whether the repository was processed without trouble, including when it was left clean
+    pub fn succeeded(&self) -> bool {
+        matches!(self.status, RepoStatus::Succeeded | RepoStatus::Clean)
+    }
+}
+
+/// Summary of every repository processed in a run
+#[derive(Serialize, Debug)]
+pub struct Summary {
+    /// Which phase of the tool produced this summary ("plan", "audit", "clean", "preview", "commit", or "enforce")
+    pub phase: String,
+    pub repositories: Vec<RepoReport>,
+    /// When the phase started, which the run history times it from
+    #[serde(skip)]
+    pub started_at: DateTime<Utc>,
+}
+
+impl Summary {
+    pub fn new(phase: &str) -> Self {
+        Self {
+            phase: phase.to_string(),
+            repositories: Vec::new(),
+            started_at: Utc::now(),
+        }
+    }
+
+    /// Record every repository in `repositories` as skipped for the same reason
+    pub fn skip_remaining<S: AsRef<str>>(&mut self, repositories: impl IntoIterator<Item = S>, reason: &str) {
+        for repo in repositories {
+            let mut report = RepoReport::new(repo.as_ref());
+            report.skip(reason);
+            self.repositories.push(report);
+        }
+    }
+
+    /// Whether any repository was skipped or failed
+    pub fn has_failures(&self) -> bool {
+        self.repositories.iter().any(|r| !r.succeeded())
+    }
+
+    /// Write the summary as JSON to the given path
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)
+            .wrap_err(format!("Unable to create summary file {path:?}"))?;
+        serde_json::to_writer_pretty(file, self)
+            .wrap_err("Error writing summary")
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let yes_no = |v: bool| if v { "yes" } else { "no" }.to_string();
+        let count = |v: Option<u64>| v.map_or("-".to_string(), |v| v.to_string());
+
+        let header = ["Repository", "Status", "Cloned", "Backed up", "Branches", "Commits", "Broken sigs", "Reclaimed", "Signed", "Pushed", "Open PRs", "Forks", "Reason"];
+        let rows = self.repositories.iter()
+            .map(|r| [
+                r.repository.clone(),
+                r.failure.map_or(r.status.to_string(), |kind| format!("{} ({kind})", r.status)),
+                yes_no(r.cloned),
+                yes_no(r.backed_up),
+                r.branches_rewritten.to_string(),
+                r.commits_changed.to_string(),
+                r.invalidated_signatures.len().to_string(),
+                r.reclaimed_bytes.map_or("-".to_string(), |b| HumanBytes(b).to_string()),
+                yes_no(r.signed),
+                yes_no(r.pushed),
+                count(r.open_pull_requests.map(|n| n as u64)),
+                count(r.forks),
+                r.reason.clone().unwrap_or_default(),
+            ])
+            .collect::<Vec<_>>();
+
+        writeln!(f, "Summary ({} phase)", self.phase)?;
+        write_table(f, header, &rows)?;
+
+        // Rules that match nothing, or far more than expected, are easiest to spot side by side
+        let matches = self.repositories.iter()
+            .flat_map(|r| r.rule_matches.iter().map(|(rule, count)| [r.repository.clone(), rule.clone(), count.to_string()]))
+            .collect::<Vec<_>>();
+        if !matches.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "Commits matched by each rule")?;
+            write_table(f, ["Repository", "Rule", "Commits"], &matches)?;
+        }
+
+        let merges = self.repositories.iter()
+            .flat_map(|r| r.merges.iter().map(|m| [
+                r.repository.clone(),
+                m.identity.clone(),
+                m.commits_folded.to_string(),
+                m.merged_from.iter().map(|(from, count)| format!("{from} ({count})")).collect::<Vec<_>>().join(", "),
+            ]))
+            .collect::<Vec<_>>();
+        if !merges.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "Merged identities")?;
+            write_table(f, ["Repository", "Identity", "Commits folded", "Merged from"], &merges)?;
+        }
+
+        // Lightweight tags only move with their commits, but annotated ones name a tagger of their own
+        let tags = self.repositories.iter()
+            .filter(|r| !r.tags.is_empty())
+            .map(|r| {
+                let annotated = r.tags.iter().filter(|t| t.kind == TagKind::Annotated).count();
+                [
+                    r.repository.clone(),
+                    (r.tags.len() - annotated).to_string(),
+                    annotated.to_string(),
+                    r.tags.iter().filter(|t| t.tagger_rewritten()).count().to_string(),
+                ]
+            })
+            .collect::<Vec<_>>();
+        if !tags.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "Tags")?;
+            write_table(f, ["Repository", "Lightweight", "Annotated", "Taggers rewritten"], &tags)?;
+        }
+
+        let updates = self.repositories.iter()
+            .flat_map(|r| r.ref_updates.iter().map(|u| [
+                r.repository.clone(),
+                u.remote_ref.clone(),
+                u.from.clone().unwrap_or_else(|| "-".to_string()),
+                u.to.clone().unwrap_or_else(|| "-".to_string()),
+                u.kind.clone(),
+            ]))
+            .collect::<Vec<_>>();
+        if !updates.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "Refs the push would update")?;
+            write_table(f, ["Repository", "Ref", "From", "To", "Update"], &updates)?;
+        }
+
+        let disallowed = self.repositories.iter()
+            .flat_map(|r| r.disallowed_emails.iter().map(|d| [r.repository.clone(), d.commit.clone(), d.field.to_string(), d.email.clone()]))
+            .collect::<Vec<_>>();
+        if !disallowed.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "Emails not on the allowlist")?;
+            write_table(f, ["Repository", "Commit", "Field", "Email"], &disallowed)?;
+        }
+
+        let leaked = self.repositories.iter()
+            .flat_map(|r| r.leaked_emails.iter().map(|l| [r.repository.clone(), l.email.clone(), l.path.clone(), l.blob.clone()]))
+            .collect::<Vec<_>>();
+        if !leaked.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "Old emails in file contents, which only a content rewrite replaces")?;
+            write_table(f, ["Repository", "Email", "Path", "Blob"], &leaked)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Write `rows` under `header`, sizing every column to its widest cell
+pub(crate) fn write_table<const N: usize>(f: &mut fmt::Formatter<'_>, header: [&str; N], rows: &[[String; N]]) -> fmt::Result {
+    let mut widths = header.map(str::len);
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let write_row = |f: &mut fmt::Formatter<'_>, cells: &[&str]| {
+        let line = cells.iter().zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join(" | ");
+        writeln!(f, "{}", line.trim_end())
+    };
+
+    write_row(f, &header)?;
+    writeln!(f, "{}", widths.map(|w| "-".repeat(w)).join("-+-"))?;
+    for row in rows {
+        write_row(f, &row.each_ref().map(String::as_str))?;
+    }
+
+    Ok(())
+}
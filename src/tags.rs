@@ -0,0 +1,71 @@
+use std::collections::BTreeMap;
+
+use eyre::Result;
+use git2::{ObjectType, Repository, Signature};
+use serde::Serialize;
+
+
+/// Whether a tag is a bare ref to a commit, or a tag object with its own tagger and message
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TagKind {
+    Lightweight,
+    Annotated,
+}
+
+/// A tag of a repository, and who tagged it before and after the rewrite. Lightweight tags have
+/// no tagger, so only ever move with the commit they point to.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TagReport {
+    pub name: String,
+    pub kind: TagKind,
+    /// `Name <email>` of an annotated tag's tagger before the rewrite
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tagger_before: Option<String>,
+    /// `Name <email>` of an annotated tag's tagger after the rewrite, unless the tag was dropped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tagger_after: Option<String>,
+}
+
+impl TagReport {
+    /// Whether the rewrite changed the tagger of this tag
+    pub fn tagger_rewritten(&self) -> bool {
+        self.kind == TagKind::Annotated && self.tagger_before != self.tagger_after
+    }
+}
+
+fn identity(signature: &Signature) -> String {
+    format!("{} <{}>", String::from_utf8_lossy(signature.name_bytes()), String::from_utf8_lossy(signature.email_bytes()))
+}
+
+/// Every tag of `repository`, with its kind and the tagger of annotated ones
+pub fn inventory(repository: &Repository) -> Result<BTreeMap<String, (TagKind, Option<String>)>> {
+    let mut tags = BTreeMap::new();
+    for reference in repository.references_glob("refs/tags/*")? {
+        let reference = reference?;
+        let (Some(name), Some(target)) = (reference.shorthand(), reference.target()) else {
+            continue;
+        };
+
+        let tag = match repository.find_object(target, None)?.kind() {
+            Some(ObjectType::Tag) => repository.find_tag(target)?,
+            _ => {
+                tags.insert(name.to_string(), (TagKind::Lightweight, None));
+                continue;
+            },
+        };
+        tags.insert(name.to_string(), (TagKind::Annotated, tag.tagger().as_ref().map(identity)));
+    }
+    Ok(tags)
+}
+
+/// Every tag in `before`, an [`inventory`] taken before rewriting, with who tags it in `repository` now
+pub fn compare(repository: &Repository, before: BTreeMap<String, (TagKind, Option<String>)>) -> Result<Vec<TagReport>> {
+    let mut after = inventory(repository)?;
+    Ok(before.into_iter()
+        .map(|(name, (kind, tagger_before))| {
+            let tagger_after = after.remove(&name).and_then(|(_, tagger)| tagger);
+            TagReport { name, kind, tagger_before, tagger_after }
+        })
+        .collect())
+}
@@ -0,0 +1,157 @@
+use std::{sync::Mutex, time::SystemTime};
+
+use eyre::{Context as _, Result};
+use opentelemetry::{
+    metrics::{Counter, MeterProvider as _},
+    trace::{Status, TraceContextExt, Tracer as _, TracerProvider as _},
+    Context, KeyValue,
+};
+use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    metrics::{PeriodicReader, SdkMeterProvider},
+    trace::{SdkTracer, SdkTracerProvider},
+    Resource,
+};
+use tracing::{debug, warn};
+
+use crate::summary::Summary;
+
+
+/// Sends a span for every repository and each step it went through, and counters of what every
+/// phase did, to an OpenTelemetry collector over OTLP/HTTP
+#[derive(Debug)]
+pub struct Telemetry {
+    /// The collector's base URL, which `/v1/traces` and `/v1/metrics` are added to
+    endpoint: String,
+    traces: SdkTracerProvider,
+    tracer: SdkTracer,
+    metrics: SdkMeterProvider,
+    repositories: Counter<u64>,
+    commits_rewritten: Counter<u64>,
+    backup_bytes: Counter<u64>,
+    phase: Mutex<Phase>,
+}
+
+#[derive(Debug)]
+struct Phase {
+    /// When the phase being recorded started, which is when the last one was exported
+    started: SystemTime,
+    /// The phase's span, which spans without a parent are part of, once it has any
+    cx: Option<Context>,
+}
+
+/// A span being timed, recorded once it is finished
+#[derive(Debug)]
+pub struct Span {
+    cx: Context,
+}
+
+impl Span {
+    /// Record a string attribute on the span
+    pub fn attribute(&mut self, key: &str, value: &str) {
+        self.cx.span().set_attribute(KeyValue::new(key.to_string(), value.to_string()));
+    }
+
+    /// Record a count as an attribute on the span
+    pub fn count(&mut self, key: &str, value: u64) {
+        self.cx.span().set_attribute(KeyValue::new(key.to_string(), i64::try_from(value).unwrap_or(i64::MAX)));
+    }
+
+    /// End the span, as failed with `error` if there is one
+    pub fn finish(self, error: Option<&eyre::Report>) {
+        let span = self.cx.span();
+        span.set_status(match error {
+            Some(e) => Status::error(format!("{e:#}")),
+            None => Status::Ok,
+        });
+        span.end();
+    }
+}
+
+impl Telemetry {
+    /// Export to the collector at `endpoint`, like `http://localhost:4318`. Headers are read from
+    /// `OTEL_EXPORTER_OTLP_HEADERS` as `key=value,key=value`, and the service name from
+    /// `OTEL_SERVICE_NAME`, as the OpenTelemetry SDKs do.
+    pub fn new(endpoint: &str) -> Result<Self> {
+        let endpoint = endpoint.trim_end_matches('/').to_string();
+        let resource = Resource::builder()
+            .with_service_name(std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| env!("CARGO_PKG_NAME").to_string()))
+            .build();
+
+        let spans = SpanExporter::builder().with_http().with_endpoint(format!("{endpoint}/v1/traces")).build()
+            .wrap_err(format!("Unable to export traces to {endpoint}"))?;
+        let traces = SdkTracerProvider::builder().with_batch_exporter(spans).with_resource(resource.clone()).build();
+        let tracer = traces.tracer(env!("CARGO_PKG_NAME"));
+
+        let counters = MetricExporter::builder().with_http().with_endpoint(format!("{endpoint}/v1/metrics")).build()
+            .wrap_err(format!("Unable to export metrics to {endpoint}"))?;
+        let metrics = SdkMeterProvider::builder().with_reader(PeriodicReader::builder(counters).build()).with_resource(resource).build();
+        let meter = metrics.meter(env!("CARGO_PKG_NAME"));
+
+        Ok(Self {
+            endpoint,
+            repositories: meter.u64_counter("repo_cleaner.repositories")
+                .with_unit("{repository}").with_description("Repositories processed, by phase and outcome").build(),
+            commits_rewritten: meter.u64_counter("repo_cleaner.commits.rewritten")
+                .with_unit("{commit}").with_description("Commits the clean phase rewrote").build(),
+            backup_bytes: meter.u64_counter("repo_cleaner.backup.bytes")
+                .with_unit("By").with_description("Bytes of backups taken before rewriting").build(),
+            phase: Mutex::new(Phase { started: SystemTime::now(), cx: None }),
+            traces,
+            tracer,
+            metrics,
+        })
+    }
+
+    /// Start a span called `name`, as part of `parent`, or of the phase without one
+    pub fn span(&self, name: &str, parent: Option<&Span>) -> Span {
+        let span = match parent {
+            Some(parent) => self.tracer.start_with_context(name.to_string(), &parent.cx),
+            None => {
+                let mut phase = self.phase.lock().expect("telemetry is never poisoned");
+                self.tracer.start_with_context(name.to_string(), self.phase_context(&mut phase))
+            },
+        };
+        Span { cx: Context::new().with_span(span) }
+    }
+
+    /// Send every span finished since the last export, as part of a span for the whole of
+    /// `summary`'s phase, and the counters with `summary` added to them. Like notifications,
+    /// failures are logged rather than returned, since they shouldn't fail a run that succeeded.
+    pub fn export(&self, summary: &Summary) {
+        let phase = KeyValue::new("repo_cleaner.phase", summary.phase.clone());
+        for report in &summary.repositories {
+            self.repositories.add(1, &[phase.clone(), KeyValue::new("repo_cleaner.status", report.status.to_string())]);
+            if summary.phase == "clean" {
+                self.commits_rewritten.add(report.commits_changed as u64, std::slice::from_ref(&phase));
+            }
+            self.backup_bytes.add(report.backup_bytes.unwrap_or_default(), std::slice::from_ref(&phase));
+        }
+
+        {
+            let mut phase_state = self.phase.lock().expect("telemetry is never poisoned");
+            let span = self.phase_context(&mut phase_state).span();
+            span.update_name(format!("{} phase", summary.phase));
+            span.set_attribute(phase);
+            span.set_attribute(KeyValue::new("repo_cleaner.repositories", summary.repositories.len() as i64));
+            span.set_status(if summary.has_failures() { Status::error("Some repositories failed") } else { Status::Ok });
+            span.end();
+            *phase_state = Phase { started: SystemTime::now(), cx: None };
+        }
+
+        for (signal, flushed) in [("traces", self.traces.force_flush()), ("metrics", self.metrics.force_flush())] {
+            match flushed {
+                Ok(()) => debug!("Sent {signal} to {}", self.endpoint),
+                Err(e) => warn!("Unable to send {signal} to the OpenTelemetry collector: {e}"),
+            }
+        }
+    }
+
+    /// The span of the phase being recorded, started back when the phase did
+    fn phase_context<'a>(&self, phase: &'a mut Phase) -> &'a Context {
+        phase.cx.get_or_insert_with(|| {
+            let span = self.tracer.span_builder("phase").with_start_time(phase.started).start(&self.tracer);
+            Context::new().with_span(span)
+        })
+    }
+}
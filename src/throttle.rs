@@ -0,0 +1,62 @@
+use std::{sync::Mutex, thread::sleep, time::{Duration, Instant}};
+
+use chrono::Local;
+use tracing::info;
+
+use crate::options::PushWindow;
+
+
+/// How pushes are spread out over time, so a commit phase doesn't set off every repository's CI at once
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PushSchedule {
+    /// How long to wait between the start of one push and the next
+    pub delay: Option<Duration>,
+    /// At most this many pushes start in any hour
+    pub rate: Option<u32>,
+    /// Only start pushes within these hours
+    pub window: Option<PushWindow>,
+}
+
+impl PushSchedule {
+    /// The shortest time between the starts of two pushes, from the delay and the rate
+    fn spacing(&self) -> Duration {
+        let rate = self.rate.map_or(Duration::ZERO, |rate| Duration::from_secs(3600) / rate.max(1));
+        self.delay.unwrap_or_default().max(rate)
+    }
+}
+
+/// Makes the threads pushing repositories take turns, waiting out a [`PushSchedule`]
+#[derive(Debug, Default)]
+pub struct PushThrottle {
+    schedule: PushSchedule,
+    /// When the last push started
+    last: Mutex<Option<Instant>>,
+}
+
+impl PushThrottle {
+    pub fn new(schedule: PushSchedule) -> Self {
+        Self { schedule, last: Mutex::new(None) }
+    }
+
+    /// Wait until `repository` may be pushed. Other threads wait their turn meanwhile, so pushes
+    /// start one at a time, at least the schedule's spacing apart.
+    pub fn wait(&self, repository: &str) {
+        let mut last = self.last.lock().expect("the push throttle is never poisoned");
+
+        let spacing = self.schedule.spacing();
+        if let Some(wait) = last.map(|last| (last + spacing).saturating_duration_since(Instant::now())).filter(|wait| !wait.is_zero()) {
+            info!("Waiting {}s before pushing {repository}", wait.as_secs());
+            sleep(wait);
+        }
+        if let Some(window) = self.schedule.window {
+            let wait = window.until_open(Local::now().time());
+            if !wait.is_zero() {
+                info!("Waiting until {} to push {repository}, since pushes only start within {window}", window.start.format("%H:%M"));
+                sleep(wait);
+            }
+        }
+
+        *last = Some(Instant::now());
+    }
+}
+
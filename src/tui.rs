@@ -0,0 +1,346 @@
+use std::{io::{self, IsTerminal, Stderr, Write}, sync::{Arc, Condvar, Mutex, MutexGuard}, thread, time::Duration};
+
+use eyre::{bail, Result};
+use ratatui::{
+    backend::CrosstermBackend,
+    crossterm::{
+        cursor::{Hide, Show},
+        event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+        execute,
+        terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    },
+    layout::{Constraint, Layout},
+    style::{Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, Borders, Paragraph, Row as TableRow, Table, TableState},
+    Frame, Terminal,
+};
+
+use crate::{RepoProgress, RepoReport, RepoStatus};
+
+
+/// How often the screen is redrawn, and keys are checked for
+const TICK: Duration = Duration::from_millis(100);
+
+/// What to do about a repository that failed while the dashboard was shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// Process it again
+    Retry,
+    /// Leave it failed, and carry on with the next one
+    Skip,
+    /// Stop the run, leaving the remaining repositories unattempted
+    Quit,
+}
+
+/// A full screen view of a run: every repository with its current phase, the log of whichever is
+/// selected, and keys to retry or skip repositories that fail.
+///
+/// Log lines are routed to the repository being processed through [`Dashboard::writer`], so it
+/// should be the writer of the tracing subscriber.
+#[derive(Debug, Default)]
+pub struct Dashboard {
+    state: Mutex<State>,
+    /// Signalled whenever a key changes what the run should do
+    decided: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Whether the dashboard is on screen, and log lines should be kept rather than printed
+    shown: bool,
+    title: String,
+    rows: Vec<Row>,
+    /// Log lines that don't belong to any repository
+    log: Vec<String>,
+    /// The repository being processed
+    current: Option<usize>,
+    selected: usize,
+    /// How many lines the log is scrolled up from its end
+    scroll: usize,
+    /// The failed repository the run is waiting on a decision for
+    paused: Option<usize>,
+    decision: Option<Decision>,
+    /// Whether every repository was processed, and the dashboard is only waiting to be closed
+    finished: bool,
+}
+
+#[derive(Debug)]
+struct Row {
+    repository: String,
+    progress: Option<RepoProgress>,
+    outcome: Option<(RepoStatus, Option<String>)>,
+    log: Vec<String>,
+}
+
+impl Dashboard {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// A writer for the tracing subscriber. Until the dashboard is shown, lines go straight to stderr.
+    pub fn writer(self: &Arc<Self>) -> DashboardWriter {
+        DashboardWriter(self.clone())
+    }
+
+    /// Take over the terminal to show `repositories` being processed
+    pub fn show(self: &Arc<Self>, title: &str, repositories: &[String]) -> Result<()> {
+        if !io::stderr().is_terminal() {
+            bail!("--tui needs a terminal");
+        }
+
+        {
+            let mut state = self.lock();
+            state.shown = true;
+            state.title = title.to_string();
+            state.rows = repositories.iter()
+                .map(|repository| Row { repository: repository.clone(), progress: None, outcome: None, log: Vec::new() })
+                .collect();
+        }
+        // The alternate screen keeps the dashboard from scrolling the shell away
+        enable_raw_mode()?;
+        execute!(io::stderr(), EnterAlternateScreen, Hide)?;
+        let mut terminal = Terminal::new(CrosstermBackend::new(io::stderr()))?;
+
+        // Redraw regularly to follow phases, which the cleaner updates without telling the dashboard
+        let dashboard = self.clone();
+        thread::spawn(move || {
+            while dashboard.draw(&mut terminal) {
+                thread::sleep(TICK);
+            }
+        });
+
+        // Polling rather than blocking on a key lets this notice the dashboard was closed
+        let dashboard = self.clone();
+        thread::spawn(move || {
+            while dashboard.lock().shown {
+                match event::poll(TICK) {
+                    Ok(false) => {},
+                    Ok(true) => match event::read() {
+                        Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => dashboard.key(key),
+                        Ok(_) => {},
+                        Err(_) => break,
+                    },
+                    Err(_) => break,
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Give the terminal back, once the summary is about to be printed
+    pub fn close(&self) {
+        let mut state = self.lock();
+        state.shown = false;
+        let _ = disable_raw_mode();
+        let _ = execute!(io::stderr(), LeaveAlternateScreen, Show);
+
+        // Lines logged between repositories were never shown next to one
+        for line in state.log.drain(..) {
+            eprintln!("{line}");
+        }
+    }
+
+    /// Whether q was pressed, so no more repositories should be started
+    pub fn quit_requested(&self) -> bool {
+        self.lock().decision == Some(Decision::Quit)
+    }
+
+    /// Start showing repository `index` as being processed, and select it
+    pub fn start(&self, index: usize) -> RepoProgress {
+        let progress = RepoProgress::hidden();
+        let mut state = self.lock();
+        state.current = Some(index);
+        state.select(index);
+        let row = &mut state.rows[index];
+        row.progress = Some(progress.clone());
+        row.outcome = None;
+        progress
+    }
+
+    /// Show how repository `index` ended. If it failed, wait for a key saying whether to retry it,
+    /// skip it, or quit.
+    pub fn finish(&self, index: usize, report: &RepoReport) -> Decision {
+        let mut state = self.lock();
+        state.current = None;
+        let row = &mut state.rows[index];
+        row.progress = None;
+        row.outcome = Some((report.status, report.reason.clone()));
+
+        if report.status != RepoStatus::Failed {
+            return Decision::Skip;
+        }
+        if state.decision == Some(Decision::Quit) {
+            return Decision::Quit;
+        }
+
+        state.select(index);
+        state.paused = Some(index);
+        state.decision = None;
+        let mut state = self.decided.wait_while(state, |state| state.decision.is_none()).unwrap();
+        state.paused = None;
+        match state.decision {
+            Some(Decision::Quit) => Decision::Quit,
+            decision => {
+                state.decision = None;
+                decision.unwrap_or(Decision::Skip)
+            },
+        }
+    }
+
+    /// Leave the finished run on screen until q is pressed, so logs can still be read
+    pub fn wait(&self) {
+        let mut state = self.lock();
+        state.finished = true;
+        let _state = self.decided.wait_while(state, |state| state.decision != Some(Decision::Quit)).unwrap();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn key(&self, key: KeyEvent) {
+        let mut state = self.lock();
+        match key.code {
+            // Raw mode delivers Ctrl-C as a key rather than a signal
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => state.decision = Some(Decision::Quit),
+            KeyCode::Up | KeyCode::Char('k') => {
+                let selected = state.selected.saturating_sub(1);
+                state.select(selected);
+            },
+            KeyCode::Down | KeyCode::Char('j') => {
+                let selected = (state.selected + 1).min(state.rows.len().saturating_sub(1));
+                state.select(selected);
+            },
+            KeyCode::PageUp => state.scroll += 10,
+            KeyCode::PageDown => state.scroll = state.scroll.saturating_sub(10),
+            KeyCode::Char('r') if state.paused.is_some() => state.decision = Some(Decision::Retry),
+            KeyCode::Char('s') if state.paused.is_some() => state.decision = Some(Decision::Skip),
+            KeyCode::Char('q') => state.decision = Some(Decision::Quit),
+            _ => return,
+        }
+        drop(state);
+        self.decided.notify_all();
+    }
+
+    /// Draw the whole screen, unless the dashboard was closed meanwhile
+    fn draw(&self, terminal: &mut Terminal<CrosstermBackend<Stderr>>) -> bool {
+        let state = self.lock();
+        if !state.shown {
+            return false;
+        }
+        let _ = terminal.draw(|frame| state.render(frame));
+        true
+    }
+}
+
+impl State {
+    fn select(&mut self, index: usize) {
+        if self.selected != index {
+            self.selected = index;
+            self.scroll = 0;
+        }
+    }
+
+    fn render(&self, frame: &mut Frame) {
+        let area = frame.area();
+        let (height, width) = (area.height as usize, area.width as usize);
+
+        // The list takes up to half the screen, scrolled to keep the selection in view
+        let list_height = self.rows.len().min(height.saturating_sub(4) / 2).max(1);
+        let [title, list, log, keys] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Length(list_height as u16),
+            Constraint::Fill(1),
+            Constraint::Length(1),
+        ]).areas(area);
+
+        let done = self.rows.iter().filter(|row| row.outcome.is_some()).count();
+        frame.render_widget(Line::from(format!("{} — {done}/{} repositories", self.title, self.rows.len())).bold(), title);
+
+        let name_width = self.rows.iter().map(|row| row.repository.len()).max().unwrap_or_default().min(width / 2);
+        let rows = self.rows.iter().map(|row| {
+            let status = match (&row.progress, &row.outcome) {
+                (Some(progress), _) => Span::raw(progress.status()).cyan(),
+                (None, Some((RepoStatus::Succeeded, _))) => Span::raw("succeeded").green(),
+                (None, Some((RepoStatus::Clean, _))) => Span::raw("clean").green(),
+                (None, Some((status, reason))) => Span::raw(format!("{status}: {}", reason.as_deref().unwrap_or_default())).red(),
+                (None, None) => Span::raw("waiting").dim(),
+            };
+            TableRow::new([Line::from(row.repository.as_str()), Line::from(status)])
+        });
+        let table = Table::new(rows, [Constraint::Length(name_width as u16), Constraint::Fill(1)]).highlight_symbol("> ");
+        frame.render_stateful_widget(table, list, &mut TableState::new().with_selected(self.selected));
+
+        let (name, lines) = match self.rows.get(self.selected) {
+            Some(row) => (row.repository.as_str(), &row.log),
+            None => ("the run", &self.log),
+        };
+        let block = Block::new().borders(Borders::TOP).title(format!(" log of {name} ")).dim();
+        let log_height = block.inner(log).height as usize;
+        let end = lines.len().saturating_sub(self.scroll.min(lines.len().saturating_sub(log_height)));
+        let text = lines[end.saturating_sub(log_height)..end].iter().map(|line| Line::from(line.as_str())).collect::<Vec<_>>();
+        frame.render_widget(Paragraph::new(text).block(block), log);
+
+        let hint = match (self.paused, self.finished) {
+            (Some(i), _) => format!("{} failed: r retry  s skip  q quit", self.rows[i].repository),
+            (None, true) => "Done: ↑↓ select  PgUp/PgDn scroll  q show the summary".to_string(),
+            (None, false) => "↑↓ select  PgUp/PgDn scroll  q quit after this repository".to_string(),
+        };
+        frame.render_widget(Paragraph::new(hint).style(Style::new().reversed()), keys);
+    }
+}
+
+/// Keeps log lines for the repository being processed, to show on the dashboard
+#[derive(Debug, Clone)]
+pub struct DashboardWriter(Arc<Dashboard>);
+
+impl Write for DashboardWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.0.lock();
+        if !state.shown {
+            return io::stderr().write(buf);
+        }
+
+        let text = String::from_utf8_lossy(buf);
+        let lines = strip_ansi(&text).lines().map(str::to_string).collect::<Vec<_>>();
+        match state.current {
+            Some(i) => state.rows[i].log.extend(lines),
+            None => state.log.extend(lines),
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// `text` without the escape sequences tracing colors its output with, which the dashboard styles itself
+fn strip_ansi(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' && chars.clone().next() == Some('[') {
+            // A control sequence ends at its first byte in @ to ~, after the [
+            chars.next();
+            chars.by_ref().find(|c| ('@'..='~').contains(c));
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_ansi_removes_colors() {
+        assert_eq!(strip_ansi("\x1b[2m2024-01-31\x1b[0m \x1b[32m INFO\x1b[0m Cloning"), "2024-01-31  INFO Cloning");
+        assert_eq!(strip_ansi("no colors [here]"), "no colors [here]");
+    }
+}
@@ -0,0 +1,84 @@
+use std::{collections::BTreeSet, path::{Path, PathBuf}};
+
+use eyre::{Context, Result};
+use git2::Repository;
+
+use crate::{config::Config, git::{git_dir, is_quarantined}};
+
+
+/// Protected identities that no longer appear in the history after rewriting
+#[derive(Debug, thiserror::Error)]
+#[error("Protected identities were modified by the rewrite: {}", .missing.join(", "))]
+pub struct PreservedIdentityError {
+    /// Every `Name <email> time` signature that was present before the rewrite but is gone now
+    pub missing: Vec<String>,
+}
+
+/// Where the protected identities seen before rewriting are kept, so the commit phase can check them too
+fn record_path(repo_dir: &Path) -> PathBuf {
+    git_dir(repo_dir).join("repo-cleaner-preserved.json")
+}
+
+/// Record every author and committer signature of a protected email, before the history is rewritten
+pub fn record(repo_dir: &Path, config: &Config) -> Result<()> {
+    if config.preserve_emails.is_empty() {
+        return Ok(());
+    }
+
+    let signatures = preserved_signatures(repo_dir, config)?;
+    let path = record_path(repo_dir);
+    std::fs::write(&path, serde_json::to_string_pretty(&signatures)?)
+        .wrap_err(format!("Unable to write protected identities to {path:?}"))
+}
+
+/// Check that every protected signature recorded before rewriting is still in the history, untouched
+pub fn check(repo_dir: &Path, config: &Config) -> Result<()> {
+    let path = record_path(repo_dir);
+    if config.preserve_emails.is_empty() || !path.exists() {
+        return Ok(());
+    }
+
+    let text = std::fs::read_to_string(&path)
+        .wrap_err(format!("Unable to read protected identities from {path:?}"))?;
+    let before: BTreeSet<String> = serde_json::from_str(&text)
+        .wrap_err(format!("Error reading protected identities from {path:?}"))?;
+    let after = preserved_signatures(repo_dir, config)?;
+
+    let missing = before.difference(&after).cloned().collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(PreservedIdentityError { missing }.into());
+    }
+
+    Ok(())
+}
+
+/// Every author and committer signature using a protected email, on any commit reachable from any ref
+fn preserved_signatures(repo_dir: &Path, config: &Config) -> Result<BTreeSet<String>> {
+    let normalize = |email: &str| if config.case_insensitive_emails { email.to_lowercase() } else { email.to_string() };
+    let preserved = config.preserve_emails.iter()
+        .map(|e| normalize(e))
+        .collect::<BTreeSet<_>>();
+
+    let repository = Repository::open(repo_dir)?;
+    let mut walk = repository.revwalk()?;
+    for reference in repository.references()?.filter_map(|r| r.ok()).filter(|r| !r.name().is_some_and(is_quarantined)) {
+        // Refs that don't lead to a commit, like tags of blobs, have no signatures to check
+        if let Ok(commit) = reference.peel_to_commit() {
+            walk.push(commit.id())?;
+        }
+    }
+
+    let mut signatures = BTreeSet::new();
+    for oid in walk {
+        let commit = repository.find_commit(oid?)?;
+        for signature in [commit.author(), commit.committer()] {
+            let email = String::from_utf8_lossy(signature.email_bytes());
+            if preserved.contains(&normalize(&email)) {
+                let name = String::from_utf8_lossy(signature.name_bytes());
+                signatures.insert(format!("{name} <{email}> {}", signature.when().seconds()));
+            }
+        }
+    }
+
+    Ok(signatures)
+}
@@ -0,0 +1,147 @@
+use std::{collections::HashSet, path::{Path, PathBuf}};
+
+use eyre::{Context, Result};
+use indicatif::HumanBytes;
+use tracing::info;
+
+use crate::{config::Config, gc::dir_size, git::git_dir, manifest::Manifest};
+
+
+/// What [`collect`] removed from a working directory
+#[derive(Debug, Default)]
+pub struct WorkdirGarbage {
+    /// Every file and directory removed, or that would be with a dry run
+    pub removed: Vec<PathBuf>,
+    /// How many bytes removing them freed
+    pub reclaimed: u64,
+}
+
+impl WorkdirGarbage {
+    fn remove(&mut self, path: &Path, dry_run: bool) -> Result<()> {
+        let metadata = std::fs::symlink_metadata(path)?;
+        let size = if metadata.is_dir() { dir_size(path)? } else { metadata.len() };
+        if !dry_run {
+            let result = if metadata.is_dir() { std::fs::remove_dir_all(path) } else { std::fs::remove_file(path) };
+            result.wrap_err(format!("Unable to remove {path:?}"))?;
+        }
+
+        info!("{} {path:?} ({})", if dry_run { "Would remove" } else { "Removed" }, HumanBytes(size));
+        self.removed.push(path.to_path_buf());
+        self.reclaimed += size;
+        Ok(())
+    }
+}
+
+/// Remove from the working directory `dir` whatever the repositories of `config` no longer need:
+/// the clones, backups, and migration notes of repositories that aren't configured anymore, the
+/// lock files runs that died left in clones, and what git-filter-repo leaves in `.git/filter-repo/`.
+/// The caller must hold the [`RunLock`](crate::RunLock) on `dir`. With `dry_run`, nothing is
+/// removed, only reported.
+pub fn collect(dir: &Path, config: &Config, dry_run: bool) -> Result<WorkdirGarbage> {
+    let configured = config.repositories.iter().map(|r| r.dir_name()).collect::<HashSet<_>>();
+    let mut garbage = WorkdirGarbage::default();
+
+    let repos = dir.join("repos");
+    let mut clones = Vec::new();
+    orphans(&repos, &repos, &configured, false, &mut clones)?;
+    for (path, orphaned) in clones {
+        if orphaned {
+            garbage.remove(&path, dry_run)?;
+        } else {
+            leftovers(&git_dir(&path), &mut garbage, dry_run)?;
+        }
+    }
+
+    let migration = dir.join("migration");
+    let mut notes = Vec::new();
+    orphans(&migration, &migration, &configured, false, &mut notes)?;
+    let backups = dir.join("backups");
+    let mut archives = Vec::new();
+    orphans(&backups, &backups, &configured, true, &mut archives)?;
+    for (path, _) in notes.into_iter().chain(archives).filter(|(_, orphaned)| *orphaned) {
+        garbage.remove(&path, dry_run)?;
+    }
+
+    if !dry_run && dir.join("manifest.json").exists() {
+        let mut manifest = Manifest::load(dir)?;
+        let recorded = manifest.repositories().map(str::to_string).collect::<Vec<_>>();
+        for repository in recorded {
+            if !config.repositories.iter().any(|r| r.name == repository) {
+                manifest.remove(&repository)?;
+                info!("Removed {repository} from the manifest, since it isn't configured");
+            }
+        }
+    }
+
+    Ok(garbage)
+}
+
+/// Find every repository's entry under `root`, the directory `dir` is in, where repositories are
+/// laid out by [`RepositoryConfig::dir_name`](crate::RepositoryConfig::dir_name), as directories
+/// or, for `backups`, as files with a backup's extension. Entries are paired with whether they are
+/// orphaned, belonging to no repository in `configured`.
+fn orphans(root: &Path, dir: &Path, configured: &HashSet<PathBuf>, backups: bool, found: &mut Vec<(PathBuf, bool)>) -> Result<()> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Ok(());
+    };
+    for entry in entries {
+        let path = entry?.path();
+        let relative = path.strip_prefix(root)?;
+        let is_entry = match backups {
+            true => path.is_file() && configured.contains(&strip_backup_extension(relative)),
+            false => configured.contains(relative),
+        };
+
+        if is_entry {
+            found.push((path, false));
+        } else if path.is_dir() && configured.iter().any(|c| c.starts_with(relative) && c != relative) {
+            orphans(root, &path, configured, backups, found)?;
+        } else {
+            found.push((path, true));
+        }
+    }
+    Ok(())
+}
+
+/// `path` without the extension a backup has, if it has one
+fn strip_backup_extension(path: &Path) -> PathBuf {
+    let text = path.to_string_lossy();
+    [".tar.age", ".tar.gpg", ".tar", ".manifest.json"].into_iter()
+        .find_map(|extension| text.strip_suffix(extension))
+        .map_or_else(|| path.to_path_buf(), PathBuf::from)
+}
+
+/// Remove the lock files and git-filter-repo state left under the `.git` directory of a clone.
+/// Nothing else uses the clone while the run lock is held, so every lock in it is stale.
+fn leftovers(git_dir: &Path, garbage: &mut WorkdirGarbage, dry_run: bool) -> Result<()> {
+    let filter_repo = git_dir.join("filter-repo");
+    if filter_repo.exists() {
+        garbage.remove(&filter_repo, dry_run)?;
+    }
+
+    let mut locks = Vec::new();
+    find_locks(git_dir, &mut locks)?;
+    for lock in locks {
+        garbage.remove(&lock, dry_run)?;
+    }
+    Ok(())
+}
+
+fn find_locks(dir: &Path, locks: &mut Vec<PathBuf>) -> Result<()> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Ok(());
+    };
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            // Objects are never locked, and there are a lot of them
+            if entry.file_name() != "objects" {
+                find_locks(&path, locks)?;
+            }
+        } else if path.extension().is_some_and(|e| e == "lock") {
+            locks.push(path);
+        }
+    }
+    Ok(())
+}
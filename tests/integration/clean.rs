@@ -0,0 +1,241 @@
+use serde_json::{json, Value};
+
+use crate::fixture::{both, commit, history, identities, messages, Identity, Workspace, KEEP, NEW, OLD};
+
+
+fn substitutions() -> Value {
+    json!({ OLD.email: { "new_email": NEW.email, "new_author": NEW.name } })
+}
+
+#[test]
+fn rewrites_only_matched_identities() {
+    let workspace = Workspace::new();
+    let remote = workspace.remote("project", &[OLD, KEEP, OLD]);
+    let original = remote.head();
+    let config = workspace.config(json!({ "repositories": [remote.url()], "email_substitutions": substitutions() }));
+
+    assert!(workspace.run(&[config]).status.success());
+
+    let clone = workspace.clone_of("project");
+    assert_eq!(identities(&clone, "refs/heads/main"), [both(NEW), both(KEEP), both(NEW)].concat());
+    // Nothing is pushed before --commit
+    assert_eq!(remote.head(), original);
+
+    let report = workspace.report("clean");
+    assert_eq!(report["status"], "succeeded");
+    // The kept commit gets a new id too, since its parent changed
+    assert_eq!(report["commits_changed"], 3);
+    assert_eq!(report["rule_matches"][format!("email_substitutions.{}", OLD.email)], 2);
+}
+
+#[test]
+fn keeps_the_ids_of_history_before_the_start_commit() {
+    let workspace = Workspace::new();
+    let remote = workspace.remote("project", &[OLD, OLD, OLD]);
+    let original = history(&remote.open(), "refs/heads/main");
+    let config = workspace.config(json!({
+        "repositories": [{ "name": remote.url(), "start_commit": original[1].to_string() }],
+        "email_substitutions": substitutions(),
+    }));
+
+    assert!(workspace.run(&[config]).status.success());
+
+    let clone = workspace.clone_of("project");
+    let rewritten = history(&clone, "refs/heads/main");
+    assert_eq!(rewritten[0], original[0]);
+    assert!(rewritten[1..].iter().zip(&original[1..]).all(|(new, old)| new != old));
+    assert_eq!(identities(&clone, "refs/heads/main"), [both(OLD), both(NEW), both(NEW)].concat());
+}
+
+#[test]
+fn keeps_the_ids_of_history_before_since_but_not_after_until() {
+    let workspace = Workspace::new();
+    // The fixture's commits are a minute apart, from 2023-11-14T22:13:20Z
+    let remote = workspace.remote("project", &[OLD, OLD, OLD]);
+    let original = history(&remote.open(), "refs/heads/main");
+    let config = workspace.config(json!({
+        "repositories": [{ "name": remote.url(), "since": "2023-11-14T22:14:00Z", "until": "2023-11-14T22:15:00Z" }],
+        "email_substitutions": substitutions(),
+    }));
+
+    assert!(workspace.run(&[config]).status.success());
+
+    let clone = workspace.clone_of("project");
+    let rewritten = history(&clone, "refs/heads/main");
+    assert_eq!(rewritten[0], original[0]);
+    assert_ne!(rewritten[1], original[1]);
+    // Commits after "until" keep their identities, but not their ids, since their parents changed
+    assert_ne!(rewritten[2], original[2]);
+    assert_eq!(identities(&clone, "refs/heads/main"), [both(OLD), both(NEW), both(OLD)].concat());
+}
+
+#[test]
+fn rewrites_emails_with_quotes_and_backslashes() {
+    // A quoted local part may hold anything, but replacement emails can't have quotes, so the new name does
+    const QUOTED: Identity = Identity { name: "O'Brien", email: r#""o'brien\dev"@example.com"# };
+    let workspace = Workspace::new();
+    let remote = workspace.remote("project", &[QUOTED, KEEP]);
+    let config = workspace.config(json!({
+        "repositories": [remote.url()],
+        "email_substitutions": { QUOTED.email: { "new_email": r"o'brien\dev@example.com", "new_author": r#"Dev "O'Brien" \ Team"# } },
+    }));
+
+    assert!(workspace.run(&[config]).status.success());
+
+    let clone = workspace.clone_of("project");
+    let rewritten = r#"Dev "O'Brien" \ Team <o'brien\dev@example.com>"#.to_string();
+    assert_eq!(identities(&clone, "refs/heads/main"), [[rewritten.clone(), rewritten], both(KEEP)].concat());
+}
+
+#[test]
+fn fills_replacement_templates_from_the_matched_identity() {
+    let workspace = Workspace::new();
+    let remote = workspace.remote("project", &[OLD, KEEP]);
+    let config = workspace.config(json!({
+        "repositories": [remote.url()],
+        "email_substitutions": { OLD.email: { "new_email": "{local}@newcorp.com", "new_author": "{first} {last_initial}. {{{domain}}}" } },
+        "trailers": ["Signed-off-by: {name} <{email}>"],
+    }));
+
+    assert!(workspace.run(&[config]).status.success());
+
+    let clone = workspace.clone_of("project");
+    let rewritten = "Old N. {example.com} <old@newcorp.com>".to_string();
+    assert_eq!(identities(&clone, "refs/heads/main"), [[rewritten.clone(), rewritten], both(KEEP)].concat());
+    // Trailers see the author as the templates filled it in
+    assert_eq!(messages(&clone, "refs/heads/main"), [
+        "Commit 0\n\nSigned-off-by: Old N. {example.com} <old@newcorp.com>\n",
+        "Commit 1\n\nSigned-off-by: Kept Name <keep@example.com>\n",
+    ]);
+}
+
+#[test]
+fn adds_trailers_to_the_trailer_block_of_each_message() {
+    let workspace = Workspace::new();
+    let remote = workspace.remote("project", &[OLD]);
+    let repository = remote.open();
+    commit(&repository, OLD, "Fix the build\n\nReviewed-by: Alex <alex@example.com>");
+    commit(&repository, OLD, "Release\n\nHistory-Rewritten-By: repo_cleaner\n");
+    commit(&repository, KEEP, "Keep\n\nSigned-off-by: Kept Name <keep@example.com>\n");
+    let config = workspace.config(json!({
+        "repositories": [remote.url()],
+        "email_substitutions": substitutions(),
+        "trailers": ["History-Rewritten-By: repo_cleaner", "Signed-off-by: {name} <{email}>"],
+    }));
+
+    assert!(workspace.run(&[config]).status.success());
+
+    let clone = workspace.clone_of("project");
+    assert_eq!(messages(&clone, "refs/heads/main"), [
+        // A message without a trailer block gets one of its own
+        "Commit 0\n\nHistory-Rewritten-By: repo_cleaner\nSigned-off-by: New Name <new@example.com>\n",
+        // Otherwise the trailers join the message's own, which are all kept
+        "Fix the build\n\nReviewed-by: Alex <alex@example.com>\nHistory-Rewritten-By: repo_cleaner\nSigned-off-by: New Name <new@example.com>\n",
+        // Trailers a message already has aren't added again
+        "Release\n\nHistory-Rewritten-By: repo_cleaner\nSigned-off-by: New Name <new@example.com>\n",
+        "Keep\n\nSigned-off-by: Kept Name <keep@example.com>\nHistory-Rewritten-By: repo_cleaner\n",
+    ]);
+}
+
+#[test]
+fn backs_up_the_original_history() {
+    let workspace = Workspace::new();
+    let remote = workspace.remote("project", &[OLD, KEEP]);
+    let original = remote.head();
+    let config = workspace.config(json!({ "repositories": [remote.url()], "email_substitutions": substitutions() }));
+
+    assert!(workspace.run(&[config]).status.success());
+
+    let backups = workspace.cleaner_dir().join("backups/local");
+    assert!(backups.join("project.tar").is_file());
+    let manifest: Value = serde_json::from_str(&std::fs::read_to_string(backups.join("project.manifest.json")).unwrap()).unwrap();
+    assert_eq!(manifest["refs"]["refs/heads/main"], original.to_string());
+    assert!(workspace.run(&["verify-backup", config]).status.success());
+}
+
+#[test]
+fn leaves_unmatched_repositories_alone() {
+    let workspace = Workspace::new();
+    let remote = workspace.remote("project", &[KEEP, KEEP]);
+    let config = workspace.config(json!({ "repositories": [remote.url()], "email_substitutions": substitutions() }));
+
+    assert!(workspace.run(&[config]).status.success());
+
+    assert_eq!(workspace.report("clean")["status"], "clean");
+    let clone = workspace.clone_of("project");
+    assert_eq!(clone.refname_to_id("refs/heads/main").unwrap(), remote.head());
+}
+
+#[test]
+fn trailers_alone_leave_unmatched_repositories_alone() {
+    let workspace = Workspace::new();
+    let remote = workspace.remote("project", &[KEEP, KEEP]);
+    let config = workspace.config(json!({
+        "repositories": [remote.url()],
+        "email_substitutions": substitutions(),
+        "trailers": ["History-Rewritten-By: repo_cleaner {run_id}"],
+    }));
+
+    assert!(workspace.run(&[config]).status.success());
+
+    assert_eq!(workspace.report("clean")["status"], "clean");
+    let clone = workspace.clone_of("project");
+    assert_eq!(clone.refname_to_id("refs/heads/main").unwrap(), remote.head());
+}
+
+#[test]
+fn adds_trailers_to_repositories_a_rule_matches() {
+    let workspace = Workspace::new();
+    let remote = workspace.remote("project", &[OLD, KEEP]);
+    let config = workspace.config(json!({
+        "repositories": [remote.url()],
+        "email_substitutions": substitutions(),
+        "trailers": ["History-Rewritten-By: repo_cleaner"],
+    }));
+
+    assert!(workspace.run(&[config]).status.success());
+
+    let report = workspace.report("clean");
+    assert_eq!(report["status"], "succeeded");
+    assert_eq!(report["rule_matches"]["trailers"], 2);
+}
+
+#[test]
+fn cleans_several_repositories_at_once_in_a_fresh_working_directory() {
+    let workspace = Workspace::new();
+    let remotes = ["one", "two", "three", "four"].map(|name| workspace.remote(name, &[OLD, KEEP]));
+    let config = workspace.config(json!({
+        "repositories": remotes.iter().map(|r| r.url()).collect::<Vec<_>>(),
+        "email_substitutions": substitutions(),
+    }));
+
+    assert!(workspace.run(&[config, "--jobs", "4"]).status.success());
+
+    let summary = workspace.summary("clean");
+    let statuses = summary["repositories"].as_array().unwrap().iter()
+        .map(|r| r["status"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(statuses, ["succeeded"; 4]);
+    // Every worker signed its entry with the one key, or the manifest wouldn't load for --commit
+    let manifest: Value = serde_json::from_str(&std::fs::read_to_string(workspace.cleaner_dir().join("manifest.json")).unwrap()).unwrap();
+    assert_eq!(manifest["repositories"].as_object().unwrap().len(), 4);
+    assert!(!workspace.cleaner_dir().join("manifest.key.partial").exists());
+}
+
+#[test]
+fn stops_on_repositories_whose_submodules_cant_be_found_with_fail_fast() {
+    let workspace = Workspace::new();
+    let remote = workspace.remote("project", &[OLD]);
+    let missing = format!("file://{}", workspace.path().join("missing.git").display());
+    let config = workspace.config(json!({ "repositories": [missing, remote.url()], "email_substitutions": substitutions() }));
+
+    assert!(!workspace.run(&[config, "--fail-fast"]).status.success());
+
+    let summary = workspace.summary("clean");
+    let statuses = summary["repositories"].as_array().unwrap().iter()
+        .map(|r| r["status"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(statuses, ["failed", "skipped"]);
+    // Finding submodules never clones anything
+    assert!(!workspace.cleaner_dir().join("repos/local/project").exists());
+}
@@ -0,0 +1,189 @@
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+use git2::{Oid, Repository, Signature, Sort, Time};
+use serde_json::Value;
+use tempfile::TempDir;
+
+
+/// Who made a fixture commit, as both its author and committer
+#[derive(Debug, Clone, Copy)]
+pub struct Identity {
+    pub name: &'static str,
+    pub email: &'static str,
+}
+
+pub const OLD: Identity = Identity { name: "Old Name", email: "old@example.com" };
+pub const NEW: Identity = Identity { name: "New Name", email: "new@example.com" };
+pub const KEEP: Identity = Identity { name: "Kept Name", email: "keep@example.com" };
+
+/// A throwaway directory holding bare "remote" repositories and the working directory
+/// `repo_cleaner` runs in, removed when dropped
+pub struct Workspace {
+    dir: TempDir,
+}
+
+impl Workspace {
+    pub fn new() -> Self {
+        let found = Command::new("git").args(["filter-repo", "--version"]).output()
+            .is_ok_and(|output| output.status.success());
+        assert!(found, "git-filter-repo must be installed to run the integration tests");
+        Self { dir: TempDir::new().expect("unable to create a temporary directory") }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Create a bare repository called `name`, with a `main` branch of one commit by each of
+    /// `commits` in order, oldest first
+    pub fn remote(&self, name: &str, commits: &[Identity]) -> Remote {
+        let path = self.path().join(format!("{name}.git"));
+        let repository = Repository::init_bare(&path).expect("unable to create the remote");
+        let remote = Remote { path };
+        for (i, identity) in commits.iter().enumerate() {
+            commit(&repository, *identity, &format!("Commit {i}"));
+        }
+        repository.set_head("refs/heads/main").expect("unable to point HEAD at main");
+        remote
+    }
+
+    /// Write `config` as the config file, returning its name relative to the workspace
+    pub fn config(&self, config: Value) -> &'static str {
+        std::fs::write(self.path().join("config.json"), config.to_string()).expect("unable to write the config");
+        "config.json"
+    }
+
+    /// Run `repo_cleaner` in the workspace with `args`
+    pub fn run(&self, args: &[&str]) -> Output {
+        let output = Command::new(env!("CARGO_BIN_EXE_repo_cleaner"))
+            .args(args)
+            .current_dir(self.path())
+            .env("NO_COLOR", "1")
+            .output()
+            .expect("unable to run repo_cleaner");
+        if !output.status.success() {
+            eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+        }
+        output
+    }
+
+    /// Where `repo_cleaner` keeps its clones, backups, manifest, and summaries
+    pub fn cleaner_dir(&self) -> PathBuf {
+        self.path().join("cleaner")
+    }
+
+    /// The clone of the local repository called `name`
+    pub fn clone_of(&self, name: &str) -> Repository {
+        Repository::open(self.cleaner_dir().join("repos/local").join(name)).expect("the repository was never cloned")
+    }
+
+    /// The JSON summary of `phase`
+    pub fn summary(&self, phase: &str) -> Value {
+        let text = std::fs::read_to_string(self.cleaner_dir().join(format!("{phase}-summary.json"))).expect("no summary was written");
+        serde_json::from_str(&text).expect("the summary isn't JSON")
+    }
+
+    /// The report on the only repository in the summary of `phase`
+    pub fn report(&self, phase: &str) -> Value {
+        let summary = self.summary(phase);
+        let repositories = summary["repositories"].as_array().expect("the summary lists no repositories");
+        assert_eq!(repositories.len(), 1, "expected a single repository in {summary}");
+        repositories[0].clone()
+    }
+}
+
+/// A bare repository standing in for a code host
+pub struct Remote {
+    path: PathBuf,
+}
+
+impl Remote {
+    /// The `file://` URL it is cloned from
+    pub fn url(&self) -> String {
+        format!("file://{}", self.path.display())
+    }
+
+    pub fn open(&self) -> Repository {
+        Repository::open_bare(&self.path).expect("unable to open the remote")
+    }
+
+    /// The commit `main` points to
+    pub fn head(&self) -> Oid {
+        self.open().refname_to_id("refs/heads/main").expect("the remote has no main branch")
+    }
+}
+
+/// Add a commit by `identity` to `main` of `repository`, leaving any working tree as it is
+pub fn commit(repository: &Repository, identity: Identity, message: &str) -> Oid {
+    let parent = repository.find_reference("refs/heads/main").ok()
+        .and_then(|r| r.peel_to_commit().ok());
+    let count = parent.as_ref().map_or(0, |p| {
+        let mut walk = repository.revwalk().unwrap();
+        walk.push(p.id()).unwrap();
+        walk.count()
+    });
+
+    // Every commit changes a file, so none are pruned for being empty
+    let blob = repository.blob(format!("{message}\n").as_bytes()).unwrap();
+    let mut tree = repository.treebuilder(parent.as_ref().map(|p| p.tree().unwrap()).as_ref()).unwrap();
+    tree.insert(format!("file{count}.txt"), blob, 0o100644).unwrap();
+    let tree = repository.find_tree(tree.write().unwrap()).unwrap();
+
+    // Fixed times keep the history the same every run
+    let time = Time::new(1_700_000_000 + count as i64 * 60, 0);
+    let signature = Signature::new(identity.name, identity.email, &time).unwrap();
+    let parents = parent.iter().collect::<Vec<_>>();
+    repository.commit(Some("refs/heads/main"), &signature, &signature, message, &tree, &parents)
+        .expect("unable to commit")
+}
+
+/// Tag the commit `main` of `repository` points to as `name`, with an annotated tag by `identity`
+pub fn tag(repository: &Repository, name: &str, identity: Identity) -> Oid {
+    let target = repository.revparse_single("refs/heads/main").expect("the repository has no main branch");
+    let signature = Signature::new(identity.name, identity.email, &Time::new(1_800_000_000, 0)).unwrap();
+    repository.tag(name, &target, &signature, &format!("Release {name}"), false)
+        .expect("unable to tag")
+}
+
+/// The tagger of the annotated tag `name` of `repository`, as `Name <email>`
+pub fn tagger(repository: &Repository, name: &str) -> String {
+    let tag = repository.find_reference(&format!("refs/tags/{name}")).expect("no such tag")
+        .peel_to_tag().expect("the tag isn't annotated");
+    let tagger = tag.tagger().expect("the tag has no tagger");
+    format!("{} <{}>", tagger.name().unwrap(), tagger.email().unwrap())
+}
+
+/// Every author and committer on `branch` of `repository`, as `Name <email>`, oldest commit first
+pub fn identities(repository: &Repository, branch: &str) -> Vec<String> {
+    let mut walk = repository.revwalk().unwrap();
+    walk.push(repository.refname_to_id(branch).expect("no such branch")).unwrap();
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE).unwrap();
+    walk.flat_map(|oid| {
+        let commit = repository.find_commit(oid.unwrap()).unwrap();
+        [commit.author(), commit.committer()].map(|s| format!("{} <{}>", s.name().unwrap(), s.email().unwrap()))
+    }).collect()
+}
+
+/// The message of every commit on `branch` of `repository`, oldest first
+pub fn messages(repository: &Repository, branch: &str) -> Vec<String> {
+    history(repository, branch).into_iter()
+        .map(|oid| repository.find_commit(oid).unwrap().message().unwrap().to_string())
+        .collect()
+}
+
+/// Every commit on `branch` of `repository`, oldest first
+pub fn history(repository: &Repository, branch: &str) -> Vec<Oid> {
+    let mut walk = repository.revwalk().unwrap();
+    walk.push(repository.refname_to_id(branch).expect("no such branch")).unwrap();
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE).unwrap();
+    walk.map(Result::unwrap).collect()
+}
+
+/// `identity` as [`identities`] lists it, once as author and once as committer
+pub fn both(identity: Identity) -> [String; 2] {
+    let text = format!("{} <{}>", identity.name, identity.email);
+    [text.clone(), text]
+}
@@ -0,0 +1,6 @@
+//! End-to-end tests of the clean and commit phases, run against throwaway repositories with
+//! `cargo test --features integration`. They need git and git-filter-repo installed.
+
+mod clean;
+mod fixture;
+mod push;
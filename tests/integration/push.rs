@@ -0,0 +1,74 @@
+use serde_json::{json, Value};
+
+use crate::fixture::{both, commit, identities, tag, tagger, Workspace, KEEP, NEW, OLD};
+
+
+fn substitutions() -> Value {
+    json!({ OLD.email: { "new_email": NEW.email, "new_author": NEW.name } })
+}
+
+#[test]
+fn commit_pushes_the_rewritten_history() {
+    let workspace = Workspace::new();
+    let remote = workspace.remote("project", &[OLD, KEEP]);
+    let config = workspace.config(json!({ "repositories": [remote.url()], "email_substitutions": substitutions() }));
+
+    assert!(workspace.run(&[config]).status.success());
+    assert!(workspace.run(&[config, "--commit"]).status.success());
+
+    let cleaned = workspace.clone_of("project").refname_to_id("refs/heads/main").unwrap();
+    assert_eq!(remote.head(), cleaned);
+    assert_eq!(identities(&remote.open(), "refs/heads/main"), [both(NEW), both(KEEP)].concat());
+    assert_eq!(workspace.report("commit")["pushed"], true);
+}
+
+#[test]
+fn commit_pushes_the_rewritten_tags() {
+    let workspace = Workspace::new();
+    let remote = workspace.remote("project", &[OLD, KEEP]);
+    tag(&remote.open(), "v1.0", OLD);
+    let config = workspace.config(json!({ "repositories": [remote.url()], "email_substitutions": substitutions() }));
+
+    assert!(workspace.run(&[config]).status.success());
+    assert!(workspace.run(&[config, "--commit"]).status.success());
+
+    let pushed = remote.open();
+    assert_eq!(tagger(&pushed, "v1.0"), both(NEW)[0]);
+    let target = pushed.find_reference("refs/tags/v1.0").unwrap().peel_to_commit().unwrap().id();
+    assert_eq!(target, remote.head(), "the tag still points at the original history");
+}
+
+#[test]
+fn commit_refuses_branches_changed_since_cleaning() {
+    let workspace = Workspace::new();
+    let remote = workspace.remote("project", &[OLD, KEEP]);
+    let config = workspace.config(json!({ "repositories": [remote.url()], "email_substitutions": substitutions() }));
+
+    let original = remote.head();
+
+    assert!(workspace.run(&[config]).status.success());
+    commit(&workspace.clone_of("project"), OLD, "Committed after cleaning");
+    let output = workspace.run(&[config, "--commit"]);
+
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(remote.head(), original, "a branch that changed after cleaning was pushed");
+    assert_eq!(workspace.report("commit")["status"], "failed");
+}
+
+#[test]
+fn cleaning_pushed_history_again_changes_nothing() {
+    let workspace = Workspace::new();
+    let remote = workspace.remote("project", &[OLD, KEEP, OLD]);
+    let config = workspace.config(json!({ "repositories": [remote.url()], "email_substitutions": substitutions() }));
+
+    assert!(workspace.run(&[config]).status.success());
+    assert!(workspace.run(&[config, "--commit"]).status.success());
+    let pushed = remote.head();
+    assert!(workspace.run(&[config]).status.success());
+
+    let report = workspace.report("clean");
+    assert_eq!(report["status"], "succeeded");
+    assert_eq!(report["commits_changed"], 0);
+    assert_eq!(workspace.clone_of("project").refname_to_id("refs/heads/main").unwrap(), pushed);
+    assert_eq!(remote.head(), pushed);
+}